@@ -8,8 +8,7 @@
 #![no_std]
 extern crate alloc;
 use core::{arch::global_asm, panic::PanicInfo};
-use alloc::string::ToString;
-use lee_os::{kernel::{clock::clock_init, console::console_init, global::{gdt_init, tss_init}, interrupt::{self, interrupt_init}, process::process_init, ramdisk::ramdisk_init}, mm::{memory::init_memory, shmem::init_shmem}, printk};
+use lee_os::{kernel::{buffer::buffer_init, cmdline::{boot_command_line, parse_boot_params}, clock::clock_init, console::{_printk, console_init, LOGLEVEL_EMERG}, global::{gdt_init, tss_init}, interrupt::{self, interrupt_init}, kaslr::show_kaslr_state, multiboot::{boot_info, boot_magic, show_multiboot_info}, percpu::percpu_init, process::process_init, ramdisk::ramdisk_init, random::random_init, zram::zram_init}, mm::{kmemleak::kmemleak_init, memory::init_memory, shmem::init_shmem, slub::kmem_cache_init_late, vmalloc::vmalloc_init, vmscan::vmscan_init}, printk};
 use proc_macro::__init;
 
 
@@ -19,7 +18,7 @@ global_asm!(include_str!("../kernel/entry.asm"));
 #[panic_handler]
 pub fn panic(_info: &PanicInfo) -> !
 {
-    printk!("{:#?}\n", _info.to_string());
+    _printk(LOGLEVEL_EMERG, format_args!("Kernel panic - {}\n", _info));
     loop {
         
     }
@@ -32,11 +31,23 @@ fn kernel_init()
 {
     unsafe
     {
+        parse_boot_params();
         console_init();
+        printk!("Command line: {}\n", boot_command_line());
+        show_kaslr_state();
+        show_multiboot_info();
         gdt_init();
+        percpu_init(0);
+        random_init();
         interrupt_init();
-        init_memory(0, core::ptr::null());
+        init_memory(boot_magic(), boot_info());
+        vmscan_init();
+        kmem_cache_init_late();
+        kmemleak_init();
+        vmalloc_init();
+        buffer_init();
         ramdisk_init(); 
+        zram_init();
         init_shmem();
         tss_init();
         clock_init();
@@ -3,6 +3,7 @@ code_selector equ (1 << 3)
 data_selector equ (2 << 3)
 elf_header_pos equ 0x20000
 ards_buffer equ 0x7c00
+cmdline_buffer equ 0x1800; 紧跟在 loader 后面, 内核从这里取命令行
 memory_base equ 0; 内存开始的位置: 基地址
 ; 内存界限 4G / 4K - 1
 memory_limit equ ((1024 * 1024 * 1024 * 4) / (1024 * 4)) - 1
@@ -151,6 +152,10 @@ long_mode:
     mov gs, ax
     mov ss, ax; 初始化段寄存器
     mov esp, 0x90000
+    mov edi, cmdline_buffer
+    mov ecx, 1
+    mov bl, 1
+    call read_disk; 第 1 个扇区是内核命令行
     call load_system64_header
 	mov	dword	[0x90000],	0x91007
 	mov	dword	[0x90800],	0x93007
@@ -0,0 +1,193 @@
+// LZ4 block format, a sequence is a token, literals, a 2 byte offset and the match length
+// the high nibble of the token counts literals, the low nibble the match length above LZ4_MIN_MATCH
+// a nibble of 15 goes on in the following bytes, each 255 means more to come
+
+const LZ4_MIN_MATCH : usize = 4;
+const LZ4_HASH_LOG : usize = 12;
+const LZ4_MAX_DISTANCE : usize = 0xffff;
+// the last match starts this far before the end at least
+const LZ4_MFLIMIT : usize = 12;
+// and the last bytes are always literals
+const LZ4_LAST_LITERALS : usize = 5;
+const RUN_MASK : usize = 15;
+
+// the worst case output for `size` bytes that don't compress at all
+pub const fn lz4_compress_bound(size : usize) -> usize
+{
+    size + size / 255 + 16
+}
+
+#[inline(always)]
+unsafe fn read_u32(ptr : *const u8) -> u32
+{
+    (ptr as *const u32).read_unaligned()
+}
+
+#[inline(always)]
+fn lz4_hash(sequence : u32) -> usize
+{
+    (sequence.wrapping_mul(2654435761) >> (32 - LZ4_HASH_LOG)) as usize
+}
+
+// the extra length bytes once a nibble is full
+unsafe fn write_length(dst : *mut u8, op : &mut usize, len : usize)
+{
+    let mut len = len;
+    while len >= 255 {
+        *dst.add(*op) = 255;
+        *op += 1;
+        len -= 255;
+    }
+    *dst.add(*op) = len as u8;
+    *op += 1;
+}
+
+// one sequence, `offset` 0 leaves the match out for the final literals
+// false when it does not fit into the `dst_len` bytes of dst
+unsafe fn emit_sequence(dst : *mut u8, dst_len : usize, op : &mut usize, literals : *const u8, lit_len : usize, offset : usize, match_len : usize) -> bool
+{
+    if *op + 1 + lit_len + lit_len / 255 + 1 + 2 + match_len / 255 + 1 > dst_len
+    {
+        return false;
+    }
+    let token = *op;
+    *op += 1;
+    let mut tag = (lit_len.min(RUN_MASK) << 4) as u8;
+    if lit_len >= RUN_MASK
+    {
+        write_length(dst, op, lit_len - RUN_MASK);
+    }
+    compiler_builtins::mem::memcpy(dst.add(*op), literals, lit_len);
+    *op += lit_len;
+    if offset != 0
+    {
+        *dst.add(*op) = offset as u8;
+        *dst.add(*op + 1) = (offset >> 8) as u8;
+        *op += 2;
+        let len = match_len - LZ4_MIN_MATCH;
+        tag |= len.min(RUN_MASK) as u8;
+        if len >= RUN_MASK
+        {
+            write_length(dst, op, len - RUN_MASK);
+        }
+    }
+    *dst.add(token) = tag;
+    true
+}
+
+// compress `src_len` bytes of src into dst with greedy matching on a hash of the next 4 bytes
+// returns the compressed length, None when the result would not fit into `dst_len` bytes
+pub unsafe fn lz4_compress(src : *const u8, src_len : usize, dst : *mut u8, dst_len : usize) -> Option<usize>
+{
+    let mut table = [0u32; 1 << LZ4_HASH_LOG];
+    let mut anchor = 0;
+    let mut ip = 0;
+    let mut op = 0;
+    if src_len > LZ4_MFLIMIT
+    {
+        let match_limit = src_len - LZ4_MFLIMIT;
+        let extend_limit = src_len - LZ4_LAST_LITERALS;
+        while ip < match_limit {
+            let sequence = read_u32(src.add(ip));
+            let hash = lz4_hash(sequence);
+            let candidate = table[hash] as usize;
+            table[hash] = ip as u32;
+            // a stale slot still holds a valid position, the bytes decide
+            if candidate >= ip || ip - candidate > LZ4_MAX_DISTANCE || read_u32(src.add(candidate)) != sequence
+            {
+                ip += 1;
+                continue;
+            }
+            let mut match_len = LZ4_MIN_MATCH;
+            while ip + match_len < extend_limit && *src.add(candidate + match_len) == *src.add(ip + match_len) {
+                match_len += 1;
+            }
+            if !emit_sequence(dst, dst_len, &mut op, src.add(anchor), ip - anchor, ip - candidate, match_len)
+            {
+                return None;
+            }
+            ip += match_len;
+            anchor = ip;
+        }
+    }
+    if !emit_sequence(dst, dst_len, &mut op, src.add(anchor), src_len - anchor, 0, 0)
+    {
+        return None;
+    }
+    Some(op)
+}
+
+// the extra length bytes after a full nibble, None past the end of the input
+unsafe fn read_length(src : *const u8, src_len : usize, ip : &mut usize) -> Option<usize>
+{
+    let mut len = 0;
+    loop {
+        if *ip >= src_len
+        {
+            return None;
+        }
+        let byte = *src.add(*ip) as usize;
+        *ip += 1;
+        len += byte;
+        if byte != 255
+        {
+            return Some(len);
+        }
+    }
+}
+
+// decompress the `src_len` bytes of src into at most `dst_len` bytes of dst
+// returns the decompressed length, None for a malformed block
+pub unsafe fn lz4_decompress(src : *const u8, src_len : usize, dst : *mut u8, dst_len : usize) -> Option<usize>
+{
+    let mut ip = 0;
+    let mut op = 0;
+    loop {
+        if ip >= src_len
+        {
+            return None;
+        }
+        let token = *src.add(ip) as usize;
+        ip += 1;
+        let mut lit_len = token >> 4;
+        if lit_len == RUN_MASK
+        {
+            lit_len += read_length(src, src_len, &mut ip)?;
+        }
+        if ip + lit_len > src_len || op + lit_len > dst_len
+        {
+            return None;
+        }
+        compiler_builtins::mem::memcpy(dst.add(op), src.add(ip), lit_len);
+        ip += lit_len;
+        op += lit_len;
+        // the last sequence ends with its literals
+        if ip == src_len
+        {
+            return Some(op);
+        }
+        if ip + 2 > src_len
+        {
+            return None;
+        }
+        let offset = *src.add(ip) as usize | (*src.add(ip + 1) as usize) << 8;
+        ip += 2;
+        let mut match_len = token & RUN_MASK;
+        if match_len == RUN_MASK
+        {
+            match_len += read_length(src, src_len, &mut ip)?;
+        }
+        match_len += LZ4_MIN_MATCH;
+        if offset == 0 || offset > op || op + match_len > dst_len
+        {
+            return None;
+        }
+        // the match may overlap the bytes it produces, copy it forward byte by byte
+        let mut var = 0;
+        while var < match_len {
+            *dst.add(op + var) = *dst.add(op + var - offset);
+            var += 1;
+        }
+        op += match_len;
+    }
+}
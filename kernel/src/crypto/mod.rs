@@ -1,5 +1,6 @@
 pub mod crc32c;
 pub mod crc16;
+pub mod lz4;
 mod crc32table;
 
 #[macro_export]
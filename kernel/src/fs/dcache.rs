@@ -1,11 +1,15 @@
-use core::{alloc::Layout, ffi::c_char, intrinsics::unlikely, mem::ManuallyDrop, ptr::{self, addr_of, addr_of_mut, null_mut}, sync::atomic::AtomicI64};
+use core::{alloc::Layout, ffi::c_char, intrinsics::unlikely, mem::ManuallyDrop, ptr::{self, addr_of, addr_of_mut, null_mut}, sync::atomic::{AtomicI64, Ordering}};
 use alloc::{collections::BTreeMap, string::{String, ToString}};
 
-use crate::kernel::{errno_base::{err_ptr, EBUSY, ENOENT, ENOSPC}, semaphore::RWLock, Err};
+use crate::{kernel::{errno_base::{err_ptr, EBUSY, ENOENT, ENOSPC}, semaphore::RWLock, Err}, mm::vmscan::{register_shrinker, Shrinker}};
 
 use super::{file::{FileMode, LogicalPart, FS}, inode::Inode, mount::{Mount, __lookup_mnt}, path::Path};
 
 
+// dentries in every tree, the roots included
+pub static mut NR_DENTRY : usize = 0;
+static DCACHE_SHRINKER : Shrinker = Shrinker { name: "dcache", count_objects: dcache_shrink_count, scan_objects: dcache_shrink_scan };
+
 pub type RevalidateFunc = fn(*mut DEntry, u32) -> i64;
 pub type HashFunc = fn(&DEntry, &QStr) -> i64;
 pub type CompareFunc = fn(&DEntry, u32, &c_char, &QStr) -> i64;
@@ -112,6 +116,11 @@ impl DEntry
         }
     }
 
+    pub fn name(&self) -> &str
+    {
+        &self.name
+    }
+
     pub fn get_parent(&mut self) -> *mut Self
     {
         self.d_parent
@@ -170,6 +179,7 @@ impl DEntry
             if prev == 1
             {
                 (*self.d_parent).d_children.remove(&self.name);
+                NR_DENTRY -= 1;
                 ptr::drop_in_place(self);
                 alloc::alloc::dealloc(self as *mut Self as *mut u8, Layout::new::<Self>());
             }
@@ -186,6 +196,7 @@ impl DEntry
                 return err_ptr(-ENOSPC);
             }
             (*ptr) = Self { d_seq: RWLock::new(), d_parent: parent, d_inode: null_mut(), d_children: BTreeMap::new(), d_ref: AtomicI64::new(1), d_op: null_mut(), d_sb: null_mut(), d_flags: DEntryFlags::empty(), name: String::new() };
+            NR_DENTRY += 1;
             ptr
          }
     }   
@@ -195,7 +206,13 @@ impl DEntry
         unsafe
         {
             self.d_seq.rdlock();
+            let mut stale = null_mut();
             let result = match self.d_children.get(name) {
+                Some(child) if !Self::d_revalidate(*child) =>
+                {
+                    stale = *child;
+                    null_mut()
+                },
                 Some(child) => 
                 {
                     path.dentry = *child;
@@ -247,10 +264,84 @@ impl DEntry
                 }
             };
             self.d_seq.rdunlock();
+            if !stale.is_null() && !self.d_invalidate(stale)
+            {
+                return null_mut();
+            }
+            if result.is_null() && !self.d_inode.is_null() && (*self.d_inode).is_dir()
+            {
+                return self.lookup_slow(name, path);
+            }
             result
         }
     }
 
+    // entries of file systems whose names come and go, like /proc/<pid>, are checked before use
+    fn d_revalidate(dentry : *mut DEntry) -> bool
+    {
+        unsafe
+        {
+            let op = (*dentry).d_op;
+            match if op.is_null() { None } else { (*op).d_revalidate } {
+                Some(revalidate) => revalidate(dentry, 0) > 0,
+                None => true
+            }
+        }
+    }
+
+    // drop a child nobody else holds along with its unused entries, false when it is still in use
+    fn d_invalidate(&mut self, child : *mut DEntry) -> bool
+    {
+        unsafe
+        {
+            (*child).prune_unused(usize::MAX);
+            self.d_seq.wrlock();
+            if (*child).d_ref.load(Ordering::Acquire) != 1 || !(*child).d_children.is_empty() || (*child).d_flags.contains(DEntryFlags::MOUNTED)
+            {
+                self.d_seq.wrunlock();
+                return false;
+            }
+            self.d_children.remove(&(*child).name);
+            self.d_ref.fetch_sub(1, Ordering::AcqRel);
+            self.d_seq.wrunlock();
+            if !(*child).d_inode.is_null()
+            {
+                (*(*child).d_sb).release_inode((*child).d_inode);
+            }
+            NR_DENTRY -= 1;
+            ptr::drop_in_place(child);
+            alloc::alloc::dealloc(child as *mut u8, Layout::new::<Self>());
+            true
+        }
+    }
+
+    // the cache does not know `name`, ask the directory's inode, file systems without a disk make up their entries here
+    // lookup fills in the new dentry, or hands back another one the name stands for
+    fn lookup_slow(&mut self, name : &str, path : &mut Path) -> *mut DEntry
+    {
+        unsafe
+        {
+            let ops = (*self.d_inode).i_operations;
+            let lookup = match if ops.is_null() { None } else { (*ops).lookup } {
+                Some(lookup) => lookup,
+                None => return null_mut()
+            };
+            let child = self.new_child(&String::from(name));
+            let alias = lookup(self.d_inode, child, 0);
+            if (*child).d_inode.is_null()
+            {
+                self.d_invalidate(child);
+                if !alias.is_null()
+                {
+                    path.dentry = alias;
+                }
+                return alias;
+            }
+            path.dentry = child;
+            child
+        }
+    }
+
     pub fn new_child(&mut self, name : &String) -> *mut Self
     {
         unsafe
@@ -271,4 +362,70 @@ impl DEntry
     {
         self.d_flags.contains(DEntryFlags::CANT_MOUNT)
     }
+
+    // only the cache holds it and nothing hangs below it
+    // directories stay, a working directory takes no reference
+    fn unused_leaf(dentry : *mut DEntry) -> bool
+    {
+        unsafe
+        {
+            (*dentry).d_ref.load(Ordering::Acquire) == 1 && (*dentry).d_children.is_empty() && !(*dentry).d_flags.contains(DEntryFlags::MOUNTED)
+            && !(*dentry).d_seq.is_locked() && !(*dentry).d_inode.is_null() && !(*(*dentry).d_inode).is_dir()
+        }
+    }
+
+    // forget the loaded entries of directories below this one nobody is using,
+    // the next look_up reads them back from disk. returns the dentries freed
+    pub fn prune_unused(&mut self, nr_to_scan : usize) -> usize
+    {
+        unsafe
+        {
+            // an allocation under this lock is reclaiming from inside look_up or new_child
+            if self.d_seq.is_locked()
+            {
+                return 0;
+            }
+            let mut freed = 0;
+            for child in self.d_children.values() {
+                if freed >= nr_to_scan
+                {
+                    return freed;
+                }
+                if !(**child).d_children.is_empty()
+                {
+                    freed += (**child).prune_unused(nr_to_scan - freed);
+                }
+            }
+            // all or nothing, a partly loaded directory would hide the rest of it
+            if freed >= nr_to_scan || self.d_children.is_empty() || !self.d_children.values().all(|child| Self::unused_leaf(*child))
+            {
+                return freed;
+            }
+            let children = core::mem::take(&mut self.d_children);
+            for child in children.into_values() {
+                (*(*child).d_sb).release_inode((*child).d_inode);
+                ptr::drop_in_place(child);
+                alloc::alloc::dealloc(child as *mut u8, Layout::new::<Self>());
+                self.d_ref.fetch_sub(1, Ordering::AcqRel);
+                NR_DENTRY -= 1;
+                freed += 1;
+            }
+            freed
+        }
+    }
+}
+
+fn dcache_shrink_count() -> usize
+{
+    unsafe { NR_DENTRY }
+}
+
+fn dcache_shrink_scan(nr_to_scan : usize) -> usize
+{
+    unsafe { FS.prune_dcache(nr_to_scan) }
+}
+
+pub fn dcache_init()
+{
+    register_shrinker(&DCACHE_SHRINKER);
 }
@@ -3,9 +3,9 @@ use core::{alloc::Layout, cmp::min, ffi::{c_char, c_void}, mem::{offset_of, size
 use alloc::{alloc::{alloc, dealloc}, collections::BTreeMap, string::{String, ToString}, vec::Vec};
 use proc_macro::__init;
 
-use crate::{crypto::{crc16::crc16, crc32c::{crc32c_le, reverse32, reverse8}}, fs::file::{FSType, EOF, FS}, kernel::{bitmap::BitMap, buffer::{self, Buffer}, device::{device_ioctl, DevT, DEV_CMD_SECTOR_COUNT}, errno_base::EINVAL, io::SECTOR_SIZE, math::{self, log2, pow}, sched::get_current_running_process, string::{memset, EOS}, time::sys_time, Err}, mm::memory::PAGE_SIZE};
+use crate::{crypto::{crc16::crc16, crc32c::{crc32c_le, reverse32, reverse8}}, fs::file::{FSType, EOF, FS}, kernel::{bitmap::BitMap, buffer::{self, Buffer, BUFFER_CACHE}, device::{device_ioctl, DevT, DEV_CMD_SECTOR_COUNT}, errno_base::EINVAL, io::SECTOR_SIZE, math::{self, log2, pow}, sched::get_current_running_process, string::{memset, EOS}, time::sys_time, Err}, mm::{filemap::filemap_read, memory::PAGE_SIZE, page::{Page, Pageflags}, page_alloc::page_address}};
 
-use super::{dcache::DEntry, dev::{new_decode_dev, old_decode_dev}, file::{disk_read, early_disk_read, DirEntry, FSPermission, FileMode, FileSystem, LogicalPart}, fs::{FileSystemType, FileSystemFlags}, fs_context::{self, FsContext, FsContextOperations}, inode::Inode, namei::namei, super_block::get_tree_bdev};
+use super::{dcache::DEntry, dev::{new_decode_dev, old_decode_dev}, file::{disk_read, early_disk_read, DirEntry, FSPermission, FileFlag, FileMode, FileSystem, LogicalPart}, fs::{AddressSpace, AddressSpaceOperations, FileSystemType, FileSystemFlags}, fs_context::{self, FsContext, FsContextOperations}, inode::Inode, namei::namei, super_block::get_tree_bdev};
 
 
 
@@ -26,6 +26,8 @@ const EXT4_DIRECT_BLOCK : u64 = 12;
 const EXT4_INDIRECT1_BLOCK : u64 = 12 + 1024;
 const EXT4_INDIRECT2_BLOCK : u64 = 12 + 1024 * 1024;
 const EXT4_INDIRECT3_BLOCK : u64 = 12 + 1024 * 1024 * 1024;
+// an extent longer than this is unwritten and its length is that much less
+const EXT_INIT_MAX_LEN : Idx = 1 << 15;
 
 const RO_COMPAT_SPARSE_SUPER : i32 = 0x1;
 const RO_COMPAT_LARGE_FILE : i32 = 0x2;
@@ -128,7 +130,7 @@ impl Ext4GroupDescInfo {
                     ext4_load_block_bitmap(self);
                 },
                 FSType::None => panic!("unknow filesystem!"),
-                FSType::Shmem => todo!(),
+                FSType::Shmem | FSType::Proc => todo!(),
             }
 
         }
@@ -365,6 +367,17 @@ pub fn ext4_fill_super(lp : *mut LogicalPart, fs_context : *mut FsContext) -> Er
     }
 }
 
+// the block goes into the buffer cache dirty, it reaches the device with the next writeback
+fn ext4_write_block(dev : DevT, idx : Idx, src : *mut c_void, blocks : usize)
+{
+    unsafe
+    {
+        let buffer = BUFFER_CACHE.getblk(dev, idx, blocks * SECTOR_SIZE as usize);
+        (*buffer).write_to_buffer(src, 0, blocks * SECTOR_SIZE as usize);
+        BUFFER_CACHE.brelse(buffer);
+    }
+}
+
 pub fn ext4_flax_group_init(dev : DevT, sb : *mut Ext4SuperBlock)
 {
     unsafe
@@ -435,22 +448,22 @@ pub fn ext4_flax_group_init(dev : DevT, sb : *mut Ext4SuperBlock)
             if (group_no % 64) == 63
             {
                 // sync to device
-                (*group_desc_buffer).write_to_device(dev, 1 * group_bach as u64, group_bach);
+                ext4_write_block(dev, 1 * group_bach as u64, (*group_desc_buffer).buffer, group_bach);
 
-                (*group_desc_buffer).write_to_device(dev, 32768 * group_bach as u64, group_bach);
+                ext4_write_block(dev, 32768 * group_bach as u64, (*group_desc_buffer).buffer, group_bach);
                 let mut back_up_blocks = 3;
                 while back_up_blocks < group_number {
-                    (*group_desc_buffer).write_to_device(dev, back_up_blocks as Idx * 32768 * group_bach as u64, group_bach);
+                    ext4_write_block(dev, back_up_blocks as Idx * 32768 * group_bach as u64, (*group_desc_buffer).buffer, group_bach);
                     back_up_blocks *= 3;
                 }
                 let mut back_up_blocks = 5;
                 while back_up_blocks < group_number {
-                    (*group_desc_buffer).write_to_device(dev, back_up_blocks as Idx * 32768 * group_bach as u64, group_bach);
+                    ext4_write_block(dev, back_up_blocks as Idx * 32768 * group_bach as u64, (*group_desc_buffer).buffer, group_bach);
                     back_up_blocks *= 5;
                 }
                 let mut back_up_blocks = 7;
                 while back_up_blocks < group_number {
-                    (*group_desc_buffer).write_to_device(dev, back_up_blocks as Idx * 32768 * group_bach as u64, group_bach);
+                    ext4_write_block(dev, back_up_blocks as Idx * 32768 * group_bach as u64, (*group_desc_buffer).buffer, group_bach);
                     back_up_blocks *= 7;
                 }
             }
@@ -537,8 +550,9 @@ pub fn devmkfs(dev : DevT, mut icount : usize)
         ext4_flax_group_init(dev, sb);
 
 
-        (*buf).write_to_device(dev, 0, SECTOR_SIZE as usize * pow(2.0, (*sb).s_log_block_size as f64) as usize);
+        ext4_write_block(dev, 0, (*buf).buffer, 2 * pow(2.0, (*sb).s_log_block_size as f64) as usize);
         (*buf).dispose();
+        BUFFER_CACHE.writeback(Some(dev), 0);
     }
 }
 
@@ -607,20 +621,16 @@ fn get_logic_block(logical_part : &mut LogicalPart, inode : *mut Inode, idx : Id
 {
     unsafe
     {
-        let mut nr = idx;
-        let mut dst_block = (&mut (*((*inode).inode_desc_ptr as *mut Ext4Inode)).i_block) as *mut i32;
-        
-        loop {
-            if level == 0
-            {
-                return *dst_block.offset(nr as isize) as u64;
-            }
-            let buffer = logical_part.get_buffer(nr);
-            (*buffer).read_from_device(logical_part.s_dev, *dst_block.offset(nr as isize) as Idx, logical_part.logic_block_size as usize * 1024);
-            dst_block = (*buffer).buffer as *mut i32;
-            nr = *dst_block.offset(nr as isize) as Idx;
+        let i_block = (&mut (*((*inode).inode_desc_ptr as *mut Ext4Inode)).i_block) as *mut i32;
+        let mut block = *i_block.offset(idx as isize) as u32 as Idx;
+        // each level copies the next block number out and lets its buffer go again
+        while level > 0 {
+            let buffer = logical_part.read_block(block as usize);
+            block = *((*buffer).buffer as *mut i32).offset(idx as isize) as u32 as Idx;
+            logical_part.release_buffer(buffer);
             level -= 1;
         }
+        block
     }
 }
 
@@ -633,58 +643,78 @@ fn ext4_get_data_block_group_idx(logical_part : &LogicalPart, idx : Idx) -> usiz
     }
 }
 
-pub fn ext4_get_logic_block_idx(logical_part : &mut LogicalPart, inode : *mut Inode, idx : Idx, create : bool) -> Idx
+// physical block behind logical block `idx` of an extent mapped inode, 0 in a hole
+// block 0 holds the boot block and the superblock, it is never file data
+pub fn ext4_bmap(logical_part : &mut LogicalPart, inode : *mut Inode, idx : Idx) -> Idx
 {
     unsafe
     {
         let ext4_inode = (*inode).inode_desc_ptr as *mut Ext4Inode;
-        let block_desc_node = &mut (*ext4_inode).i_block as *mut i32 as *mut Ext4InodeExtentDesc;
-        assert!((*block_desc_node).head.eh_magic as u16 == 0xf30a);
+        let mut head = &mut (*ext4_inode).i_block as *mut i32 as *mut Ext4ExtentHeader;
+        assert!((*head).eh_magic as u16 == 0xf30a);
+        let mut buff : *mut Buffer = null_mut();
         loop {
-            if (*block_desc_node).head.eh_depth == 0
+            let entries = (*head).eh_entries as usize;
+            let node = head.add(1) as *mut Ext4ExtentDescTreeNode;
+            if (*head).eh_depth == 0
             {
+                let mut result = 0;
                 let mut var = 0;
-                while var < 4 {
-                    if (*block_desc_node).node[var].leaf_node.ee_block as u64 <= idx && ((*block_desc_node).node[var].leaf_node.ee_block as u64 + (*block_desc_node).node[var].leaf_node.ee_len as u64) > idx
+                while var < entries {
+                    let extent = &(*node.add(var)).leaf_node;
+                    let mut len = extent.ee_len as u16 as Idx;
+                    if len > EXT_INIT_MAX_LEN
                     {
-                        return (*block_desc_node).node[var].leaf_node.ee_start_lo as Idx + (((*block_desc_node).node[var].leaf_node.ee_start_hi as Idx) << 32) + idx - (*block_desc_node).node[var].leaf_node.ee_block as Idx;
+                        len -= EXT_INIT_MAX_LEN;
                     }
-                    var += 1;
-                }
-                panic!("read file block out of range!\n");
-            }
-            else {
-                let mut buff = null_mut();
-                let mut var = 0;
-                let mut extent_block = null_mut();
-                while var < ((*block_desc_node).head.eh_entries - 1) as usize {
-                    if (*block_desc_node).node[var + 1].nonleaf.ei_block as Idx > idx
+                    if (extent.ee_block as u32 as Idx) <= idx && idx < extent.ee_block as u32 as Idx + len
                     {
-                        let dst_block = (((*block_desc_node).node[var].nonleaf.ei_leaf_hi as Idx) << 32) + ((*block_desc_node).node[var].nonleaf.ei_leaf_lo as Idx);
-                        buff = logical_part.read_block(dst_block as usize);
-                        extent_block = (*buff).buffer as *mut Ext4ExtentBlock;
+                        result = extent.ee_start_lo as u32 as Idx + ((extent.ee_start_hi as u16 as Idx) << 32) + idx - extent.ee_block as u32 as Idx;
                         break;
                     }
                     var += 1;
                 }
-                while !extent_block.is_null() && (*extent_block).head.eh_depth != 0 {
-                    var = 0;
-                    while ((*extent_block).node[var].nonleaf.ei_block as Idx) < idx {
-                        let dst_block = (((*extent_block).node[var].nonleaf.ei_leaf_hi as Idx) << 32) + ((*extent_block).node[var].nonleaf.ei_leaf_lo as Idx);
-                        logical_part.release_buffer(buff, idx);
-                        buff = logical_part.read_block(dst_block as usize);
-                        extent_block = (*buff).buffer as *mut Ext4ExtentBlock;
-                    }
+                if !buff.is_null()
+                {
+                    logical_part.release_buffer(buff);
                 }
-                while ((*extent_block).node[var].leaf_node.ee_block as Idx + (*extent_block).node[var].leaf_node.ee_len as Idx) > idx {
-                    logical_part.release_buffer(buff, idx);
-                    return (*extent_block).node[var].leaf_node.ee_start_lo as Idx + (((*extent_block).node[var].leaf_node.ee_start_hi as Idx) << 32) + idx - (*extent_block).node[var].leaf_node.ee_block as Idx;
+                return result;
+            }
+            // the last index starting at or before `idx` covers it
+            let mut var = 0;
+            while var + 1 < entries && (*node.add(var + 1)).nonleaf.ei_block as u32 as Idx <= idx {
+                var += 1;
+            }
+            if entries == 0 || (*node.add(var)).nonleaf.ei_block as u32 as Idx > idx
+            {
+                if !buff.is_null()
+                {
+                    logical_part.release_buffer(buff);
                 }
+                return 0;
             }
+            let index = &(*node.add(var)).nonleaf;
+            let dst_block = ((index.ei_leaf_hi as u16 as Idx) << 32) + index.ei_leaf_lo as u32 as Idx;
+            if !buff.is_null()
+            {
+                logical_part.release_buffer(buff);
+            }
+            buff = logical_part.read_block(dst_block as usize);
+            head = (*buff).buffer as *mut Ext4ExtentHeader;
         }
     }
 }
 
+pub fn ext4_get_logic_block_idx(logical_part : &mut LogicalPart, inode : *mut Inode, idx : Idx, _create : bool) -> Idx
+{
+    let block = ext4_bmap(logical_part, inode, idx);
+    if block == 0
+    {
+        panic!("read file block out of range!\n");
+    }
+    block
+}
+
 pub fn ext2_or_ext3_get_logic_block_idx(logical_part : &mut LogicalPart, inode : *mut Inode, idx : Idx, create : bool) -> Idx
 {
     let mut level = 0;
@@ -797,31 +827,62 @@ pub fn ext4_group_desc_csum(logic_part : &LogicalPart, block_group : u32, gdp :
 
 }
 
-pub fn ext4_inode_read(logical_part : &mut LogicalPart, inode : *mut Inode, mut dst : *mut c_void, len : usize, offset : usize) -> i64
+pub fn ext4_inode_read(_logical_part : &mut LogicalPart, inode : *mut Inode, dst : *mut c_void, len : usize, offset : usize) -> i64
 {
     unsafe
     {
         let ext4_desc_ptr = (*inode).inode_desc_ptr as *mut Ext4Inode;
         assert!(is_file((*ext4_desc_ptr).i_mode) || is_dir((*ext4_desc_ptr).i_mode));
-        let file_size = ((*ext4_desc_ptr).i_size_lo as i64 + (((*ext4_desc_ptr).i_size_high as i64) << 32)) as usize;
-        if offset >= file_size
+        filemap_read((*inode).address_space, dst, len, offset)
+    }
+}
+
+// regular files and directories are read through the page cache
+fn ext4_init_mapping(inode : *mut Inode)
+{
+    unsafe
+    {
+        (*inode).address_space = null_mut();
+        if (*inode).is_file() || (*inode).is_dir()
         {
-            return EOF;
+            (*inode).address_space = AddressSpace::new(inode, &EXT4_AOPS, Pageflags::empty(), FileFlag::empty());
         }
-        let mut read_begin = offset;
-        let mut left = min(len, file_size - offset);
-        while left > 0 {
-            let idx = offset as u64 / 1024 / logical_part.logic_block_size as u64;
+    }
+}
+
+pub static EXT4_AOPS : AddressSpaceOperations = AddressSpaceOperations
+{
+    read_folio: Some(ext4_read_folio)
+};
+
+// fill one page cache page from the file's blocks, the part past eof reads as zeros
+fn ext4_read_folio(mapping : *mut AddressSpace, page : *mut Page) -> Err
+{
+    unsafe
+    {
+        let inode = (*mapping).host;
+        let logical_part = (*inode).logical_part_ptr;
+        let block_size = 1024 * (*logical_part).logic_block_size as usize;
+        let file_size = (*inode).get_size();
+        let dst = page_address(page) as *mut u8;
+        let page_start = (*page).reserved as usize * PAGE_SIZE;
+        let mut done = 0;
+        while done < PAGE_SIZE {
+            let pos = page_start + done;
+            if pos >= file_size
+            {
+                compiler_builtins::mem::memset(dst.add(done), 0, PAGE_SIZE - done);
+                break;
+            }
+            let idx = (pos / block_size) as Idx;
             let buffer = ext4_inode_block_read(logical_part, inode, idx);
-            let start = read_begin % (1024 * logical_part.logic_block_size as usize);
-            let read_num = min((1024 * logical_part.logic_block_size as usize) - start, left);
-            left -= read_num;
-            read_begin += read_num;
-            (*buffer).read_from_buffer(dst, start, read_num);
-            dst = dst.offset(read_num as isize);
-            logical_part.release_buffer(buffer, idx);
+            let start = pos % block_size;
+            let read_num = min(block_size - start, PAGE_SIZE - done);
+            (*buffer).read_from_buffer(dst.add(done).cast(), start, read_num);
+            (*logical_part).release_buffer(buffer);
+            done += read_num;
         }
-        return (read_begin - offset) as i64;
+        0
     }
 }
 
@@ -891,6 +952,7 @@ pub fn ext4_iget(logical_part : &mut LogicalPart, inode : *mut Inode, logic_bloc
         (*inode).i_uid = (*raw_inode).i_uid as u32;
         (*inode).i_gid = (*raw_inode).i_gid as u32;
         (*inode).i_nlink = AtomicI64::new((*raw_inode).i_links_count as i64);
+        ext4_init_mapping(inode);
         // special inode
         if (*inode).is_blk() || (*inode).is_chr() || (*inode).is_fifo() || (*inode).is_sock()
         {
@@ -1,11 +1,11 @@
-use core::{alloc::Layout, ffi::{c_char, c_void, CStr}, intrinsics::{ptr_offset_from, unlikely}, mem::size_of, panic, ptr::{addr_of_mut, null_mut}, sync::atomic::{AtomicI64, AtomicU32}};
+use core::{alloc::Layout, ffi::{c_char, c_void, CStr}, intrinsics::{ptr_offset_from, unlikely}, mem::size_of, panic, ptr::{addr_of_mut, null, null_mut}, sync::atomic::{AtomicI64, AtomicU32}};
 
 use alloc::{alloc::dealloc, collections::{BTreeMap, LinkedList}, rc::Rc, string::String, sync::Arc, vec::Vec};
 use proc_macro::__init;
 use crate::{crypto::crc32c::crc32c_le, kernel::{errno_base::{EBUSY, EINVAL, ENOTBLK}, io::SECTOR_SIZE, semaphore::Semaphore, string::strchr, Err}};
-use crate::{fs::ext4::{ext4_get_logic_block_idx, ext4_init_fs, ext4_iget, ext4_load_block_bitmap, ext4_load_inode_bitmaps, EXT4_FS_TYPE}, kernel::{bitmap::BitMap, buffer::Buffer, console::CONSOLE, device::DevT, errno_base::{EEXIST, EFAULT, ENOMEM, EPERM}, list::ListHead, math::{self, pow}, process::PCB, sched::get_current_running_process, semaphore::RWLock, Off}, mm::{memory::PAGE_SIZE, shmem::{shmem_init_fs_context, init_shmem}}, printk};
+use crate::{fs::ext4::{ext4_bmap, ext4_get_logic_block_idx, ext4_init_fs, ext4_iget, ext4_load_block_bitmap, ext4_load_inode_bitmaps, EXT4_FS_TYPE}, kernel::{bitmap::BitMap, buffer::{Buffer, BUFFER_CACHE}, console::console_write, device::DevT, errno_base::{EBADF, EEXIST, EFAULT, ENOENT, ENOMEM, EPERM}, list::ListHead, math::{self, pow}, process::PCB, sched::get_current_running_process, semaphore::RWLock, Off}, mm::{memory::PAGE_SIZE, shmem::{shmem_file_read, shmem_init_fs_context, init_shmem, shmem_kern_mount, shmem_setsize}}, printk};
 
-use super::{dcache::{DEntry, DEntryOperations}, ext4::{ext4_kill_sb, ext4_init_fs_context, ext4_group_desc_csum, ext4_inode_block_read, ext4_inode_read, ext4_match_name, Ext4DirEntry2, Ext4GroupDesc, Ext4SuperBlock, Ext4SuperBlockInfo, Idx}, fs::{AddressSpace, FileSystemType, FileSystemFlags}, fs_context::FsContext, inode::Inode, mnt_idmapping::MntIdmap, mount::{Mount, init_mount_tree}, namei::{named, namei}, path::Path, super_block::{kill_litter_super, mount_block_root}};
+use super::{proc::{proc_file_read, proc_file_write, proc_root_init}, dcache::{dcache_init, DEntry, DEntryOperations}, ext4::{ext4_kill_sb, ext4_init_fs_context, ext4_group_desc_csum, ext4_inode_block_read, ext4_inode_read, ext4_match_name, Ext4DirEntry2, Ext4GroupDesc, Ext4SuperBlock, Ext4SuperBlockInfo, Idx}, fs::{AddressSpace, FileSystemType, FileSystemFlags}, fs_context::FsContext, inode::Inode, mnt_idmapping::MntIdmap, mount::{Mount, init_mount_tree}, namei::{d_path, named, namei, Fd}, path::Path, super_block::{kill_litter_super, mount_block_root}};
 pub static mut FS : FileSystem = FileSystem::new();
 pub static mut ROOTFS_FS_TYPE : FileSystemType = FileSystemType
 {
@@ -87,13 +87,23 @@ pub struct File
     pub flag : FileFlag,
     pub offset : usize,
     pub inode : *mut Inode,
-    pub f_mapping : *mut AddressSpace
+    pub f_mapping : *mut AddressSpace,
+    pub f_count : AtomicI64, // fd slots and mappings each hold one
+    // absolute name it was opened by, empty for files the kernel makes itself
+    pub f_path : String
 }
 
 impl File {
     pub fn new() -> Self
     {
-        Self { inode: null_mut(), flag: FileFlag::empty(), offset: 0, f_mapping: null_mut() }
+        Self { inode: null_mut(), flag: FileFlag::empty(), offset: 0, f_mapping: null_mut(), f_count: AtomicI64::new(1), f_path: String::new() }
+    }
+
+    // another holder, FS.release_file drops it again
+    pub fn get(&mut self) -> *mut File
+    {
+        self.f_count.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        self
     }
 
     pub fn get_inode(&self) -> *mut Inode
@@ -115,10 +125,31 @@ impl FileSystem {
     #[__init]
     pub fn init(&mut self)
     {
+        dcache_init();
         mnt_init();
         init_mount_tree();
     }
 
+    // drop unused dentries of the disk file systems, shmem ones are the only copy of their files
+    pub fn prune_dcache(&mut self, nr_to_scan : usize) -> usize
+    {
+        unsafe
+        {
+            let mut freed = 0;
+            for lp in self.logical_part.values() {
+                if freed >= nr_to_scan
+                {
+                    break;
+                }
+                if (**lp).old_fs_type == FSType::Ext4 && !(**lp).s_root.is_null()
+                {
+                    freed += (*(**lp).s_root).prune_unused(nr_to_scan - freed);
+                }
+            }
+            freed
+        }
+    }
+
     // pub fn read_file_logic_block(&mut self, file_t : *mut FileStruct, block_idx : Idx) -> *mut Buffer
     // {
         
@@ -319,6 +350,11 @@ impl FileSystem {
         }
     }
 
+    pub unsafe fn write_file(&mut self, file_t : *mut File, buffer : *const c_void, len : usize, offset : Off) -> i64
+    {
+        (*(*(*file_t).inode).logical_part_ptr).write_inode((*file_t).inode, buffer, len, offset)
+    }
+
     pub fn release_file(&mut self, file_t : *mut File)
     {
         unsafe
@@ -327,14 +363,13 @@ impl FileSystem {
             {
                 return;
             }
-            let logical_part = self.logical_part.get_mut(&(*(*file_t).inode).dev);
-            match logical_part {
-                Some(x) => 
-                {
-                    (**x).release_file(file_t);
-                },
-                None => panic!("no device {}", (*(*file_t).inode).dev),
+            // the last holder releases it
+            if (*file_t).f_count.fetch_sub(1, core::sync::atomic::Ordering::AcqRel) != 1
+            {
+                return;
             }
+            // kernel internal mounts like shmem are not in the device table
+            (*(*(*file_t).inode).logical_part_ptr).release_file(file_t);
         }
     }
 
@@ -454,10 +489,18 @@ impl FileSystem {
         unsafe
         {
             let path = namei(file_name);
+            if path.dentry.is_null() || (*path.dentry).d_inode.is_null()
+            {
+                return null_mut();
+            }
             let file_t = alloc::alloc::alloc(Layout::new::<File>()) as *mut File;
+            file_t.write(File::new());
+            // the dentry keeps its inode, the file holds a reference of its own
             (*file_t).inode = (*path.dentry).d_inode;
+            (*(*file_t).inode).count.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
             (*file_t).flag = flags;
-
+            (*file_t).f_mapping = (*(*file_t).inode).address_space;
+            (*file_t).f_path = d_path(&path);
             file_t
         }
 
@@ -482,14 +525,8 @@ impl FileSystem {
     {
         unsafe
         {
-            let logic_part = self.logical_part.get_mut(&(*inode).dev);
-            if logic_part.is_some()
-            {
-                (**logic_part.unwrap()).read_inode(inode, buffer, len, offset)
-            }
-            else {
-                panic!("not fund device {}\n", &(*inode).dev);
-            }
+            // kernel internal mounts like shmem are not in the device table
+            (*(*inode).logical_part_ptr).read_inode(inode, buffer, len, offset)
         }
     }
 
@@ -603,7 +640,6 @@ pub struct LogicalPart
     pub logic_block_size : i32,
     pub logic_block_count : usize,
     pub inode_count : usize,
-    pub data_map : BTreeMap<Idx, *mut Buffer>,
     pub s_root : *mut DEntry,
     pub s_mounts : ListHead,
     pub sb_mount : *mut Mount,
@@ -614,7 +650,8 @@ pub struct LogicalPart
 pub enum FSType {
     None,
     Ext4,
-    Shmem
+    Shmem,
+    Proc
 }
 
 pub struct DirEntry
@@ -639,7 +676,7 @@ impl DirEntry {
                     
                     printk!("entery file name :{}\n", String::from_raw_parts((*(self.entry_ptr as *mut Ext4DirEntry2)).name.as_mut_ptr() as *mut u8, self.name_length(), self.name_length()));
                 },
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => unimplemented!()
             }
         }
     }
@@ -653,7 +690,7 @@ impl DirEntry {
                 FSType::Ext4 => {
                     self.entry_ptr = self.entry_ptr.offset((*(self.entry_ptr as *mut Ext4DirEntry2)).rec_len as isize)
                 },
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => unimplemented!()
             }
         }
     }
@@ -667,7 +704,7 @@ impl DirEntry {
                 FSType::Ext4 => {
                     (*(self.entry_ptr as *mut Ext4DirEntry2)).name_len as usize
                 },
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => unimplemented!()
             }
         }
     }
@@ -681,7 +718,7 @@ impl DirEntry {
                 FSType::Ext4 => {
                     alloc::alloc::dealloc(self.entry_ptr as *mut u8, Layout::new::<Ext4DirEntry2>());
                 },
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => unimplemented!()
             }
         }
     }
@@ -703,7 +740,7 @@ impl DirEntry {
             match self.dir_entry_type {
                 FSType::None => panic!("unsupport fs\n"),
                 FSType::Ext4 => (*(self.entry_ptr as *const Ext4DirEntry2)).inode as Idx,
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => unimplemented!()
             }
         }
     }
@@ -714,7 +751,7 @@ impl DirEntry {
             match self.dir_entry_type {
                 FSType::None => panic!("unsupport fs\n"),
                 FSType::Ext4 => (*(self.entry_ptr as *const Ext4DirEntry2)).rec_len as usize,
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => unimplemented!()
             }
         }
     }
@@ -726,7 +763,7 @@ impl DirEntry {
             match self.dir_entry_type {
                 FSType::None => panic!("unsupport fs\n"),
                 FSType::Ext4 => ext4_match_name(name, (*(self.entry_ptr as *const Ext4DirEntry2)).name.as_ptr(), next),
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => unimplemented!()
             }
         }
 
@@ -738,32 +775,24 @@ impl LogicalPart {
     {
         unsafe
         {
-            self.release_inode((*file_t).inode);
+            // moved out so its name is dropped with it
+            let file = file_t.read();
             dealloc(file_t as *mut u8, Layout::new::<File>());
+            self.release_inode(file.inode);
         }
     }
 
-    pub fn release_buffer(&mut self, buffer : *mut Buffer, idx : Idx)
+    // blocks stay in the buffer cache after the last release
+    pub fn release_buffer(&mut self, buffer : *mut Buffer)
     {
+        if buffer.is_null()
+        {
+            panic!("try release null buffer\n");
+        }
         unsafe
         {
-            if buffer.is_null()
-            {
-                panic!("try release null buffer\n");
-            }
-            (*buffer).count -= 1;
-            if (*buffer).count != 0
-            {
-                return;
-            }
-            if (*buffer).dirty
-            {
-                (*buffer).write_to_device((*buffer).get_dev(), idx, (self.logic_block_size * 2) as usize);
-            }
-            self.data_map.remove(&(*buffer).get_idx());
-            (*buffer).dispose();
+            BUFFER_CACHE.brelse(buffer);
         }
-
     }
 
     pub fn open_file(&mut self, nr : Idx, flag : FileFlag) -> *mut File
@@ -773,34 +802,33 @@ impl LogicalPart {
 
             let inode = self.iget(nr);
             let f_struct = alloc::alloc::alloc(Layout::new::<File>()) as *mut File;
-            (*f_struct) = File::new();
             if !f_struct.is_null()
             {
+                f_struct.write(File::new());
                 (*f_struct).inode = inode;
                 (*f_struct).flag = flag;
+                (*f_struct).f_mapping = (*inode).address_space;
             }
             f_struct
         }
     }
 
-    pub fn get_buffer(&mut self, idx : Idx) -> *mut Buffer 
+    // buffer of logical block `idx`, not read in yet if it was not cached
+    pub fn get_buffer(&mut self, idx : Idx) -> *mut Buffer
     {
         unsafe
         {
-            let result = self.data_map.get(&idx);
-            if result.is_some()
-            {
-                (**result.unwrap()).count += 1;
-                *result.unwrap()
-            }
-            else {
-                let buff = alloc::alloc::alloc(Layout::new::<Buffer>()) as *mut Buffer;
-                *buff = Buffer::new(self.logic_block_size as usize * 1024);
-                self.data_map.insert(idx, buff);
-                buff
-            }
+            BUFFER_CACHE.getblk(self.s_dev, idx * 2 * self.logic_block_size as Idx, self.logic_block_size as usize * 1024)
         }
+    }
 
+    // physical block behind logical block `idx`, 0 in a hole or where the fs keeps nothing on a device
+    pub fn bmap(&mut self, inode : *mut Inode, idx : Idx) -> Idx
+    {
+        match self.old_fs_type {
+            FSType::Ext4 => ext4_bmap(self, inode, idx),
+            _ => 0
+        }
     }
 
     pub fn get_logic_block_idx(&mut self, inode : *mut Inode, idx : Idx, create : bool) -> Idx
@@ -816,16 +844,27 @@ impl LogicalPart {
     {
         match self.old_fs_type {
             FSType::Ext4 => ext4_inode_read(self, inode, buffer, len, offset),
+            FSType::Shmem => shmem_file_read(inode, buffer, len, offset),
+            FSType::Proc => proc_file_read(inode, buffer, len, offset),
             _ => panic!("unsupport fs type!\n"),
         }
     }
 
+    // the disk file systems are read-only here
+    pub fn write_inode(&mut self, inode : *mut Inode, buffer : *const c_void, len : usize, offset : usize) -> i64
+    {
+        match self.old_fs_type {
+            FSType::Proc => proc_file_write(inode, buffer, len, offset),
+            _ => -EINVAL,
+        }
+    }
+
     pub fn new() -> *mut Self
     {
         unsafe
         {
             let ptr = alloc::alloc::alloc(Layout::new::<Self>()).cast();
-            *ptr = Self { old_fs_type: FSType::None, logic_block_size: 0, logic_block_count: 0, inode_count: 0, s_dev: 0, data_map: BTreeMap::new(), s_d_op: null_mut(), s_root: null_mut(), sb_mount: null_mut(), s_sbi: null_mut(), fs_type: null_mut(), s_flags: 0, s_mounts: ListHead::empty() };
+            *ptr = Self { old_fs_type: FSType::None, logic_block_size: 0, logic_block_count: 0, inode_count: 0, s_dev: 0, s_d_op: null_mut(), s_root: null_mut(), sb_mount: null_mut(), s_sbi: null_mut(), fs_type: null_mut(), s_flags: 0, s_mounts: ListHead::empty() };
             (*ptr).s_mounts.init();
             ptr
         }
@@ -833,7 +872,10 @@ impl LogicalPart {
 
     pub fn read_block(&self, logic_block_no : usize) -> *mut Buffer
     {
-        disk_read(self.s_dev,  self.logic_block_size as u64 * 2 * logic_block_no as u64, (self.logic_block_size * 2).try_into().unwrap())
+        unsafe
+        {
+            BUFFER_CACHE.bread(self.s_dev, self.logic_block_size as u64 * 2 * logic_block_no as u64, self.logic_block_size as usize * 1024)
+        }
     }
 
     pub fn release_inode(&mut self, inode : *mut Inode)
@@ -843,11 +885,25 @@ impl LogicalPart {
             let prev = (*inode).count.fetch_sub(1, core::sync::atomic::Ordering::Relaxed);
             if prev == 1
             {
-                alloc::alloc::dealloc(inode as *mut u8, Layout::new::<Inode>());
+                Self::evict_inode(inode);
             }
         }
     }
 
+    // the last reference is gone, its cached pages go with it
+    fn evict_inode(inode : *mut Inode)
+    {
+        unsafe
+        {
+            if !(*inode).address_space.is_null()
+            {
+                (*(*inode).address_space).truncate_pages();
+                (*(*inode).address_space).destory();
+            }
+            alloc::alloc::dealloc(inode as *mut u8, Layout::new::<Inode>());
+        }
+    }
+
     fn get_free_inode(&self) -> *mut Inode
     {
         unsafe { alloc::alloc::alloc(Layout::new::<Inode>()) as *mut Inode }
@@ -863,6 +919,7 @@ impl LogicalPart {
             (*inode).nr = inode_idx;
             (*inode).count = AtomicU32::new(1);
             (*inode).logical_part_ptr = self;
+            (*inode).i_operations = null();
             match self.old_fs_type
             {
                 FSType::Ext4 =>
@@ -877,29 +934,92 @@ impl LogicalPart {
     }
 }
 
-pub fn sys_open(file_name : *const c_char, flags : FileFlag, mode : FSPermission)
+pub fn sys_open(file_name : *const c_char, flags : FileFlag, mode : FSPermission) -> i64
 {
-    let pcb = get_current_running_process();
-    
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        if file_name.is_null()
+        {
+            return -EFAULT;
+        }
+        let file_t = FS.open_file(file_name, flags);
+        if file_t.is_null()
+        {
+            return -ENOENT;
+        }
+        (*pcb).insert_to_fd(file_t) as i64
+    }
 }
 
+pub fn sys_close(fd : Fd) -> i64
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        let file_t = (*pcb).get_file(fd);
+        if file_t.is_null()
+        {
+            return -EBADF;
+        }
+        // mappings hold references of their own and keep using it
+        FS.release_file(file_t);
+        (*pcb).files[fd] = null_mut();
+        0
+    }
+}
 
-#[inline(always)]
-fn get_device_buffer(dev : DevT, block : Idx) -> *mut Buffer
+pub fn sys_read(fd : Fd, buf : *mut c_void, count : usize) -> i64
 {
     unsafe
     {
-        let logic_part = FS.logical_part.get_mut(&dev);
-        let buffer;
-        if logic_part.is_some()
+        let pcb = get_current_running_process();
+        let file_t = (*pcb).get_file(fd);
+        if file_t.is_null()
         {
-            buffer = (**logic_part.unwrap()).get_buffer(block);
+            return -EBADF;
         }
-        else
+        let result = FS.read_file(file_t, buf, count, (*file_t).offset);
+        if result > 0
         {
-            return null_mut();
+            (*file_t).offset += result as usize;
         }
-        buffer
+        result
+    }
+}
+
+pub fn sys_pread64(fd : Fd, buf : *mut c_void, count : usize, offset : Off) -> i64
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        let file_t = (*pcb).get_file(fd);
+        if file_t.is_null()
+        {
+            return -EBADF;
+        }
+        FS.read_file(file_t, buf, count, offset)
+    }
+}
+
+// only shmem files can change size, the disk file systems are read-only here
+pub fn sys_ftruncate(fd : Fd, length : Off) -> Err
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        let file_t = (*pcb).get_file(fd);
+        if file_t.is_null()
+        {
+            return -EBADF;
+        }
+        let inode = (*file_t).inode;
+        if !(*inode).is_file() || (*(*inode).logical_part_ptr).old_fs_type != FSType::Shmem
+        {
+            return -EINVAL;
+        }
+        shmem_setsize(inode, length);
+        0
     }
 }
 
@@ -907,9 +1027,7 @@ pub fn disk_read(dev : DevT, idx : Idx, blocks : usize) -> *mut Buffer
 {
     unsafe
     {
-        let buffer = get_device_buffer(dev, idx);
-        (*buffer).read_from_device(dev, idx, blocks);
-        buffer
+        BUFFER_CACHE.bread(dev, idx, blocks * SECTOR_SIZE as usize)
     }
 }
 
@@ -924,14 +1042,25 @@ pub fn early_disk_read(dev : DevT, idx : Idx, blocks : usize) -> *mut Buffer
     }
 }
 
-pub fn sys_write(fd : FileDescriptor, buf : *const c_void, count : usize)
+pub unsafe fn sys_write(fd : FileDescriptor, buf : *const c_void, count : usize) -> i64
 {
     if fd == STDOUT
     {
-        unsafe { 
-            CONSOLE.write(buf as *const c_char, count);
-        }
+        console_write(buf as *const c_char, count);
+        return count as i64;
+    }
+    let pcb = get_current_running_process();
+    let file_t = (*pcb).get_file(fd as Fd);
+    if file_t.is_null()
+    {
+        return -EBADF;
+    }
+    let result = FS.write_file(file_t, buf, count, (*file_t).offset);
+    if result > 0
+    {
+        (*file_t).offset += result as usize;
     }
+    result
 }
 
 pub fn init_rootfs()
@@ -946,5 +1075,7 @@ pub fn mnt_init()
 {
     init_rootfs();
     init_shmem();
+    shmem_kern_mount();
     ext4_init_fs();
+    proc_root_init();
 }
@@ -2,7 +2,7 @@ use core::{alloc::Layout, ffi::c_void, mem::ManuallyDrop, ptr::{self, null_mut}}
 
 use alloc::{collections::{BTreeSet, BTreeMap}, string::String};
 
-use crate::{bit, kernel::{buffer::Buffer, semaphore::RWLock, Err}, mm::page::Pageflags};
+use crate::{bit, kernel::{buffer::Buffer, semaphore::{RWLock, UnreenterabkeSpinLock}, Err}, mm::{page::{Page, Pageflags}, page_alloc::{get_page, put_page}, vmscan::{lru_cache_add, lru_cache_del}}};
 
 use super::{ext4::Idx, file::{FileFlag, LogicalPart}, fs_context::FsContext, inode::Inode};
 
@@ -84,10 +84,20 @@ pub struct FileSystemType
     pub fs_flags : FileSystemFlags
 }
 
+pub type ReadFolio = fn(*mut AddressSpace, *mut Page) -> Err;
+
+pub struct AddressSpaceOperations
+{
+    pub read_folio : Option<ReadFolio> // fill a fresh page from the backing store before it is cached
+}
+
 pub struct AddressSpace
 {
-    host : *const Inode,
-    i_pages : BTreeMap<Idx, *mut c_void>,
+    pub host : *mut Inode,
+    i_pages : BTreeMap<Idx, *mut Page>,
+    tree_lock : UnreenterabkeSpinLock,
+    pub nrpages : usize,
+    pub a_ops : *const AddressSpaceOperations,
     invalidate_lock : RWLock,
     fgp_mask : Pageflags,
     flags : FileFlag,
@@ -96,12 +106,12 @@ pub struct AddressSpace
 
 impl AddressSpace
 {
-    pub fn new(host : *const Inode, fgp_mask : Pageflags, flags : FileFlag) -> *mut Self
+    pub fn new(host : *mut Inode, a_ops : *const AddressSpaceOperations, fgp_mask : Pageflags, flags : FileFlag) -> *mut Self
     {
         unsafe
         {
             let ptr = alloc::alloc::alloc(Layout::new::<Self>()) as *mut Self;
-            *ptr = Self { host, i_pages: BTreeMap::<Idx, *mut c_void>::new(), invalidate_lock: RWLock::new(), fgp_mask, flags };
+            ptr.write(Self { host, i_pages: BTreeMap::<Idx, *mut Page>::new(), tree_lock: UnreenterabkeSpinLock::new(1), nrpages: 0, a_ops, invalidate_lock: RWLock::new(), fgp_mask, flags });
             ptr
         }
     }
@@ -115,11 +125,89 @@ impl AddressSpace
         }
     }
 
-    pub fn seek(&self, idx : Idx) -> *mut c_void
+    pub fn seek(&self, idx : Idx) -> *mut Page
     {
         match self.i_pages.get(&idx) {
             Some(page) => *page,
             None => null_mut(),
         }
     }
+
+    // cached page with a reference taken, null when `idx` is not cached
+    pub fn find_get_page(&mut self, idx : Idx) -> *mut Page
+    {
+        self.tree_lock.acquire(1);
+        let page = self.seek(idx);
+        if !page.is_null()
+        {
+            unsafe
+            {
+                get_page(&*page);
+                (*page).flags.insert(Pageflags::PgReferenced);
+            }
+        }
+        self.tree_lock.release(1);
+        page
+    }
+
+    // cache `page` at `idx`, the cache keeps its own reference
+    // if another reader got there first their page is returned instead, referenced for the caller
+    pub unsafe fn add_to_page_cache(&mut self, page : *mut Page, idx : Idx) -> *mut Page
+    {
+        self.tree_lock.acquire(1);
+        let result = match self.i_pages.get(&idx) {
+            Some(cached) => *cached,
+            None =>
+            {
+                self.i_pages.insert(idx, page);
+                self.nrpages += 1;
+                lru_cache_add(page);
+                page
+            }
+        };
+        get_page(&*result);
+        self.tree_lock.release(1);
+        result
+    }
+
+    // drop every page from the cache, mapped pages live on until their last pte goes
+    pub fn truncate_pages(&mut self)
+    {
+        self.truncate_pages_from(0);
+    }
+
+    // the same for page `start` onwards, what a shrunk file no longer covers
+    pub fn truncate_pages_from(&mut self, start : Idx)
+    {
+        self.tree_lock.acquire(1);
+        let pages = self.i_pages.split_off(&start);
+        self.nrpages -= pages.len();
+        self.tree_lock.release(1);
+        for page in pages.into_values() {
+            unsafe
+            {
+                lru_cache_del(page);
+                put_page(page);
+            }
+        }
+    }
+
+    // reclaim taking `page` out of the cache, the caller inherits the cache's reference
+    // false when it is no longer cached or the tree is busy
+    pub unsafe fn delete_from_page_cache(&mut self, page : *mut Page) -> bool
+    {
+        if !self.tree_lock.try_acquire(1)
+        {
+            return false;
+        }
+        let idx = (*page).reserved as Idx;
+        let cached = self.seek(idx) == page;
+        if cached
+        {
+            self.i_pages.remove(&idx);
+            self.nrpages -= 1;
+        }
+        self.tree_lock.release(1);
+        cached
+    }
 }
@@ -33,6 +33,7 @@ pub struct Inode
     pub i_rdev : DevT,
     pub dev : DevT,
     pub nr : Idx,
+    pub i_size : usize, // file size for filesystems without an on-disk inode
 }
 
 impl Inode {
@@ -41,7 +42,7 @@ impl Inode {
         unsafe
         {
             let ptr = alloc::alloc::alloc(Layout::new::<Self>()) as *mut Self;
-            *ptr = Self { inode_block_buffer: null_mut(), inode_desc_ptr: null_mut(), logical_part_ptr: null_mut(), count: AtomicU32::new(1), rx_waiter: null_mut(), tx_waiter: null_mut(), dev: 0, nr: 0, i_perm, i_uid: 0, i_gid: 0, i_nlink: AtomicI64::new(1), i_operations, address_space: null_mut(), i_mode: FileMode::empty(), i_rdev: 0 };
+            *ptr = Self { inode_block_buffer: null_mut(), inode_desc_ptr: null_mut(), logical_part_ptr: null_mut(), count: AtomicU32::new(1), rx_waiter: null_mut(), tx_waiter: null_mut(), dev: 0, nr: 0, i_perm, i_uid: 0, i_gid: 0, i_nlink: AtomicI64::new(1), i_operations, address_space: null_mut(), i_mode: FileMode::empty(), i_rdev: 0, i_size: 0 };
             ptr
         }
     }
@@ -122,7 +123,7 @@ impl Inode {
             match (*self.logical_part_ptr).old_fs_type {
                 FSType::None => panic!("unsupport fs\n"),
                 FSType::Ext4 => (*(self.inode_desc_ptr as *mut Ext4Inode)).i_size_lo as usize + (((*(self.inode_desc_ptr as *mut Ext4Inode)).i_size_high as usize) << 32),
-                FSType::Shmem => unimplemented!()
+                FSType::Shmem | FSType::Proc => self.i_size
             }
         }
     }
@@ -134,7 +135,7 @@ impl Inode {
             match (*self.logical_part_ptr).old_fs_type {
                 FSType::None => panic!("unsupport fs\n"),
                 FSType::Ext4 => ext4_load_all_entries(&mut *dentry, self),
-                FSType::Shmem => { },
+                FSType::Shmem | FSType::Proc => { },
             }
         }
 
@@ -147,7 +148,7 @@ impl Inode {
             match (*self.logical_part_ptr).old_fs_type {
                 FSType::None => panic!("unsupport fs\n"),
                 FSType::Ext4 => ext4_find_entry(self, name, next, result_entry),
-                FSType::Shmem => panic!("unsupport fs\n")
+                FSType::Shmem | FSType::Proc => panic!("unsupport fs\n")
             }
         }
     }
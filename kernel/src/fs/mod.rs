@@ -15,5 +15,7 @@ pub mod path;
 pub mod ns_common;
 pub mod ida;
 pub mod pnode;
+pub mod proc;
+pub mod task_mmu;
 
 pub const PART_FS_EXTENDED : u32 = 5;
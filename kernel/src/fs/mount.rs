@@ -1,8 +1,8 @@
-use core::{alloc::Layout, ffi::{c_char, c_void, CStr}, intrinsics::{likely, unlikely}, ptr::{addr_of, addr_of_mut, null_mut}, sync::atomic::AtomicI64};
+use core::{alloc::Layout, ffi::{c_char, c_void, CStr}, intrinsics::{likely, unlikely}, ptr::{addr_of, addr_of_mut, null, null_mut}, sync::atomic::AtomicI64};
 use proc_macro::__init; 
 use alloc::{collections::{BTreeMap, BTreeSet, LinkedList}, string::String, sync::Arc};
 use bitflags::bitflags;
-use crate::{bit, container_of, fs::{fs::{FileSystemType, SB_DIRSYNC, SB_I_VERSION, SB_LAZYTIME, SB_MANDLOCK, SB_POSIXACL, SB_RDONLY, SB_SILENT, SB_SYNCHRONOUS}, pnode::set_mnt_shared}, kernel::{errno_base::{err_ptr, is_err, is_err_or_null, ptr_err, EBUSY, EEXIST, EFAULT, EINVAL, EISDIR, ELOOP, ENOMEM, ENOSPC, ENOTDIR, EPERM}, list::ListHead, sched::get_current_running_process, semaphore::Semaphore, Err}};
+use crate::{__setup, bit, container_of, fs::{fs::{FileSystemType, SB_DIRSYNC, SB_I_VERSION, SB_LAZYTIME, SB_MANDLOCK, SB_POSIXACL, SB_RDONLY, SB_SILENT, SB_SYNCHRONOUS}, pnode::set_mnt_shared}, kernel::{errno_base::{err_ptr, is_err, is_err_or_null, ptr_err, EBUSY, EEXIST, EFAULT, EINVAL, EISDIR, ELOOP, ENOMEM, ENOSPC, ENOTDIR, EPERM}, list::ListHead, sched::get_current_running_process, semaphore::Semaphore, Err}};
 use crate::mm::memory::PAGE_SIZE;
 use super::{dcache::{DEntry, DEntryFlags}, file::{FileMode, LogicalPart, FS, ROOTFS_FS_TYPE}, fs::{SB_I_NODEV, SB_I_NOEXEC, SB_I_USERNS_VISIBLE, SB_NOUSER}, fs_context::{parse_monolithic_mount_data, vfs_parse_fs_string, FsContext}, ida::Ida, namei::namei, ns_common::NsCommon, path::Path, super_block::vfs_get_tree};
 
@@ -14,7 +14,12 @@ static mut MNT_GROUP_IDA : Ida = Ida::new();
 static mut MOUNT_LOCK : Semaphore = Semaphore::new(1);
 
 const PATH_MAX : usize = 4096;
-pub const ROOT_MOUNTFLAGS : u32 = SB_SILENT;
+// ro and rw on the command line
+pub static mut ROOT_MOUNTFLAGS : u32 = SB_SILENT;
+// rootfstype=, a comma separated list, empty tries every block file system
+static mut ROOT_FS_NAMES : &str = "";
+// rootflags=, handed to the file system like mount's data
+static mut ROOT_MOUNT_DATA : &str = "";
 bitflags! {
     #[derive(Copy, Clone)]
     pub struct MntFlags : u32
@@ -985,12 +990,15 @@ pub fn mount_root_generic(name : *const c_char, pretty_name : *const c_char, fla
     {
         let mut p = alloc::alloc::alloc(Layout::new::<[c_void; PAGE_SIZE]>());
         let p_start = p;
-        let num_fs = FS.list_bdev_fs_names(p.cast(), PAGE_SIZE);
+        let num_fs = if ROOT_FS_NAMES.is_empty() { FS.list_bdev_fs_names(p.cast(), PAGE_SIZE) } else { split_fs_names(p.cast(), PAGE_SIZE, ROOT_FS_NAMES) };
+        let mut root_data = String::from(ROOT_MOUNT_DATA);
+        root_data.push('\0');
+        let data = if ROOT_MOUNT_DATA.is_empty() { null() } else { root_data.as_ptr().cast() };
         let mut err = 0;
         let mut i = 0;
         while i < num_fs
         {
-            err = do_mount_root(name, p.cast(), flags, null_mut());
+            err = do_mount_root(name, p.cast(), flags, data);
             if err == 0
             {
                 alloc::alloc::dealloc(p_start, Layout::new::<[c_void; PAGE_SIZE]>());
@@ -1006,6 +1014,56 @@ pub fn mount_root_generic(name : *const c_char, pretty_name : *const c_char, fla
     }
 }
 
+// "ext4,vfat" into nul terminated names one after another, the layout list_bdev_fs_names fills in
+#[__init]
+fn split_fs_names(buf : *mut c_char, size : usize, names : &str) -> usize
+{
+    unsafe
+    {
+        let mut count = 0;
+        let mut offset = 0;
+        for name in names.split(',').filter(|name| !name.is_empty()) {
+            if offset + name.len() + 1 > size
+            {
+                break;
+            }
+            compiler_builtins::mem::memcpy(buf.add(offset).cast(), name.as_ptr(), name.len());
+            *buf.add(offset + name.len()) = 0;
+            offset += name.len() + 1;
+            count += 1;
+        }
+        count
+    }
+}
+
+fn fs_names_setup(names : &'static str) -> bool
+{
+    unsafe { ROOT_FS_NAMES = names; }
+    true
+}
+__setup!("rootfstype=", fs_names_setup);
+
+fn root_data_setup(data : &'static str) -> bool
+{
+    unsafe { ROOT_MOUNT_DATA = data; }
+    true
+}
+__setup!("rootflags=", root_data_setup);
+
+fn readonly(_ : &'static str) -> bool
+{
+    unsafe { ROOT_MOUNTFLAGS |= SB_RDONLY; }
+    true
+}
+__setup!("ro", readonly);
+
+fn readwrite(_ : &'static str) -> bool
+{
+    unsafe { ROOT_MOUNTFLAGS &= !SB_RDONLY; }
+    true
+}
+__setup!("rw", readwrite);
+
 #[__init]
 pub fn do_mount_root(name : *const c_char, fs : *const c_char, flags : u32, data : *const c_void) -> Err
 {
@@ -1020,7 +1078,9 @@ pub fn do_mount_root(name : *const c_char, fs : *const c_char, flags : u32, data
             {
                 return -ENOMEM;
             }
-            compiler_builtins::mem::memcpy(data_page.cast(), data.cast(), PAGE_SIZE);
+            let len = compiler_builtins::mem::strlen(data.cast()).min(PAGE_SIZE - 1);
+            compiler_builtins::mem::memcpy(data_page.cast(), data.cast(), len);
+            compiler_builtins::mem::memset(data_page.add(len).cast(), 0, PAGE_SIZE - len);
         }
         let ret = init_mount(name, "/root\0".as_ptr().cast(), fs, flags, data_page);
         if ret != 0
@@ -1,6 +1,6 @@
 use core::{alloc::Layout, ffi::{c_char, c_void, CStr}, intrinsics::unlikely, iter::empty, ptr::{addr_of_mut, null_mut}};
 use core::intrinsics::ptr_offset_from_unsigned;
-use alloc::string::String;
+use alloc::{string::String, vec::Vec};
 
 use crate::kernel::{device::DevT, errno_base::EXDEV, sched::get_current_running_process, string::strsep};
 
@@ -189,4 +189,43 @@ fn choose_mountpoint(mut m : *mut Mount, root : &mut Path, path : &mut Path) ->
         }
         false
     }
-}
\ No newline at end of file
+}
+// the absolute name of `path`, mounts are followed back up to their mountpoints
+pub fn d_path(path : &Path) -> String
+{
+    unsafe
+    {
+        let mut names = Vec::new();
+        let mut dentry = path.dentry;
+        let mut m = if path.mnt.is_null() { null_mut() } else { real_mount(path.mnt) };
+        while !dentry.is_null() {
+            if !m.is_null() && dentry == (*m).mnt.mnt_root
+            {
+                if !mnt_has_parent(m)
+                {
+                    break;
+                }
+                dentry = (*m).mnt_mountpoint;
+                m = (*m).mnt_parent;
+                continue;
+            }
+            let parent = (*dentry).get_parent();
+            if parent.is_null() || parent == dentry
+            {
+                break;
+            }
+            names.push((*dentry).name());
+            dentry = parent;
+        }
+        if names.is_empty()
+        {
+            return String::from("/");
+        }
+        let mut result = String::new();
+        for name in names.iter().rev() {
+            result.push('/');
+            result.push_str(name);
+        }
+        result
+    }
+}
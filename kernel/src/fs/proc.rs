@@ -0,0 +1,336 @@
+use core::{ffi::{c_char, c_void, CStr}, fmt::Write, ptr::{addr_of, addr_of_mut, null, null_mut}};
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use proc_macro::__init;
+
+use crate::{kernel::{errno_base::{EINVAL, EISDIR, ENOMEM, ESRCH}, process::{find_task_by_pid, Pid, PCB}, sched::get_current_running_process, Err}, logk, mm::{kmemleak, ksm, slub}};
+
+use super::{dcache::{DEntry, DEntryOperations}, ext4::Idx, file::{FSPermission, FSType, FileMode, LogicalPart, FS}, fs::{FileSystemFlags, FileSystemType}, fs_context::{FsContext, FsContextOperations}, inode::{Inode, InodeOperations}, mount::do_mount, namei::namei, path::Path, super_block::{get_tree_nodev, kill_litter_super}, task_mmu};
+
+// an inode number is the pid shifted over the kind of entry it is
+const PROC_KIND_BITS : u32 = 8;
+const PROC_ROOT : Idx = 0;
+const PROC_PID_DIR : Idx = 1;
+const PROC_PID_MAPS : Idx = 2;
+const PROC_PID_STATUS : Idx = 3;
+const PROC_PID_SMAPS : Idx = 4;
+const PROC_KSM : Idx = 5;
+const PROC_SLABINFO : Idx = 6;
+const PROC_KMEMLEAK : Idx = 7;
+
+// the files in every /proc/<pid>
+const PID_ENTRIES : [(&str, Idx); 3] = [("maps", PROC_PID_MAPS), ("status", PROC_PID_STATUS), ("smaps", PROC_PID_SMAPS)];
+// the files right in /proc, they belong to no task
+const ROOT_ENTRIES : [(&str, Idx); 3] = [("ksm", PROC_KSM), ("slabinfo", PROC_SLABINFO), ("kmemleak", PROC_KMEMLEAK)];
+
+static mut PROC_FS_TYPE : FileSystemType = FileSystemType
+{
+    name : "proc\0",
+    next : null_mut(),
+    init_fs_context : Some(proc_init_fs_context),
+    fs_supers : BTreeMap::new(),
+    kill_sb : Some(kill_litter_super),
+    fs_flags : FileSystemFlags::USERNS_MOUNT
+};
+
+static mut PROC_FS_CONTEXT_OPS : FsContextOperations = FsContextOperations
+{
+    parse_param: None,
+    get_tree: Some(proc_get_tree),
+    parse_monolithic: None
+};
+
+static mut PROC_DENTRY_OPERATIONS : DEntryOperations = DEntryOperations
+{
+    d_revalidate: Some(pid_revalidate),
+    d_weak_revalidate: None,
+    d_hash: None,
+    d_compare: None,
+    d_delete: None,
+    d_init: None,
+    d_release: None,
+    d_prune: None,
+    d_iput: None,
+    d_dname: None,
+};
+
+static PROC_ROOT_INODE_OPERATIONS : InodeOperations = InodeOperations
+{
+    lookup: Some(proc_root_lookup),
+    mknod: None,
+    mkdir: None
+};
+
+static PROC_PID_INODE_OPERATIONS : InodeOperations = InodeOperations
+{
+    lookup: Some(proc_pid_lookup),
+    mknod: None,
+    mkdir: None
+};
+
+fn proc_inode_pid(inode : *mut Inode) -> Pid
+{
+    unsafe { ((*inode).nr >> PROC_KIND_BITS) as Pid }
+}
+
+fn proc_inode_kind(inode : *mut Inode) -> Idx
+{
+    unsafe { (*inode).nr & ((1 << PROC_KIND_BITS) - 1) }
+}
+
+fn proc_root_entry(kind : Idx) -> bool
+{
+    ROOT_ENTRIES.iter().any(|(_, entry)| *entry == kind)
+}
+
+unsafe fn proc_get_inode(lp : *mut LogicalPart, pid : Pid, kind : Idx, mode : FileMode, perm : FSPermission, ops : *const InodeOperations) -> *mut Inode
+{
+    let inode = Inode::new(ops, perm);
+    if inode.is_null()
+    {
+        return null_mut();
+    }
+    (*inode).logical_part_ptr = lp;
+    (*inode).dev = (*lp).s_dev;
+    (*inode).nr = (pid as Idx) << PROC_KIND_BITS | kind;
+    (*inode).i_mode = mode;
+    inode
+}
+
+// a dentry below /proc/<pid> goes stale with the task
+fn pid_revalidate(dentry : *mut DEntry, _flags : u32) -> i64
+{
+    unsafe
+    {
+        let inode = (*dentry).d_inode;
+        if inode.is_null() || proc_inode_kind(inode) == PROC_ROOT || proc_root_entry(proc_inode_kind(inode))
+        {
+            return 1;
+        }
+        !find_task_by_pid(proc_inode_pid(inode)).is_null() as i64
+    }
+}
+
+// /proc/<pid> for a running task, /proc/self stands for the caller's own
+fn proc_root_lookup(dir : *mut Inode, dentry : *mut DEntry, _flags : u64) -> *mut DEntry
+{
+    unsafe
+    {
+        let name = String::from((*dentry).name());
+        if name == "self"
+        {
+            let mut path = Path::empty();
+            let mut pid = String::new();
+            let _ = write!(pid, "{}", (*get_current_running_process()).pid);
+            return (*(*dentry).d_parent).look_up(&pid, &mut path);
+        }
+        if let Some((_, kind)) = ROOT_ENTRIES.iter().find(|(entry, _)| *entry == name)
+        {
+            let mut perm = FSPermission::IRUSR | FSPermission::IRGRP | FSPermission::IROTH;
+            // takes commands as well
+            if *kind == PROC_KMEMLEAK
+            {
+                perm |= FSPermission::IWUSR;
+            }
+            let inode = proc_get_inode((*dir).logical_part_ptr, 0, *kind, FileMode::IFREG, perm, null());
+            if !inode.is_null()
+            {
+                (*dentry).d_inode = inode;
+            }
+            return null_mut();
+        }
+        let pid = match name.parse::<Pid>() {
+            Ok(pid) => pid,
+            Err(_) => return null_mut()
+        };
+        let task = find_task_by_pid(pid);
+        if task.is_null()
+        {
+            return null_mut();
+        }
+        let perm = FSPermission::IRUSR | FSPermission::IXUSR | FSPermission::IRGRP | FSPermission::IXGRP | FSPermission::IROTH | FSPermission::IXOTH;
+        let inode = proc_get_inode((*dir).logical_part_ptr, pid, PROC_PID_DIR, FileMode::IFDIR, perm, addr_of!(PROC_PID_INODE_OPERATIONS));
+        if inode.is_null()
+        {
+            return null_mut();
+        }
+        (*inode).i_uid = (*task).uid;
+        (*inode).i_gid = (*task).gid;
+        (*dentry).d_inode = inode;
+        null_mut()
+    }
+}
+
+fn proc_pid_lookup(dir : *mut Inode, dentry : *mut DEntry, _flags : u64) -> *mut DEntry
+{
+    unsafe
+    {
+        let kind = match PID_ENTRIES.iter().find(|(name, _)| *name == (*dentry).name()) {
+            Some((_, kind)) => *kind,
+            None => return null_mut()
+        };
+        let perm = FSPermission::IRUSR | FSPermission::IRGRP | FSPermission::IROTH;
+        let inode = proc_get_inode((*dir).logical_part_ptr, proc_inode_pid(dir), kind, FileMode::IFREG, perm, null());
+        if inode.is_null()
+        {
+            return null_mut();
+        }
+        (*inode).i_uid = (*dir).i_uid;
+        (*inode).i_gid = (*dir).i_gid;
+        (*dentry).d_inode = inode;
+        null_mut()
+    }
+}
+
+unsafe fn task_name(task : *mut PCB) -> &'static str
+{
+    CStr::from_ptr((*task).name.as_ptr() as *const c_char).to_str().unwrap_or("")
+}
+
+unsafe fn proc_pid_status(task : *mut PCB) -> String
+{
+    let mut buf = String::new();
+    let (uid, gid) = ((*task).uid, (*task).gid);
+    let _ = write!(buf, "Name:\t{}\nTgid:\t{}\nPid:\t{}\nPPid:\t{}\nUid:\t{}\t{}\t{}\t{}\nGid:\t{}\t{}\t{}\t{}\nFDSize:\t{}\n",
+        task_name(task), (*task).pid, (*task).pid, (*task).ppid, uid, uid, uid, uid, gid, gid, gid, gid, (*task).files.len());
+    task_mmu::task_mem(&mut buf, &(*task).mm);
+    buf.push_str("Threads:\t1\n");
+    buf
+}
+
+// the text of a file right in /proc
+fn proc_root_show(kind : Idx) -> String
+{
+    match kind {
+        PROC_KSM => ksm::ksm_stat(),
+        PROC_SLABINFO => slub::slabinfo(),
+        PROC_KMEMLEAK => kmemleak::kmemleak_read(),
+        _ => String::new()
+    }
+}
+
+// the text is made again on every read, `offset` picks up where the last read stopped
+unsafe fn proc_read(inode : *mut Inode, buffer : *mut c_void, len : usize, offset : usize) -> i64
+{
+    if (*inode).is_dir()
+    {
+        return -EISDIR;
+    }
+    let kind = proc_inode_kind(inode);
+    let text = if proc_root_entry(kind)
+    {
+        proc_root_show(kind)
+    }
+    else {
+        let task = find_task_by_pid(proc_inode_pid(inode));
+        if task.is_null()
+        {
+            return -ESRCH;
+        }
+        match kind {
+            PROC_PID_MAPS => task_mmu::show_maps(addr_of_mut!((*task).mm), false),
+            PROC_PID_SMAPS => task_mmu::show_maps(addr_of_mut!((*task).mm), true),
+            PROC_PID_STATUS => proc_pid_status(task),
+            _ => return -EINVAL
+        }
+    };
+    if offset >= text.len()
+    {
+        return 0;
+    }
+    let count = len.min(text.len() - offset);
+    compiler_builtins::mem::memcpy(buffer.cast(), text.as_ptr().add(offset), count);
+    count as i64
+}
+
+pub(crate) fn proc_file_read(inode : *mut Inode, buffer : *mut c_void, len : usize, offset : usize) -> i64
+{
+    unsafe { proc_read(inode, buffer, len, offset) }
+}
+
+// every write is one whole command, the offset means nothing
+unsafe fn proc_write(inode : *mut Inode, buffer : *const c_void, len : usize) -> i64
+{
+    if (*inode).is_dir()
+    {
+        return -EISDIR;
+    }
+    let cmd = match core::str::from_utf8(core::slice::from_raw_parts(buffer as *const u8, len)) {
+        Ok(cmd) => cmd,
+        Err(_) => return -EINVAL
+    };
+    let ret = match proc_inode_kind(inode) {
+        PROC_KMEMLEAK => kmemleak::kmemleak_write(cmd),
+        _ => -EINVAL
+    };
+    if ret < 0 { ret } else { len as i64 }
+}
+
+pub(crate) fn proc_file_write(inode : *mut Inode, buffer : *const c_void, len : usize, _offset : usize) -> i64
+{
+    unsafe { proc_write(inode, buffer, len) }
+}
+
+fn proc_fill_super(lp : *mut LogicalPart, _fc : *mut FsContext) -> Err
+{
+    unsafe
+    {
+        (*lp).old_fs_type = FSType::Proc;
+        (*lp).s_d_op = addr_of_mut!(PROC_DENTRY_OPERATIONS);
+        let perm = FSPermission::IRUSR | FSPermission::IXUSR | FSPermission::IRGRP | FSPermission::IXGRP | FSPermission::IROTH | FSPermission::IXOTH;
+        let inode = proc_get_inode(lp, 0, PROC_ROOT, FileMode::IFDIR, perm, addr_of!(PROC_ROOT_INODE_OPERATIONS));
+        if inode.is_null()
+        {
+            return -ENOMEM;
+        }
+        (*lp).s_root = DEntry::make_root(inode);
+        if (*lp).s_root.is_null()
+        {
+            return -ENOMEM;
+        }
+        0
+    }
+}
+
+fn proc_get_tree(fc : *mut FsContext) -> Err
+{
+    get_tree_nodev(fc, proc_fill_super)
+}
+
+fn proc_init_fs_context(fc : *mut FsContext) -> Err
+{
+    unsafe
+    {
+        (*fc).ops = addr_of_mut!(PROC_FS_CONTEXT_OPS);
+        0
+    }
+}
+
+#[__init]
+pub fn proc_root_init()
+{
+    unsafe
+    {
+        FS.register_filesystem(addr_of_mut!(PROC_FS_TYPE));
+    }
+}
+
+// the root file system brings an empty /proc, it gets mounted right after the root
+#[__init]
+pub fn proc_mount()
+{
+    unsafe
+    {
+        let path = namei("/proc\0".as_ptr().cast());
+        if path.dentry.is_null() || (*path.dentry).name() != "proc" || !(*path.dentry).is_dir()
+        {
+            logk!("proc: no /proc directory on the root, not mounted\n");
+            return;
+        }
+        let ret = do_mount(Arc::new(String::from("proc\0")), Arc::new(String::from("/proc\0")), Arc::new(String::from("proc\0")), 0, null());
+        if ret < 0
+        {
+            logk!("proc: mount failed {}\n", ret);
+        }
+    }
+}
@@ -1,13 +1,17 @@
 use core::{alloc::Layout, ffi::{c_char, c_void, CStr}, intrinsics::unlikely, ptr::{self, drop_in_place, addr_of, addr_of_mut, null_mut, null}};
 
+use alloc::string::String;
 use proc_macro::__init;
 
-use crate::{fs::{file::{FS, FileMode}, namei::sys_mknod, mount::{MS_MOVE, sys_mount, ROOT_MOUNTFLAGS, mount_root_generic}}, kernel::{device::{self, get_device, DevT, DeviceType, DEV_NULL, mkdev}, errno_base::{is_err, ptr_err, EBUSY, EINVAL, ENOSPC}, Err}, logk, printk};
+use crate::{__setup, fs::{file::{FS, FileMode}, namei::sys_mknod, mount::{MS_MOVE, sys_mount, ROOT_MOUNTFLAGS, mount_root_generic}}, kernel::{device::{self, get_device, DevT, DeviceType, DEV_NULL, mkdev}, errno_base::{is_err, ptr_err, EBUSY, EINVAL, ENOSPC}, Err}, logk, printk};
 
 use super::{ext4::{Ext4DirEntry, Ext4DirEntry2}, file::{early_disk_read, FileSystem, LogicalPart}, fs::{SB_ACTIVE, SB_RDONLY}, ida::Ida, fs_context::FsContext};
 
 static mut UNNAMED_DEV_IDA : Ida = Ida::new();
-pub static ROOT_DEV : DevT = mkdev(259, 0);
+// the first partition of the first disk unless root= says otherwise
+pub static mut ROOT_DEV : DevT = mkdev(259, 0);
+// root=, a /dev name or major:minor
+static mut SAVED_ROOT_NAME : &str = "";
 
 #[__init]
 unsafe fn test_fs()
@@ -219,7 +223,38 @@ pub fn kill_litter_super(sb : *mut LogicalPart)
 #[__init]
 pub fn mount_block_root(root_device_name : *const c_char)
 {
-    sys_mknod("/dev/root\0".as_ptr().cast(), FileMode::IFBLK, ROOT_DEV);
-    mount_root_generic("/dev/root\0".as_ptr().cast(), root_device_name, ROOT_MOUNTFLAGS); 
+    unsafe
+    {
+        sys_mknod("/dev/root\0".as_ptr().cast(), FileMode::IFBLK, ROOT_DEV);
+        mount_root_generic("/dev/root\0".as_ptr().cast(), root_device_name, ROOT_MOUNTFLAGS); 
+    }
     sys_mount(".\0".as_ptr().cast(), "/\0".as_ptr().cast(), null(), MS_MOVE, null());
 }
+
+fn root_dev_setup(name : &'static str) -> bool
+{
+    unsafe { SAVED_ROOT_NAME = name; }
+    true
+}
+__setup!("root=", root_dev_setup);
+
+// the disks are known by now, so root= can be looked up
+#[__init]
+pub fn prepare_namespace()
+{
+    unsafe
+    {
+        if SAVED_ROOT_NAME.is_empty()
+        {
+            mount_block_root("/dev/sda\0".as_ptr().cast());
+            return;
+        }
+        ROOT_DEV = match device::name_to_dev_t(SAVED_ROOT_NAME) {
+            Some(dev) => dev,
+            None => panic!("VFS: Cannot open root device \"{}\"", SAVED_ROOT_NAME)
+        };
+        let mut pretty_name = String::from(SAVED_ROOT_NAME);
+        pretty_name.push('\0');
+        mount_block_root(pretty_name.as_ptr().cast());
+    }
+}
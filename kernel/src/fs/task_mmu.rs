@@ -0,0 +1,282 @@
+use core::fmt::Write;
+
+use alloc::string::String;
+
+use crate::{kernel::{device::{major, minor}, interrupt::{interrupt_disable, set_interrupt_state}}, mm::{huge_memory::{HPAGE_PMD_MASK, HPAGE_PMD_SIZE}, memory::{follow_pmd, follow_pte, pmd_dirty, pmd_page, pmd_trans_huge, pmd_young, pte_page, PAGE_SIZE}, mm_type::{MMCounter, MMStruct, MmapType, VMAreaStruct}, page::{Page, Pageflags}, rmap}};
+
+// pss keeps this many bits below a byte, a page shared by three adds up right again
+const PSS_SHIFT : u32 = 12;
+// where the name starts in a maps line, the same column as on linux
+const MAPS_NAME_COLUMN : usize = 73;
+
+#[derive(Default)]
+struct MemSizeStats
+{
+    resident : u64,
+    shared_clean : u64,
+    shared_dirty : u64,
+    private_clean : u64,
+    private_dirty : u64,
+    referenced : u64,
+    anonymous : u64,
+    lazyfree : u64,
+    anonymous_thp : u64,
+    swap : u64,
+    pss : u64,
+    pss_locked : u64
+}
+
+fn vma_is_stack(mm : &MMStruct, vma : *mut VMAreaStruct) -> bool
+{
+    unsafe { vma == mm.stack || (*vma).get_flags().contains(MmapType::VM_GROWSDOWN) }
+}
+
+fn vma_is_shared(vma : *mut VMAreaStruct) -> bool
+{
+    unsafe { (*vma).get_flags().intersects(MmapType::MAP_SHARED | MmapType::VM_MAYSHARE) }
+}
+
+fn vma_is_readable(vma : *mut VMAreaStruct) -> bool
+{
+    unsafe { (*vma).get_vm_prot().contains(MmapType::PROT_READ) }
+}
+
+fn vma_is_writable(vma : *mut VMAreaStruct) -> bool
+{
+    unsafe { (*vma).get_vm_prot().contains(MmapType::PROT_WRITE) }
+}
+
+fn vma_pages(vma : *mut VMAreaStruct) -> usize
+{
+    unsafe { ((*vma).get_end() + 1 - (*vma).get_start()) as usize / PAGE_SIZE }
+}
+
+// the name after the inode column, the path a file was opened by
+unsafe fn vma_name(mm : &MMStruct, vma : *mut VMAreaStruct) -> &str
+{
+    let file = (*vma).get_file();
+    if !file.is_null()
+    {
+        return &(*file).f_path;
+    }
+    if (*vma).get_start() <= mm.brk && (*vma).get_end() + 1 >= mm.start_brk
+    {
+        return "[heap]";
+    }
+    if vma_is_stack(mm, vma)
+    {
+        return "[stack]";
+    }
+    ""
+}
+
+unsafe fn show_map_vma(buf : &mut String, mm : &MMStruct, vma : *mut VMAreaStruct)
+{
+    let line_start = buf.len();
+    let flags = (*vma).get_flags();
+    let file = (*vma).get_file();
+    let (dev, ino, pgoff) = if file.is_null() { (0, 0, 0) } else { ((*(*file).inode).dev, (*(*file).inode).nr, (*vma).get_offset()) };
+    let _ = write!(buf, "{:08x}-{:08x} {}{}{}{} {:08x} {:02x}:{:02x} {} ", (*vma).get_start(), (*vma).get_end() + 1,
+        if vma_is_readable(vma) { 'r' } else { '-' }, if vma_is_writable(vma) { 'w' } else { '-' }, if flags.contains(MmapType::VM_EXEC) { 'x' } else { '-' }, if vma_is_shared(vma) { 's' } else { 'p' },
+        pgoff, major(dev), minor(dev), ino);
+    let name = vma_name(mm, vma);
+    if !name.is_empty()
+    {
+        while buf.len() - line_start < MAPS_NAME_COLUMN {
+            buf.push(' ');
+        }
+        buf.push_str(name);
+    }
+    buf.push('\n');
+}
+
+// one mapped page or huge page of `size` bytes, `page` may be null for frames outside mem_map
+unsafe fn smaps_account(mss : &mut MemSizeStats, page : *mut Page, size : u64, young : bool, dirty : bool, locked : bool)
+{
+    mss.resident += size;
+    if page.is_null()
+    {
+        mss.pss += size << PSS_SHIFT;
+        if dirty { mss.private_dirty += size; } else { mss.private_clean += size; }
+        return;
+    }
+    let dirty = dirty || (*page).flags.contains(Pageflags::PgDirty);
+    if rmap::page_anon(&*page)
+    {
+        mss.anonymous += size;
+        if (*page).flags.contains(Pageflags::PgLazyfree) && !dirty
+        {
+            mss.lazyfree += size;
+        }
+    }
+    if young || (*page).flags.contains(Pageflags::PgReferenced)
+    {
+        mss.referenced += size;
+    }
+    let mapcount = rmap::page_mapcount(&*page).max(1) as u64;
+    let pss = (size << PSS_SHIFT) / mapcount;
+    mss.pss += pss;
+    if locked
+    {
+        mss.pss_locked += pss;
+    }
+    match (mapcount >= 2, dirty) {
+        (true, true) => mss.shared_dirty += size,
+        (true, false) => mss.shared_clean += size,
+        (false, true) => mss.private_dirty += size,
+        (false, false) => mss.private_clean += size
+    }
+}
+
+// walk the page tables under `vma`, nothing is faulted in
+unsafe fn smap_gather_stats(mm : *mut MMStruct, vma : *mut VMAreaStruct, mss : &mut MemSizeStats)
+{
+    let locked = (*vma).get_flags().contains(MmapType::VM_LOCKED);
+    let end = (*vma).get_end() + 1;
+    let mut addr = (*vma).get_start();
+    while addr < end {
+        let pmd = follow_pmd(mm, addr);
+        if !pmd.is_null() && pmd_trans_huge(&*pmd)
+        {
+            let page = pmd_page(&*pmd);
+            smaps_account(mss, page, HPAGE_PMD_SIZE, pmd_young(&*pmd), pmd_dirty(&*pmd), locked);
+            if !page.is_null() && rmap::page_anon(&*page)
+            {
+                mss.anonymous_thp += HPAGE_PMD_SIZE;
+            }
+            addr = (addr & HPAGE_PMD_MASK) + HPAGE_PMD_SIZE;
+            continue;
+        }
+        let mut step = PAGE_SIZE as u64;
+        let pte = follow_pte(mm, addr, &mut step);
+        if pte.is_null()
+        {
+            addr = (addr & !(step - 1)) + step;
+            continue;
+        }
+        if (*pte).get_present() != 0
+        {
+            smaps_account(mss, pte_page((*pte).raw()), PAGE_SIZE as u64, (*pte).get_accessed() != 0, (*pte).get_dirty() != 0, locked);
+        }
+        else if (*pte).raw() != 0
+        {
+            mss.swap += PAGE_SIZE as u64;
+        }
+        addr += PAGE_SIZE as u64;
+    }
+}
+
+// the two letter codes linux prints, for the flags this kernel has
+fn show_smap_vma_flags(buf : &mut String, vma : *mut VMAreaStruct)
+{
+    const MNEMONICS : [(MmapType, &str); 12] = [
+        (MmapType::VM_GROWSDOWN, "gd"), (MmapType::VM_LOCKED, "lo"), (MmapType::VM_IO, "io"),
+        (MmapType::VM_SEQ_READ, "sr"), (MmapType::VM_RAND_READ, "rr"), (MmapType::VM_DONTCOPY, "dc"),
+        (MmapType::VM_DONTEXPAND, "de"), (MmapType::VM_NORESERVE, "nr"), (MmapType::VM_DONTDUMP, "dd"),
+        (MmapType::VM_HUGEPAGE, "hg"), (MmapType::VM_NOHUGEPAGE, "nh"), (MmapType::VM_MERGEABLE, "mg")
+    ];
+    let flags = unsafe { (*vma).get_flags() };
+    buf.push_str("VmFlags: ");
+    if vma_is_readable(vma)
+    {
+        buf.push_str("rd ");
+    }
+    if vma_is_writable(vma)
+    {
+        buf.push_str("wr ");
+    }
+    if flags.contains(MmapType::VM_EXEC)
+    {
+        buf.push_str("ex ");
+    }
+    if vma_is_shared(vma)
+    {
+        buf.push_str("sh ");
+    }
+    for (flag, name) in MNEMONICS.iter() {
+        if flags.contains(*flag)
+        {
+            buf.push_str(name);
+            buf.push(' ');
+        }
+    }
+    buf.push('\n');
+}
+
+unsafe fn show_smap(buf : &mut String, mm : *mut MMStruct, vma : *mut VMAreaStruct)
+{
+    let mut mss = MemSizeStats::default();
+    smap_gather_stats(mm, vma, &mut mss);
+    show_map_vma(buf, &*mm, vma);
+    let _ = write!(buf, "Size:           {:>8} kB\nKernelPageSize: {:>8} kB\nMMUPageSize:    {:>8} kB\n", vma_pages(vma) * PAGE_SIZE / 1024, PAGE_SIZE / 1024, PAGE_SIZE / 1024);
+    let fields = [
+        ("Rss", mss.resident), ("Pss", mss.pss >> PSS_SHIFT), ("Shared_Clean", mss.shared_clean), ("Shared_Dirty", mss.shared_dirty),
+        ("Private_Clean", mss.private_clean), ("Private_Dirty", mss.private_dirty), ("Referenced", mss.referenced), ("Anonymous", mss.anonymous),
+        ("LazyFree", mss.lazyfree), ("AnonHugePages", mss.anonymous_thp), ("Swap", mss.swap), ("Locked", mss.pss_locked >> PSS_SHIFT)
+    ];
+    for (name, bytes) in fields.iter() {
+        let _ = writeln!(buf, "{:<16}{:>8} kB", alloc::format!("{}:", name), bytes / 1024);
+    }
+    show_smap_vma_flags(buf, vma);
+}
+
+// /proc/<pid>/maps, one line per area, or /proc/<pid>/smaps with the page counts of each
+pub unsafe fn show_maps(mm : *mut MMStruct, smaps : bool) -> String
+{
+    let mut buf = String::new();
+    // the areas can't be split, merged or unmapped under the walk
+    let state = interrupt_disable();
+    let mut vma = (*mm).mmap;
+    while !vma.is_null() {
+        if smaps
+        {
+            show_smap(&mut buf, mm, vma);
+        }
+        else {
+            show_map_vma(&mut buf, &*mm, vma);
+        }
+        vma = (*vma).get_next();
+    }
+    set_interrupt_state(state);
+    buf
+}
+
+// the Vm and Rss lines of /proc/<pid>/status
+pub fn task_mem(buf : &mut String, mm : &MMStruct)
+{
+    let (mut data, mut stack, mut exec) = (0, 0, 0);
+    let state = interrupt_disable();
+    let mut vma = mm.mmap;
+    unsafe
+    {
+        while !vma.is_null() {
+            let pages = vma_pages(vma);
+            if vma_is_stack(mm, vma)
+            {
+                stack += pages;
+            }
+            else if vma_is_writable(vma) && !vma_is_shared(vma)
+            {
+                data += pages;
+            }
+            else if (*vma).get_flags().contains(MmapType::VM_EXEC) && !vma_is_writable(vma)
+            {
+                exec += pages;
+            }
+            vma = (*vma).get_next();
+        }
+    }
+    set_interrupt_state(state);
+    let anon = mm.get_mm_counter(MMCounter::AnonPages);
+    let file = mm.get_mm_counter(MMCounter::FilePages);
+    let shmem = mm.get_mm_counter(MMCounter::ShmemPages);
+    let total_vm = mm.total_vm();
+    let fields = [
+        ("VmPeak", mm.hiwater_vm.max(total_vm)), ("VmSize", total_vm), ("VmLck", mm.locked_vm()),
+        ("VmHWM", mm.hiwater_rss.max(mm.get_mm_rss())), ("VmRSS", mm.get_mm_rss()), ("RssAnon", anon), ("RssFile", file), ("RssShmem", shmem),
+        ("VmData", data), ("VmStk", stack), ("VmExe", exec), ("VmSwap", mm.get_mm_counter(MMCounter::SwapEnts))
+    ];
+    for (name, pages) in fields.iter() {
+        let _ = writeln!(buf, "{}:\t{:>8} kB", name, pages * PAGE_SIZE / 1024);
+    }
+}
@@ -0,0 +1,78 @@
+use core::ptr::null_mut;
+
+use alloc::vec::Vec;
+
+use crate::fs::file::FS;
+
+use super::{errno_base::ENOEXEC, execve::{open_exec, prepare_binprm, search_binary_handler, LinuxBinfmt, LinuxBinprm, BINPRM_BUF_SIZE}, Err};
+
+pub static SCRIPT_FORMAT : LinuxBinfmt = LinuxBinfmt { name: "script", load_binary: load_script };
+
+fn is_blank(c : u8) -> bool
+{
+    c == b' ' || c == b'\t'
+}
+
+// "#!interpreter [arg]", the whole rest of the line is one argument like on linux
+// argv becomes: interpreter [arg] script original-argv[1..]
+fn load_script(bprm : &mut LinuxBinprm) -> Err
+{
+    if bprm.buf_len < 2 || bprm.buf[0] != b'#' || bprm.buf[1] != b'!'
+    {
+        return -ENOEXEC;
+    }
+    let mut end = 2;
+    while end < bprm.buf_len && bprm.buf[end] != b'\n' && bprm.buf[end] != 0 {
+        end += 1;
+    }
+    // the interpreter line got cut off by the buffer
+    if end == BINPRM_BUF_SIZE
+    {
+        return -ENOEXEC;
+    }
+    let mut start = 2;
+    while start < end && is_blank(bprm.buf[start]) {
+        start += 1;
+    }
+    while end > start && (is_blank(bprm.buf[end - 1]) || bprm.buf[end - 1] == b'\r') {
+        end -= 1;
+    }
+    if start == end
+    {
+        return -ENOEXEC;
+    }
+    let mut name_end = start;
+    while name_end < end && !is_blank(bprm.buf[name_end]) {
+        name_end += 1;
+    }
+    let mut interp = Vec::from(&bprm.buf[start..name_end]);
+    interp.push(0);
+    let mut arg_start = name_end;
+    while arg_start < end && is_blank(bprm.buf[arg_start]) {
+        arg_start += 1;
+    }
+    // the script replaces argv[0], it is opened again by path in the interpreter
+    bprm.argv.pop_front();
+    bprm.argv.push_front(bprm.filename.clone());
+    if arg_start < end
+    {
+        let mut arg = Vec::from(&bprm.buf[arg_start..end]);
+        arg.push(0);
+        bprm.argv.push_front(arg);
+    }
+    bprm.argv.push_front(interp.clone());
+
+    unsafe
+    {
+        FS.release_file(bprm.file);
+    }
+    bprm.file = null_mut();
+    match open_exec(interp.as_ptr().cast()) {
+        Ok(file_t) => bprm.file = file_t,
+        Err(err) => return err
+    }
+    bprm.filename = interp;
+    bprm.depth += 1;
+    prepare_binprm(bprm);
+    search_binary_handler(bprm)
+}
@@ -69,6 +69,27 @@ impl BitMap {
         }
     }
 
+    // first clear bit at or after `start`, whole bytes of ones are skipped
+    pub fn find_next_zero(&mut self, mut idx : usize) -> Option<usize>
+    {
+        unsafe
+        {
+            while idx < self.length {
+                if idx % 8 == 0 && *self.data.add(idx / 8) == 0xff
+                {
+                    idx += 8;
+                    continue;
+                }
+                if (*self.data.add(idx / 8)) & (1 << idx % 8) == 0
+                {
+                    return Some(idx);
+                }
+                idx += 1;
+            }
+            None
+        }
+    }
+
     pub fn test_and_set(&mut self, idx : usize) -> bool
     {
         unsafe
@@ -1,10 +1,20 @@
-use core::{ffi::c_void, alloc::{Layout, GlobalAlloc}};
+use core::{ffi::c_void, alloc::{Layout, GlobalAlloc}, ptr::{addr_of_mut, null_mut}};
 
-use alloc::alloc::{alloc, dealloc};
+use alloc::{alloc::{alloc, dealloc}, vec::Vec};
+use proc_macro::__init;
 
-use crate::fs::ext4::Idx;
+use crate::{container_of, fs::{ext4::Idx, file::FSType, namei::Fd}, logk, mm::{memory::{get_cr3_reg, MemoryPool, PAGE_SIZE}, vmscan::{register_shrinker, Shrinker}}, printk};
 
-use super::{semaphore::RWLock, device::{DevT, DevReqType, device_request}};
+use super::{clock::{schedule_timeout, JIFFIES, JIFFY}, device::{DevT, DevReqType, device_request}, errno_base::EBADF, io::SECTOR_SIZE, list::ListHead, process::{PCB, PF_KTHREAD}, sched::get_current_running_process, semaphore::{RWLock, UnreenterabkeSpinLock}, Err};
+
+// hash chains of the buffer cache, keyed by (dev, first sector)
+const NR_HASH : usize = 307;
+// the flusher wakes every 5s and writes back what has been dirty for 30s
+const FLUSH_INTERVAL : u64 = 5000 / JIFFY;
+const DIRTY_EXPIRE : u64 = 30000 / JIFFY;
+
+pub static mut BUFFER_CACHE : BufferCache = BufferCache::new();
+static BUFFER_SHRINKER : Shrinker = Shrinker { name: "buffer", count_objects: buffer_shrink_count, scan_objects: buffer_shrink_scan };
 
 pub struct Buffer
 {
@@ -15,7 +25,30 @@ pub struct Buffer
     buffer_size : usize,
     pub count : usize,
     avaliable : bool,
-    pub dirty : bool
+    pub dirty : bool,
+    dirtied_when : u64, // jiffies of the first write since the last writeback
+    hash_node : ListHead,
+    lru_node : ListHead // on the lru while nobody holds the buffer
+}
+
+#[derive(Clone, Copy)]
+pub struct BufferCacheStats
+{
+    pub hits : u64,
+    pub misses : u64,
+    pub evictions : u64,
+    pub writebacks : u64
+}
+
+pub struct BufferCache
+{
+    hash_table : [ListHead; NR_HASH],
+    lru : ListHead, // unused buffers, the least recently released first
+    lock : UnreenterabkeSpinLock,
+    pub nr_buffers : usize,
+    pub nr_bytes : usize,
+    pub max_bytes : usize,
+    pub stats : BufferCacheStats
 }
 
 impl Buffer {
@@ -47,7 +80,7 @@ impl Buffer {
     pub fn new(buffer_size : usize) -> Self
     {
         let buffer = unsafe { alloc(Layout::from_size_align(buffer_size, 8).unwrap()) as *mut c_void };
-        Self { rw_lock: RWLock::new(), buffer, buffer_size, count: 1, avaliable: false, dirty: false, dev: 0, idx: 0 }
+        Self { rw_lock: RWLock::new(), buffer, buffer_size, count: 1, avaliable: false, dirty: false, dev: 0, idx: 0, dirtied_when: 0, hash_node: ListHead::empty(), lru_node: ListHead::empty() }
     }
 
     pub fn dispose(&mut self)
@@ -63,9 +96,10 @@ impl Buffer {
     {
         if len + offset <= self.buffer_size
         {
-            self.rw_lock.wrlock(); 
+            self.rw_lock.wrlock();
             unsafe { compiler_builtins::mem::memcpy(self.buffer.offset(offset as isize) as *mut u8, src as *mut u8, len) };
             self.avaliable = true;
+            self.mark_dirty();
             self.rw_lock.wrunlock();
         }
         else {
@@ -92,7 +126,7 @@ impl Buffer {
         }
         if len + offset <= self.buffer_size
         {
-            self.rw_lock.rdlock(); 
+            self.rw_lock.rdlock();
             unsafe { compiler_builtins::mem::memcpy(dst as *mut u8, self.buffer.offset(offset as isize) as *mut u8, len) };
             self.rw_lock.rdunlock();
         }
@@ -101,4 +135,346 @@ impl Buffer {
         }
     }
 
-}
\ No newline at end of file
+    // the flusher picks it up once it has been dirty for DIRTY_EXPIRE
+    pub fn mark_dirty(&mut self)
+    {
+        if !self.dirty
+        {
+            self.dirty = true;
+            self.dirtied_when = unsafe { JIFFIES };
+        }
+    }
+}
+
+impl BufferCacheStats {
+    const fn new() -> Self
+    {
+        Self { hits: 0, misses: 0, evictions: 0, writebacks: 0 }
+    }
+}
+
+impl BufferCache {
+    const fn new() -> Self
+    {
+        Self { hash_table: [ListHead::empty(); NR_HASH], lru: ListHead::empty(), lock: UnreenterabkeSpinLock::new(1), nr_buffers: 0, nr_bytes: 0, max_bytes: 0, stats: BufferCacheStats::new() }
+    }
+
+    #[inline(always)]
+    fn hashfn(dev : DevT, idx : Idx) -> usize
+    {
+        ((dev as Idx ^ idx) % NR_HASH as Idx) as usize
+    }
+
+    unsafe fn lookup(&mut self, dev : DevT, idx : Idx, size : usize) -> *mut Buffer
+    {
+        let head = addr_of_mut!(self.hash_table[Self::hashfn(dev, idx)]);
+        let mut node = (*head).next;
+        while node != head {
+            let buffer = container_of!(node, Buffer, hash_node);
+            if (*buffer).dev == dev && (*buffer).idx == idx && (*buffer).buffer_size == size
+            {
+                return buffer;
+            }
+            node = (*node).next;
+        }
+        null_mut()
+    }
+
+    // buffer of `size` bytes starting at sector `idx`, referenced for the caller
+    // a new one has no contents until it is read or written
+    pub fn getblk(&mut self, dev : DevT, idx : Idx, size : usize) -> *mut Buffer
+    {
+        unsafe
+        {
+            self.lock.acquire(1);
+            let buffer = self.lookup(dev, idx, size);
+            if !buffer.is_null()
+            {
+                if (*buffer).count == 0
+                {
+                    (*buffer).lru_node.delete();
+                }
+                (*buffer).count += 1;
+                self.stats.hits += 1;
+                self.lock.release(1);
+                return buffer;
+            }
+            self.stats.misses += 1;
+            let buffer = alloc(Layout::new::<Buffer>()) as *mut Buffer;
+            buffer.write(Buffer::new(size));
+            (*buffer).dev = dev;
+            (*buffer).idx = idx;
+            (*buffer).hash_node.head_insert(&mut self.hash_table[Self::hashfn(dev, idx)]);
+            self.nr_buffers += 1;
+            self.nr_bytes += size;
+            self.lock.release(1);
+            if self.nr_bytes > self.max_bytes
+            {
+                self.shrink(self.max_bytes);
+            }
+            buffer
+        }
+    }
+
+    // getblk with the contents read in from the device
+    pub fn bread(&mut self, dev : DevT, idx : Idx, size : usize) -> *mut Buffer
+    {
+        unsafe
+        {
+            let buffer = self.getblk(dev, idx, size);
+            if !(*buffer).is_avaliable()
+            {
+                (*buffer).read_from_device(dev, idx, size / SECTOR_SIZE as usize);
+            }
+            buffer
+        }
+    }
+
+    // drop a reference, an unused buffer stays cached at the hot end of the lru
+    pub fn brelse(&mut self, buffer : *mut Buffer)
+    {
+        unsafe
+        {
+            self.lock.acquire(1);
+            assert!((*buffer).count > 0, "release free buffer");
+            (*buffer).count -= 1;
+            if (*buffer).count == 0
+            {
+                (*buffer).lru_node.tail_insert(&mut self.lru);
+            }
+            self.lock.release(1);
+        }
+    }
+
+    unsafe fn write_buffer(&mut self, buffer : *mut Buffer)
+    {
+        (*buffer).dirty = false;
+        (*buffer).write_to_device((*buffer).dev, (*buffer).idx, (*buffer).buffer_size / SECTOR_SIZE as usize);
+        self.stats.writebacks += 1;
+    }
+
+    // write back a buffer the caller holds a reference on, if it is dirty
+    pub unsafe fn sync_buffer(&mut self, buffer : *mut Buffer)
+    {
+        if (*buffer).dirty
+        {
+            self.write_buffer(buffer);
+        }
+    }
+
+    // write back the cached buffer of `size` bytes at sector `idx` if there is a dirty one
+    pub fn sync_block(&mut self, dev : DevT, idx : Idx, size : usize)
+    {
+        unsafe
+        {
+            self.lock.acquire(1);
+            let buffer = self.lookup(dev, idx, size);
+            if buffer.is_null() || !(*buffer).dirty
+            {
+                self.lock.release(1);
+                return;
+            }
+            if (*buffer).count == 0
+            {
+                (*buffer).lru_node.delete();
+            }
+            (*buffer).count += 1;
+            self.lock.release(1);
+            self.write_buffer(buffer);
+            self.brelse(buffer);
+        }
+    }
+
+    // evict unused buffers from the cold end of the lru until the cache fits in `target` bytes
+    // dirty ones are written back first, returns how many were freed
+    pub fn shrink(&mut self, target : usize) -> usize
+    {
+        unsafe
+        {
+            let mut freed = 0;
+            loop {
+                self.lock.acquire(1);
+                if self.nr_bytes <= target || self.lru.is_empty()
+                {
+                    self.lock.release(1);
+                    return freed;
+                }
+                let buffer = container_of!(self.lru.next, Buffer, lru_node);
+                (*buffer).lru_node.delete();
+                if (*buffer).dirty
+                {
+                    (*buffer).count = 1;
+                    self.lock.release(1);
+                    self.write_buffer(buffer);
+                    self.brelse(buffer);
+                    continue;
+                }
+                (*buffer).hash_node.delete();
+                self.nr_buffers -= 1;
+                self.nr_bytes -= (*buffer).buffer_size;
+                self.stats.evictions += 1;
+                self.lock.release(1);
+                (*buffer).dispose();
+                freed += 1;
+            }
+        }
+    }
+
+    // write back the dirty buffers of `dev`, or of every device for None,
+    // that have been dirty for at least `expire` jiffies
+    pub fn writeback(&mut self, dev : Option<DevT>, expire : u64) -> usize
+    {
+        unsafe
+        {
+            let mut dirty = Vec::new();
+            self.lock.acquire(1);
+            let mut var = 0;
+            while var < NR_HASH {
+                let head = addr_of_mut!(self.hash_table[var]);
+                let mut node = (*head).next;
+                while node != head {
+                    let buffer = container_of!(node, Buffer, hash_node);
+                    if (*buffer).dirty && dev.map_or(true, |dev| dev == (*buffer).dev) && JIFFIES - (*buffer).dirtied_when >= expire
+                    {
+                        if (*buffer).count == 0
+                        {
+                            (*buffer).lru_node.delete();
+                        }
+                        (*buffer).count += 1;
+                        dirty.push(buffer);
+                    }
+                    node = (*node).next;
+                }
+                var += 1;
+            }
+            self.lock.release(1);
+            for buffer in dirty.iter() {
+                self.write_buffer(*buffer);
+                self.brelse(*buffer);
+            }
+            dirty.len()
+        }
+    }
+}
+
+// an eighth of memory for cached blocks
+#[__init]
+pub fn buffer_init()
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < NR_HASH {
+            BUFFER_CACHE.hash_table[var].init();
+            var += 1;
+        }
+        BUFFER_CACHE.lru.init();
+        BUFFER_CACHE.max_bytes = MemoryPool::total_pages() * PAGE_SIZE / 8;
+        register_shrinker(&BUFFER_SHRINKER);
+        logk!("buffer cache: {} hash chains, up to {} bytes\n", NR_HASH, BUFFER_CACHE.max_bytes);
+    }
+}
+
+// cached bytes in pages
+fn buffer_shrink_count() -> usize
+{
+    unsafe { BUFFER_CACHE.nr_bytes / PAGE_SIZE }
+}
+
+fn buffer_shrink_scan(nr_to_scan : usize) -> usize
+{
+    unsafe
+    {
+        // an allocation made under the cache lock has to leave the cache alone
+        if !BUFFER_CACHE.lock.try_acquire(1)
+        {
+            return 0;
+        }
+        BUFFER_CACHE.lock.release(1);
+        BUFFER_CACHE.shrink(BUFFER_CACHE.nr_bytes.saturating_sub(nr_to_scan * PAGE_SIZE))
+    }
+}
+
+fn bdflush()
+{
+    unsafe
+    {
+        loop {
+            schedule_timeout(FLUSH_INTERVAL);
+            BUFFER_CACHE.writeback(None, DIRTY_EXPIRE);
+        }
+    }
+}
+
+// kernel thread writing back old dirty buffers
+pub fn bdflush_init()
+{
+    unsafe
+    {
+        let pcb = PCB::create_new_process(bdflush as u64, 0);
+        (*pcb).flags |= PF_KTHREAD;
+        compiler_builtins::mem::memcpy((*pcb).name.as_ptr() as *mut u8, "bdflush".as_ptr(), 7);
+        (*pcb).pml4 = get_cr3_reg() as *mut _;
+        (*pcb).insert_to_task_table();
+    }
+}
+
+pub fn show_buffer_cache()
+{
+    unsafe
+    {
+        let stats = BUFFER_CACHE.stats;
+        printk!("buffers: {} ({} bytes of {}), hits {}, misses {}, evictions {}, writebacks {}\n", BUFFER_CACHE.nr_buffers, BUFFER_CACHE.nr_bytes, BUFFER_CACHE.max_bytes, stats.hits, stats.misses, stats.evictions, stats.writebacks);
+    }
+}
+
+pub fn sys_sync() -> Err
+{
+    unsafe
+    {
+        BUFFER_CACHE.writeback(None, 0);
+    }
+    0
+}
+
+// the data blocks of the file and the block holding its inode, a block device syncs all of it
+pub fn sys_fsync(fd : Fd) -> Err
+{
+    unsafe
+    {
+        let file_t = (*get_current_running_process()).get_file(fd);
+        if file_t.is_null()
+        {
+            return -EBADF;
+        }
+        let inode = (*file_t).inode;
+        if (*inode).is_blk()
+        {
+            BUFFER_CACHE.writeback(Some((*inode).i_rdev), 0);
+            return 0;
+        }
+        let logical_part = (*inode).logical_part_ptr;
+        // shmem and proc have nothing on a device
+        if logical_part.is_null() || (*logical_part).old_fs_type != FSType::Ext4
+        {
+            return 0;
+        }
+        let block_size = (*logical_part).logic_block_size as usize * 1024;
+        let blocks = (*inode).get_size().div_ceil(block_size);
+        let mut var = 0;
+        while var < blocks {
+            // a hole has nothing to write back
+            let block_idx = (*logical_part).bmap(inode, var as Idx);
+            if block_idx != 0
+            {
+                BUFFER_CACHE.sync_block((*logical_part).s_dev, block_idx * 2 * (*logical_part).logic_block_size as Idx, block_size);
+            }
+            var += 1;
+        }
+        if !(*inode).inode_block_buffer.is_null()
+        {
+            BUFFER_CACHE.sync_buffer((*inode).inode_block_buffer);
+        }
+        0
+    }
+}
@@ -1,7 +1,7 @@
 use core::arch::asm;
 use proc_macro::__init;
 
-use crate::{logk, kernel::{sched, process::{self, PtRegs}}};
+use crate::{logk, kernel::{percpu, sched, process::{self, PtRegs}}};
 
 use super::{io::{self, outb, inb}, interrupt::{self, IRQ_CLOCK}};
 
@@ -19,6 +19,8 @@ pub const JIFFY : u64 = 1000 / HZ;
 
 static mut BEEPING : bool = false;
 pub static mut JIFFIES : u64 = 0;
+// the earliest jiffy a task sleeping in schedule_timeout wants to run again
+static mut NEXT_TIMER : u64 = u64::MAX;
 
 extern "C" fn clock_handler(vector : u64, pt_regs : PtRegs)
 {
@@ -28,6 +30,10 @@ extern "C" fn clock_handler(vector : u64, pt_regs : PtRegs)
         // logk!("clock interrupt occured\n");
         interrupt::send_eoi(vector as u32);
         JIFFIES += 1;
+        if JIFFIES >= timer_expires()
+        {
+            timer_wakeup();
+        }
         process::schedule();
     }
 }
@@ -55,12 +61,49 @@ pub fn clock_init()
 
 fn timer_expires() -> u64
 {
-    0
+    unsafe { NEXT_TIMER }
 }
 
+// every task whose sleep ran out goes back on the run queue
 fn timer_wakeup()
 {
+    unsafe
+    {
+        let mut next = u64::MAX;
+        process::for_each_process(|pcb| {
+            if (*pcb).sleep_until == 0
+            {
+                return;
+            }
+            if (*pcb).sleep_until <= JIFFIES
+            {
+                (*pcb).sleep_until = 0;
+                process::awake_process(pcb);
+            }
+            else {
+                next = next.min((*pcb).sleep_until);
+            }
+        });
+        NEXT_TIMER = next;
+    }
+}
 
+// sleep off the run queue for `timeout` jiffies, wake_up_process ends it early
+pub fn schedule_timeout(timeout : u64)
+{
+    unsafe
+    {
+        let pcb = sched::get_current_running_process();
+        if pcb.is_null() || percpu::preempt_count() != 0
+        {
+            return;
+        }
+        let state = interrupt::interrupt_disable();
+        (*pcb).sleep_until = JIFFIES + timeout.max(1);
+        NEXT_TIMER = NEXT_TIMER.min((*pcb).sleep_until);
+        process::schedule_block();
+        interrupt::set_interrupt_state(state);
+    }
 }
 
 pub fn start_beep()
@@ -0,0 +1,234 @@
+use core::ptr::addr_of;
+
+use proc_macro::__init;
+
+pub const COMMAND_LINE_SIZE : usize = 256;
+pub const MAX_INIT_ARGS : usize = 32;
+pub const MAX_INIT_ENVS : usize = 32;
+// the sector after the boot sector, image.mk writes $(CMDLINE) there and the loader reads it in
+const BOOT_CMDLINE_ADDR : *const u8 = 0xffff800000001800 as *const u8;
+
+static mut BOOT_COMMAND_LINE : [u8; COMMAND_LINE_SIZE] = [0; COMMAND_LINE_SIZE];
+
+// a handler for `name=` or a bare `name`, __setup! puts these between __setup_start and __setup_end
+pub struct ObsKernelParam
+{
+    pub name : &'static str,
+    // gets what follows the `=`, false hands the parameter on to init
+    pub setup_func : fn(&'static str) -> bool
+}
+
+#[macro_export]
+macro_rules! __setup {
+    ($name:expr, $func:path) => {
+        const _ : () = {
+            #[used]
+            #[link_section = ".init.setup"]
+            static __SETUP_PARAM : $crate::kernel::cmdline::ObsKernelParam = $crate::kernel::cmdline::ObsKernelParam { name: $name, setup_func: $func };
+        };
+    };
+}
+
+extern "C"
+{
+    static __setup_start : u8;
+    static __setup_end : u8;
+}
+
+// what init gets besides its path, words nobody claimed become argv, `name=value` ones the environment
+static mut ARGV_INIT : [&str; MAX_INIT_ARGS] = [""; MAX_INIT_ARGS];
+static mut ARGV_INIT_CNT : usize = 0;
+static mut ENVP_INIT : [(&str, &str); MAX_INIT_ENVS] = [("", ""); MAX_INIT_ENVS];
+static mut ENVP_INIT_CNT : usize = 0;
+
+// runs before relocation, nothing here may go through a pointer stored in data
+#[__init]
+pub fn setup_boot_command_line()
+{
+    unsafe { copy_boot_command_line(BOOT_CMDLINE_ADDR) }
+}
+
+// a multiboot loader hands over its own string, anything past COMMAND_LINE_SIZE is cut off
+#[__init]
+pub unsafe fn copy_boot_command_line(src : *const u8)
+{
+    let mut var = 0;
+    while var < COMMAND_LINE_SIZE - 1 && *src.add(var) != 0 {
+        BOOT_COMMAND_LINE[var] = *src.add(var);
+        var += 1;
+    }
+    BOOT_COMMAND_LINE[var] = 0;
+}
+
+fn command_line_bytes() -> &'static [u8]
+{
+    unsafe
+    {
+        let cmdline = &*addr_of!(BOOT_COMMAND_LINE);
+        let mut len = 0;
+        while len < COMMAND_LINE_SIZE && cmdline[len] != 0 {
+            len += 1;
+        }
+        &cmdline[..len]
+    }
+}
+
+pub fn boot_command_line() -> &'static str
+{
+    core::str::from_utf8(command_line_bytes()).unwrap_or("")
+}
+
+// true when `option` shows up as a word of its own, "nokaslr" but not "nokaslr=1"
+pub fn cmdline_find_option_bool(option : &str) -> bool
+{
+    command_line_bytes().split(|c| *c == b' ' || *c == b'\t').any(|word| word == option.as_bytes())
+}
+
+// split off the first parameter as (token, name, value, rest), quotes may hold spaces and are dropped
+fn next_arg(args : &'static str) -> (&'static str, &'static str, Option<&'static str>, &'static str)
+{
+    let bytes = args.as_bytes();
+    let quoted = bytes.first() == Some(&b'"');
+    let start = quoted as usize;
+    let mut in_quote = quoted;
+    let mut equals = None;
+    let mut var = start;
+    while var < bytes.len() {
+        if (bytes[var] == b' ' || bytes[var] == b'\t') && !in_quote
+        {
+            break;
+        }
+        if equals.is_none() && bytes[var] == b'='
+        {
+            equals = Some(var);
+        }
+        if bytes[var] == b'"'
+        {
+            in_quote = !in_quote;
+        }
+        var += 1;
+    }
+    let rest = args[var..].trim_start_matches([' ', '\t']);
+    let mut end = var;
+    if quoted && end > start && bytes[end - 1] == b'"'
+    {
+        end -= 1;
+    }
+    let token = &args[start..end];
+    match equals {
+        Some(equals) if equals < end =>
+        {
+            let mut value = &args[equals + 1..end];
+            if value.len() >= 2 && value.starts_with('"') && value.ends_with('"')
+            {
+                value = &value[1..value.len() - 1];
+            }
+            (token, &args[start..equals], Some(value), rest)
+        },
+        _ => (token, token, None, rest)
+    }
+}
+
+fn setup_params() -> &'static [ObsKernelParam]
+{
+    unsafe
+    {
+        let start = addr_of!(__setup_start) as *const ObsKernelParam;
+        let end = addr_of!(__setup_end) as *const ObsKernelParam;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+// `name=` handlers want a value, bare ones match only without one
+fn obsolete_checksetup(param : &str, value : Option<&'static str>) -> bool
+{
+    for p in setup_params() {
+        let matched = match (p.name.strip_suffix('='), value) {
+            (Some(name), Some(value)) => name == param && (p.setup_func)(value),
+            (None, None) => p.name == param && (p.setup_func)(""),
+            _ => false
+        };
+        if matched
+        {
+            return true;
+        }
+    }
+    false
+}
+
+fn set_init_arg(arg : &'static str)
+{
+    unsafe
+    {
+        if ARGV_INIT_CNT == MAX_INIT_ARGS
+        {
+            return;
+        }
+        ARGV_INIT[ARGV_INIT_CNT] = arg;
+        ARGV_INIT_CNT += 1;
+    }
+}
+
+// a later `name=` replaces an earlier one
+fn set_init_env(name : &'static str, value : &'static str)
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < ENVP_INIT_CNT {
+            if ENVP_INIT[var].0 == name
+            {
+                ENVP_INIT[var].1 = value;
+                return;
+            }
+            var += 1;
+        }
+        if ENVP_INIT_CNT == MAX_INIT_ENVS
+        {
+            return;
+        }
+        ENVP_INIT[ENVP_INIT_CNT] = (name, value);
+        ENVP_INIT_CNT += 1;
+    }
+}
+
+// run every parameter past the __setup! handlers, the rest is for init, everything after `--` too
+// this runs before the console is up, so nothing here may print and what doesn't fit is dropped
+#[__init]
+pub fn parse_boot_params()
+{
+    set_init_env("HOME", "/");
+    set_init_env("TERM", "linux");
+    let mut args = boot_command_line().trim_start_matches([' ', '\t']);
+    while !args.is_empty() {
+        let (token, param, value, rest) = next_arg(args);
+        args = rest;
+        if token == "--"
+        {
+            while !args.is_empty() {
+                let (token, _, _, rest) = next_arg(args);
+                set_init_arg(token);
+                args = rest;
+            }
+            break;
+        }
+        if obsolete_checksetup(param, value)
+        {
+            continue;
+        }
+        match value {
+            Some(value) => set_init_env(param, value),
+            None => set_init_arg(param)
+        }
+    }
+}
+
+pub fn init_argv() -> &'static [&'static str]
+{
+    unsafe { &(*addr_of!(ARGV_INIT))[..ARGV_INIT_CNT] }
+}
+
+pub fn init_envp() -> &'static [(&'static str, &'static str)]
+{
+    unsafe { &(*addr_of!(ENVP_INIT))[..ENVP_INIT_CNT] }
+}
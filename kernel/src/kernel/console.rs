@@ -3,7 +3,7 @@ use core::fmt;
 use core::arch::asm;
 use proc_macro::__init;
 
-use crate::bochs_break;
+use crate::{__setup, bochs_break};
 
 use super::{io, string};
 
@@ -42,7 +42,38 @@ const BLINK : u8 = 0x80;
 const BOLD : u8 = 0x0f;
 const UNDER : u8 = 0x0f;
 
+// 8250 uarts for console=ttyS<n>[,baud]
+const SERIAL_PORTS : [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+const UART_BAUD_BASE : u32 = 115200;
+const UART_DEFAULT_BAUD : u32 = 9600;
+const UART_TX : u16 = 0;
+const UART_DLL : u16 = 0;
+const UART_IER : u16 = 1;
+const UART_DLM : u16 = 1;
+const UART_FCR : u16 = 2;
+const UART_LCR : u16 = 3;
+const UART_MCR : u16 = 4;
+const UART_LSR : u16 = 5;
+const UART_LCR_DLAB : u8 = 0x80;
+const UART_LCR_WLEN8 : u8 = 0x3;
+const UART_FCR_ENABLE_FIFO : u8 = 0xc7;
+const UART_MCR_DTR_RTS_OUT2 : u8 = 0xb;
+const UART_LSR_THRE : u8 = 0x20;
+
+// printk goes out at the warning level, logk at debug, a message shows when its level is below loglevel=
+// panics go out at the emergency level, which no loglevel= can hide
+pub const LOGLEVEL_EMERG : u32 = 0;
+pub const LOGLEVEL_WARNING : u32 = 4;
+pub const LOGLEVEL_DEBUG : u32 = 7;
+const CONSOLE_LOGLEVEL_DEBUG : u32 = 10;
+const MINIMUM_CONSOLE_LOGLEVEL : u32 = 1;
+
 pub static mut CONSOLE : Console = Console::new();
+static mut SERIAL_CONSOLE : Option<SerialConsole> = None;
+// the screen is the console until a console= names others
+static mut VGA_CONSOLE_ENABLED : bool = true;
+static mut CONSOLE_CMDLINE_SEEN : bool = false;
+static mut CONSOLE_LOGLEVEL : u32 = CONSOLE_LOGLEVEL_DEBUG;
 
 static START_STR : &str = "
  _                _____ _____ 
@@ -252,6 +283,59 @@ impl Console
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct SerialConsole
+{
+    base : u16
+}
+
+impl SerialConsole
+{
+    // 8n1 with the fifo on and interrupts off, output is polled
+    fn init(base : u16, baud : u32) -> SerialConsole
+    {
+        let divisor = (UART_BAUD_BASE / baud.clamp(1, UART_BAUD_BASE)) as u16;
+        io::outb(base + UART_IER, 0);
+        io::outb(base + UART_LCR, UART_LCR_DLAB);
+        io::outb(base + UART_DLL, divisor as u8);
+        io::outb(base + UART_DLM, (divisor >> 8) as u8);
+        io::outb(base + UART_LCR, UART_LCR_WLEN8);
+        io::outb(base + UART_FCR, UART_FCR_ENABLE_FIFO);
+        io::outb(base + UART_MCR, UART_MCR_DTR_RTS_OUT2);
+        SerialConsole { base }
+    }
+
+    fn putc(&self, chr : u8)
+    {
+        while io::inb(self.base + UART_LSR) & UART_LSR_THRE == 0 {}
+        io::outb(self.base + UART_TX, chr);
+    }
+
+    pub unsafe fn write(&self, buffer : *const i8, cnt : usize) -> usize
+    {
+        let mut var = 0;
+        while var < cnt && *buffer.add(var) != NUL {
+            if *buffer.add(var) == LF
+            {
+                self.putc(CR as u8);
+            }
+            self.putc(*buffer.add(var) as u8);
+            var += 1;
+        }
+        var
+    }
+}
+
+impl fmt::Write for SerialConsole {
+    fn write_str(&mut self, output_string : &str) ->fmt::Result
+    {
+        unsafe{
+            self.write(output_string.as_ptr() as *const c_char, output_string.len());
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Write for Console {
     fn write_str(&mut self, output_string : &str) ->fmt::Result
     {
@@ -266,16 +350,96 @@ pub fn _print(args : fmt::Arguments)
 {
 
     unsafe {
-        CONSOLE.write_fmt(args).unwrap()
+        if VGA_CONSOLE_ENABLED
+        {
+            CONSOLE.write_fmt(args).unwrap()
+        }
+        if let Some(mut serial) = SERIAL_CONSOLE
+        {
+            serial.write_fmt(args).unwrap()
+        }
     }
 }
 
+pub fn _printk(level : u32, args : fmt::Arguments)
+{
+    if level < unsafe { CONSOLE_LOGLEVEL }
+    {
+        _print(args);
+    }
+}
+
+// what a write to stdout ends up in, every console that is on
+pub unsafe fn console_write(buffer : *const c_char, cnt : usize) -> usize
+{
+    let mut written = 0;
+    if VGA_CONSOLE_ENABLED
+    {
+        written = CONSOLE.write(buffer, cnt);
+    }
+    if let Some(serial) = SERIAL_CONSOLE
+    {
+        written = written.max(serial.write(buffer, cnt));
+    }
+    written
+}
+
+// console=tty0 is the screen, console=ttyS<n>[,baud] a serial port, may be given more than once
+fn console_setup(options : &'static str) -> bool
+{
+    unsafe
+    {
+        if !CONSOLE_CMDLINE_SEEN
+        {
+            CONSOLE_CMDLINE_SEEN = true;
+            VGA_CONSOLE_ENABLED = false;
+        }
+        let (name, options) = options.split_once(',').unwrap_or((options, ""));
+        if let Some(idx) = name.strip_prefix("ttyS")
+        {
+            if let Ok(idx) = idx.parse::<usize>()
+            {
+                if idx < SERIAL_PORTS.len()
+                {
+                    // 115200n8 and the like, only the speed matters here
+                    let baud = options.split(|c : char| !c.is_ascii_digit()).next().and_then(|baud| baud.parse().ok()).unwrap_or(UART_DEFAULT_BAUD);
+                    SERIAL_CONSOLE = Some(SerialConsole::init(SERIAL_PORTS[idx], baud));
+                }
+            }
+        }
+        else if name.starts_with("tty")
+        {
+            VGA_CONSOLE_ENABLED = true;
+        }
+    }
+    true
+}
+__setup!("console=", console_setup);
+
+fn loglevel(level : &'static str) -> bool
+{
+    match level.parse::<u32>() {
+        Ok(level) =>
+        {
+            unsafe { CONSOLE_LOGLEVEL = level.max(MINIMUM_CONSOLE_LOGLEVEL); }
+            true
+        },
+        Err(_) => false
+    }
+}
+__setup!("loglevel=", loglevel);
+
 #[no_mangle]
 #[__init]
 pub unsafe fn console_init()
 {
     unsafe
     {
+        // console= named nothing usable, don't boot blind
+        if !VGA_CONSOLE_ENABLED && SERIAL_CONSOLE.is_none()
+        {
+            VGA_CONSOLE_ENABLED = true;
+        }
         CONSOLE.init();
     }
 }
@@ -284,7 +448,7 @@ pub unsafe fn console_init()
 macro_rules! printk {
     ($($arg:tt)*) => 
     ({
-        $crate::kernel::console::_print(format_args!($($arg)*))
+        $crate::kernel::console::_printk($crate::kernel::console::LOGLEVEL_WARNING, format_args!($($arg)*))
     });
 }
 
@@ -293,7 +457,7 @@ macro_rules! printk {
 macro_rules! logk {
     ($($arg:tt)*) => 
     ({
-        $crate::kernel::console::_print(format_args!($($arg)*))
+        $crate::kernel::console::_printk($crate::kernel::console::LOGLEVEL_DEBUG, format_args!($($arg)*))
     });
 }
 
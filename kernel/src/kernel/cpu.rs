@@ -91,6 +91,26 @@ bitflags!
     }
 }
 
+bitflags!
+{
+    pub struct Cr4RegLabel : u64
+    {
+        const CR4_PSE = 1 << 4;         // Page Size Extensions
+        const CR4_PAE = 1 << 5;         // Physical Address Extension
+        const CR4_PGE = 1 << 7;         // Page Global Enable
+        const CR4_OSFXSR = 1 << 9;      // fxsave / fxrstor and sse enable
+        const CR4_OSXMMEXCPT = 1 << 10; // unmasked simd floating point exceptions
+        const CR4_FSGSBASE = 1 << 16;   // rdfsbase / wrfsbase enable
+        const CR4_OSXSAVE = 1 << 18;    // xsave / xgetbv / xsetbv enable
+    }
+}
+
+// XCR0 state components
+pub const XFEATURE_X87 : u64 = 1 << 0;
+pub const XFEATURE_SSE : u64 = 1 << 1;
+pub const XFEATURE_AVX : u64 = 1 << 2;
+pub const CPUID_XSAVE_LEAF : u32 = 0xd;
+
 pub fn get_cpu_number() -> usize
 {
     1
@@ -110,6 +130,22 @@ pub  fn wrmsr(dst : u64, value : u64)
     }
 }
 
+pub fn rdmsr(src : u64) -> u64
+{
+    let high : u64;
+    let low : u64;
+    unsafe
+    {
+        asm!(
+            "rdmsr",
+            in("rcx") src,
+            out("rdx") high,
+            out("rax") low,
+        )
+    }
+    (high << 32) | (low & 0xffffffff)
+}
+
 #[inline(always)]
 pub fn get_cr2_reg() -> *const c_void
 {
@@ -167,6 +203,67 @@ pub fn set_cr0(cr0 : u64)
     }
 }
 
+#[inline(always)]
+pub fn get_cr4() -> u64
+{
+    let result;
+    unsafe { asm!("mov rax, cr4\n", out("rax") result) };
+    result
+}
+
+pub fn set_cr4(cr4 : u64)
+{
+    unsafe
+    {
+        asm!("mov cr4, rax", in("rax") cr4);
+    }
+}
+
+// clear CR0.TS
+#[inline(always)]
+pub fn clts()
+{
+    unsafe
+    {
+        asm!("clts");
+    }
+}
+
+#[inline(always)]
+pub fn stts()
+{
+    set_cr0(get_cr0() | Cr0RegLabel::CR0_TS.bits());
+}
+
+pub fn xgetbv(index : u32) -> u64
+{
+    let high : u64;
+    let low : u64;
+    unsafe
+    {
+        asm!(
+            "xgetbv",
+            in("ecx") index,
+            out("rdx") high,
+            out("rax") low,
+        )
+    }
+    (high << 32) | (low & 0xffffffff)
+}
+
+pub fn xsetbv(index : u32, value : u64)
+{
+    unsafe
+    {
+        asm!(
+            "xsetbv",
+            in("ecx") index,
+            in("rdx") value >> 32,
+            in("rax") value & 0xffffffff,
+        )
+    }
+}
+
 // pub fn fpu_enable()
 // {
 //     set_cr0(get_cr0() & !(Cr0RegLabel::CR0_EM.bits() | Cr0RegLabel::CR0_TS.bits()) as u64);
@@ -192,6 +289,28 @@ pub fn __cpuid(selector : u32) -> CpuidResult
     }
 }
 
+// cpuid with sub-leaf in ecx, leaf 0xd etc. need it
+#[inline(always)]
+pub fn __cpuid_count(selector : u32, subleaf : u32) -> CpuidResult
+{
+    let mut result: CpuidResult = Default::default();
+    unsafe {
+        asm!(
+            "push rbx",
+            "cpuid",
+            "mov rdi, rbx",
+            "pop rbx",
+            in("eax") selector,
+            in("ecx") subleaf,
+            lateout("edx") result.edx,
+            lateout("ecx") result.ecx,
+            lateout("edi") result.ebx,
+            lateout("eax") result.eax
+        );
+        result
+    }
+}
+
 #[inline(always)]
 pub unsafe fn flush_tlb(vaddr : *const c_void)
 {
@@ -199,4 +318,37 @@ pub unsafe fn flush_tlb(vaddr : *const c_void)
         "invlpg [{bad_page}]",
         bad_page = in(reg) vaddr
     )
-}
\ No newline at end of file
+}
+#[inline(always)]
+pub fn rdtsc() -> u64
+{
+    let high : u64;
+    let low : u64;
+    unsafe
+    {
+        asm!(
+            "rdtsc",
+            out("rdx") high,
+            out("rax") low,
+        )
+    }
+    (high << 32) | (low & 0xffffffff)
+}
+
+// returns None when the hardware rng ran dry
+#[inline(always)]
+pub fn rdrand64() -> Option<u64>
+{
+    let value : u64;
+    let ok : u8;
+    unsafe
+    {
+        asm!(
+            "rdrand {value}",
+            "setc {ok}",
+            value = out(reg) value,
+            ok = out(reg_byte) ok
+        )
+    }
+    if ok != 0 { Some(value) } else { None }
+}
@@ -240,6 +240,31 @@ pub fn regist_device(dev_no : DevT, ioctl_fn : Option<DeviceIoCtlFn>, read_fn :
     }
 }
 
+// root= style names, /dev/hda1, hda1 or major:minor
+pub fn name_to_dev_t(name : &str) -> Option<DevT>
+{
+    unsafe
+    {
+        if let Some((major, minor)) = name.split_once(':')
+        {
+            return match (major.parse(), minor.parse()) {
+                (Ok(major), Ok(minor)) => get_device(mkdev(major, minor)).map(|device| device.dev),
+                _ => None
+            };
+        }
+        let name = name.strip_prefix("/dev/").unwrap_or(name);
+        for devices in DEVICES.values() {
+            for device in devices {
+                if CStr::from_ptr(device.name.as_ptr()).to_bytes() == name.as_bytes()
+                {
+                    return Some(device.dev);
+                }
+            }
+        }
+        None
+    }
+}
+
 pub fn device_install(dev_no : DevT, ptr : *mut c_void, name : &CStr, parent : DevT, flags : u32, device_type : FileMode) -> DevT
 {
     unsafe
@@ -315,11 +340,13 @@ pub fn device_ioctl(dev_t : DevT, cmd : i64, args : *mut c_void, flags : u32) ->
 }
 
 #[inline(always)]
-fn create_request(buffer : *mut c_void, count : usize, dev : u32, offset : usize) -> *mut RequestDescriptor
+fn create_request(buffer : *mut c_void, count : usize, dev : u32, offset : usize, req_type : DevReqType) -> *mut RequestDescriptor
 {
     unsafe
     {
         let request =  alloc(Layout::new::<RequestDescriptor>()) as *mut RequestDescriptor;
+        (*request).req_type = req_type;
+        (*request).flags = 0;
         (*request).buffer = buffer;
         (*request).count = count;
         (*request).dev_idx = dev;
@@ -354,7 +381,26 @@ fn do_request(request : &mut RequestDescriptor) -> i64
                     None => -1,
                 }
             },
-            DevReqType::Write => todo!(),
+            DevReqType::Write => {
+                match get_device(request.dev_idx) {
+                    Some(device) =>
+                    {
+                        match DEVICES_DRIVER.get(&major(request.dev_idx)) {
+                            Some(driver) =>
+                            {
+                                match driver.write {
+                                    Some(write_fn) => write_fn(device.ptr, request.idx as u64, request.count, request.buffer, request.flags),
+                                    None => -1,
+                                }
+
+                            },
+                            None => -1,
+                        }
+
+                    },
+                    None => -1,
+                }
+            },
         }
     }
 }
@@ -385,7 +431,7 @@ pub fn ide_disk_ioctl(disk : *mut IdeDiskT, cmd : i64, _args : *mut c_void, _fla
 
 }
 
-pub fn device_request(mut dev : DevT, buffer : *mut c_void, count : usize, idx : Idx, _flags : u32,_req_typee : DevReqType) -> i64
+pub fn device_request(mut dev : DevT, buffer : *mut c_void, count : usize, idx : Idx, _flags : u32, req_type : DevReqType) -> i64
 {
     match get_device(dev) {
         Some(mut device) => 
@@ -396,7 +442,7 @@ pub fn device_request(mut dev : DevT, buffer : *mut c_void, count : usize, idx :
                 dev = device.parent;
                 device = get_device(device.parent).unwrap();
             }
-            let request = create_request(buffer, count, dev, offset);
+            let request = create_request(buffer, count, dev, offset, req_type);
             logk!("dev {}, request idx {}\n", dev, offset);
             let empty = device.empty_req_list();
             device.insert_request(request);
@@ -1,9 +1,14 @@
-use core::{alloc::Layout, ffi::{c_char, c_void}, iter::empty, mem::size_of, ptr::null_mut};
+use core::{alloc::Layout, cmp, ffi::{c_char, c_void}, iter::empty, mem::size_of, ptr::null_mut};
 
-use crate::{fs::file::{File, EOF, FS}, mm::{mmap::{sys_mmap, __do_mmap}, memory::PAGE_SIZE, mm_type::MmapType}};
+use alloc::vec::Vec;
 
-use super::{Off, io};
+use crate::{fs::file::{File, FileFlag, EOF, FS}, mm::{mmap::{arch_pick_mmap_layout, arch_setup_brk, sys_mmap, __do_mmap}, memory::{ELF_ET_DYN_BASE, ELF_ET_DYN_RND_RANGE, PAGE_SIZE}, mm_type::MmapType}};
 
+use super::{errno_base::ENOEXEC, execve::{start_thread, LinuxBinfmt, LinuxBinprm}, fpu, percpu, process::{sys_exit, MAX_PROCSEE_STACK_SIZE}, random::randomize_page, sched::get_current_running_process, Err, Off, io};
+
+
+// exit code of a task whose exec failed past the point of no return, SIGSEGV like linux forces
+const EXEC_FAILED_CODE : i64 = 11;
 
 const EI_NIDENT : usize = 0x10;
 
@@ -212,6 +217,35 @@ const PT_PHDR : u32 = 6;    // 程序头表
 const PT_LOPROC : u32 = 0x70000000;
 const PT_HIPROC : u32 = 0x7fffffff;
 
+// auxiliary vector entries passed on the initial user stack
+pub const AT_NULL : u64 = 0;
+pub const AT_IGNORE : u64 = 1;
+pub const AT_EXECFD : u64 = 2;
+pub const AT_PHDR : u64 = 3;    // program headers of the executable
+pub const AT_PHENT : u64 = 4;   // size of one program header
+pub const AT_PHNUM : u64 = 5;   // number of program headers
+pub const AT_PAGESZ : u64 = 6;
+pub const AT_BASE : u64 = 7;    // load address of the interpreter
+pub const AT_FLAGS : u64 = 8;
+pub const AT_ENTRY : u64 = 9;   // entry of the executable
+
+// interpreter (PT_INTERP) is loaded here, minus a random shift
+pub const ELF_INTERP_BASE : u64 = 0x7f0000000000;
+const ELF_INTERP_RND_RANGE : u64 = 1 << 36;
+const ELF_INTERP_NAME_LEN : usize = 256;
+
+#[derive(Default)]
+pub struct ElfLoadInfo
+{
+    pub entry : u64,
+    pub phdr : u64,
+    pub phent : u64,
+    pub phnum : u64,
+    pub interp_base : u64,
+    pub load_bias : u64, // where an ET_DYN executable got placed, 0 for ET_EXEC
+    pub end_data : u64, // end of the highest segment, the heap follows it
+}
+
 #[repr(packed)]
 #[repr(C)]
 pub struct Elf64Ehdr
@@ -292,8 +326,8 @@ fn elf64_validate(ehdr : *const Elf64Ehdr) -> bool
         {
             return false;
         }
-        // 不是 386 程序
-        if (*ehdr).e_machine != EM_386
+        // 不是 x86_64 程序
+        if (*ehdr).e_machine != EM_X86_64
         {
             return false;
         }
@@ -310,42 +344,239 @@ fn elf64_validate(ehdr : *const Elf64Ehdr) -> bool
     }
 }
 
-pub fn load_elf64(file_t : *mut File) -> i64
+// load the program, and its interpreter if it has one
+// returns the address user mode starts at
+pub fn load_elf64(file_t : *mut File, info : &mut ElfLoadInfo, randomize : bool) -> i64
 {
     unsafe
     {
         let ehdr = alloc::alloc::alloc(Layout::new::<Elf64Ehdr>()) as *mut Elf64Ehdr;
         FS.read_file(file_t, ehdr.cast(), size_of::<Elf64Ehdr>(), 0);
-        if elf64_validate(ehdr)
+        if !elf64_validate(ehdr)
         {
+            alloc::alloc::dealloc(ehdr.cast(), Layout::new::<Elf64Ehdr>());
             return EOF;
         }
-        let mut phdr = alloc::alloc::alloc(Layout::from_size_align(size_of::<Elf64Phdr>() * (*ehdr).e_phnum as usize, 8).unwrap()) as *mut Elf64Phdr;
+        let phdr_layout = Layout::from_size_align(size_of::<Elf64Phdr>() * (*ehdr).e_phnum as usize, 8).unwrap();
+        let phdr_table = alloc::alloc::alloc(phdr_layout) as *mut Elf64Phdr;
         // __do_mmap(null_mut(), (*ehdr).e_phnum as usize * (*ehdr).e_phentsize as usize + size_of::<Elf64Ehdr>(), MmapType::PROT_KERNEL | MmapType::PROT_READ, MmapType::MAP_PRIVATE, null_mut(), 0);
-        FS.read_file(file_t, phdr.cast(), (*ehdr).e_phnum as usize * (*ehdr).e_phentsize as usize, (*ehdr).e_phoff as Off);
-        let first_pt_load = true;
+        FS.read_file(file_t, phdr_table.cast(), (*ehdr).e_phnum as usize * (*ehdr).e_phentsize as usize, (*ehdr).e_phoff as Off);
+        let mut phdr = phdr_table;
+        let mut first_pt_load = true;
+        let mut interp_name : *mut c_char = null_mut();
+        // pie executables are linked at 0 and can go anywhere
+        let load_bias = if (*ehdr).e_type == Etype::EtDyn as u16
+        {
+            if randomize { randomize_page(ELF_ET_DYN_BASE, ELF_ET_DYN_RND_RANGE) } else { ELF_ET_DYN_BASE }
+        }
+        else {
+            0
+        };
+        let mut result = (load_bias + (*ehdr).e_entry) as i64;
         let mut var = 0;
         while var < (*ehdr).e_phnum {
             match (*phdr).p_type {
                 PT_LOAD =>
                 {
-                    if !load_segment64(phdr, file_t)
+                    if !load_segment64(phdr, file_t, load_bias)
                     {
-                        return EOF;
+                        result = EOF;
+                        break;
                     }
+                    // without PT_PHDR the headers sit at the start of the first segment
+                    if first_pt_load && info.phdr == 0
+                    {
+                        info.phdr = load_bias + (*phdr).p_vaddr - (*phdr).p_offset + (*ehdr).e_phoff;
+                    }
+                    first_pt_load = false;
+                    info.end_data = cmp::max(info.end_data, load_bias + (*phdr).p_vaddr + (*phdr).p_memsz);
+                },
+                PT_PHDR => info.phdr = load_bias + (*phdr).p_vaddr,
+                PT_INTERP =>
+                {
+                    if (*phdr).p_filesz as usize >= ELF_INTERP_NAME_LEN || !interp_name.is_null()
+                    {
+                        result = EOF;
+                        break;
+                    }
+                    interp_name = alloc::alloc::alloc_zeroed(Layout::from_size_align(ELF_INTERP_NAME_LEN, 8).unwrap()) as *mut c_char;
+                    FS.read_file(file_t, interp_name.cast(), (*phdr).p_filesz as usize, (*phdr).p_offset as Off);
                 },
                 _ => { }
             }
             phdr = phdr.offset(1);
             var += 1;
         }
-        alloc::alloc::dealloc(phdr.cast(), Layout::from_size_align(size_of::<Elf64Phdr>() * (*ehdr).e_phnum as usize, 8).unwrap());
+        info.entry = load_bias + (*ehdr).e_entry;
+        info.phent = (*ehdr).e_phentsize as u64;
+        info.phnum = (*ehdr).e_phnum as u64;
+        info.load_bias = load_bias;
+        if result != EOF && !interp_name.is_null()
+        {
+            let interp_base = if randomize { ELF_INTERP_BASE - randomize_page(0, ELF_INTERP_RND_RANGE) } else { ELF_INTERP_BASE };
+            result = load_elf64_interp(interp_name, interp_base);
+            info.interp_base = interp_base;
+        }
+        if !interp_name.is_null()
+        {
+            alloc::alloc::dealloc(interp_name.cast(), Layout::from_size_align(ELF_INTERP_NAME_LEN, 8).unwrap());
+        }
+        alloc::alloc::dealloc(phdr_table.cast(), phdr_layout);
+        alloc::alloc::dealloc(ehdr.cast(), Layout::new::<Elf64Ehdr>());
+        result
+    }
+}
+
+// map the dynamic linker at `base`, returns its entry
+fn load_elf64_interp(interp_name : *const c_char, base : u64) -> i64
+{
+    unsafe
+    {
+        let file_t = FS.open_file(interp_name, FileFlag::O_RDONLY);
+        if file_t.is_null()
+        {
+            return EOF;
+        }
+        if !(*(*file_t).inode).is_file()
+        {
+            FS.release_file(file_t);
+            return EOF;
+        }
+        let ehdr = alloc::alloc::alloc(Layout::new::<Elf64Ehdr>()) as *mut Elf64Ehdr;
+        FS.read_file(file_t, ehdr.cast(), size_of::<Elf64Ehdr>(), 0);
+        if !elf64_validate(ehdr) || (*ehdr).e_type != Etype::EtDyn as u16
+        {
+            alloc::alloc::dealloc(ehdr.cast(), Layout::new::<Elf64Ehdr>());
+            FS.release_file(file_t);
+            return EOF;
+        }
+        let phdr_layout = Layout::from_size_align(size_of::<Elf64Phdr>() * (*ehdr).e_phnum as usize, 8).unwrap();
+        let phdr_table = alloc::alloc::alloc(phdr_layout) as *mut Elf64Phdr;
+        FS.read_file(file_t, phdr_table.cast(), (*ehdr).e_phnum as usize * (*ehdr).e_phentsize as usize, (*ehdr).e_phoff as Off);
+        let mut result = (base + (*ehdr).e_entry) as i64;
+        let mut phdr = phdr_table;
+        let mut var = 0;
+        while var < (*ehdr).e_phnum {
+            if (*phdr).p_type == PT_LOAD && !load_segment64(phdr, file_t, base)
+            {
+                result = EOF;
+                break;
+            }
+            phdr = phdr.offset(1);
+            var += 1;
+        }
+        alloc::alloc::dealloc(phdr_table.cast(), phdr_layout);
+        alloc::alloc::dealloc(ehdr.cast(), Layout::new::<Elf64Ehdr>());
+        // its segments keep the file mapped
+        FS.release_file(file_t);
+        result
+    }
+}
+
+// build argc, argv, envp and the auxiliary vector below `stack_top`
+// returns the initial user rsp, which points at argc
+pub fn create_elf_tables(info : &ElfLoadInfo, stack_top : u64, bprm : &LinuxBinprm) -> u64
+{
+    unsafe
+    {
+        let auxv = [
+            (AT_PHDR, info.phdr),
+            (AT_PHENT, info.phent),
+            (AT_PHNUM, info.phnum),
+            (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_BASE, info.interp_base),
+            (AT_ENTRY, info.entry),
+            (AT_NULL, 0)
+        ];
+        // strings go at the very top, pointers to them below
+        let mut p = stack_top;
+        let mut envp_ptrs = Vec::with_capacity(bprm.envp.len());
+        for string in bprm.envp.iter() {
+            p -= string.len() as u64;
+            compiler_builtins::mem::memcpy(p as *mut u8, string.as_ptr(), string.len());
+            envp_ptrs.push(p);
+        }
+        let mut argv_ptrs = Vec::with_capacity(bprm.argv.len());
+        for string in bprm.argv.iter() {
+            p -= string.len() as u64;
+            compiler_builtins::mem::memcpy(p as *mut u8, string.as_ptr(), string.len());
+            argv_ptrs.push(p);
+        }
+        // argc, argv, null, envp, null
+        let words = 1 + argv_ptrs.len() + 1 + envp_ptrs.len() + 1 + auxv.len() * 2;
+        let sp = ((p - (words * size_of::<u64>()) as u64) & !0xf) as *mut u64;
+        let mut slot = sp;
+        *slot = argv_ptrs.len() as u64;
+        slot = slot.add(1);
+        for ptr in argv_ptrs.iter() {
+            *slot = *ptr;
+            slot = slot.add(1);
+        }
+        *slot = 0;
+        slot = slot.add(1);
+        for ptr in envp_ptrs.iter() {
+            *slot = *ptr;
+            slot = slot.add(1);
+        }
+        *slot = 0;
+        slot = slot.add(1);
+        let mut var = 0;
+        while var < auxv.len() {
+            *slot.add(var * 2) = auxv[var].0;
+            *slot.add(var * 2 + 1) = auxv[var].1;
+            var += 1;
+        }
+        sp as u64
+    }
+}
+
+pub static ELF_FORMAT : LinuxBinfmt = LinuxBinfmt { name: "elf", load_binary: load_elf_binary };
+
+fn load_elf_binary(bprm : &mut LinuxBinprm) -> Err
+{
+    unsafe
+    {
+        if bprm.buf_len < size_of::<Elf64Ehdr>() || !elf64_validate(bprm.buf.as_ptr().cast())
+        {
+            return -ENOEXEC;
+        }
+        let pcb = get_current_running_process();
+        // point of no return, the old image is gone after this
+        (*pcb).mm.release_all();
+        let randomize = (*pcb).randomize_va_space();
+        arch_pick_mmap_layout(&mut (*pcb).mm, randomize);
+
+        let mut info = ElfLoadInfo::default();
+        let entry = load_elf64(bprm.file, &mut info, randomize);
+        if entry == EOF
+        {
+            // the old address space is gone, there is nothing left to return -ENOEXEC to
+            sys_exit(EXEC_FAILED_CODE);
+            return -ENOEXEC;
+        }
+        // build user stack area
+        let stack_top = (*pcb).mm.stack_top;
+        let stack_vma = (*pcb).mm.create_new_mem_area(stack_top - MAX_PROCSEE_STACK_SIZE as u64, stack_top);
+        (*stack_vma).set_prot(MmapType::PROT_READ | MmapType::PROT_WRITE);
+        (*stack_vma).set_flags(MmapType::VM_GROWSDOWN);
+
+        // set heap memory address
+        arch_setup_brk(&mut (*pcb).mm, info.end_data, randomize);
+
+        // new image starts without tls
+        (*pcb).fs_base = 0;
+        (*pcb).gs_base = 0;
+        percpu::write_user_fs_base(0);
+        percpu::write_user_gs_base(0);
+        fpu::fpu_release(pcb);
 
-        (*ehdr).e_entry as i64
+        let user_sp = create_elf_tables(&info, stack_top, bprm);
+        start_thread((*pcb).get_intr_frame(), entry as u64, user_sp);
+        0
     }
 }
 
-fn load_segment64(elf64_phdr : *mut Elf64Phdr, file_t : *mut File) -> bool
+fn load_segment64(elf64_phdr : *mut Elf64Phdr, file_t : *mut File, bias : u64) -> bool
 {
     unsafe
     {
@@ -370,8 +601,8 @@ fn load_segment64(elf64_phdr : *mut Elf64Phdr, file_t : *mut File) -> bool
         {
             prot.insert(MmapType::PROT_READ);
         }
-        let vma;
-        if (*elf64_phdr).p_vaddr == 0
+        let start;
+        if (*elf64_phdr).p_vaddr == 0 && bias == 0
         {
             // FS.read_file(file_t, null_mut(), (*elf64_phdr).p_filesz as usize, (*elf64_phdr).p_offset as usize);
             return true;
@@ -379,14 +610,14 @@ fn load_segment64(elf64_phdr : *mut Elf64Phdr, file_t : *mut File) -> bool
         if (*elf64_phdr).p_filesz == 0
         {
             flags.insert(MmapType::MAP_ANONYMOUS);
-            vma = __do_mmap(((*elf64_phdr).p_vaddr as usize - loffset) as *mut c_void, map_size, prot, flags, null_mut(), (*elf64_phdr).p_offset as usize - loffset);
+            start = __do_mmap(((bias + (*elf64_phdr).p_vaddr) as usize - loffset) as *mut c_void, map_size, prot, flags, null_mut(), (*elf64_phdr).p_offset as usize - loffset);
         }
         else
         {
-            vma = __do_mmap(((*elf64_phdr).p_vaddr as usize - loffset) as *mut c_void, map_size, prot, flags, file_t, (*elf64_phdr).p_offset as usize - loffset);
+            start = __do_mmap(((bias + (*elf64_phdr).p_vaddr) as usize - loffset) as *mut c_void, map_size, prot, flags, file_t, (*elf64_phdr).p_offset as usize - loffset);
 
         }
-        if vma.is_null()
+        if start.is_null()
         {
             false
         }
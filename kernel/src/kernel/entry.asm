@@ -1,12 +1,177 @@
 .section .text.entry
 .globl _start
 _start:
+    jmp .Lboot_legacy # the loader comes in here in long mode, multiboot loaders use the headers below
+
+# multiboot2 for grub, both headers have the loader put system.bin flat at 1M and enter at multiboot_entry
+    .balign 8
+.Lmb2_header:
+    .long 0xe85250d6
+    .long 0 # i386
+    .long .Lmb2_header_end - .Lmb2_header
+    .long 0x100000000 - (0xe85250d6 + (.Lmb2_header_end - .Lmb2_header))
+    .balign 8 # address tag
+    .short 2, 0
+    .long 24
+    .long .Lmb2_header - _start + 0x100000
+    .long 0x100000
+    .long 0 # load the whole file
+    .long 0 # the bss is part of the file
+    .balign 8 # entry address tag
+    .short 3, 0
+    .long 12
+    .long multiboot_entry - _start + 0x100000
+    .balign 8 # console tag, the console writes to ega text memory
+    .short 4, 0
+    .long 12
+    .long 2
+    .balign 8 # page aligned modules, they become ramdisks in place
+    .short 6, 0
+    .long 8
+    .balign 8 # end tag
+    .short 0, 0
+    .long 8
+.Lmb2_header_end:
+
+# multiboot for qemu -kernel: page aligned modules, memory info and the address fields
+    .balign 4
+.Lmb_header:
+    .long 0x1badb002
+    .long 0x00010003
+    .long 0x100000000 - (0x1badb002 + 0x00010003)
+    .long .Lmb_header - _start + 0x100000
+    .long 0x100000
+    .long 0
+    .long 0
+    .long multiboot_entry - _start + 0x100000
+
+.Lboot_legacy:
     mov rsp, 0xffff800000090000
     mov rbp, 0 # prepare basic running stack
     mov rdi, 0xffff800000020000
     call kernel_relocation
+.Lrelocate:
+    lea rbx, [rip + .Lrelocated] # kernel_relocation hands back the kaslr slide
+    add rbx, rax
+    jmp rbx
+.Lrelocated:
     call kernel_init
     xchg bx, bx
     hlt
 flag:
-    jmp flag
\ No newline at end of file
+    jmp flag
+
+# protected mode without paging, eax holds the magic and ebx the boot information
+.code32
+multiboot_entry:
+    cli
+    mov edi, eax
+    mov esi, ebx
+    mov ebp, offset .Lpic_phys # the headers put us at 1M, everything below is relative to here
+.Lpic:
+    lea esp, [ebp + .Lstack_at] # no stack yet, low memory may hold what the loader left us
+    add esp, dword ptr [esp]
+    lea ebx, [ebp + .Lpd_at]
+    add ebx, dword ptr [ebx]
+    mov edx, ebx
+    mov eax, 0x83
+    xor ecx, ecx
+.Lfill_pd: # the first 1G in 2M pages
+    mov dword ptr [ebx + ecx * 8], eax
+    mov dword ptr [ebx + ecx * 8 + 4], 0
+    add eax, 0x200000
+    inc ecx
+    cmp ecx, 512
+    jb .Lfill_pd
+    lea ebx, [ebp + .Lpdpt_at]
+    add ebx, dword ptr [ebx]
+    or edx, 3
+    mov dword ptr [ebx], edx
+    mov edx, ebx
+    or edx, 3
+    lea ebx, [ebp + .Lpml4_at]
+    add ebx, dword ptr [ebx]
+    mov dword ptr [ebx], edx # identity, kernel map and linear map like the loader sets up
+    mov dword ptr [ebx + 256 * 8], edx
+    mov dword ptr [ebx + 272 * 8], edx
+    mov eax, cr4
+    or eax, 0x30
+    mov cr4, eax
+    mov cr3, ebx
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, 0x100
+    wrmsr
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+    lea eax, [ebp + .Lgdt_at]
+    mov dword ptr [ebp + .Lgdt_ptr_base_at], eax
+    lgdt [ebp + .Lgdt_ptr_at]
+    lea eax, [ebp + .Lmultiboot64_at]
+    push 0x8
+    push eax
+    retf
+
+.code64
+.Lmultiboot64:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+    lea rax, [rip + .Lmultiboot_high]
+    mov rcx, 0xffff800000000000
+    add rax, rcx
+    add rsp, rcx # the same boot stack through the linear map
+    jmp rax
+.Lmultiboot_high:
+    mov rbp, 0
+    mov edi, edi # magic
+    mov esi, esi # physical address of the boot information
+    call multiboot_relocation
+    jmp .Lrelocate
+
+    .balign 8
+.Lboot_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff # 64 bit code
+    .quad 0x00cf92000000ffff # data
+.Lboot_gdt_ptr:
+    .short .Lboot_gdt_ptr - .Lboot_gdt - 1
+    .long 0 # physical address of .Lboot_gdt, filled in before lgdt
+
+# the page tables and the stack live in the bss, each word holds the distance to its table or the stack top
+    .balign 4
+.Lpml4_rel:
+    .long .Lboot_pml4 - .Lpml4_rel
+.Lpdpt_rel:
+    .long .Lboot_pdpt - .Lpdpt_rel
+.Lpd_rel:
+    .long .Lboot_pd - .Lpd_rel
+.Lstack_rel:
+    .long .Lboot_stack_top - .Lstack_rel
+
+.set .Lpic_phys, .Lpic - _start + 0x100000
+.set .Lpml4_at, .Lpml4_rel - .Lpic
+.set .Lpdpt_at, .Lpdpt_rel - .Lpic
+.set .Lpd_at, .Lpd_rel - .Lpic
+.set .Lstack_at, .Lstack_rel - .Lpic
+.set .Lgdt_at, .Lboot_gdt - .Lpic
+.set .Lgdt_ptr_at, .Lboot_gdt_ptr - .Lpic
+.set .Lgdt_ptr_base_at, .Lboot_gdt_ptr + 2 - .Lpic
+.set .Lmultiboot64_at, .Lmultiboot64 - .Lpic
+
+.section .bss.multiboot, "aw", @nobits
+    .balign 4096
+.Lboot_pml4:
+    .skip 4096
+.Lboot_pdpt:
+    .skip 4096
+.Lboot_pd:
+    .skip 4096
+.Lboot_stack: # kernel_init runs on it until the first task switch
+    .skip 0x20000
+.Lboot_stack_top:
+.section .text.entry
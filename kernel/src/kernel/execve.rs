@@ -1,68 +1,302 @@
 use core::ptr::null_mut;
 use core::{ffi::c_char, alloc::Layout, arch::asm};
 use core::ffi::c_void;
+use core::{cmp, mem::size_of};
 
-use crate::fs::file::{FSPermission, FileFlag};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::fs::file::{FSPermission, FileFlag, File};
 use crate::kernel::process::MAX_PROCSEE_STACK_SIZE;
 use crate::kernel::relocation::process_relocation;
-use crate::mm::mm_type::MmapType;
-use crate::{mm::memory::{self, USER_STACK_TOP}, fs::{namei::{namei, permission}, file::{EOF, FS, sys_write, STDOUT}}, bochs_break, logk};
+use crate::mm::memory::{PAGE_SIZE, USER_STACK_TOP};
+use crate::{fs::{namei::{namei, permission}, file::{EOF, FS, sys_write, STDOUT}}, bochs_break, logk};
+
+use super::{binfmt_script, elf64, errno_base::{E2BIG, EACCES, EBUSY, EFAULT, ELOOP, ENOENT, ENOEXEC}, process::{PtRegs, interrupt_exit, PROCESS_NAME_LEN}, sched::get_current_running_process, syscall, Err};
 
-use super::{process::{PtRegs, interrupt_exit, PROCESS_NAME_LEN}, sched::get_current_running_process, elf64::load_elf64, syscall};
+pub const BINPRM_BUF_SIZE : usize = 256;
+const MAX_ARG_STRLEN : usize = PAGE_SIZE * 32;
+// strings and pointers must leave most of the stack to the program
+const MAX_ARG_SIZE : usize = MAX_PROCSEE_STACK_SIZE / 4;
+// how many #! interpreters may be chained
+const MAX_BINFMT_DEPTH : u32 = 4;
+const MAX_BINFMT_NUM : usize = 8;
+
+// everything a binary format needs to know about the exec in progress
+pub struct LinuxBinprm
+{
+    pub file : *mut File,
+    pub filename : Vec<u8>, // nul terminated
+    pub buf : [u8; BINPRM_BUF_SIZE], // head of the file
+    pub buf_len : usize,
+    pub argv : VecDeque<Vec<u8>>, // strings are nul terminated
+    pub envp : Vec<Vec<u8>>,
+    pub depth : u32
+}
 
-pub fn sys_execve(filename : *const c_char, argv : *mut *mut c_char, envp : *mut *mut c_char)
+pub struct LinuxBinfmt
+{
+    pub name : &'static str,
+    // -ENOEXEC means "not mine", anything else ends the search
+    pub load_binary : fn(&mut LinuxBinprm) -> Err
+}
+
+static mut FORMATS : [Option<&'static LinuxBinfmt>; MAX_BINFMT_NUM] = [None; MAX_BINFMT_NUM];
+
+pub fn register_binfmt(fmt : &'static LinuxBinfmt) -> Err
 {
     unsafe
     {
-        do_execve(filename, argv, envp);
+        let mut var = 0;
+        while var < MAX_BINFMT_NUM {
+            if FORMATS[var].is_none()
+            {
+                FORMATS[var] = Some(fmt);
+                logk!("binfmt {} registered\n", fmt.name);
+                return 0;
+            }
+            var += 1;
+        }
+        -EBUSY
     }
 }
 
-unsafe fn do_execve(file_name : *const c_char, argv : *mut *mut c_char, envp : *mut *mut c_char) -> i64
+pub fn unregister_binfmt(fmt : &'static LinuxBinfmt)
 {
-    let pcb = get_current_running_process();
-    let pt_regs = ((*pcb).get_process_kernel_stack() as *mut PtRegs).offset(-1);
-    let file_t = FS.open_file(file_name, FileFlag::empty());
-    if file_t.is_null()
+    unsafe
     {
-        return EOF;
+        let mut var = 0;
+        while var < MAX_BINFMT_NUM {
+            if let Some(old) = FORMATS[var]
+            {
+                if core::ptr::eq(old, fmt)
+                {
+                    FORMATS[var] = None;
+                }
+            }
+            var += 1;
+        }
     }
-    if !(*(*file_t).inode).is_file()
+}
+
+pub fn binfmt_init()
+{
+    register_binfmt(&elf64::ELF_FORMAT);
+    register_binfmt(&binfmt_script::SCRIPT_FORMAT);
+}
+
+// hand the binary to each format in turn until one accepts it
+pub fn search_binary_handler(bprm : &mut LinuxBinprm) -> Err
+{
+    unsafe
     {
-        FS.release_file(file_t);
-        return EOF;
+        if bprm.depth > MAX_BINFMT_DEPTH
+        {
+            return -ELOOP;
+        }
+        let mut var = 0;
+        while var < MAX_BINFMT_NUM {
+            if let Some(fmt) = FORMATS[var]
+            {
+                let retval = (fmt.load_binary)(bprm);
+                if retval != -ENOEXEC
+                {
+                    return retval;
+                }
+            }
+            var += 1;
+        }
+        -ENOEXEC
     }
-    if !permission((*file_t).inode, FSPermission::EXEC)
+}
+
+// open a regular file the caller may execute
+pub fn open_exec(name : *const c_char) -> Result<*mut File, Err>
+{
+    unsafe
     {
-        FS.release_file(file_t);
-        return EOF;
+        let file_t = FS.open_file(name, FileFlag::O_RDONLY);
+        if file_t.is_null()
+        {
+            return Err(-ENOENT);
+        }
+        if !(*(*file_t).inode).is_file() || !permission((*file_t).inode, FSPermission::EXEC)
+        {
+            FS.release_file(file_t);
+            return Err(-EACCES);
+        }
+        Ok(file_t)
     }
-    logk!("prepare load elf file\n");
-    compiler_builtins::mem::memcpy((*pcb).name.as_ptr() as *mut u8, file_name as *const u8, PROCESS_NAME_LEN);
-    // copy argv env
-    // todo!()
+}
 
-    // release memory
-    (*pcb).mm.release_all();
+// (re)fill the header buffer from the current file
+pub fn prepare_binprm(bprm : &mut LinuxBinprm)
+{
+    unsafe
+    {
+        bprm.buf = [0; BINPRM_BUF_SIZE];
+        let len = FS.read_file(bprm.file, bprm.buf.as_mut_ptr().cast(), BINPRM_BUF_SIZE, 0);
+        bprm.buf_len = if len > 0 { len as usize } else { 0 };
+    }
+}
 
-    // load program
-    let entry = load_elf64(file_t);
-    // build user stack area
-    let stack_vma = (*pcb).mm.create_new_mem_area(USER_STACK_TOP.offset(-(MAX_PROCSEE_STACK_SIZE as isize)) as u64, memory::USER_STACK_TOP as u64);
-    (*stack_vma).set_prot(MmapType::PROT_READ | MmapType::PROT_WRITE);
+// nul terminated copy of a kernel or user string
+pub unsafe fn copy_string(src : *const c_char) -> Result<Vec<u8>, Err>
+{
+    let mut result = Vec::new();
+    let mut var = 0;
+    loop {
+        if var >= MAX_ARG_STRLEN
+        {
+            return Err(-E2BIG);
+        }
+        let c = *src.add(var) as u8;
+        result.push(c);
+        if c == 0
+        {
+            break;
+        }
+        var += 1;
+    }
+    Ok(result)
+}
 
-    // set heap memory address
+// copy a null terminated user string array
+unsafe fn copy_strings(strings : *mut *mut c_char, total : &mut usize) -> Result<Vec<Vec<u8>>, Err>
+{
+    let mut result = Vec::new();
+    if strings.is_null()
+    {
+        return Ok(result);
+    }
+    let mut var = 0;
+    loop {
+        let str_ptr = *strings.add(var);
+        if str_ptr.is_null()
+        {
+            break;
+        }
+        if str_ptr as u64 >= USER_STACK_TOP as u64
+        {
+            return Err(-EFAULT);
+        }
+        let string = copy_string(str_ptr)?;
+        *total += string.len() + size_of::<u64>();
+        if *total > MAX_ARG_SIZE
+        {
+            return Err(-E2BIG);
+        }
+        result.push(string);
+        var += 1;
+    }
+    Ok(result)
+}
+
+// set the registers user mode starts with
+pub unsafe fn start_thread(pt_regs : *mut PtRegs, rip : u64, rsp : u64)
+{
+    (*pt_regs).rip = rip;
+    (*pt_regs).rbp = 0;
+    (*pt_regs).rsp = rsp;
+    // argc, argv
+    (*pt_regs).rdi = *(rsp as *const u64);
+    (*pt_regs).rsi = rsp + 8;
+}
 
-    (*pt_regs).rip = entry as u64;
-    (*pt_regs).rbp = USER_STACK_TOP as u64;
-    (*pt_regs).rsp = USER_STACK_TOP as u64;
+pub fn sys_execve(filename : *const c_char, argv : *mut *mut c_char, envp : *mut *mut c_char) -> Err
+{
+    unsafe
+    {
+        do_execve(filename, argv, envp)
+    }
+}
+
+unsafe fn do_execve(file_name : *const c_char, argv : *mut *mut c_char, envp : *mut *mut c_char) -> Err
+{
+    let file_t = match open_exec(file_name) {
+        Ok(file_t) => file_t,
+        Err(err) => return err
+    };
+    // argv and envp live in the image that is about to go away
+    let mut total = 0;
+    let filename = copy_string(file_name);
+    let argv = copy_strings(argv, &mut total);
+    let envp = copy_strings(envp, &mut total);
+    let (filename, argv, envp) = match (filename, argv, envp) {
+        (Ok(filename), Ok(argv), Ok(envp)) => (filename, argv, envp),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) =>
+        {
+            FS.release_file(file_t);
+            return err;
+        }
+    };
+    bprm_execve(file_t, filename, argv, envp)
+}
+
+// exec from a kernel thread, the strings come from the kernel rather than a user stack
+pub fn kernel_execve(file_name : &str, argv : &[&str], envp : &[&str]) -> Err
+{
+    let mut filename = Vec::from(file_name.as_bytes());
+    filename.push(0);
+    let file_t = match open_exec(filename.as_ptr().cast()) {
+        Ok(file_t) => file_t,
+        Err(err) => return err
+    };
+    let mut total = 0;
+    let mut copy_strings_kernel = |strings : &[&str]| -> Result<Vec<Vec<u8>>, Err> {
+        let mut result = Vec::new();
+        for string in strings {
+            let mut copy = Vec::from(string.as_bytes());
+            copy.push(0);
+            total += copy.len() + size_of::<u64>();
+            if copy.len() > MAX_ARG_STRLEN || total > MAX_ARG_SIZE
+            {
+                return Err(-E2BIG);
+            }
+            result.push(copy);
+        }
+        Ok(result)
+    };
+    let (argv, envp) = match (copy_strings_kernel(argv), copy_strings_kernel(envp)) {
+        (Ok(argv), Ok(envp)) => (argv, envp),
+        (Err(err), _) | (_, Err(err)) =>
+        {
+            unsafe { FS.release_file(file_t); }
+            return err;
+        }
+    };
+    unsafe { bprm_execve(file_t, filename, argv, envp) }
+}
+
+// the file is open and the strings copied, only comes back on failure
+unsafe fn bprm_execve(file_t : *mut File, filename : Vec<u8>, argv : Vec<Vec<u8>>, envp : Vec<Vec<u8>>) -> Err
+{
+    let pcb = get_current_running_process();
+    let pt_regs = ((*pcb).get_process_kernel_stack() as *mut PtRegs).offset(-1);
+    let mut name = [0; PROCESS_NAME_LEN];
+    let name_len = cmp::min(filename.len(), PROCESS_NAME_LEN);
+    name[..name_len].copy_from_slice(&filename[..name_len]);
+    let mut bprm = LinuxBinprm { file: file_t, filename, buf: [0; BINPRM_BUF_SIZE], buf_len: 0, argv: VecDeque::from(argv), envp, depth: 0 };
+    prepare_binprm(&mut bprm);
+    logk!("prepare load binary\n");
+    let retval = search_binary_handler(&mut bprm);
+    if retval < 0
+    {
+        if !bprm.file.is_null()
+        {
+            FS.release_file(bprm.file);
+        }
+        return retval;
+    }
+    compiler_builtins::mem::memcpy((*pcb).name.as_ptr() as *mut u8, name.as_ptr(), PROCESS_NAME_LEN);
+    // the new image's mappings hold the file now
+    FS.release_file(bprm.file);
     asm!(
         "mov rsp, {aim_frame}",
         "jmp [interrupt_exit@GOTPCREL + rip]",
         aim_frame = in(reg) pt_regs as u64
     );
-    FS.release_file(file_t);
-    return EOF;
+    EOF
 }
 
 unsafe fn test() -> !
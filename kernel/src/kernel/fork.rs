@@ -1,8 +1,8 @@
-use core::{ffi::c_void, ptr::null_mut, mem::size_of, arch::asm};
+use core::{ffi::c_void, ptr::{addr_of_mut, null_mut}, mem::size_of, arch::asm};
 
-use crate::{mm::memory::{CloneFlags, copy_page_table, Pml4}, bochs_break, logk, kernel::process::{PROCESS_NAME_LEN, PtRegs}};
+use crate::{mm::{huge_memory, memory::{CloneFlags, copy_page_table, Pml4}}, bochs_break, logk, kernel::process::{PROCESS_NAME_LEN, PtRegs}};
 
-use super::{process::{Pid, PCB, task_switch}, sched::get_current_running_process, Err};
+use super::{fpu, percpu, process::{Pid, PCB, task_switch}, sched::get_current_running_process, Err};
 
 pub struct KernelCloneArgs
 {
@@ -43,7 +43,11 @@ unsafe fn dup_pcb(src_pcb : *mut PCB, node : u32) -> *mut PCB
     (*p).ppid = (*src_pcb).pid;
     (*p).uid = (*src_pcb).uid;
     compiler_builtins::mem::memcpy((*p).name.as_ptr() as *mut u8, (*src_pcb).name.as_ptr() as *const u8, PROCESS_NAME_LEN); // copy process name
-    compiler_builtins::mem::memcpy((*p).get_intr_frame() as *mut u8, ((*src_pcb).stack as *mut u8).offset(0x30), size_of::<PtRegs>()); // copy return interrupt frame
+    (*p).personality = (*src_pcb).personality;
+    (*p).fs_base = percpu::read_user_fs_base();
+    (*p).gs_base = percpu::read_user_gs_base();
+    fpu::fpu_copy(p, src_pcb);
+    compiler_builtins::mem::memcpy((*p).get_intr_frame() as *mut u8, (*src_pcb).get_intr_frame() as *const u8, size_of::<PtRegs>()); // copy return interrupt frame
     (*p).build_task_stack();
     p
 }
@@ -85,7 +89,18 @@ pub fn kernel_clone(args : &KernelCloneArgs) -> Pid
 fn copy_mm(clone_flags : &CloneFlags, dst_pcb : *mut PCB) -> Err
 {
     unsafe {
-        (*dst_pcb).pml4 = copy_page_table(get_current_running_process(), clone_flags) as *mut Pml4;        
+        let src_pcb = get_current_running_process();
+        if !clone_flags.contains(CloneFlags::CLONE_VM)
+        {
+            // huge pages are never shared, both sides get 4k copy on write pages instead
+            let err = huge_memory::split_huge_pmd_mm(addr_of_mut!((*src_pcb).mm));
+            if err < 0
+            {
+                return err;
+            }
+            (*dst_pcb).mm.dup_mmap(&(*src_pcb).mm);
+        }
+        (*dst_pcb).pml4 = copy_page_table(src_pcb, addr_of_mut!((*dst_pcb).mm), clone_flags) as *mut Pml4;
     }
     0
 }
\ No newline at end of file
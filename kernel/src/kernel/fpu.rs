@@ -1,20 +1,42 @@
-use core::{arch::asm, ptr::null_mut};
+use core::{alloc::{GlobalAlloc, Layout}, arch::asm, ptr::null_mut};
 use proc_macro::__init;
 
-use crate::{logk, kernel::{cpu::{set_cr0, get_cr0, Cr0RegLabel}, interrupt::HandlerFn, sched}, bochs_break};
+use crate::{logk, kernel::{cpu::{set_cr0, get_cr0, Cr0RegLabel, Cr4RegLabel}, interrupt::HandlerFn, sched}, bochs_break, mm::memory::MEMORY_POOL};
 
-use super::{cpu, process::{self, PCB}, interrupt};
+use super::{cpu::{self, CpuVersion}, interrupt, percpu, process::{self, PCB}, string::memset};
 
+// task whose fpu state is currently live in the registers
 static mut LAST_FPU_TASK : *mut process::PCB = null_mut();
+static mut XSAVE_ENABLED : bool = false;
+static mut XFEATURE_MASK : u64 = 0;
+// size of the per task save area, 512 for fxsave
+static mut FPU_STATE_SIZE : usize = 512;
+const FPU_STATE_ALIGN : usize = 64;
+const FXSAVE_FCW_OFFSET : usize = 0;
+const FXSAVE_MXCSR_OFFSET : usize = 24;
 
 fn fpu_handler(vector : u64)
 {
-    logk!("fpu exception occured\n");
     assert!(vector == interrupt::INTR_NM);
-    set_cr0(get_cr0() & !(Cr0RegLabel::CR0_EM.bits() | Cr0RegLabel::CR0_TS.bits()));
-    let running_process = sched::get_current_running_process();
-    // assert(task->uid);
-    fpu_enable();
+    unsafe
+    {
+        cpu::clts();
+        let running_process = sched::get_current_running_process();
+        if LAST_FPU_TASK == running_process
+        {
+            return;
+        }
+        if !LAST_FPU_TASK.is_null()
+        {
+            fpu_save((*LAST_FPU_TASK).fpu_state);
+        }
+        if (*running_process).fpu_state.is_null()
+        {
+            (*running_process).fpu_state = fpu_alloc_state();
+        }
+        fpu_restore((*running_process).fpu_state);
+        LAST_FPU_TASK = running_process;
+    }
 }
 
 fn fpu_enable()
@@ -27,6 +49,145 @@ fn fpu_enable()
 
 }
 
+fn fpu_save(state : *mut u8)
+{
+    unsafe
+    {
+        if XSAVE_ENABLED
+        {
+            asm!(
+                "xsave64 [{state}]",
+                state = in(reg) state,
+                in("rdx") XFEATURE_MASK >> 32,
+                in("rax") XFEATURE_MASK & 0xffffffff
+            );
+        }
+        else {
+            asm!("fxsave64 [{state}]", state = in(reg) state);
+        }
+    }
+}
+
+fn fpu_restore(state : *mut u8)
+{
+    unsafe
+    {
+        if XSAVE_ENABLED
+        {
+            asm!(
+                "xrstor64 [{state}]",
+                state = in(reg) state,
+                in("rdx") XFEATURE_MASK >> 32,
+                in("rax") XFEATURE_MASK & 0xffffffff
+            );
+        }
+        else {
+            asm!("fxrstor64 [{state}]", state = in(reg) state);
+        }
+    }
+}
+
+// a zeroed area (xstate_bv = 0) restores every component to its init state
+fn fpu_alloc_state() -> *mut u8
+{
+    unsafe
+    {
+        let state = MEMORY_POOL.alloc(Layout::from_size_align_unchecked(FPU_STATE_SIZE, FPU_STATE_ALIGN));
+        if state.is_null()
+        {
+            panic!("system out of memory!");
+        }
+        assert!(state as usize % FPU_STATE_ALIGN == 0);
+        memset(state, 0, FPU_STATE_SIZE);
+        *(state.add(FXSAVE_FCW_OFFSET) as *mut u16) = 0x37f;
+        *(state.add(FXSAVE_MXCSR_OFFSET) as *mut u32) = 0x1f80;
+        state
+    }
+}
+
+fn fpu_free_state(state : *mut u8)
+{
+    unsafe
+    {
+        MEMORY_POOL.dealloc(state, Layout::from_size_align_unchecked(FPU_STATE_SIZE, FPU_STATE_ALIGN));
+    }
+}
+
+// called on task switch, the next task gets its state back on the first #NM
+pub fn fpu_switch_to(next : *mut PCB)
+{
+    unsafe
+    {
+        if next == LAST_FPU_TASK
+        {
+            cpu::clts();
+        }
+        else {
+            cpu::stts();
+        }
+    }
+}
+
+// fork: the child starts with a copy of the parent's registers
+pub unsafe fn fpu_copy(dst : *mut PCB, src : *mut PCB)
+{
+    unsafe
+    {
+        if LAST_FPU_TASK == src
+        {
+            cpu::clts();
+            fpu_save((*src).fpu_state);
+        }
+        if (*src).fpu_state.is_null()
+        {
+            (*dst).fpu_state = null_mut();
+            return;
+        }
+        (*dst).fpu_state = fpu_alloc_state();
+        compiler_builtins::mem::memcpy((*dst).fpu_state, (*src).fpu_state, FPU_STATE_SIZE);
+    }
+}
+
+// execve / exit: drop the state, next use starts from the init state
+pub unsafe fn fpu_release(pcb : *mut PCB)
+{
+    unsafe
+    {
+        if LAST_FPU_TASK == pcb
+        {
+            LAST_FPU_TASK = null_mut();
+            cpu::stts();
+        }
+        if !(*pcb).fpu_state.is_null()
+        {
+            fpu_free_state((*pcb).fpu_state);
+            (*pcb).fpu_state = null_mut();
+        }
+    }
+}
+
+// allow kernel code to use fpu / simd registers, must not sleep before kernel_fpu_end
+pub fn kernel_fpu_begin()
+{
+    percpu::preempt_disable();
+    unsafe
+    {
+        cpu::clts();
+        if !LAST_FPU_TASK.is_null()
+        {
+            fpu_save((*LAST_FPU_TASK).fpu_state);
+            LAST_FPU_TASK = null_mut();
+        }
+        fpu_enable();
+    }
+}
+
+pub fn kernel_fpu_end()
+{
+    cpu::stts();
+    percpu::preempt_enable();
+}
+
 #[__init]
 fn fpu_check() -> bool
 {
@@ -59,6 +220,34 @@ fn fpu_check() -> bool
     }
 }
 
+#[__init]
+fn xsave_init()
+{
+    unsafe
+    {
+        cpu::set_cr4(cpu::get_cr4() | (Cr4RegLabel::CR4_OSFXSR | Cr4RegLabel::CR4_OSXMMEXCPT).bits());
+        let version = CpuVersion::from_bits_truncate(cpu::__cpuid(cpu::GET_CPU_VERSION).ecx);
+        if !version.contains(CpuVersion::ECX_XSAVE)
+        {
+            logk!("xsave unsupported, fxsave area {} bytes\n", FPU_STATE_SIZE);
+            return;
+        }
+        cpu::set_cr4(cpu::get_cr4() | Cr4RegLabel::CR4_OSXSAVE.bits());
+        let supported = cpu::__cpuid_count(cpu::CPUID_XSAVE_LEAF, 0);
+        let mut mask = cpu::XFEATURE_X87 | cpu::XFEATURE_SSE;
+        if version.contains(CpuVersion::ECX_AVX)
+        {
+            mask |= cpu::XFEATURE_AVX;
+        }
+        XFEATURE_MASK = mask & (supported.eax as u64 | (supported.edx as u64) << 32);
+        cpu::xsetbv(0, XFEATURE_MASK);
+        // ebx: size needed by the features enabled in XCR0
+        FPU_STATE_SIZE = cpu::__cpuid_count(cpu::CPUID_XSAVE_LEAF, 0).ebx as usize;
+        XSAVE_ENABLED = true;
+        logk!("xsave enabled, features {:#x}, area {} bytes\n", XFEATURE_MASK, FPU_STATE_SIZE);
+    }
+}
+
 #[__init]
 pub fn fpu_init()
 {
@@ -67,7 +256,9 @@ pub fn fpu_init()
     assert!(fpu_exist);
     if fpu_exist
     {
+        xsave_init();
         interrupt::set_interrupt_handler(fpu_handler as interrupt::HandlerFn, interrupt::INTR_NM as u8);
-        set_cr0(get_cr0() | (Cr0RegLabel::CR0_EM.bits() | Cr0RegLabel::CR0_TS.bits() | Cr0RegLabel::CR0_NE.bits()) as u64);
+        // EM would turn sse instructions into #UD, only TS is used for lazy switching
+        set_cr0((get_cr0() & !Cr0RegLabel::CR0_EM.bits()) | (Cr0RegLabel::CR0_MP.bits() | Cr0RegLabel::CR0_TS.bits() | Cr0RegLabel::CR0_NE.bits()) as u64);
     }
 }
\ No newline at end of file
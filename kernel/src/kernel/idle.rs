@@ -10,7 +10,8 @@ pub fn idle()
         loop {
             IDLE_CNT += 1;
             logk!("idle!");
-            asm!("hlt");
+            // a sleeping task may switch here with interrupts off
+            asm!("sti", "hlt");
         }
     }
 }
\ No newline at end of file
@@ -43,8 +43,7 @@
     mov r15, [rsp + 1 * 8]
     mov ds, [rsp + 0 * 8 + 6]
     mov es, [rsp + 0 * 8 + 4]
-    mov fs, [rsp + 0 * 8 + 2]
-    mov gs, [rsp + 0 * 8 + 0]
+    // fs / gs selectors are not reloaded, it would clobber the fs / gs base
     add rsp, 8 * 16
 .endm
 
@@ -59,6 +58,11 @@ interrupt_handler_\NAME\():
     jmp interrupt_entry
 .endm
 interrupt_entry:
+    // came from user mode, switch to per-cpu gs base
+    test qword ptr [rsp + 3 * 8], 3
+    jz 1f
+    swapgs
+1:
     SAVE_CONTEXT
     lea rsi, [rsp]
     mov rdi, [rsp + 16 * 8]
@@ -66,43 +70,48 @@ interrupt_entry:
     mov rax, [rax + rdi * 8]
     call rax
 interrupt_exit:
-    // call task signal
+    // a killed task exits here instead of going back to user mode
+    lea rdi, [rsp]
+    call [exit_to_user_mode@GOTPCREL + rip]
     RECOVER_CONTEXT
     add rsp, 0x10
+    test qword ptr [rsp + 1 * 8], 3
+    jz 1f
+    swapgs
+1:
     xchg bx, bx
     iretq
 
+// per-cpu area offsets, see kernel/percpu.rs
+.set PERCPU_KERNEL_STACK, 0x10
+.set PERCPU_USER_STACK, 0x18
+.set USER_CS, 0x23
+.set USER_SS, 0x1b
+
 _syscall_start:
-    sub rsp, 8 * 16
-    mov [rsp + 15 * 8], rax
-    mov [rsp + 14 * 8], rcx
-    mov [rsp + 13 * 8], rdx
-    mov [rsp + 12 * 8], rbx
-    mov [rsp + 11 * 8], rsi
-    mov [rsp + 10 * 8], rdi
-    mov [rsp + 8 * 8], r8
-    mov [rsp + 7 * 8], r9
-    mov [rsp + 6 * 8], r10
-    mov [rsp + 5 * 8], r11
-    mov [rsp + 4 * 8], r12
-    mov [rsp + 3 * 8], r13
-    mov [rsp + 2 * 8], r14
-    mov [rsp + 1 * 8], r15
-    mov [rsp + 0 * 8], rsp
+    swapgs
+    mov gs:[PERCPU_USER_STACK], rsp
+    mov rsp, gs:[PERCPU_KERNEL_STACK]
+    // build the same frame as an interrupt from user mode
+    push USER_SS
+    push qword ptr gs:[PERCPU_USER_STACK]
+    push r11
+    push USER_CS
+    push rcx
+    push 0
+    push 0
+    SAVE_CONTEXT
     lea rdi, [rsp]
     call [syscall_function@GOTPCREL + rip]
 _syscall_end:
-    mov rax, [rsp + 15 * 8]
-    mov rcx, [rsp + 14 * 8]
-    mov rbx, [rsp + 12 * 8]
-    mov rbp, [rsp + 9 * 8]
-    mov r11, [rsp + 5 * 8]
-    mov r12, [rsp + 4 * 8]
-    mov r13, [rsp + 3 * 8]
-    mov r14, [rsp + 2 * 8]
-    mov r15, [rsp + 1 * 8]
-    mov rsp, [rsp + 0 * 8]
-    add rsp, 8 * 16
+    lea rdi, [rsp]
+    call [exit_to_user_mode@GOTPCREL + rip]
+    RECOVER_CONTEXT
+    add rsp, 0x10
+    mov rcx, [rsp + 0 * 8]
+    mov r11, [rsp + 2 * 8]
+    mov rsp, [rsp + 3 * 8]
+    swapgs
     xchg bx, bx
     sysretq
 
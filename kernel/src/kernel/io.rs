@@ -369,6 +369,20 @@ fn ide_pio_read_sector(disk : &IdeDiskT, mut offset : *mut u16)
     }
 }
 
+fn ide_pio_write_sector(disk : &IdeDiskT, mut offset : *const u16)
+{
+    let mut cnt = 0;
+    unsafe
+    {
+        while cnt < SECTOR_SIZE / 2
+        {
+            outw((*(disk.ctrl)).iobase + IDE_DATA, *offset);
+            offset = offset.offset(1);
+            cnt += 1;
+        }
+    }
+}
+
 fn ide_swap_pairs(buf : *mut c_char, len : u32)
 {
     unsafe
@@ -517,9 +531,9 @@ fn ide_install()
     unsafe
     {
         regist_device(252, Some(core::mem::transmute::<*mut(), DeviceIoCtlFn>(device::ide_disk_ioctl as *mut())), 
-                Some(core::mem::transmute::<*mut(), DeviceReadFn>(ide_pio_sync_read as *mut())), Option::None, None);
+                Some(core::mem::transmute::<*mut(), DeviceReadFn>(ide_pio_sync_read as *mut())), Some(core::mem::transmute::<*mut(), DeviceWriteFn>(ide_pio_sync_write as *mut())), None);
         regist_device(259, Some(core::mem::transmute::<*mut(), DeviceIoCtlFn>(device::ide_part_ioctl as *mut())), 
-                Some(core::mem::transmute::<*mut(), DeviceReadFn>(ide_pio_sync_read as *mut())), Option::None, None);
+                Some(core::mem::transmute::<*mut(), DeviceReadFn>(ide_pio_sync_read as *mut())), Some(core::mem::transmute::<*mut(), DeviceWriteFn>(ide_pio_sync_write as *mut())), None);
         let mut cidx = 0;
         while cidx < IDE_CTRL_NR {
             let ctrl = &mut CONTROLLERS[cidx];
@@ -639,3 +653,28 @@ pub fn ide_pio_sync_read(disk : &IdeDiskT, start_block : u32, num_blocks : u8, d
         }
     }
 }
+
+pub fn ide_pio_sync_write(disk : &IdeDiskT, start_block : u32, num_blocks : u8, src : *const u8)
+{
+    let mut var = 0u64;
+    if num_blocks <= 0
+    {
+        panic!("write blocks can't lower than 1");
+    }
+    else
+    {
+        unsafe
+        {
+            ide_select_drive(disk);
+            ide_busy_wait(disk.ctrl, IDE_SR_DRDY);
+            ide_select_sector(disk, start_block as u64, num_blocks);
+            outb((*(disk.ctrl)).iobase + IDE_COMMAND, IDE_CMD_WRITE);
+            while var < num_blocks as u64 {
+                ide_busy_wait(disk.ctrl, IDE_SR_DRQ);
+                ide_pio_write_sector(disk, (src as u64 + SECTOR_SIZE * var) as *const u16);
+                var += 1;
+            }
+            ide_busy_wait(disk.ctrl, IDE_SR_NULL);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use core::ptr::{addr_of, addr_of_mut};
+
+use proc_macro::__init;
+
+use crate::{__setup, logk, mm::memory::{get_cr3_reg, set_cr3_reg, VIRTADDR_START}};
+
+use super::{cmdline::cmdline_find_option_bool, cpu::{self, CpuVersion, GET_CPU_VERSION}};
+
+// the kernel image moves somewhere into this gigabyte, where linux keeps __START_KERNEL_map
+pub const KASLR_REGION_START : u64 = 0xffffffff80000000;
+const KASLR_REGION_SIZE : u64 = 1 << 30;
+// one large page per slot, the image keeps its offset into the first one
+const KASLR_ALIGN : u64 = 1 << 21;
+const RDRAND_RETRY : usize = 10;
+// present, writable and for the pd entries a 2M page
+const KASLR_TABLE_FLAGS : u64 = 0x3;
+const KASLR_LARGE_PAGE_FLAGS : u64 = 0x83;
+
+// what relocation added to every absolute address, 0 when the kernel runs where it was linked
+pub static mut KASLR_SLIDE : u64 = 0;
+
+#[repr(C, align(4096))]
+struct PageTable([u64; 512]);
+
+// the loader's page tables only know the link address, these map the slot until init_memory builds its own
+static mut KASLR_PDPT : PageTable = PageTable([0; 512]);
+static mut KASLR_PD : PageTable = PageTable([0; 512]);
+
+// nothing is set up yet this early, rdrand when the cpu has it, mixed with the tsc either way
+fn kaslr_get_random_long() -> u64
+{
+    let mut random = cpu::rdtsc();
+    if cpu::__cpuid(GET_CPU_VERSION).ecx & CpuVersion::ECX_RDRAND.bits() != 0
+    {
+        let mut var = 0;
+        while var < RDRAND_RETRY {
+            if let Some(value) = cpu::rdrand64()
+            {
+                random ^= value;
+                break;
+            }
+            var += 1;
+        }
+    }
+    random ^= cpu::rdtsc().rotate_left(32);
+    random.wrapping_mul(0x9e3779b97f4a7c15) ^ (random >> 29)
+}
+
+// still running at the link address, so a static's physical address is its offset into the kernel map
+#[__init]
+unsafe fn kaslr_map_slot(base : u64, slots : u64)
+{
+    let pml4 = (VIRTADDR_START as u64 + (get_cr3_reg() & 0xfffffffffffff000)) as *mut u64;
+    let pdpt = addr_of_mut!(KASLR_PDPT) as *mut u64;
+    let pd = addr_of_mut!(KASLR_PD) as *mut u64;
+    *pml4.add(511) = (addr_of!(KASLR_PDPT) as u64 - VIRTADDR_START as u64) | KASLR_TABLE_FLAGS;
+    *pdpt.add(((base >> 30) & 0x1ff) as usize) = (addr_of!(KASLR_PD) as u64 - VIRTADDR_START as u64) | KASLR_TABLE_FLAGS;
+    let first = ((base >> 21) & 0x1ff) as usize;
+    let mut var = 0;
+    while var < slots as usize {
+        *pd.add(first + var) = (var as u64 * KASLR_ALIGN) | KASLR_LARGE_PAGE_FLAGS;
+        var += 1;
+    }
+    set_cr3_reg(get_cr3_reg() as *const _);
+}
+
+// pick a slot for the image ending at `image_end` and map it, returns the slide relocation has to apply
+// nokaslr on the command line keeps the link address
+#[__init]
+pub unsafe fn choose_random_location(image_end : u64) -> u64
+{
+    if cmdline_find_option_bool("nokaslr")
+    {
+        return 0;
+    }
+    // the image starts 1M into the kernel map, the slot covers that megabyte too
+    let slots = (image_end - VIRTADDR_START as u64).div_ceil(KASLR_ALIGN);
+    // one pd entry stays free behind the image, init_memory maps a page more than it needs
+    let choices = KASLR_REGION_SIZE / KASLR_ALIGN - slots;
+    let base = KASLR_REGION_START + kaslr_get_random_long() % choices * KASLR_ALIGN;
+    kaslr_map_slot(base, slots);
+    KASLR_SLIDE = base - VIRTADDR_START as u64;
+    KASLR_SLIDE
+}
+
+// already acted on before relocation, only keeps init from seeing it
+fn nokaslr(_ : &'static str) -> bool
+{
+    true
+}
+__setup!("nokaslr", nokaslr);
+
+// the virtual address the kernel map starts at, physical 0 sits here
+pub fn kernel_map_base() -> u64
+{
+    unsafe { VIRTADDR_START as u64 + KASLR_SLIDE }
+}
+
+pub fn kaslr_enabled() -> bool
+{
+    unsafe { KASLR_SLIDE != 0 }
+}
+
+pub fn show_kaslr_state()
+{
+    unsafe
+    {
+        if KASLR_SLIDE == 0
+        {
+            logk!("KASLR disabled, kernel at {:#x}\n", kernel_map_base());
+            return;
+        }
+        logk!("KASLR enabled, kernel at {:#x}, slide {:#x}\n", kernel_map_base(), KASLR_SLIDE);
+    }
+}
@@ -31,11 +31,11 @@ impl ListHead {
     {
         unsafe 
         {
-            if self.prev.is_null()
+            if !self.prev.is_null()
             {
                 (*self.prev).next = self.next;
             }
-            if self.next.is_null()
+            if !self.next.is_null()
             {
                 (*self.next).prev = self.prev;
             }
@@ -51,6 +51,10 @@ impl ListHead {
         {
             self.prev = addr_of_mut!(*head);
             self.next = head.next;
+            if !head.next.is_null()
+            {
+                (*head.next).prev = addr_of_mut!(*self);
+            }
             head.next = addr_of_mut!(*self);
         }
     }
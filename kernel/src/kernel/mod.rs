@@ -21,14 +21,22 @@ pub mod time;
 pub mod device;
 pub mod buffer;
 pub mod execve;
+pub mod binfmt_script;
 pub mod elf64;
 pub mod fork;
 pub mod keyboard;
 pub mod rtc;
 pub mod input;
 pub mod ramdisk;
+pub mod zram;
 pub mod errno_base;
 pub mod syscall_defs;
+pub mod percpu;
+pub mod random;
+pub mod cmdline;
+pub mod kaslr;
+pub mod multiboot;
+pub mod stacktrace;
 
 pub type Off = usize;
 pub type Err = i64;
@@ -0,0 +1,482 @@
+use core::{ffi::{c_void, CStr, c_char}, ptr::{addr_of, addr_of_mut, null}};
+
+use proc_macro::__init;
+
+use crate::{logk, printk, mm::memory::{phys2virt, VIRTADDR_START}};
+
+use super::{cmdline::copy_boot_command_line, ramdisk::RamDisk};
+
+// eax when a multiboot loader jumps to multiboot_entry
+pub const MULTIBOOT_BOOTLOADER_MAGIC : u32 = 0x2badb002;
+pub const MULTIBOOT2_BOOTLOADER_MAGIC : u32 = 0x36d76289;
+
+pub const MULTIBOOT_TAG_TYPE_END : u32 = 0;
+pub const MULTIBOOT_TAG_TYPE_CMDLINE : u32 = 1;
+pub const MULTIBOOT_TAG_TYPE_BOOT_LOADER_NAME : u32 = 2;
+pub const MULTIBOOT_TAG_TYPE_MODULE : u32 = 3;
+pub const MULTIBOOT_TAG_TYPE_MMAP : u32 = 6;
+pub const MULTIBOOT_TAG_TYPE_FRAMEBUFFER : u32 = 8;
+
+// which fields of a multiboot 1 information structure are valid
+const MULTIBOOT_INFO_MEMORY : u32 = 1 << 0;
+const MULTIBOOT_INFO_CMDLINE : u32 = 1 << 2;
+const MULTIBOOT_INFO_MODS : u32 = 1 << 3;
+const MULTIBOOT_INFO_MEM_MAP : u32 = 1 << 6;
+const MULTIBOOT_INFO_BOOT_LOADER_NAME : u32 = 1 << 9;
+const MULTIBOOT_INFO_FRAMEBUFFER_INFO : u32 = 1 << 12;
+
+const MULTIBOOT_MEMORY_AVAILABLE : u32 = 1;
+const MULTIBOOT_FRAMEBUFFER_TYPE_RGB : u8 = 1;
+const MULTIBOOT_FRAMEBUFFER_TYPE_EGA_TEXT : u8 = 2;
+
+// multiboot_entry maps the first gigabyte, anything the loader put above it is out of reach
+const BOOT_MAP_SIZE : u64 = 1 << 30;
+const BOOT_INFO_SIZE : usize = 0x4000;
+
+#[repr(C, align(8))]
+struct BootInfo([u8; BOOT_INFO_SIZE]);
+
+// the loader's information copied into the kernel, multiboot 1 is turned into multiboot 2 tags on the way
+static mut BOOT_INFO : BootInfo = BootInfo([0; BOOT_INFO_SIZE]);
+// what the loader passed in eax, 0 for the loader in boot/
+static mut BOOT_MAGIC : u32 = 0;
+
+#[repr(C)]
+pub struct MultibootTag
+{
+    pub tag_type : u32,
+    pub size : u32
+}
+
+#[repr(C)]
+struct MultibootTagString
+{
+    tag_type : u32,
+    size : u32,
+    string : [u8; 0]
+}
+
+#[repr(C)]
+struct MultibootTagModule
+{
+    tag_type : u32,
+    size : u32,
+    mod_start : u32,
+    mod_end : u32,
+    cmdline : [u8; 0]
+}
+
+// each entry starts like an e820 ards
+#[repr(C)]
+pub struct MultibootTagMmap
+{
+    pub tag_type : u32,
+    pub size : u32,
+    pub entry_size : u32,
+    pub entry_version : u32
+}
+
+#[repr(C)]
+struct MultibootMmapEntry
+{
+    addr : u64,
+    len : u64,
+    entry_type : u32,
+    zero : u32
+}
+
+#[repr(C, packed)]
+struct MultibootTagFramebuffer
+{
+    tag_type : u32,
+    size : u32,
+    framebuffer_addr : u64,
+    framebuffer_pitch : u32,
+    framebuffer_width : u32,
+    framebuffer_height : u32,
+    framebuffer_bpp : u8,
+    framebuffer_type : u8,
+    reserved : u16
+}
+
+#[repr(C, packed)]
+struct MultibootInfo
+{
+    flags : u32,
+    mem_lower : u32,
+    mem_upper : u32,
+    boot_device : u32,
+    cmdline : u32,
+    mods_count : u32,
+    mods_addr : u32,
+    syms : [u32; 4],
+    mmap_length : u32,
+    mmap_addr : u32,
+    drives_length : u32,
+    drives_addr : u32,
+    config_table : u32,
+    boot_loader_name : u32,
+    apm_table : u32,
+    vbe_control_info : u32,
+    vbe_mode_info : u32,
+    vbe_mode : u16,
+    vbe_interface_seg : u16,
+    vbe_interface_off : u16,
+    vbe_interface_len : u16,
+    framebuffer_addr : u64,
+    framebuffer_pitch : u32,
+    framebuffer_width : u32,
+    framebuffer_height : u32,
+    framebuffer_bpp : u8,
+    framebuffer_type : u8
+}
+
+#[repr(C, packed)]
+struct MultibootMmapEntryV1
+{
+    size : u32,
+    addr : u64,
+    len : u64,
+    entry_type : u32
+}
+
+#[repr(C)]
+struct MultibootModuleV1
+{
+    mod_start : u32,
+    mod_end : u32,
+    cmdline : u32,
+    pad : u32
+}
+
+pub struct MultibootTagIter
+{
+    tag : *const MultibootTag
+}
+
+impl Iterator for MultibootTagIter
+{
+    type Item = *const MultibootTag;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        unsafe
+        {
+            if self.tag.is_null() || (*self.tag).tag_type == MULTIBOOT_TAG_TYPE_END
+            {
+                return None;
+            }
+            let tag = self.tag;
+            self.tag = (tag as *const u8).add(((*tag).size as usize + 7) & !7) as *const MultibootTag;
+            Some(tag)
+        }
+    }
+}
+
+// the tags behind the 8 byte total_size/reserved header
+pub unsafe fn multiboot_tags(info : *const c_void) -> MultibootTagIter
+{
+    if info.is_null()
+    {
+        return MultibootTagIter { tag: null() };
+    }
+    MultibootTagIter { tag: (info as *const u8).add(8) as *const MultibootTag }
+}
+
+pub unsafe fn multiboot_find_tag(info : *const c_void, tag_type : u32) -> *const MultibootTag
+{
+    multiboot_tags(info).find(|tag| (**tag).tag_type == tag_type).unwrap_or(null())
+}
+
+#[inline(always)]
+fn boot_phys2virt(paddr : u64) -> *mut u8
+{
+    (paddr + VIRTADDR_START as u64) as *mut u8
+}
+
+#[__init]
+unsafe fn boot_strlen(mut s : *const u8) -> usize
+{
+    let mut len = 0;
+    while *s != 0 {
+        len += 1;
+        s = s.add(1);
+    }
+    len
+}
+
+// room for a tag of `size` bytes behind `pos`, keeping 8 for the end tag
+#[__init]
+unsafe fn boot_info_alloc(pos : &mut usize, tag_type : u32, size : usize) -> Option<*mut u8>
+{
+    let aligned = (size + 7) & !7;
+    if *pos + aligned + 8 > BOOT_INFO_SIZE
+    {
+        return None;
+    }
+    let tag = (addr_of_mut!(BOOT_INFO) as *mut u8).add(*pos);
+    (*(tag as *mut MultibootTag)).tag_type = tag_type;
+    (*(tag as *mut MultibootTag)).size = size as u32;
+    *pos += aligned;
+    Some(tag)
+}
+
+#[__init]
+unsafe fn boot_info_push_string(pos : &mut usize, tag_type : u32, s : *const u8)
+{
+    let len = boot_strlen(s) + 1;
+    if let Some(tag) = boot_info_alloc(pos, tag_type, 8 + len)
+    {
+        core::ptr::copy_nonoverlapping(s, (*(tag as *mut MultibootTagString)).string.as_mut_ptr(), len);
+    }
+}
+
+#[__init]
+unsafe fn multiboot_push_mmap_entry(entry : *mut MultibootMmapEntry, addr : u64, len : u64, entry_type : u32)
+{
+    (*entry).addr = addr;
+    (*entry).len = len;
+    (*entry).entry_type = entry_type;
+    (*entry).zero = 0;
+}
+
+// the multiboot 2 layout is what everything after relocation reads, so the old one is converted
+#[__init]
+unsafe fn multiboot_convert_info(info : *const MultibootInfo, pos : &mut usize)
+{
+    let flags = (*info).flags;
+    if flags & MULTIBOOT_INFO_CMDLINE != 0
+    {
+        // qemu and grub legacy put the kernel's file name in front of the arguments
+        let mut cmdline = boot_phys2virt((*info).cmdline as u64) as *const u8;
+        while *cmdline != 0 && *cmdline != b' ' {
+            cmdline = cmdline.add(1);
+        }
+        while *cmdline == b' ' {
+            cmdline = cmdline.add(1);
+        }
+        boot_info_push_string(pos, MULTIBOOT_TAG_TYPE_CMDLINE, cmdline);
+    }
+    if flags & MULTIBOOT_INFO_BOOT_LOADER_NAME != 0
+    {
+        boot_info_push_string(pos, MULTIBOOT_TAG_TYPE_BOOT_LOADER_NAME, boot_phys2virt((*info).boot_loader_name as u64));
+    }
+    if flags & MULTIBOOT_INFO_MODS != 0
+    {
+        let mods = boot_phys2virt((*info).mods_addr as u64) as *const MultibootModuleV1;
+        let mut var = 0;
+        while var < (*info).mods_count as usize {
+            let module = mods.add(var);
+            let cmdline = boot_phys2virt((*module).cmdline as u64) as *const u8;
+            let len = boot_strlen(cmdline) + 1;
+            if let Some(tag) = boot_info_alloc(pos, MULTIBOOT_TAG_TYPE_MODULE, 16 + len)
+            {
+                let tag = tag as *mut MultibootTagModule;
+                (*tag).mod_start = (*module).mod_start;
+                (*tag).mod_end = (*module).mod_end;
+                core::ptr::copy_nonoverlapping(cmdline, (*tag).cmdline.as_mut_ptr(), len);
+            }
+            var += 1;
+        }
+    }
+    if flags & MULTIBOOT_INFO_MEM_MAP != 0
+    {
+        let start = boot_phys2virt((*info).mmap_addr as u64);
+        let end = start.add((*info).mmap_length as usize);
+        let mut count = 0;
+        let mut entry = start;
+        while entry < end {
+            count += 1;
+            entry = entry.add((*(entry as *const MultibootMmapEntryV1)).size as usize + 4);
+        }
+        if let Some(tag) = boot_info_alloc(pos, MULTIBOOT_TAG_TYPE_MMAP, 16 + count * 24)
+        {
+            let tag = tag as *mut MultibootTagMmap;
+            (*tag).entry_size = 24;
+            (*tag).entry_version = 0;
+            let mut dst = tag.add(1) as *mut MultibootMmapEntry;
+            entry = start;
+            while entry < end {
+                let src = entry as *const MultibootMmapEntryV1;
+                multiboot_push_mmap_entry(dst, (*src).addr, (*src).len, (*src).entry_type);
+                dst = dst.add(1);
+                entry = entry.add((*src).size as usize + 4);
+            }
+        }
+    }
+    else if flags & MULTIBOOT_INFO_MEMORY != 0
+    {
+        // no map, only the sizes of low memory and of what follows 1M
+        if let Some(tag) = boot_info_alloc(pos, MULTIBOOT_TAG_TYPE_MMAP, 16 + 2 * 24)
+        {
+            let tag = tag as *mut MultibootTagMmap;
+            (*tag).entry_size = 24;
+            (*tag).entry_version = 0;
+            let entry = tag.add(1) as *mut MultibootMmapEntry;
+            multiboot_push_mmap_entry(entry, 0, (*info).mem_lower as u64 * 1024, MULTIBOOT_MEMORY_AVAILABLE);
+            multiboot_push_mmap_entry(entry.add(1), 0x100000, (*info).mem_upper as u64 * 1024, MULTIBOOT_MEMORY_AVAILABLE);
+        }
+    }
+    if flags & MULTIBOOT_INFO_FRAMEBUFFER_INFO != 0
+    {
+        if let Some(tag) = boot_info_alloc(pos, MULTIBOOT_TAG_TYPE_FRAMEBUFFER, 32)
+        {
+            let tag = tag as *mut MultibootTagFramebuffer;
+            (*tag).framebuffer_addr = (*info).framebuffer_addr;
+            (*tag).framebuffer_pitch = (*info).framebuffer_pitch;
+            (*tag).framebuffer_width = (*info).framebuffer_width;
+            (*tag).framebuffer_height = (*info).framebuffer_height;
+            (*tag).framebuffer_bpp = (*info).framebuffer_bpp;
+            (*tag).framebuffer_type = (*info).framebuffer_type;
+            (*tag).reserved = 0;
+        }
+    }
+}
+
+// tags that do not fit are dropped, the end tag always does
+#[__init]
+unsafe fn multiboot2_copy_info(info : *const u8, pos : &mut usize)
+{
+    let end = info.add(*(info as *const u32) as usize);
+    let mut tag = info.add(8);
+    while tag < end {
+        let tag_type = (*(tag as *const MultibootTag)).tag_type;
+        let size = (*(tag as *const MultibootTag)).size as usize;
+        if tag_type == MULTIBOOT_TAG_TYPE_END
+        {
+            break;
+        }
+        if let Some(dst) = boot_info_alloc(pos, tag_type, size)
+        {
+            core::ptr::copy_nonoverlapping(tag, dst, size);
+        }
+        tag = tag.add((size + 7) & !7);
+    }
+}
+
+// runs before relocation from multiboot_relocation, nothing here may go through a pointer stored in data
+// keeps the loader's information and command line, returns where the modules to keep end (physical)
+#[__init]
+pub unsafe fn multiboot_save_info(magic : u32, info : u64) -> u64
+{
+    let mut pos = 8;
+    match magic {
+        MULTIBOOT2_BOOTLOADER_MAGIC => multiboot2_copy_info(boot_phys2virt(info), &mut pos),
+        MULTIBOOT_BOOTLOADER_MAGIC => multiboot_convert_info(boot_phys2virt(info) as *const MultibootInfo, &mut pos),
+        _ => return 0
+    }
+    let buffer = addr_of_mut!(BOOT_INFO) as *mut u8;
+    let end = buffer.add(pos) as *mut MultibootTag;
+    (*end).tag_type = MULTIBOOT_TAG_TYPE_END;
+    (*end).size = 8;
+    *(buffer as *mut u32) = (pos + 8) as u32;
+    BOOT_MAGIC = magic;
+    let mut reserved_end = 0;
+    let mut tag = buffer.add(8) as *const MultibootTag;
+    while (*tag).tag_type != MULTIBOOT_TAG_TYPE_END {
+        match (*tag).tag_type {
+            MULTIBOOT_TAG_TYPE_CMDLINE => copy_boot_command_line((*(tag as *const MultibootTagString)).string.as_ptr()),
+            MULTIBOOT_TAG_TYPE_MODULE =>
+            {
+                let module = tag as *const MultibootTagModule;
+                if (*module).mod_end as u64 <= BOOT_MAP_SIZE && (*module).mod_end as u64 > reserved_end
+                {
+                    reserved_end = (*module).mod_end as u64;
+                }
+            },
+            _ => {}
+        }
+        tag = (tag as *const u8).add(((*tag).size as usize + 7) & !7) as *const MultibootTag;
+    }
+    reserved_end
+}
+
+// the magic the kernel was started with, 0 when it came through the loader in boot/
+pub fn boot_magic() -> u32
+{
+    unsafe { BOOT_MAGIC }
+}
+
+// the saved tags in multiboot 2 layout whichever version the loader spoke, null without a multiboot loader
+pub fn boot_info() -> *const c_void
+{
+    unsafe
+    {
+        if BOOT_MAGIC == 0
+        {
+            return null();
+        }
+        addr_of!(BOOT_INFO) as *const c_void
+    }
+}
+
+unsafe fn tag_string(string : *const u8) -> &'static str
+{
+    CStr::from_ptr(string as *const c_char).to_str().unwrap_or("")
+}
+
+pub fn show_multiboot_info()
+{
+    unsafe
+    {
+        let version = match BOOT_MAGIC {
+            MULTIBOOT2_BOOTLOADER_MAGIC => 2,
+            MULTIBOOT_BOOTLOADER_MAGIC => 1,
+            _ => return
+        };
+        let name = multiboot_find_tag(boot_info(), MULTIBOOT_TAG_TYPE_BOOT_LOADER_NAME);
+        printk!("Multiboot{} loader: {}\n", if version == 2 { "2" } else { "" }, if name.is_null() { "unknown" } else { tag_string((*(name as *const MultibootTagString)).string.as_ptr()) });
+        for tag in multiboot_tags(boot_info()) {
+            match (*tag).tag_type {
+                MULTIBOOT_TAG_TYPE_MODULE =>
+                {
+                    let module = tag as *const MultibootTagModule;
+                    logk!("module: {:#x}-{:#x} {}\n", (*module).mod_start, (*module).mod_end, tag_string((*module).cmdline.as_ptr()));
+                },
+                MULTIBOOT_TAG_TYPE_FRAMEBUFFER =>
+                {
+                    let fb = tag as *const MultibootTagFramebuffer;
+                    let (addr, pitch, width, height, bpp, fb_type) = ((*fb).framebuffer_addr, (*fb).framebuffer_pitch, (*fb).framebuffer_width, (*fb).framebuffer_height, (*fb).framebuffer_bpp, (*fb).framebuffer_type);
+                    logk!("framebuffer: {:#x} {}x{}x{} pitch {} type {}\n", addr, width, height, bpp, pitch, fb_type);
+                    if fb_type == MULTIBOOT_FRAMEBUFFER_TYPE_RGB
+                    {
+                        printk!("framebuffer is graphical, the vga text console stays blank, use console=ttyS0\n");
+                    }
+                    else if fb_type == MULTIBOOT_FRAMEBUFFER_TYPE_EGA_TEXT && addr != 0xb8000
+                    {
+                        printk!("ega text framebuffer at {:#x} instead of 0xb8000\n", addr);
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+// every module the loader brought becomes a ramdisk in load order, root=/dev/ram0 boots from the first
+pub fn multiboot_initrd_init()
+{
+    unsafe
+    {
+        for tag in multiboot_tags(boot_info()) {
+            if (*tag).tag_type != MULTIBOOT_TAG_TYPE_MODULE
+            {
+                continue;
+            }
+            let module = tag as *const MultibootTagModule;
+            let (start, end) = ((*module).mod_start as u64, (*module).mod_end as u64);
+            if end > BOOT_MAP_SIZE
+            {
+                printk!("initrd: module at {:#x} is above the boot mapping, skipped\n", start);
+                continue;
+            }
+            let dev = RamDisk::create_from(phys2virt(start as *const c_void), (end - start) as usize);
+            if dev == 0
+            {
+                printk!("initrd: module at {:#x} is not page aligned or too small, skipped\n", start);
+                continue;
+            }
+            logk!("initrd: {} ({}KB) as device {:#x}\n", tag_string((*module).cmdline.as_ptr()), (end - start) / 1024, dev);
+        }
+    }
+}
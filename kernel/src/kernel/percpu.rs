@@ -0,0 +1,169 @@
+use core::{arch::asm, mem::offset_of, ptr::{addr_of_mut, null_mut}};
+use proc_macro::__init;
+use static_assertions::const_assert_eq;
+
+use crate::logk;
+
+use super::{cpu::{self, rdmsr, wrmsr}, process::PCB};
+
+pub const MAX_CPU_NUM : usize = 8;
+pub const IA32_FS_BASE : u64 = 0xc0000100;
+pub const IA32_GS_BASE : u64 = 0xc0000101;
+pub const IA32_KERNEL_GS_BASE : u64 = 0xc0000102;
+
+pub static mut PER_CPU : [PerCpu; MAX_CPU_NUM] = [PerCpu::new(); MAX_CPU_NUM];
+
+// the syscall / interrupt entry code in interrupt.asm addresses these fields
+// through gs with hardcoded offsets, keep them in sync
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PerCpu
+{
+    pub self_ptr : *mut PerCpu,     // gs:0x00
+    pub current : *mut PCB,         // gs:0x08
+    pub kernel_stack : u64,         // gs:0x10 top of current task kernel stack
+    pub user_stack : u64,           // gs:0x18 scratch slot for user rsp on syscall
+    pub preempt_count : u64,        // gs:0x20
+    pub cpu_id : u64                // gs:0x28
+}
+
+const_assert_eq!(offset_of!(PerCpu, self_ptr), 0x00);
+const_assert_eq!(offset_of!(PerCpu, current), 0x08);
+const_assert_eq!(offset_of!(PerCpu, kernel_stack), 0x10);
+const_assert_eq!(offset_of!(PerCpu, user_stack), 0x18);
+const_assert_eq!(offset_of!(PerCpu, preempt_count), 0x20);
+
+impl PerCpu {
+    pub const fn new() -> Self
+    {
+        Self { self_ptr: null_mut(), current: null_mut(), kernel_stack: 0, user_stack: 0, preempt_count: 0, cpu_id: 0 }
+    }
+}
+
+// load per-cpu area of cpu `cpu_id` into gs base, kernel gs base keeps user gs (0 at boot)
+#[__init]
+pub fn percpu_init(cpu_id : usize)
+{
+    unsafe
+    {
+        assert!(cpu_id < MAX_CPU_NUM);
+        let area = addr_of_mut!(PER_CPU[cpu_id]);
+        (*area).self_ptr = area;
+        (*area).cpu_id = cpu_id as u64;
+        wrmsr(IA32_GS_BASE, area as u64);
+        wrmsr(IA32_KERNEL_GS_BASE, 0);
+        logk!("cpu {} per-cpu area at {:#x}\n", cpu_id, area as u64);
+    }
+}
+
+#[inline(always)]
+pub fn this_cpu() -> *mut PerCpu
+{
+    let result;
+    unsafe
+    {
+        asm!(
+            "mov {area}, gs:[0]",
+            area = out(reg) result
+        );
+    }
+    result
+}
+
+#[inline(always)]
+pub fn this_cpu_read(offset : usize) -> u64
+{
+    let result;
+    unsafe
+    {
+        asm!(
+            "mov {value}, gs:[{offset}]",
+            offset = in(reg) offset,
+            value = out(reg) result
+        );
+    }
+    result
+}
+
+#[inline(always)]
+pub fn this_cpu_write(offset : usize, value : u64)
+{
+    unsafe
+    {
+        asm!(
+            "mov gs:[{offset}], {value}",
+            offset = in(reg) offset,
+            value = in(reg) value
+        );
+    }
+}
+
+#[inline(always)]
+pub fn get_current() -> *mut PCB
+{
+    this_cpu_read(offset_of!(PerCpu, current)) as *mut PCB
+}
+
+#[inline(always)]
+pub fn set_current(pcb : *mut PCB)
+{
+    this_cpu_write(offset_of!(PerCpu, current), pcb as u64);
+}
+
+#[inline(always)]
+pub fn set_kernel_stack(stack_top : u64)
+{
+    this_cpu_write(offset_of!(PerCpu, kernel_stack), stack_top);
+}
+
+#[inline(always)]
+pub fn preempt_count() -> u64
+{
+    this_cpu_read(offset_of!(PerCpu, preempt_count))
+}
+
+#[inline(always)]
+pub fn preempt_disable()
+{
+    this_cpu_write(offset_of!(PerCpu, preempt_count), preempt_count() + 1);
+}
+
+#[inline(always)]
+pub fn preempt_enable()
+{
+    let count = preempt_count();
+    assert!(count > 0, "unbalanced preempt_enable");
+    this_cpu_write(offset_of!(PerCpu, preempt_count), count - 1);
+}
+
+pub fn smp_processor_id() -> usize
+{
+    this_cpu_read(offset_of!(PerCpu, cpu_id)) as usize
+}
+
+// user fs base is live in IA32_FS_BASE while a thread runs
+pub fn read_user_fs_base() -> u64
+{
+    rdmsr(IA32_FS_BASE)
+}
+
+pub fn write_user_fs_base(base : u64)
+{
+    wrmsr(IA32_FS_BASE, base);
+}
+
+// inside the kernel the user gs base is parked in IA32_KERNEL_GS_BASE by swapgs
+pub fn read_user_gs_base() -> u64
+{
+    rdmsr(IA32_KERNEL_GS_BASE)
+}
+
+pub fn write_user_gs_base(base : u64)
+{
+    wrmsr(IA32_KERNEL_GS_BASE, base);
+}
+
+pub fn cpu_count() -> usize
+{
+    cpu::get_cpu_number()
+}
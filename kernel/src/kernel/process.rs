@@ -1,12 +1,12 @@
 use core::{alloc::{GlobalAlloc, Layout}, arch::asm, cell::OnceCell, cmp, ffi::{c_char, c_void}, mem::size_of, ptr::{addr_of_mut, null, null_mut}};
 use core::intrinsics::{likely, unlikely};
-use alloc::{collections::{BinaryHeap, btree_map, LinkedList}, vec::Vec};
+use alloc::{collections::{BinaryHeap, btree_map, LinkedList}, format, string::String, vec::Vec};
 use proc_macro::__init;
-use crate::{crypto::crc32c::init_crc32, fs::{dcache::DEntry, file::{File, FS}, namei::Fd, path::Path, super_block::{super_init, mount_block_root}}, kernel::{clock::clock_init, fpu::fpu_init, global::{set_tss64, KERNEL_TSS}, idle, interrupt::{self, interrupt_disable, set_interrupt_state}, io::ide_init, keyboard::keyboard_init, sched::{self, get_current_running_process, set_running_process}, syscall::syscall_init, time::time_init}, logk, mm::{memory::{get_cr3_reg, set_cr3_reg, Pml4, USER_STACK_TOP}, mm_type::{self, MmapType}}, printk};
+use crate::{__setup, crypto::crc32c::init_crc32, fs::{dcache::DEntry, file::{File, FS}, namei::Fd, path::Path, super_block::{super_init, prepare_namespace}, proc::proc_mount}, kernel::{clock::clock_init, fpu::fpu_init, global::{set_tss64, KERNEL_TSS}, idle, interrupt::{self, interrupt_disable, set_interrupt_state}, io::ide_init, keyboard::keyboard_init, multiboot::multiboot_initrd_init, sched::{self, get_current_running_process, set_running_process}, syscall::syscall_init, time::time_init, zram::Zram}, logk, mm::{memory::{get_cr3_reg, set_cr3_reg, MemoryPool, Pml4, PAGE_SIZE, USER_STACK_TOP}, kmemleak, ksm, mm_type::{self, MmapType}, vmscan}, printk};
 pub type Priority = u8;
 use crate::mm::memory;
 
-use super::{execve, global::{USER_DATA_IDX, USER_CODE_IDX}};
+use super::{buffer, cmdline::{init_argv, init_envp}, errno_base::{EFAULT, EINVAL, EPERM}, execve, fpu, global::{USER_DATA_IDX, USER_CODE_IDX}, percpu, syscall_defs::{ADDR_NO_RANDOMIZE, ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS}, Err};
 pub type PCB = ProcessControlBlock;
 const MAX_PROGRESS_NUM : Pid = 65536;
 pub const MAX_PROCSEE_STACK_SIZE : usize = 0x4000000;
@@ -17,7 +17,13 @@ static mut WAIT_MAP : btree_map::BTreeMap<Priority, LinkedList<*mut PCB>> = btre
 static mut IDLE : *mut PCB = null_mut();
 static mut PROCESS_ID_SEQ : Pid = 0;
 pub const PROCESS_NAME_LEN : usize = 256;
-const THREAD_SIZE : usize = 16 * 1024;
+pub const THREAD_SIZE : usize = 16 * 1024;
+// init=, tried alone when given, otherwise the usual places in turn
+static mut EXECUTE_COMMAND : &str = "";
+const DEFAULT_INIT_PROCESSES : [&str; 4] = ["/sbin/init", "/etc/init", "/bin/init", "/bin/sh"];
+// ProcessControlBlock::flags
+pub const PF_MEMALLOC : u32 = 0x00000800; // reclaiming, allocations may dip below the watermarks
+pub const PF_KTHREAD : u32 = 0x00200000; // kernel thread, never an oom victim
 
 #[repr(C, packed)]
 #[derive(Default)]
@@ -80,12 +86,21 @@ fn init_thread()
     logk!("kernel init!\n");
     super_init();
     ide_init();
-    mount_block_root("/dev/sda\0".as_ptr().cast());
+    // a quarter of memory for a compressed swap or scratch disk
+    Zram::create(MemoryPool::total_pages() / 4 * PAGE_SIZE);
+    multiboot_initrd_init();
+    prepare_namespace();
+    proc_mount();
+    buffer::bdflush_init();
+    vmscan::kswapd_init();
+    ksm::ksm_init();
+    kmemleak::kmemleak_late_init();
     time_init();
     fpu_init();
     keyboard_init();
     init_crc32();
     syscall_init();
+    execve::binfmt_init();
     task_to_user_mode();
 }
 
@@ -115,6 +130,13 @@ pub struct ProcessControlBlock
     pub blocked : u32,
     pub iroot : Path,
     pub ipwd : Path,
+    pub fs_base : u64, // user fs base, saved on task switch
+    pub gs_base : u64, // user gs base, saved on task switch
+    pub fpu_state : *mut u8, // xsave area, allocated on first fpu use
+    pub personality : u64, // execution domain flags, kept across exec
+    pub flags : u32, // PF_*
+    pub sleep_until : u64, // jiffy schedule_timeout wakes the task at, 0 while it is runnable
+    pub kill_code : i64, // exit code of a pending kill, the task exits on its way back to user mode
     pub magic : u64
 }
 
@@ -135,6 +157,10 @@ pub fn awake_process(pcb : *mut PCB)
 
 pub unsafe fn schedule()
 {
+    if unlikely(percpu::preempt_count() != 0)
+    {
+        return;
+    }
     let current = sched::get_current_running_process();
     if likely(!current.is_null())
     {
@@ -197,9 +223,85 @@ fn task_to_user_mode()
         (*pt_regs).gs = 0;
         (*pt_regs).cs = (USER_CODE_IDX << 3 | 0b11) as u64;
         (*pt_regs).rflags = 0 << 12 | 0b10 | 1 << 9;
-        logk!("calling init\n");
-        execve::sys_execve("bin/init\0".as_ptr() as *const c_char, null_mut(), null_mut());
-        panic!("exec /bin/init failure")
+        if !EXECUTE_COMMAND.is_empty()
+        {
+            let ret = run_init_process(EXECUTE_COMMAND);
+            panic!("Requested init {} failed (error {}).", EXECUTE_COMMAND, ret);
+        }
+        for init in DEFAULT_INIT_PROCESSES.iter() {
+            run_init_process(init);
+        }
+        panic!("No working init found.  Try passing init= option to kernel.")
+    }
+}
+
+fn init_setup(command : &'static str) -> bool
+{
+    unsafe { EXECUTE_COMMAND = command; }
+    true
+}
+__setup!("init=", init_setup);
+
+// argv and environment come from the command line, only returns when the exec failed
+fn run_init_process(init_filename : &str) -> Err
+{
+    let mut argv = Vec::from([init_filename]);
+    argv.extend_from_slice(init_argv());
+    let envp : Vec<String> = init_envp().iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    let envp : Vec<&str> = envp.iter().map(|env| env.as_str()).collect();
+    logk!("Run {} as init process\n", init_filename);
+    execve::kernel_execve(init_filename, &argv, &envp)
+}
+
+pub fn sys_arch_prctl(code : u64, addr : u64) -> Err
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        match code {
+            ARCH_SET_FS | ARCH_SET_GS =>
+            {
+                if addr >= USER_STACK_TOP as u64
+                {
+                    return -EPERM;
+                }
+                if code == ARCH_SET_FS
+                {
+                    (*pcb).fs_base = addr;
+                    percpu::write_user_fs_base(addr);
+                }
+                else {
+                    (*pcb).gs_base = addr;
+                    percpu::write_user_gs_base(addr);
+                }
+                0
+            },
+            ARCH_GET_FS | ARCH_GET_GS =>
+            {
+                if addr == 0 || addr >= USER_STACK_TOP as u64
+                {
+                    return -EFAULT;
+                }
+                *(addr as *mut u64) = if code == ARCH_GET_FS { percpu::read_user_fs_base() } else { percpu::read_user_gs_base() };
+                0
+            },
+            _ => -EINVAL
+        }
+    }
+}
+
+// 0xffffffff only queries the current personality
+pub fn sys_personality(persona : u64) -> u64
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        let old = (*pcb).personality;
+        if persona != 0xffffffff
+        {
+            (*pcb).personality = persona & 0xffffffff;
+        }
+        old
     }
 }
 
@@ -208,32 +310,113 @@ pub fn sys_exit(error_code : i64)
     do_exit(error_code);
 }
 
+// every task in the table, in pid order
+pub fn for_each_process(mut f : impl FnMut(*mut PCB))
+{
+    unsafe
+    {
+        let mut pid = 0;
+        while pid < PROCESS_ID_SEQ {
+            let pcb = TASK_TABLE[pid as usize];
+            if !pcb.is_null()
+            {
+                f(pcb);
+            }
+            pid += 1;
+        }
+    }
+}
+
+// null once the task has exited
+pub fn find_task_by_pid(pid : Pid) -> *mut PCB
+{
+    unsafe
+    {
+        if pid < 0 || pid >= MAX_PROGRESS_NUM
+        {
+            return null_mut();
+        }
+        TASK_TABLE[pid as usize]
+    }
+}
+
+// leave a kill pending, the task tears itself down in exit_to_user_mode
+// whatever it is doing in the kernel right now finishes first, nothing is freed under it
+pub unsafe fn kill_process(pcb : *mut PCB, error_code : i64)
+{
+    if (*pcb).kill_code == 0
+    {
+        (*pcb).kill_code = error_code;
+    }
+    wake_up_process(pcb);
+}
+
+pub fn fatal_signal_pending(pcb : &PCB) -> bool
+{
+    pcb.kill_code != 0
+}
+
+// last stop of every interrupt and syscall before user mode
+#[no_mangle]
+pub unsafe fn exit_to_user_mode(regs : *mut PtRegs)
+{
+    if (*regs).cs & 3 == 0
+    {
+        return;
+    }
+    let pcb = get_current_running_process();
+    if !pcb.is_null() && fatal_signal_pending(&*pcb)
+    {
+        do_exit((*pcb).kill_code);
+    }
+}
+
 fn do_exit(error_code : i64)
 {
     unsafe
     {
         let pcb = get_current_running_process();
+        TASK_TABLE[(*pcb).pid as usize] = null_mut();
+        fpu::fpu_release(pcb);
+        (*pcb).mm.release_all();
         PCB::distory_task_control_block(pcb);
-        match WAIT_MAP.first_entry() {
-            Some(mut entry) => 
-            {
-                match entry.get_mut().pop_front() {
-                    Some(next_process) => 
+        schedule_block();
+    }
+}
+
+// switch to the next runnable task without putting the current one back on the run queue
+// only awake_process brings it back, interrupts have to be off so the clock cannot requeue it first
+pub unsafe fn schedule_block()
+{
+    match WAIT_MAP.first_entry() {
+        Some(mut entry) => 
+        {
+            match entry.get_mut().pop_front() {
+                Some(next_process) => 
+                {
+                    if likely(entry.get_mut().is_empty())
                     {
-                        if likely(entry.get_mut().is_empty())
-                        {
-                            entry.remove();
-                        }
-                        else {
-                            task_switch(next_process);
-                        }
-                    },
-                    None => panic!("next process can't be empty!"),
-                }
-            },
-            None => task_switch(IDLE),
-        }
+                        entry.remove();
+                    }
+                    task_switch(next_process);
+                },
+                None => panic!("next process can't be empty!"),
+            }
+        },
+        None => task_switch(IDLE),
+    }
+}
+
+// cut a schedule_timeout short, nothing happens to a task that is not sleeping
+pub unsafe fn wake_up_process(pcb : *mut PCB)
+{
+    let state = interrupt_disable();
+    if (*pcb).sleep_until != 0
+    {
+        (*pcb).sleep_until = 0;
+        awake_process(pcb);
     }
+    set_interrupt_state(state);
 }
 
 impl ProcessControlBlock {
@@ -318,7 +501,7 @@ impl ProcessControlBlock {
             {
                 panic!("system out of memory!");
             }
-            (*result) = ProcessControlBlock { priority: 0, jiffies: 0, name: [0; PROCESS_NAME_LEN], uid: 0, gid: 0, pid: 0, ppid: 0, pgid: 0, pml4: null_mut(), wait_pid: 0, blocked: 0, mm: mm_type::MMStruct::new(result), stack: null_mut(), iroot: Path::empty(), ipwd: Path::empty(), files: Vec::new(), magic: 0x55aa55aa55aa55aa };
+            (*result) = ProcessControlBlock { priority: 0, jiffies: 0, name: [0; PROCESS_NAME_LEN], uid: 0, gid: 0, pid: 0, ppid: 0, pgid: 0, pml4: null_mut(), wait_pid: 0, blocked: 0, mm: mm_type::MMStruct::new(result), stack: null_mut(), iroot: Path::empty(), ipwd: Path::empty(), files: Vec::new(), fs_base: 0, gs_base: 0, fpu_state: null_mut(), personality: 0, flags: 0, sleep_until: 0, kill_code: 0, magic: 0x55aa55aa55aa55aa };
             result
         }
     }
@@ -345,6 +528,11 @@ impl ProcessControlBlock {
         }
     }
 
+    pub fn randomize_va_space(&self) -> bool
+    {
+        self.personality & ADDR_NO_RANDOMIZE == 0
+    }
+
     pub fn get_intr_frame(&self) -> *mut PtRegs
     {
         unsafe {
@@ -357,8 +545,10 @@ impl ProcessControlBlock {
         unsafe
         {
             let pcb_addr = ProcessControlBlock::create_task_control_block();
-            let stack_vma = (*pcb_addr).mm.create_new_mem_area(USER_STACK_TOP.offset(-(MAX_PROCSEE_STACK_SIZE as isize)) as u64, memory::USER_STACK_TOP as u64);
+            let stack_top = (*pcb_addr).mm.stack_top;
+            let stack_vma = (*pcb_addr).mm.create_new_mem_area(stack_top - MAX_PROCSEE_STACK_SIZE as u64, stack_top);
             (*stack_vma).set_prot(MmapType::PROT_READ | MmapType::PROT_WRITE);
+            (*stack_vma).set_flags(MmapType::VM_GROWSDOWN);
             let process_frame = (((*pcb_addr).get_process_kernel_stack() as *mut c_void) as *mut TaskFrame).offset(-1);
             (*pcb_addr).stack = (((*pcb_addr).get_process_kernel_stack() as *mut c_void) as *mut c_void).offset(-8 * 18);
             (*process_frame).rbx = 1;
@@ -394,6 +584,7 @@ pub fn process_init()
     {
         printk!("initing task\n");
         IDLE = PCB::create_new_process(idle::idle as u64, 255);
+        (*IDLE).flags |= PF_KTHREAD;
         compiler_builtins::mem::memcpy((*IDLE).name.as_ptr() as *mut u8, "idle".as_ptr(), 4);
         
         (*IDLE).insert_to_task_table();
@@ -421,6 +612,8 @@ pub unsafe fn task_switch(pcb : *mut ProcessControlBlock)
     set_running_process(pcb);
     if likely(!old_pcb.is_null())
     {
+        (*old_pcb).fs_base = percpu::read_user_fs_base();
+        (*old_pcb).gs_base = percpu::read_user_gs_base();
         asm!(
             "mov [rsp + -5 * 8], rbx",
             "mov [rsp + -4 * 8], r12",
@@ -435,6 +628,10 @@ pub unsafe fn task_switch(pcb : *mut ProcessControlBlock)
     {
         set_cr3_reg((*pcb).pml4 as *mut c_void);
     }
+    percpu::write_user_fs_base((*pcb).fs_base);
+    percpu::write_user_gs_base((*pcb).gs_base);
+    percpu::set_kernel_stack(dst_stack);
+    fpu::fpu_switch_to(pcb);
     set_tss64(addr_of_mut!(KERNEL_TSS), dst_stack, dst_stack, dst_stack, dst_stack, dst_stack, dst_stack, dst_stack, dst_stack, dst_stack, dst_stack);
     asm!(
         "mov rsp, rax",
@@ -1,6 +1,6 @@
 use core::{ffi::{c_void, CStr, c_char}, alloc::Layout};
 
-use alloc::{vec::Vec, alloc::alloc, string::String};
+use alloc::{boxed::Box, vec::Vec, alloc::alloc, string::String};
 use proc_macro::__init;
 
 use crate::{fs::{ext4::Idx, file::FileMode}, mm::memory::PAGE_SIZE};
@@ -8,9 +8,9 @@ use crate::{fs::{ext4::Idx, file::FileMode}, mm::memory::PAGE_SIZE};
 use super::device::{DEV_CMD_SECTOR_COUNT, DEV_CMD_SECTOR_START, DevT, device_install, regist_device, DeviceIoCtlFn, DeviceWriteFn, DeviceReadFn};
 
 const SECTOR_SIZE : usize = 0x1000;
-static mut RAMDISKS : Vec<RamDisk> = Vec::new();
+static mut RAMDISKS : Vec<*mut RamDisk> = Vec::new();
 
-struct RamDisk
+pub struct RamDisk
 {
     start : *mut c_void,
     length : usize
@@ -38,7 +38,7 @@ impl RamDisk {
         {
             let start_addr = (*disk).start.offset(start_block as isize * SECTOR_SIZE as isize);
             let len = num_blocks as usize * SECTOR_SIZE;
-            assert!(start_addr.offset(len as isize) > (*disk).start.offset((*disk).length as isize));
+            assert!(start_addr.offset(len as isize) <= (*disk).start.offset((*disk).length as isize));
             compiler_builtins::mem::memcpy(buf as *mut u8, start_addr as *const u8, len);
         }
     }
@@ -49,29 +49,39 @@ impl RamDisk {
         {
             let start_addr = (*disk).start.offset(idx as isize * SECTOR_SIZE as isize);
             let len = count as usize * SECTOR_SIZE;
-            assert!(start_addr.offset(len as isize) > (*disk).start.offset((*disk).length as isize));
+            assert!(start_addr.offset(len as isize) <= (*disk).start.offset((*disk).length as isize));
             compiler_builtins::mem::memcpy(start_addr as *mut u8, buf as *const u8, len);
         }
     }
 
     pub fn create(size : usize) -> DevT
+    {
+        if size & 0xfff != 0
+        {
+            return 0;
+        }
+        Self::install(Self { start: unsafe { alloc(Layout::from_size_align(size, PAGE_SIZE).unwrap()) } as *mut c_void, length: size })
+    }
+
+    // a disk over memory that is already there, like an initrd the boot loader left behind
+    pub fn create_from(start : *mut c_void, length : usize) -> DevT
+    {
+        if start as usize & 0xfff != 0 || length < SECTOR_SIZE
+        {
+            return 0;
+        }
+        Self::install(Self { start, length: length & !(SECTOR_SIZE - 1) })
+    }
+
+    fn install(disk : RamDisk) -> DevT
     {
         unsafe
         {
-            if size & 0xfff != 0
-            {
-                return 0;
-            }
-            RAMDISKS.push(Self { start: alloc(Layout::from_size_align(size, PAGE_SIZE).unwrap()) as *mut c_void, length: size  });
-            match RAMDISKS.last() {
-                Some(disk) => 
-                {
-                    let mut name = String::new();
-                    let _ = core::fmt::write(&mut name, format_args!("ram{}\0", RAMDISKS.len() - 1));
-                    device_install(1, disk as *const RamDisk as *mut c_void, CStr::from_ptr(name.as_ptr() as *const c_char), 0, 0, FileMode::IFBLK)
-                },
-                None => return 0
-            }
+            let disk = Box::into_raw(Box::new(disk));
+            RAMDISKS.push(disk);
+            let mut name = String::new();
+            let _ = core::fmt::write(&mut name, format_args!("ram{}\0", RAMDISKS.len() - 1));
+            device_install(1, disk as *mut c_void, CStr::from_ptr(name.as_ptr() as *const c_char), 0, 0, FileMode::IFBLK)
         }
     }
 }
@@ -0,0 +1,67 @@
+use proc_macro::__init;
+
+use crate::{logk, mm::memory::PAGE_SIZE};
+
+use super::cpu::{self, CpuVersion, GET_CPU_VERSION};
+
+const RDRAND_RETRY : usize = 10;
+
+static mut HAS_RDRAND : bool = false;
+static mut JITTER_STATE : u64 = 0;
+
+#[__init]
+pub fn random_init()
+{
+    unsafe
+    {
+        HAS_RDRAND = cpu::__cpuid(GET_CPU_VERSION).ecx & CpuVersion::ECX_RDRAND.bits() != 0;
+        JITTER_STATE = cpu::rdtsc();
+        logk!("random source: {}\n", if HAS_RDRAND { "rdrand" } else { "tsc jitter" });
+    }
+}
+
+// fold the low bits of several tsc reads into the pool, the low bits wobble
+// with cache and interrupt timing even on an idle machine
+fn tsc_jitter() -> u64
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < 8 {
+            JITTER_STATE ^= cpu::rdtsc();
+            JITTER_STATE = JITTER_STATE.rotate_left(17).wrapping_mul(0x9e3779b97f4a7c15);
+            var += 1;
+        }
+        JITTER_STATE ^ (JITTER_STATE >> 31)
+    }
+}
+
+pub fn get_random_u64() -> u64
+{
+    unsafe
+    {
+        if HAS_RDRAND
+        {
+            let mut var = 0;
+            while var < RDRAND_RETRY {
+                if let Some(value) = cpu::rdrand64()
+                {
+                    return value;
+                }
+                var += 1;
+            }
+        }
+        tsc_jitter()
+    }
+}
+
+// a page aligned address in [start, start + range)
+pub fn randomize_page(start : u64, range : u64) -> u64
+{
+    let pages = range / PAGE_SIZE as u64;
+    if pages == 0
+    {
+        return start;
+    }
+    start + (get_random_u64() % pages) * PAGE_SIZE as u64
+}
@@ -1,37 +1,48 @@
 use proc_macro::__init;
 
+use super::cmdline::setup_boot_command_line;
 use super::cpu::get_cpu_number;
+use super::kaslr::choose_random_location;
+use super::multiboot::multiboot_save_info;
 use super::elf64::{Elf64Shdr, Elf64Phdr, Elf64Ehdr};
 use super::io::{self, IdeCtrlT, IDE_IOBASE_PRIMARY, IDE_LBA_MASTER, IDE_FEATURE, IDE_SECTOR, IDE_LBA_LOW, IDE_LBA_MID, IDE_LBA_HIGH, IDE_HDDEVSEL, outb, IDE_SR_BSY, IDE_SR_ERR, IDE_ALT_STATUS, IDE_DATA, inw, SECTOR_SIZE, inb, IDE_SR_DRDY, IDE_CMD_READ, IDE_COMMAND, IDE_SR_DRQ};
 use super::sched;
 use core::arch::asm;
-use core::ptr::null_mut;
+use core::ptr::{addr_of, null_mut};
 use super::string::memset;
 use core::ffi::c_char;
 use core::mem::size_of;
+use crate::mm::memory::VIRTADDR_START;
 const SHF_ALLOC : u64 = 0b10;
 
 pub static mut KERNEL_SIZE : usize = 0;
 
+extern "C"
+{
+    static __rela_dyn_start : u8;
+    static __rela_dyn_end : u8;
+    static ekernel : u8;
+}
+
 
 #[__init]
 unsafe fn system_relocate64(elf64_shdr : *mut Elf64Shdr, base_addr : u64)
 {
-    let mut reloc_info;
-    unsafe
+    relocate_rela((*elf64_shdr).sh_addr as *mut Elf64Rela, (*elf64_shdr).sh_size / size_of::<Elf64Rela>() as u64, base_addr);
+}
+
+#[__init]
+unsafe fn relocate_rela(mut reloc_info : *mut Elf64Rela, rela_num : u64, base_addr : u64)
+{
+    let mut var = 0;
+    while var < rela_num
     {
-        reloc_info = ((*elf64_shdr).sh_addr) as *mut Elf64Rela;
-        let rela_num = (*elf64_shdr).sh_size / size_of::<Elf64Rela>() as u64;
-        let mut var = 0;
-        while var < rela_num
-        {
-            match (*reloc_info).r_type & 0xffffffff {
-                R_X86_64_RELATIVE => RX86_64Relative_Relocate(reloc_info, base_addr),
-                _ => panic!("unknown relocation type")
-            }
-            reloc_info = reloc_info.offset(1);
-            var += 1;
+        match (*reloc_info).r_type & 0xffffffff {
+            R_X86_64_RELATIVE => RX86_64Relative_Relocate(reloc_info, base_addr),
+            _ => panic!("unknown relocation type")
         }
+        reloc_info = reloc_info.offset(1);
+        var += 1;
     }
 }
 
@@ -39,7 +50,7 @@ unsafe fn system_relocate64(elf64_shdr : *mut Elf64Shdr, base_addr : u64)
 #[inline(always)]
 unsafe fn RX86_64Relative_Relocate(elf64_rela : *mut Elf64Rela, base_addr : u64)
 {
-    *((*elf64_rela).r_offset as *mut u64) += (*elf64_rela).r_addend | 0xffff8 << 44;
+    *((*elf64_rela).r_offset as *mut u64) = ((*elf64_rela).r_addend | 0xffff8 << 44).wrapping_add(base_addr);
 }
 
 
@@ -244,14 +255,16 @@ pub unsafe fn process_relocation(elf64_ehdr : *mut Elf64Ehdr, base_addr : u64)
     }
 }
 
+// returns how far the image was moved, _start jumps over by that much before kernel_init
 #[__init]
 #[no_mangle]
-pub unsafe fn kernel_relocation(elf64_ehdr : *mut Elf64Ehdr)
+pub unsafe fn kernel_relocation(elf64_ehdr : *mut Elf64Ehdr) -> u64
 {
     let mut shdr;
     let mut phdr;
     let mut var = 1;
     let mut kernel_size = 0;
+    let slide;
     unsafe {
         phdr = ((elf64_ehdr as u64 + (*elf64_ehdr).e_phoff) as *mut Elf64Phdr).offset(1);
         
@@ -264,6 +277,9 @@ pub unsafe fn kernel_relocation(elf64_ehdr : *mut Elf64Ehdr)
             phdr = phdr.offset(1);
             var += 1;
         }
+        // the bss was just cleared, the command line can be kept now
+        setup_boot_command_line();
+        slide = choose_random_location(kernel_size as u64);
         let start_pos = (*elf64_ehdr).e_shoff - (*elf64_ehdr).e_shoff % io::SECTOR_SIZE;
         ide_early_pio_sync_read(((start_pos / io::SECTOR_SIZE) + 10) as u32, ((*elf64_ehdr).e_shoff + ((*elf64_ehdr).e_shnum  as u64) * (size_of::<Elf64Shdr>() as u64) - start_pos).div_ceil(io::SECTOR_SIZE) as u8 , (elf64_ehdr as *mut u8).offset(4096));
         shdr = ((elf64_ehdr as u64 + (*elf64_ehdr).e_shoff % 512) + 4096) as *mut Elf64Shdr;
@@ -272,11 +288,28 @@ pub unsafe fn kernel_relocation(elf64_ehdr : *mut Elf64Ehdr)
         {
             if (*shdr).sh_type == 4
             {
-                system_relocate64(shdr, 0);
+                system_relocate64(shdr, slide);
             }
             shdr = shdr.offset(1);
             var += 1;
         }
         KERNEL_SIZE = kernel_size;
     }
-}
\ No newline at end of file
+    slide
+}
+
+// grub or qemu -kernel already put the whole flat image at 1M, only the boot information and the relocations are left
+#[__init]
+#[no_mangle]
+pub unsafe fn multiboot_relocation(magic : u32, info : u64) -> u64
+{
+    let image_end = addr_of!(ekernel) as usize;
+    // nothing the loader left us may be overwritten before it is copied, the command line comes along
+    let reserved_end = multiboot_save_info(magic, info);
+    let slide = choose_random_location(image_end as u64);
+    let rela_start = addr_of!(__rela_dyn_start) as u64;
+    relocate_rela(rela_start as *mut Elf64Rela, (addr_of!(__rela_dyn_end) as u64 - rela_start) / size_of::<Elf64Rela>() as u64, slide);
+    // the modules stay where they are, the page allocator starts behind them
+    KERNEL_SIZE = image_end.max((reserved_end as usize + VIRTADDR_START + 0xfff) & !0xfff);
+    slide
+}
@@ -44,6 +44,13 @@ impl UnreenterabkeSpinLock
         }
     }
 
+    // acquire without spinning, false when the lock is taken
+    pub fn try_acquire(&mut self, cnt : i64) -> bool
+    {
+        let expect = self.counting.load(atomic::Ordering::Acquire);
+        expect - cnt >= 0 && self.counting.compare_exchange(expect, expect - cnt, atomic::Ordering::Release, atomic::Ordering::Relaxed).is_ok()
+    }
+
     pub fn release(&mut self, cnt : i64)
     {
         if cnt <= 0 
@@ -82,6 +89,12 @@ impl RWLock {
         Self { readers: BTreeMap::new(), change_mutex: SpinLock::new(1), writer_mutex: SpinLock::new(1) }
     }
 
+    // someone is reading or writing under this lock
+    pub fn is_locked(&self) -> bool
+    {
+        self.change_mutex.counting.load(atomic::Ordering::Acquire) < 1 || self.writer_mutex.counting.load(atomic::Ordering::Acquire) < 1 || !self.readers.is_empty()
+    }
+
     pub fn rdunlock(&mut self)
     {
         self.change_mutex.acquire(1);
@@ -0,0 +1,53 @@
+use core::{arch::asm, fmt::Write};
+
+use alloc::string::String;
+
+use super::process::THREAD_SIZE;
+
+// the kernel is built with frame pointers, [rbp] is the caller's rbp and [rbp + 8] the return address
+// code without them leaves rbp alone, so the walk only skips its frames
+
+// return addresses of the callers, innermost first, `skip` of them left out
+// stops at the bottom of the stack or at a frame that does not lie above the last one
+#[inline(never)]
+pub fn save_stack_trace(trace : &mut [u64], skip : usize) -> usize
+{
+    unsafe
+    {
+        let mut rbp : u64;
+        asm!("mov {}, rbp", out(reg) rbp);
+        let mut skip = skip;
+        let mut nr = 0;
+        while nr < trace.len() && rbp != 0 && rbp & 7 == 0 {
+            let next = *(rbp as *const u64);
+            let ret = *((rbp + 8) as *const u64);
+            if ret == 0
+            {
+                break;
+            }
+            if skip > 0
+            {
+                skip -= 1;
+            }
+            else {
+                trace[nr] = ret;
+                nr += 1;
+            }
+            // a user rbp or garbage ends the walk before it is dereferenced
+            if next <= rbp || next - rbp > THREAD_SIZE as u64
+            {
+                break;
+            }
+            rbp = next;
+        }
+        nr
+    }
+}
+
+// one "[<address>]" per entry up to the first empty one, each line starting with `indent`
+pub fn print_stack_trace(out : &mut String, trace : &[u64], indent : &str)
+{
+    for addr in trace.iter().take_while(|addr| **addr != 0) {
+        let _ = writeln!(out, "{}[<{:#x}>]", indent, addr);
+    }
+}
@@ -1,7 +1,7 @@
 use core::{ptr::null_mut, ffi::{c_void, c_char}};
 use proc_macro::__init;
 
-use crate::{bochs_break, fs::file::sys_write, kernel::{fork::sys_fork, process::{self, sys_yield, sys_exit}, sched::get_current_running_process, syscall_defs::{__NR_FORK, __NR_SCHED_YIELD, __NR_WRITE, __NR_SYS_EXECVE, __NR_EXIT}, execve::sys_execve}, logk};
+use crate::{bochs_break, fs::file::{sys_close, sys_ftruncate, sys_open, sys_pread64, sys_read, sys_write}, mm::{madvise::sys_madvise, mincore::sys_mincore, mlock::{sys_mlock, sys_mlockall, sys_munlock, sys_munlockall}, mmap::{sys_brk, sys_mmap, sys_mprotect, sys_munmap}, mremap::sys_mremap, shmem::{sys_shm_open, sys_shm_unlink}, swapfile::{sys_swapoff, sys_swapon}}, kernel::{fork::sys_fork, process::{self, sys_yield, sys_exit, sys_arch_prctl, sys_personality}, sched::get_current_running_process, syscall_defs::{__NR_CLOSE, __NR_MMAP, __NR_OPEN, __NR_PREAD64, __NR_READ, __NR_FORK, __NR_SCHED_YIELD, __NR_WRITE, __NR_SYS_EXECVE, __NR_EXIT, __NR_ARCH_PRCTL, __NR_BRK, __NR_PERSONALITY, __NR_FSYNC, __NR_SYNC, __NR_SWAPON, __NR_SWAPOFF, __NR_MPROTECT, __NR_MUNMAP, __NR_MADVISE, __NR_FTRUNCATE, __NR_SHM_OPEN, __NR_SHM_UNLINK, __NR_MINCORE, __NR_MLOCK, __NR_MUNLOCK, __NR_MLOCKALL, __NR_MUNLOCKALL, __NR_MREMAP}, execve::sys_execve, buffer::{sys_fsync, sys_sync}}, logk};
 
 use super::{cpu, process::PtRegs, interrupt::HANDLER_TABLE};
 use core::arch::asm;
@@ -13,7 +13,7 @@ extern "C"
 } 
 
 #[no_mangle]
-pub static mut SYSTEM_CALL_TABLE : [SyscallrFn; 256] = [unsafe { core::mem::transmute::<*mut(), SyscallrFn>(default_syscall as *mut()) }; 256];
+pub static mut SYSTEM_CALL_TABLE : [SyscallrFn; 512] = [unsafe { core::mem::transmute::<*mut(), SyscallrFn>(default_syscall as *mut()) }; 512];
 
 pub unsafe fn default_syscall()
 {
@@ -26,36 +26,27 @@ pub unsafe fn set_syscall_return_value(ret : u64)
     (*(*pcb).get_intr_frame()).rax = ret;
 }
 
+// entered from _syscall_start on the kernel stack, pt_regs is the frame at the top of it
 #[no_mangle]
-pub unsafe fn syscall_function(pt_regs : PtRegs)
+pub unsafe fn syscall_function(pt_regs : *mut PtRegs)
 {
-    let pcb = get_current_running_process();
     let result;
-    asm!(
-        "mov [{pcb_stack}], rsp",
-        "mov rsp, {kernel_stack}",
-        kernel_stack = in(reg) (*pcb).get_process_kernel_stack(),
-        pcb_stack = in(reg) &(*pcb).stack as *const *mut c_void
-    );
     bochs_break!();
     asm!(
-        "mov rcx, [SYSTEM_CALL_TABLE@GOTPCREL + rip]",
-        "call [rcx + 8 * rax]",
-        in("rdi") pt_regs.rdi,
-        in("rsi") pt_regs.rsi,
-        in("rdx") pt_regs.rdx,
-        in("r10") pt_regs.r10,
-        in("r8") pt_regs.r8,
-        in("r9") pt_regs.r9,
-        in("rax") pt_regs.rax,
-        lateout("rax") result
+        "mov r11, [SYSTEM_CALL_TABLE@GOTPCREL + rip]",
+        "call [r11 + 8 * rax]",
+        in("rdi") (*pt_regs).rdi,
+        in("rsi") (*pt_regs).rsi,
+        in("rdx") (*pt_regs).rdx,
+        // the 4th argument arrives in r10, handlers expect it in rcx
+        in("rcx") (*pt_regs).r10,
+        in("r8") (*pt_regs).r8,
+        in("r9") (*pt_regs).r9,
+        in("rax") (*pt_regs).rax,
+        lateout("rax") result,
+        clobber_abi("C")
     );
     set_syscall_return_value(result);
-    let tmp_pcb = get_current_running_process();
-    asm!(
-        "mov rsp, {restore_stack}",
-        restore_stack = in(reg) (*tmp_pcb).stack
-    );
 }
 
 #[__init]
@@ -71,11 +62,35 @@ pub fn syscall_init()
     cpu::wrmsr(0xc0000084, 0x1 << 9);
     // regist syscall to syscall table
     unsafe {
+        SYSTEM_CALL_TABLE[__NR_READ] = core::mem::transmute::<*mut(), SyscallrFn>(sys_read as *mut());
         SYSTEM_CALL_TABLE[__NR_WRITE] = core::mem::transmute::<*mut(), SyscallrFn>(sys_write as *mut());
+        SYSTEM_CALL_TABLE[__NR_OPEN] = core::mem::transmute::<*mut(), SyscallrFn>(sys_open as *mut());
+        SYSTEM_CALL_TABLE[__NR_CLOSE] = core::mem::transmute::<*mut(), SyscallrFn>(sys_close as *mut());
+        SYSTEM_CALL_TABLE[__NR_MMAP] = core::mem::transmute::<*mut(), SyscallrFn>(sys_mmap as *mut());
+        SYSTEM_CALL_TABLE[__NR_MPROTECT] = core::mem::transmute::<*mut(), SyscallrFn>(sys_mprotect as *mut());
+        SYSTEM_CALL_TABLE[__NR_MUNMAP] = core::mem::transmute::<*mut(), SyscallrFn>(sys_munmap as *mut());
+        SYSTEM_CALL_TABLE[__NR_BRK] = core::mem::transmute::<*mut(), SyscallrFn>(sys_brk as *mut());
+        SYSTEM_CALL_TABLE[__NR_MREMAP] = core::mem::transmute::<*mut(), SyscallrFn>(sys_mremap as *mut());
+        SYSTEM_CALL_TABLE[__NR_MADVISE] = core::mem::transmute::<*mut(), SyscallrFn>(sys_madvise as *mut());
+        SYSTEM_CALL_TABLE[__NR_MINCORE] = core::mem::transmute::<*mut(), SyscallrFn>(sys_mincore as *mut());
+        SYSTEM_CALL_TABLE[__NR_MLOCK] = core::mem::transmute::<*mut(), SyscallrFn>(sys_mlock as *mut());
+        SYSTEM_CALL_TABLE[__NR_MUNLOCK] = core::mem::transmute::<*mut(), SyscallrFn>(sys_munlock as *mut());
+        SYSTEM_CALL_TABLE[__NR_MLOCKALL] = core::mem::transmute::<*mut(), SyscallrFn>(sys_mlockall as *mut());
+        SYSTEM_CALL_TABLE[__NR_MUNLOCKALL] = core::mem::transmute::<*mut(), SyscallrFn>(sys_munlockall as *mut());
+        SYSTEM_CALL_TABLE[__NR_PREAD64] = core::mem::transmute::<*mut(), SyscallrFn>(sys_pread64 as *mut());
         SYSTEM_CALL_TABLE[__NR_SCHED_YIELD] = core::mem::transmute::<*mut(), SyscallrFn>(sys_yield as *mut());
         SYSTEM_CALL_TABLE[__NR_FORK] = core::mem::transmute::<*mut(), SyscallrFn>(sys_fork as *mut());
         SYSTEM_CALL_TABLE[__NR_SYS_EXECVE] = core::mem::transmute::<*mut(), SyscallrFn>(sys_execve as *mut());
         SYSTEM_CALL_TABLE[__NR_EXIT] = core::mem::transmute::<*mut(), SyscallrFn>(sys_exit as *mut());
+        SYSTEM_CALL_TABLE[__NR_FSYNC] = core::mem::transmute::<*mut(), SyscallrFn>(sys_fsync as *mut());
+        SYSTEM_CALL_TABLE[__NR_FTRUNCATE] = core::mem::transmute::<*mut(), SyscallrFn>(sys_ftruncate as *mut());
+        SYSTEM_CALL_TABLE[__NR_PERSONALITY] = core::mem::transmute::<*mut(), SyscallrFn>(sys_personality as *mut());
+        SYSTEM_CALL_TABLE[__NR_ARCH_PRCTL] = core::mem::transmute::<*mut(), SyscallrFn>(sys_arch_prctl as *mut());
+        SYSTEM_CALL_TABLE[__NR_SYNC] = core::mem::transmute::<*mut(), SyscallrFn>(sys_sync as *mut());
+        SYSTEM_CALL_TABLE[__NR_SWAPON] = core::mem::transmute::<*mut(), SyscallrFn>(sys_swapon as *mut());
+        SYSTEM_CALL_TABLE[__NR_SWAPOFF] = core::mem::transmute::<*mut(), SyscallrFn>(sys_swapoff as *mut());
+        SYSTEM_CALL_TABLE[__NR_SHM_OPEN] = core::mem::transmute::<*mut(), SyscallrFn>(sys_shm_open as *mut());
+        SYSTEM_CALL_TABLE[__NR_SHM_UNLINK] = core::mem::transmute::<*mut(), SyscallrFn>(sys_shm_unlink as *mut());
  
     }
 }
@@ -0,0 +1,268 @@
+use core::{alloc::Layout, ffi::{c_char, c_void, CStr}, ptr::null_mut};
+
+use alloc::{alloc::{alloc, dealloc}, boxed::Box, string::String, vec::Vec};
+use proc_macro::__init;
+
+use crate::{crypto::lz4::{lz4_compress, lz4_compress_bound, lz4_decompress}, fs::{ext4::Idx, file::FileMode}, logk, mm::memory::PAGE_SIZE};
+
+use super::{device::{device_install, regist_device, DevT, DeviceIoCtlFn, DeviceReadFn, DeviceWriteFn, DEV_CMD_SECTOR_COUNT, DEV_CMD_SECTOR_START}, errno_base::{EINVAL, EIO, ENOMEM}, io::SECTOR_SIZE};
+
+const ZRAM_MAJOR : DevT = 251;
+const SECTORS_PER_PAGE : usize = PAGE_SIZE / SECTOR_SIZE as usize;
+// anything bigger leaves the kmalloc caches, the page is kept as it is then
+const HUGE_CLASS_SIZE : usize = 2048;
+
+// copies a ZramStats into args
+pub const DEV_CMD_ZRAM_STATS : i64 = 3;
+
+static mut ZRAMS : Vec<*mut Zram> = Vec::new();
+// every request runs to completion before the next one, so one set of buffers does
+static mut ZRAM_PAGE_BUFFER : [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+static mut ZRAM_COMPRESS_BUFFER : [u8; lz4_compress_bound(PAGE_SIZE)] = [0; lz4_compress_bound(PAGE_SIZE)];
+
+const ZRAM_ZERO : u32 = 1 << 0; // filled with zeros, nothing stored
+const ZRAM_HUGE : u32 = 1 << 1; // did not compress, stored as is
+
+// one page of the disk, a slot never written reads as zeros
+#[derive(Clone, Copy)]
+struct ZramSlot
+{
+    handle : *mut u8,
+    size : usize,
+    flags : u32
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ZramStats
+{
+    pub disksize : u64,
+    pub orig_data_size : u64, // bytes of the pages stored, zero pages included
+    pub compr_data_size : u64, // bytes they take compressed
+    pub zero_pages : u64,
+    pub huge_pages : u64,
+    pub num_reads : u64,
+    pub num_writes : u64,
+    pub failed_reads : u64,
+    pub failed_writes : u64
+}
+
+pub struct Zram
+{
+    table : Vec<ZramSlot>,
+    stats : ZramStats
+}
+
+impl Zram {
+    fn ioctl(zram : *mut Zram, cmd : i64, args : *mut c_void, _flags : u32) -> i64
+    {
+        unsafe
+        {
+            match cmd {
+                DEV_CMD_SECTOR_START => 0,
+                DEV_CMD_SECTOR_COUNT => ((*zram).stats.disksize / SECTOR_SIZE) as i64,
+                DEV_CMD_ZRAM_STATS =>
+                {
+                    if args.is_null()
+                    {
+                        return -EINVAL;
+                    }
+                    *(args as *mut ZramStats) = (*zram).stats;
+                    0
+                },
+                _ => -EINVAL
+            }
+        }
+    }
+
+    fn slot_free(&mut self, index : usize)
+    {
+        let slot = self.table[index];
+        if slot.flags & ZRAM_ZERO != 0
+        {
+            self.stats.zero_pages -= 1;
+        }
+        else if !slot.handle.is_null()
+        {
+            if slot.flags & ZRAM_HUGE != 0
+            {
+                self.stats.huge_pages -= 1;
+            }
+            self.stats.compr_data_size -= slot.size as u64;
+            unsafe { dealloc(slot.handle, Layout::from_size_align(slot.size, 8).unwrap()) };
+        }
+        else {
+            return;
+        }
+        self.stats.orig_data_size -= PAGE_SIZE as u64;
+        self.table[index] = ZramSlot { handle: null_mut(), size: 0, flags: 0 };
+    }
+
+    unsafe fn read_page(&mut self, index : usize, dst : *mut u8) -> i64
+    {
+        let slot = self.table[index];
+        if slot.handle.is_null()
+        {
+            compiler_builtins::mem::memset(dst, 0, PAGE_SIZE);
+        }
+        else if slot.flags & ZRAM_HUGE != 0
+        {
+            compiler_builtins::mem::memcpy(dst, slot.handle, PAGE_SIZE);
+        }
+        else if lz4_decompress(slot.handle, slot.size, dst, PAGE_SIZE) != Some(PAGE_SIZE)
+        {
+            logk!("zram: page {} is corrupted\n", index);
+            return -EIO;
+        }
+        0
+    }
+
+    unsafe fn write_page(&mut self, index : usize, src : *const u8) -> i64
+    {
+        self.slot_free(index);
+        let words = src as *const u64;
+        let mut var = 0;
+        while var < PAGE_SIZE / 8 && *words.add(var) == 0 {
+            var += 1;
+        }
+        if var == PAGE_SIZE / 8
+        {
+            self.table[index].flags = ZRAM_ZERO;
+            self.stats.zero_pages += 1;
+            self.stats.orig_data_size += PAGE_SIZE as u64;
+            return 0;
+        }
+        let compress_buffer = ZRAM_COMPRESS_BUFFER.as_mut_ptr();
+        let (data, size, flags) = match lz4_compress(src, PAGE_SIZE, compress_buffer, HUGE_CLASS_SIZE) {
+            Some(size) => (compress_buffer as *const u8, size, 0),
+            None => (src, PAGE_SIZE, ZRAM_HUGE)
+        };
+        let handle = alloc(Layout::from_size_align(size, 8).unwrap());
+        if handle.is_null()
+        {
+            return -ENOMEM;
+        }
+        compiler_builtins::mem::memcpy(handle, data, size);
+        self.table[index] = ZramSlot { handle, size, flags };
+        if flags & ZRAM_HUGE != 0
+        {
+            self.stats.huge_pages += 1;
+        }
+        self.stats.compr_data_size += size as u64;
+        self.stats.orig_data_size += PAGE_SIZE as u64;
+        0
+    }
+
+    // a request need not cover whole pages, the partial ones go through the page buffer
+    unsafe fn rw_sectors(&mut self, idx : Idx, count : usize, buf : *mut u8, write : bool) -> i64
+    {
+        if (idx as usize + count) * SECTOR_SIZE as usize > self.stats.disksize as usize
+        {
+            return -EIO;
+        }
+        let page_buffer = ZRAM_PAGE_BUFFER.as_mut_ptr();
+        let mut sector = idx as usize;
+        let mut done = 0;
+        while done < count * SECTOR_SIZE as usize {
+            let index = sector / SECTORS_PER_PAGE;
+            let offset = (sector % SECTORS_PER_PAGE) * SECTOR_SIZE as usize;
+            let len = (PAGE_SIZE - offset).min(count * SECTOR_SIZE as usize - done);
+            let mut ret;
+            if len == PAGE_SIZE
+            {
+                ret = if write { self.write_page(index, buf.add(done)) } else { self.read_page(index, buf.add(done)) };
+            }
+            else {
+                ret = self.read_page(index, page_buffer);
+                if ret == 0 && write
+                {
+                    compiler_builtins::mem::memcpy(page_buffer.add(offset), buf.add(done), len);
+                    ret = self.write_page(index, page_buffer);
+                }
+                else if ret == 0
+                {
+                    compiler_builtins::mem::memcpy(buf.add(done), page_buffer.add(offset), len);
+                }
+            }
+            if ret < 0
+            {
+                return ret;
+            }
+            sector += len / SECTOR_SIZE as usize;
+            done += len;
+        }
+        0
+    }
+
+    fn read(zram : *mut Zram, idx : Idx, count : usize, buf : *mut c_void, _flags : u32) -> i64
+    {
+        unsafe
+        {
+            let ret = (*zram).rw_sectors(idx, count, buf as *mut u8, false);
+            (*zram).stats.num_reads += 1;
+            if ret < 0
+            {
+                (*zram).stats.failed_reads += 1;
+            }
+            ret
+        }
+    }
+
+    fn write(zram : *mut Zram, idx : Idx, count : usize, buf : *mut c_void, _flags : u32) -> i64
+    {
+        unsafe
+        {
+            let ret = (*zram).rw_sectors(idx, count, buf as *mut u8, true);
+            (*zram).stats.num_writes += 1;
+            if ret < 0
+            {
+                (*zram).stats.failed_writes += 1;
+            }
+            ret
+        }
+    }
+
+    // a new /dev/zram<n> of `size` bytes, nothing is allocated until it gets written
+    pub fn create(size : usize) -> DevT
+    {
+        unsafe
+        {
+            if size == 0 || size & (PAGE_SIZE - 1) != 0
+            {
+                return 0;
+            }
+            let zram = Box::into_raw(Box::new(Zram {
+                table: alloc::vec![ZramSlot { handle: null_mut(), size: 0, flags: 0 }; size / PAGE_SIZE],
+                stats: ZramStats { disksize: size as u64, ..Default::default() }
+            }));
+            ZRAMS.push(zram);
+            let mut name = String::new();
+            let _ = core::fmt::write(&mut name, format_args!("zram{}\0", ZRAMS.len() - 1));
+            device_install(ZRAM_MAJOR, zram as *mut c_void, CStr::from_ptr(name.as_ptr() as *const c_char), 0, 0, FileMode::IFBLK)
+        }
+    }
+}
+
+#[__init]
+pub fn zram_init()
+{
+    unsafe
+    {
+        regist_device(ZRAM_MAJOR, Some(core::mem::transmute::<*mut(), DeviceIoCtlFn>(Zram::ioctl as *mut())),
+            Some(core::mem::transmute::<*mut(), DeviceReadFn>(Zram::read as *mut())),
+            Some(core::mem::transmute::<*mut(), DeviceWriteFn>(Zram::write as *mut())), None)
+    }
+}
+
+pub fn show_zram_state()
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < ZRAMS.len() {
+            let stats = &(*ZRAMS[var]).stats;
+            logk!("zram{}: orig {} bytes, compressed {} bytes, zero pages {}, huge pages {}\n", var, stats.orig_data_size, stats.compr_data_size, stats.zero_pages, stats.huge_pages);
+            var += 1;
+        }
+    }
+}
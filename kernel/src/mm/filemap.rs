@@ -0,0 +1,80 @@
+use core::{cmp::min, ffi::c_void};
+
+use crate::{fs::{ext4::Idx, file::EOF, fs::AddressSpace}, kernel::{errno_base::{EIO, ENOMEM}, Err}};
+
+use super::{memory::{PAGE_SHIFT, PAGE_SIZE}, page::{Page, Pageflags, GFP}, page_alloc::{alloc_pages, page_address, put_page}};
+
+// page `index` of the mapping, read in through a_ops on a miss
+// the caller owns one reference of the returned page
+pub unsafe fn read_cache_page(mapping : *mut AddressSpace, index : Idx) -> Result<*mut Page, Err>
+{
+    let page = (*mapping).find_get_page(index);
+    if !page.is_null()
+    {
+        return Ok(page);
+    }
+    let read_folio = match (*(*mapping).a_ops).read_folio {
+        Some(read_folio) => read_folio,
+        None => return Err(-EIO)
+    };
+    let page = alloc_pages(GFP::USER, 0);
+    if page.is_null()
+    {
+        return Err(-ENOMEM);
+    }
+    (*page).mapping = mapping.cast();
+    (*page).reserved = index;
+    let err = read_folio(mapping, page);
+    if err != 0
+    {
+        put_page(page);
+        return Err(err);
+    }
+    (*page).flags.insert(Pageflags::PgUptodate);
+    let cached = (*mapping).add_to_page_cache(page, index);
+    if cached != page
+    {
+        put_page(page);
+    }
+    Ok(cached)
+}
+
+// copy file contents out of the page cache, stops at the host's size
+pub unsafe fn filemap_read(mapping : *mut AddressSpace, dst : *mut c_void, len : usize, offset : usize) -> i64
+{
+    let size = (*(*mapping).host).get_size();
+    if offset >= size
+    {
+        return EOF;
+    }
+    let end = offset + min(len, size - offset);
+    let mut pos = offset;
+    while pos < end {
+        let page = match read_cache_page(mapping, (pos >> PAGE_SHIFT) as Idx) {
+            Ok(page) => page,
+            Err(err) => return if pos > offset { (pos - offset) as i64 } else { err }
+        };
+        let start = pos % PAGE_SIZE;
+        let read_num = min(PAGE_SIZE - start, end - pos);
+        compiler_builtins::mem::memcpy(dst.add(pos - offset) as *mut u8, (page_address(page) as *const u8).add(start), read_num);
+        put_page(page);
+        pos += read_num;
+    }
+    (pos - offset) as i64
+}
+
+
+
+// bring `nr` pages from `index` on into the cache without mapping them, stops at the end of the file
+pub unsafe fn page_cache_readahead(mapping : *mut AddressSpace, index : Idx, nr : usize)
+{
+    let end = min(index + nr as Idx, (*(*mapping).host).get_size().div_ceil(PAGE_SIZE) as Idx);
+    let mut idx = index;
+    while idx < end {
+        match read_cache_page(mapping, idx) {
+            Ok(page) => put_page(page),
+            Err(_) => return
+        }
+        idx += 1;
+    }
+}
@@ -0,0 +1,236 @@
+use core::ptr::addr_of_mut;
+
+use crate::{kernel::{math, sched::get_current_running_process, Err}, logk};
+
+use super::{memory::{self, follow_pmd, pmd_alloc, pmd_none, pmd_page, pmd_trans_huge, virt2phys, PdtEntry, MAX_USER_STACK_SIZE, PAGE_SHIFT, PAGE_SIZE}, mm_type::{MMCounter, MMStruct, MmapType, VMAreaStruct}, page::{Page, Pageflags, GFP}, page_alloc, rmap, vmscan};
+
+pub const HPAGE_PMD_SHIFT : usize = 21;
+pub const HPAGE_PMD_ORDER : usize = HPAGE_PMD_SHIFT - PAGE_SHIFT;
+pub const HPAGE_PMD_NR : usize = 1 << HPAGE_PMD_ORDER;
+pub const HPAGE_PMD_SIZE : u64 = 1 << HPAGE_PMD_SHIFT;
+pub const HPAGE_PMD_MASK : u64 = !(HPAGE_PMD_SIZE - 1);
+
+// a huge page is only worth it when it comes cheap, no direct reclaim and no oom kill for it
+const GFP_TRANSHUGE : GFP = GFP::__IO.union(GFP::__FS).union(GFP::__HARDWALL).union(GFP::__NOWARN).union(GFP::__ZERO);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ThpMode
+{
+    Always, // every suitable anonymous area, unless madvise(MADV_NOHUGEPAGE)
+    Madvise, // only areas marked with madvise(MADV_HUGEPAGE)
+    Never
+}
+
+pub static mut TRANSPARENT_HUGEPAGE : ThpMode = ThpMode::Always;
+pub static mut NR_ANON_THPS : usize = 0;
+static mut THP_FAULT_ALLOC : usize = 0;
+static mut THP_FAULT_FALLBACK : usize = 0;
+static mut THP_SPLIT_PMD : usize = 0;
+
+// a huge page is mapped by exactly one pde of one mm, it has a single rmap entry on the head
+// and stays off the lru, fork and partial unmap or mprotect split it back to 4k pages first
+
+fn hugepage_vma_enabled(vma : &VMAreaStruct) -> bool
+{
+    let flags = vma.get_flags();
+    // shared anonymous memory and file mappings keep 4k pages
+    if !vma.get_file().is_null() || flags.contains(MmapType::MAP_SHARED) || flags.contains(MmapType::VM_NOHUGEPAGE)
+    {
+        return false;
+    }
+    match unsafe { TRANSPARENT_HUGEPAGE } {
+        ThpMode::Always => true,
+        ThpMode::Madvise => flags.contains(MmapType::VM_HUGEPAGE),
+        ThpMode::Never => false
+    }
+}
+
+// the aligned 2 MiB around `haddr` lies inside the area
+fn transhuge_vma_suitable(vma : &VMAreaStruct, haddr : u64) -> bool
+{
+    haddr >= vma.get_start() && haddr + HPAGE_PMD_SIZE - 1 <= vma.get_end()
+}
+
+unsafe fn prep_transhuge_page(page : *mut Page)
+{
+    (*page).flags.insert(Pageflags::PgHead);
+    let mut var = 1;
+    while var < HPAGE_PMD_NR {
+        (*page.add(var)).flags.insert(Pageflags::PgTail);
+        var += 1;
+    }
+}
+
+// anonymous fault in a suitable area, false sends the caller down the 4k path
+pub unsafe fn do_huge_pmd_anonymous_page(vma : *mut VMAreaStruct, addr : u64) -> bool
+{
+    let haddr = addr & HPAGE_PMD_MASK;
+    if !hugepage_vma_enabled(&*vma) || !transhuge_vma_suitable(&*vma, haddr)
+    {
+        return false;
+    }
+    let mm = &mut (*get_current_running_process()).mm;
+    let pmd = pmd_alloc(mm, haddr);
+    // part of the range already has a page table
+    if pmd.is_null() || !pmd_none(&*pmd)
+    {
+        return false;
+    }
+    let page = page_alloc::alloc_pages(GFP_TRANSHUGE, HPAGE_PMD_ORDER);
+    if page.is_null()
+    {
+        THP_FAULT_FALLBACK += 1;
+        return false;
+    }
+    prep_transhuge_page(page);
+    rmap::page_add_new_anon_rmap(page, mm, haddr);
+    memory::set_pmd_huge(pmd, virt2phys(page_alloc::page_address(page)), (*vma).get_prot());
+    mm.add_mm_counter(MMCounter::AnonPages, HPAGE_PMD_NR as isize);
+    NR_ANON_THPS += 1;
+    THP_FAULT_ALLOC += 1;
+    true
+}
+
+// write to a huge page mprotect left read-only, its only mapper just takes write access back
+pub unsafe fn do_huge_pmd_wp_page(addr : u64) -> bool
+{
+    let haddr = addr & HPAGE_PMD_MASK;
+    let pmd = follow_pmd(addr_of_mut!((*get_current_running_process()).mm), haddr);
+    if pmd.is_null() || !pmd_trans_huge(&*pmd)
+    {
+        return false;
+    }
+    memory::pmd_set_wr(pmd, haddr, true);
+    true
+}
+
+// unmap a whole huge page and give its 512 frames back at once
+pub unsafe fn zap_huge_pmd(mm : *mut MMStruct, pmd : *mut PdtEntry, haddr : u64)
+{
+    let page = pmd_page(&*pmd);
+    memory::pmd_clear(pmd, haddr);
+    rmap::page_remove_rmap(page, mm, haddr);
+    (*mm).add_mm_counter(MMCounter::AnonPages, -(HPAGE_PMD_NR as isize));
+    NR_ANON_THPS -= 1;
+    page_alloc::__free_pages(page, HPAGE_PMD_ORDER);
+}
+
+// turn the huge page at `haddr` into 512 ordinary anonymous pages, nothing when it is not one
+// the huge page stays as it was when there is no page table for it
+pub unsafe fn split_huge_pmd(mm : *mut MMStruct, haddr : u64) -> Err
+{
+    let pmd = follow_pmd(mm, haddr);
+    if pmd.is_null() || !pmd_trans_huge(&*pmd)
+    {
+        return 0;
+    }
+    let page = pmd_page(&*pmd);
+    let err = memory::pmd_split_to_pt(pmd, haddr);
+    if err < 0
+    {
+        return err;
+    }
+    rmap::page_remove_rmap(page, mm, haddr);
+    // a locked huge page becomes 512 locked pages, lru_cache_add passes them over
+    let mlocked = (*page).flags.contains(Pageflags::PgMlocked);
+    // every subpage already holds the reference the allocation gave it
+    let mut var = 0;
+    while var < HPAGE_PMD_NR {
+        let subpage = page.add(var);
+        (*subpage).flags.remove(Pageflags::PgHead | Pageflags::PgTail);
+        if mlocked
+        {
+            (*subpage).flags.insert(Pageflags::PgMlocked);
+        }
+        rmap::page_add_new_anon_rmap(subpage, mm, haddr + (var * PAGE_SIZE) as u64);
+        vmscan::lru_cache_add(subpage);
+        var += 1;
+    }
+    NR_ANON_THPS -= 1;
+    THP_SPLIT_PMD += 1;
+    0
+}
+
+// move the huge page at `old_haddr` to `new_haddr` without touching its frames
+// false when the pde there already holds a page table, the caller splits and moves ptes then
+pub unsafe fn move_huge_pmd(mm : *mut MMStruct, old_haddr : u64, new_haddr : u64) -> bool
+{
+    let new_pmd = pmd_alloc(mm, new_haddr);
+    if new_pmd.is_null() || !pmd_none(&*new_pmd)
+    {
+        return false;
+    }
+    let old_pmd = follow_pmd(mm, old_haddr);
+    let page = pmd_page(&*old_pmd);
+    memory::pmd_move(old_pmd, old_haddr, new_pmd);
+    rmap::page_remove_rmap(page, mm, old_haddr);
+    rmap::page_add_anon_rmap(page, mm, new_haddr);
+    true
+}
+
+pub unsafe fn split_huge_pmd_range(mm : *mut MMStruct, start : u64, end : u64) -> Err
+{
+    let mut haddr = start & HPAGE_PMD_MASK;
+    while haddr < end {
+        let err = split_huge_pmd(mm, haddr);
+        if err < 0
+        {
+            return err;
+        }
+        haddr += HPAGE_PMD_SIZE;
+    }
+    0
+}
+
+pub unsafe fn split_huge_pmd_mm(mm : *mut MMStruct) -> Err
+{
+    let mut vma = (*mm).mmap;
+    while !vma.is_null() {
+        let err = split_huge_pmd_range(mm, (*vma).get_start(), (*vma).get_end() + 1);
+        if err < 0
+        {
+            return err;
+        }
+        vma = (*vma).get_next();
+    }
+    0
+}
+
+// a 2 MiB aligned spot for an anonymous mapping big enough to hold huge pages, 0 leaves it to the usual search
+pub fn thp_get_unmapped_area(mm : &mut MMStruct, length : usize, flags : MmapType) -> u64
+{
+    if unsafe { TRANSPARENT_HUGEPAGE } == ThpMode::Never || flags.contains(MmapType::MAP_SHARED) || (length as u64) < HPAGE_PMD_SIZE
+    {
+        return 0;
+    }
+    let limit = mm.stack_top - MAX_USER_STACK_SIZE as u64;
+    let mut addr = math::upround(mm.mmap_base, HPAGE_PMD_SIZE);
+    let mut vma = mm.mmap;
+    unsafe
+    {
+        while !vma.is_null() {
+            if (*vma).get_end() >= addr
+            {
+                if (*vma).get_start() >= addr + length as u64
+                {
+                    break;
+                }
+                addr = math::upround((*vma).get_end() + 1, HPAGE_PMD_SIZE);
+            }
+            vma = (*vma).get_next();
+        }
+    }
+    if addr + length as u64 > limit
+    {
+        return 0;
+    }
+    addr
+}
+
+pub fn show_thp_state()
+{
+    unsafe
+    {
+        logk!("anon huge pages: {} ({}KB), fault alloc {}, fault fallback {}, split pmd {}\n", NR_ANON_THPS, NR_ANON_THPS * (HPAGE_PMD_SIZE as usize / 1024), THP_FAULT_ALLOC, THP_FAULT_FALLBACK, THP_SPLIT_PMD);
+    }
+}
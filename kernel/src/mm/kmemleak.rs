@@ -0,0 +1,527 @@
+use core::{ffi::{c_void, CStr}, fmt::Write, mem::size_of, ptr::{addr_of, null_mut}};
+
+use alloc::{string::String, vec::Vec};
+use proc_macro::__init;
+
+use crate::{kernel::{clock::{schedule_timeout, JIFFIES, JIFFY}, errno_base::EINVAL, percpu::get_current, process::{for_each_process, PCB, PF_KTHREAD, THREAD_SIZE}, semaphore::UnreenterabkeSpinLock, stacktrace::{print_stack_trace, save_stack_trace}, Err}, logk};
+
+use super::{memory::{get_cr3_reg, virt2page, MemoryPool, LINEAR_MAP_AREA_START, MEMORY_POOL}, page::{self, Pageflags}, slub::{kmem_cache_create, nearest_obj, KmemCache}, vmalloc::is_vmalloc_addr};
+
+// every object of the slab caches and every large kmalloc block is recorded here,
+// a scan looks for pointers to them from data, bss, page descriptors and stacks
+// and follows the ones it finds through the objects themselves
+
+const OBJECT_ALLOCATED : u32 = 1 << 0;
+const OBJECT_REPORTED : u32 = 1 << 1;
+const OBJECT_LARGE : u32 = 1 << 2; // straight from the page allocator, found by range
+const OBJECT_UNREFERENCED : u32 = 1 << 3; // nothing pointed at it in the last scan
+const OBJECT_IGNORED : u32 = 1 << 4; // cleared by the user, never reported again
+
+const HASH_BITS : usize = 12;
+const HASH_SIZE : usize = 1 << HASH_BITS;
+// younger objects may still be on their way into a data structure
+const MSECS_MIN_AGE : u64 = 5000;
+const SECS_SCAN_WAIT : u64 = 600;
+const EARLY_LOG_SIZE : usize = 1024;
+const HEX_DUMP_BYTES : usize = 32;
+const COMM_LEN : usize = 16;
+// return addresses kept per object, the allocator's own frames come first
+const MAX_TRACE : usize = 8;
+
+struct KmemleakObject
+{
+    flags : u32,
+    count : u32, // references found by the current scan
+    min_count : u32, // fewer references than this is a leak
+    pointer : u64,
+    size : usize,
+    trace : [u64; MAX_TRACE],
+    pid : i32,
+    comm : [u8; COMM_LEN],
+    jiffies : u64,
+    next : *mut KmemleakObject, // hash chain
+    large_next : *mut KmemleakObject,
+    gray_next : *mut KmemleakObject
+}
+
+// allocations before the object cache exists, replayed by kmemleak_init
+#[derive(Clone, Copy)]
+struct EarlyLog
+{
+    pointer : u64,
+    size : usize,
+    trace : [u64; MAX_TRACE],
+    large : bool,
+    freed : bool
+}
+
+// what a report needs, copied out so formatting can allocate
+struct LeakRecord
+{
+    pointer : u64,
+    size : usize,
+    trace : [u64; MAX_TRACE],
+    pid : i32,
+    comm : [u8; COMM_LEN],
+    jiffies : u64,
+    bytes : [u8; HEX_DUMP_BYTES]
+}
+
+pub static mut KMEMLEAK_ENABLED : bool = cfg!(feature = "kmemleak");
+static mut KMEMLEAK_INITIALIZED : bool = false;
+static mut KMEMLEAK_LOCK : UnreenterabkeSpinLock = UnreenterabkeSpinLock::new(1);
+static mut OBJECT_HASH : [*mut KmemleakObject; HASH_SIZE] = [null_mut(); HASH_SIZE];
+static mut LARGE_OBJECTS : *mut KmemleakObject = null_mut();
+static mut OBJECT_CACHE : *mut KmemCache = null_mut();
+static mut EARLY_LOG : [EarlyLog; EARLY_LOG_SIZE] = [EarlyLog { pointer: 0, size: 0, trace: [0; MAX_TRACE], large: false, freed: false }; EARLY_LOG_SIZE];
+static mut EARLY_LOG_LEN : usize = 0;
+static mut NR_OBJECTS : usize = 0;
+static mut NR_LEAKS : usize = 0;
+
+extern "C"
+{
+    static sdata : u8;
+    static edata : u8;
+    static sinit : u8;
+}
+
+#[inline(always)]
+fn hash_index(pointer : u64) -> usize
+{
+    ((pointer >> 4) ^ (pointer >> (4 + HASH_BITS))) as usize & (HASH_SIZE - 1)
+}
+
+unsafe fn lookup_object(pointer : u64) -> *mut KmemleakObject
+{
+    let mut object = OBJECT_HASH[hash_index(pointer)];
+    while !object.is_null() && (*object).pointer != pointer {
+        object = (*object).next;
+    }
+    object
+}
+
+// the object `value` points into, null when it is not a tracked block
+unsafe fn find_object(value : u64) -> *mut KmemleakObject
+{
+    let linear_start = LINEAR_MAP_AREA_START as u64 + 0x100000;
+    if value >= linear_start && (virt2page(value as *const c_void) as usize) < MemoryPool::total_pages()
+    {
+        let page = MEMORY_POOL.mem_map.offset(virt2page(value as *const c_void) as isize);
+        if (*page).flags.contains(Pageflags::PgSlab)
+        {
+            let start = nearest_obj(value as *const c_void);
+            return if start.is_null() { null_mut() } else { lookup_object(start as u64) };
+        }
+    }
+    else if !is_vmalloc_addr(value as *const c_void)
+    {
+        return null_mut();
+    }
+    let mut object = LARGE_OBJECTS;
+    while !object.is_null() {
+        if value >= (*object).pointer && value < (*object).pointer + (*object).size as u64
+        {
+            return object;
+        }
+        object = (*object).large_next;
+    }
+    null_mut()
+}
+
+unsafe fn create_object(pointer : u64, size : usize, min_count : u32, trace : [u64; MAX_TRACE], large : bool)
+{
+    let object = (*OBJECT_CACHE).alloc() as *mut KmemleakObject;
+    if object.is_null()
+    {
+        logk!("kmemleak: Cannot allocate a kmemleak_object structure\n");
+        KMEMLEAK_ENABLED = false;
+        return;
+    }
+    let current = get_current();
+    let mut comm = [0; COMM_LEN];
+    let mut pid = 0;
+    if !current.is_null()
+    {
+        pid = (*current).pid;
+        let name = CStr::from_ptr((*current).name.as_ptr()).to_bytes();
+        let len = name.len().min(COMM_LEN - 1);
+        comm[..len].copy_from_slice(&name[..len]);
+    }
+    object.write(KmemleakObject { flags: OBJECT_ALLOCATED | if large { OBJECT_LARGE } else { 0 }, count: 0, min_count, pointer, size, trace, pid, comm, jiffies: JIFFIES, next: null_mut(), large_next: null_mut(), gray_next: null_mut() });
+    KMEMLEAK_LOCK.acquire(1);
+    let bucket = hash_index(pointer);
+    (*object).next = OBJECT_HASH[bucket];
+    OBJECT_HASH[bucket] = object;
+    if large
+    {
+        (*object).large_next = LARGE_OBJECTS;
+        LARGE_OBJECTS = object;
+    }
+    NR_OBJECTS += 1;
+    KMEMLEAK_LOCK.release(1);
+}
+
+unsafe fn delete_object(pointer : u64)
+{
+    KMEMLEAK_LOCK.acquire(1);
+    let mut link = &mut OBJECT_HASH[hash_index(pointer)] as *mut *mut KmemleakObject;
+    while !(*link).is_null() && (**link).pointer != pointer {
+        link = &mut (**link).next;
+    }
+    let object = *link;
+    if object.is_null()
+    {
+        KMEMLEAK_LOCK.release(1);
+        return;
+    }
+    *link = (*object).next;
+    if (*object).flags & OBJECT_LARGE != 0
+    {
+        let mut large = &mut LARGE_OBJECTS as *mut *mut KmemleakObject;
+        while *large != object {
+            large = &mut (**large).large_next;
+        }
+        *large = (*object).large_next;
+    }
+    NR_OBJECTS -= 1;
+    KMEMLEAK_LOCK.release(1);
+    (*OBJECT_CACHE).free(object as *mut c_void);
+}
+
+unsafe fn log_early(pointer : u64, size : usize, trace : [u64; MAX_TRACE], large : bool)
+{
+    if EARLY_LOG_LEN == EARLY_LOG_SIZE
+    {
+        logk!("kmemleak: Early log buffer exceeded ({}), kmemleak disabled\n", EARLY_LOG_SIZE);
+        KMEMLEAK_ENABLED = false;
+        return;
+    }
+    EARLY_LOG[EARLY_LOG_LEN] = EarlyLog { pointer, size, trace, large, freed: false };
+    EARLY_LOG_LEN += 1;
+}
+
+// an early block freed before kmemleak_init never needs an object
+unsafe fn log_early_free(pointer : u64)
+{
+    let mut var = EARLY_LOG_LEN;
+    while var > 0 {
+        var -= 1;
+        if EARLY_LOG[var].pointer == pointer && !EARLY_LOG[var].freed
+        {
+            EARLY_LOG[var].freed = true;
+            return;
+        }
+    }
+}
+
+// called by the allocators for every block they hand out
+pub fn kmemleak_alloc(cache : *const KmemCache, pointer : *const c_void, size : usize)
+{
+    unsafe
+    {
+        if !KMEMLEAK_ENABLED || pointer.is_null() || (cache == OBJECT_CACHE && !cache.is_null())
+        {
+            return;
+        }
+        let mut trace = [0; MAX_TRACE];
+        save_stack_trace(&mut trace, 1);
+        let large = cache.is_null();
+        if !KMEMLEAK_INITIALIZED
+        {
+            log_early(pointer as u64, size, trace, large);
+            return;
+        }
+        create_object(pointer as u64, size, 1, trace, large);
+    }
+}
+
+// a vmalloc block is referenced by its area as well, one pointer to it is not enough
+pub fn kmemleak_vmalloc(pointer : *const c_void, size : usize)
+{
+    unsafe
+    {
+        if !KMEMLEAK_ENABLED || !KMEMLEAK_INITIALIZED
+        {
+            return;
+        }
+        let mut trace = [0; MAX_TRACE];
+        save_stack_trace(&mut trace, 1);
+        create_object(pointer as u64, size, 2, trace, true);
+    }
+}
+
+pub fn kmemleak_free(cache : *const KmemCache, pointer : *const c_void)
+{
+    unsafe
+    {
+        if !KMEMLEAK_ENABLED || pointer.is_null() || (cache == OBJECT_CACHE && !cache.is_null())
+        {
+            return;
+        }
+        if !KMEMLEAK_INITIALIZED
+        {
+            log_early_free(pointer as u64);
+            return;
+        }
+        delete_object(pointer as u64);
+    }
+}
+
+fn is_old(object : &KmemleakObject) -> bool
+{
+    unsafe
+    {
+        (JIFFIES - object.jiffies) * JIFFY >= MSECS_MIN_AGE
+    }
+}
+
+// every word of [start, end) that points into a tracked block is a reference,
+// a block seen the first time goes onto the gray list to be scanned itself
+unsafe fn scan_block(start : u64, end : u64, gray : &mut *mut KmemleakObject)
+{
+    let mut ptr = (start + 7) & !7;
+    while ptr + 8 <= end {
+        let object = find_object(*(ptr as *const u64));
+        if !object.is_null() && (*object).flags & OBJECT_ALLOCATED != 0
+        {
+            (*object).count += 1;
+            if (*object).count == (*object).min_count
+            {
+                (*object).gray_next = *gray;
+                *gray = object;
+            }
+        }
+        ptr += 8;
+    }
+}
+
+// one scan pass, returns the leaks not reported before
+unsafe fn kmemleak_scan() -> usize
+{
+    KMEMLEAK_LOCK.acquire(1);
+    let mut var = 0;
+    while var < HASH_SIZE {
+        let mut object = OBJECT_HASH[var];
+        while !object.is_null() {
+            (*object).count = 0;
+            (*object).gray_next = null_mut();
+            object = (*object).next;
+        }
+        var += 1;
+    }
+    let mut gray = null_mut();
+    // data and bss, the boot stack and the per-cpu areas included
+    scan_block(addr_of!(sdata) as u64, addr_of!(edata) as u64, &mut gray);
+    scan_block(addr_of!(edata) as u64, addr_of!(sinit) as u64, &mut gray);
+    // page descriptors point at address spaces and slab caches
+    let mem_map = MEMORY_POOL.mem_map as u64;
+    scan_block(mem_map, mem_map + (MemoryPool::total_pages() * size_of::<page::Page>()) as u64, &mut gray);
+    for_each_process(|pcb| {
+        scan_block(pcb as u64, pcb as u64 + THREAD_SIZE as u64, &mut gray);
+    });
+    while !gray.is_null() {
+        let object = gray;
+        gray = (*object).gray_next;
+        scan_block((*object).pointer, (*object).pointer + (*object).size as u64, &mut gray);
+    }
+    let mut new_leaks = 0;
+    NR_LEAKS = 0;
+    var = 0;
+    while var < HASH_SIZE {
+        let mut object = OBJECT_HASH[var];
+        while !object.is_null() {
+            if (*object).count < (*object).min_count && (*object).flags & OBJECT_IGNORED == 0 && is_old(&*object)
+            {
+                (*object).flags |= OBJECT_UNREFERENCED;
+                NR_LEAKS += 1;
+                if (*object).flags & OBJECT_REPORTED == 0
+                {
+                    (*object).flags |= OBJECT_REPORTED;
+                    new_leaks += 1;
+                }
+            }
+            else {
+                (*object).flags &= !OBJECT_UNREFERENCED;
+            }
+            object = (*object).next;
+        }
+        var += 1;
+    }
+    KMEMLEAK_LOCK.release(1);
+    new_leaks
+}
+
+// the unreferenced objects of the last scan, no allocation under the lock
+unsafe fn collect_leaks() -> Vec<LeakRecord>
+{
+    let mut leaks = Vec::with_capacity(NR_LEAKS);
+    KMEMLEAK_LOCK.acquire(1);
+    let mut var = 0;
+    while var < HASH_SIZE && leaks.len() < leaks.capacity() {
+        let mut object = OBJECT_HASH[var];
+        while !object.is_null() && leaks.len() < leaks.capacity() {
+            if (*object).flags & OBJECT_UNREFERENCED != 0
+            {
+                let mut bytes = [0; HEX_DUMP_BYTES];
+                let len = (*object).size.min(HEX_DUMP_BYTES);
+                core::ptr::copy_nonoverlapping((*object).pointer as *const u8, bytes.as_mut_ptr(), len);
+                leaks.push(LeakRecord { pointer: (*object).pointer, size: (*object).size, trace: (*object).trace, pid: (*object).pid, comm: (*object).comm, jiffies: (*object).jiffies, bytes });
+            }
+            object = (*object).next;
+        }
+        var += 1;
+    }
+    KMEMLEAK_LOCK.release(1);
+    leaks
+}
+
+fn print_leak(out : &mut String, leak : &LeakRecord)
+{
+    let comm = CStr::from_bytes_until_nul(&leak.comm).ok().and_then(|comm| comm.to_str().ok()).unwrap_or("?");
+    let _ = writeln!(out, "unreferenced object {:#x} (size {}):", leak.pointer, leak.size);
+    let _ = writeln!(out, "  comm \"{}\", pid {}, jiffies {}", comm, leak.pid, leak.jiffies);
+    let len = leak.size.min(HEX_DUMP_BYTES);
+    let _ = writeln!(out, "  hex dump (first {} bytes):", len);
+    let mut var = 0;
+    while var < len {
+        if var % 16 == 0
+        {
+            let _ = write!(out, "   ");
+        }
+        let _ = write!(out, " {:02x}", leak.bytes[var]);
+        if var % 16 == 15 || var + 1 == len
+        {
+            let _ = writeln!(out);
+        }
+        var += 1;
+    }
+    let _ = writeln!(out, "  backtrace:");
+    if leak.trace[0] == 0
+    {
+        let _ = writeln!(out, "    [<unknown>]");
+    }
+    else {
+        print_stack_trace(out, &leak.trace, "    ");
+    }
+}
+
+// contents of /proc/kmemleak
+pub fn kmemleak_read() -> String
+{
+    let mut out = String::new();
+    unsafe
+    {
+        if !KMEMLEAK_INITIALIZED
+        {
+            return out;
+        }
+        for leak in collect_leaks().iter() {
+            print_leak(&mut out, leak);
+        }
+    }
+    out
+}
+
+// commands written to /proc/kmemleak: scan, clear and off
+pub fn kmemleak_write(cmd : &str) -> Err
+{
+    unsafe
+    {
+        if !KMEMLEAK_INITIALIZED
+        {
+            return -EINVAL;
+        }
+        match cmd.trim() {
+            "scan" => {
+                let new_leaks = kmemleak_scan();
+                if new_leaks != 0
+                {
+                    logk!("kmemleak: {} new suspected memory leaks (see /proc/kmemleak)\n", new_leaks);
+                }
+                0
+            },
+            "clear" => {
+                KMEMLEAK_LOCK.acquire(1);
+                let mut var = 0;
+                while var < HASH_SIZE {
+                    let mut object = OBJECT_HASH[var];
+                    while !object.is_null() {
+                        if (*object).flags & OBJECT_UNREFERENCED != 0
+                        {
+                            (*object).flags = ((*object).flags & !OBJECT_UNREFERENCED) | OBJECT_IGNORED;
+                        }
+                        object = (*object).next;
+                    }
+                    var += 1;
+                }
+                NR_LEAKS = 0;
+                KMEMLEAK_LOCK.release(1);
+                0
+            },
+            "off" => {
+                KMEMLEAK_ENABLED = false;
+                0
+            },
+            _ => -EINVAL
+        }
+    }
+}
+
+fn kmemleak_scan_thread()
+{
+    unsafe
+    {
+        loop {
+            schedule_timeout(SECS_SCAN_WAIT * 1000 / JIFFY);
+            if KMEMLEAK_ENABLED
+            {
+                kmemleak_write("scan");
+            }
+        }
+    }
+}
+
+#[__init]
+pub fn kmemleak_init()
+{
+    unsafe
+    {
+        if !KMEMLEAK_ENABLED
+        {
+            return;
+        }
+        OBJECT_CACHE = kmem_cache_create("kmemleak_object", size_of::<KmemleakObject>() as u32, 8, null_mut(), page::Pageflags::empty());
+        KMEMLEAK_INITIALIZED = true;
+        let mut var = 0;
+        while var < EARLY_LOG_LEN {
+            let log = EARLY_LOG[var];
+            if !log.freed
+            {
+                create_object(log.pointer, log.size, 1, log.trace, log.large);
+            }
+            var += 1;
+        }
+        // the log must not keep those blocks referenced
+        compiler_builtins::mem::memset(addr_of!(EARLY_LOG) as *mut u8, 0, size_of::<[EarlyLog; EARLY_LOG_SIZE]>());
+        EARLY_LOG_LEN = 0;
+        logk!("kmemleak: Kernel memory leak detector initialized, {} objects\n", NR_OBJECTS);
+    }
+}
+
+// the scan thread needs the scheduler
+pub fn kmemleak_late_init()
+{
+    unsafe
+    {
+        if !KMEMLEAK_INITIALIZED
+        {
+            return;
+        }
+        let pcb = PCB::create_new_process(kmemleak_scan_thread as u64, 0);
+        compiler_builtins::mem::memcpy((*pcb).name.as_ptr() as *mut u8, "kmemleak".as_ptr(), 8);
+        (*pcb).flags |= PF_KTHREAD;
+        (*pcb).pml4 = get_cr3_reg() as *mut _;
+        (*pcb).insert_to_task_table();
+        logk!("kmemleak: Automatic memory scanning thread started\n");
+    }
+}
@@ -0,0 +1,324 @@
+use core::{ffi::c_void, fmt::Write, ptr::addr_of_mut, sync::atomic::Ordering};
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use crate::{kernel::{clock::schedule_timeout, cpu::flush_tlb, interrupt::{interrupt_disable, set_interrupt_state}, process::{for_each_process, PCB, PF_KTHREAD}, sched::get_current_running_process}, logk};
+
+use super::{madvise::MADV_MERGEABLE, memory::{follow_page, follow_pte, get_cr3_reg, populate_vma_page_range, virt2phys, PAGE_SHIFT, PAGE_SIZE}, mm_type::{MMStruct, MmapType, VMAreaStruct}, page::{Page, Pageflags}, page_alloc::{get_page, page_address, put_page}, rmap, vmscan};
+
+// ksmd goes over every mergeable page once per this many jiffies
+const KSM_SLEEP_JIFFIES : u64 = 20;
+
+// merged pages by checksum, the tree holds a reference on each so it outlives its last mapper until the next pass
+static mut STABLE_TREE : BTreeMap<u32, Vec<*mut Page>> = BTreeMap::new();
+// pages that did not change since the previous pass, the candidates for a new merge, rebuilt every pass
+// an unmap or exit takes its addresses out, so every (mm, vaddr) here is still mapped
+static mut UNSTABLE_TREE : BTreeMap<u32, Vec<(*mut MMStruct, u64)>> = BTreeMap::new();
+// checksum every scanned address had at its last visit, and the pass that saw it
+static mut OLD_CHECKSUMS : BTreeMap<(usize, u64), (u32, usize)> = BTreeMap::new();
+
+pub static mut KSM_PAGES_SHARED : usize = 0; // merged pages in the stable tree
+pub static mut KSM_PAGES_SHARING : usize = 0; // further mappings of them, the pages saved
+pub static mut KSM_FULL_SCANS : usize = 0;
+
+// only private memory may share frames behind its owner's back
+pub fn ksm_madvise(flags : MmapType, advice : i32) -> MmapType
+{
+    let mut flags = flags;
+    if advice == MADV_MERGEABLE
+    {
+        if !flags.intersects(MmapType::MAP_SHARED | MmapType::VM_IO | MmapType::VM_PFNMAP)
+        {
+            flags.insert(MmapType::VM_MERGEABLE);
+        }
+    }
+    else {
+        flags.remove(MmapType::VM_MERGEABLE);
+    }
+    flags
+}
+
+pub fn page_ksm(page : &Page) -> bool
+{
+    page.flags.contains(Pageflags::PgKsm)
+}
+
+// cheap hash of the contents, pages with equal checksums are still compared byte for byte
+unsafe fn calc_checksum(page : *mut Page) -> u32
+{
+    let words = page_address(page) as *const u64;
+    let mut hash : u64 = 0xcbf29ce484222325;
+    let mut var = 0;
+    while var < PAGE_SIZE / 8 {
+        hash = (hash ^ *words.add(var)).wrapping_mul(0x100000001b3);
+        var += 1;
+    }
+    (hash ^ (hash >> 32)) as u32
+}
+
+unsafe fn pages_identical(page1 : *mut Page, page2 : *mut Page) -> bool
+{
+    compiler_builtins::mem::memcmp(page_address(page1) as *const u8, page_address(page2) as *const u8, PAGE_SIZE) == 0
+}
+
+// an anonymous page ksmd may fold away, small, not merged yet and referenced only by its ptes
+unsafe fn page_mergeable(page : *mut Page) -> bool
+{
+    !page.is_null() && rmap::page_anon(&*page) && !(*page).flags.intersects(Pageflags::PgHead | Pageflags::PgKsm | Pageflags::PgMlocked) && (*page)._refcount.load(Ordering::Relaxed) == rmap::page_mapcount(&*page)
+}
+
+// take write access away from the pte mapping `page` at (mm, vaddr)
+// false when someone besides the page tables holds a reference and might still write to it
+unsafe fn write_protect_pte(mm : *mut MMStruct, vaddr : u64, page : *mut Page) -> bool
+{
+    let mut step = PAGE_SIZE as u64;
+    let pte = follow_pte(mm, vaddr, &mut step);
+    if pte.is_null() || (*pte).get_present() == 0
+    {
+        return false;
+    }
+    if (*pte).get_wr() != 0
+    {
+        (*pte).set_wr(0);
+        flush_tlb(vaddr as *const c_void);
+    }
+    (*page)._refcount.load(Ordering::Relaxed) == rmap::page_mapcount(&*page)
+}
+
+// take write access away from every pte of `page`, the next write goes through copy_on_write
+unsafe fn write_protect_page(page : *mut Page)
+{
+    rmap::rmap_walk(page, &mut |mm, vaddr| {
+        let mut step = PAGE_SIZE as u64;
+        let pte = follow_pte(mm, vaddr, &mut step);
+        if !pte.is_null() && (*pte).get_wr() != 0
+        {
+            (*pte).set_wr(0);
+            flush_tlb(vaddr as *const c_void);
+        }
+        true
+    });
+}
+
+// map the merged `kpage` read-only where `page` was mapped at (mm, vaddr)
+unsafe fn replace_page(mm : *mut MMStruct, vaddr : u64, page : *mut Page, kpage : *mut Page)
+{
+    let mut step = PAGE_SIZE as u64;
+    let pte = follow_pte(mm, vaddr, &mut step);
+    (*pte).set_page_offset(virt2phys(page_address(kpage)) as u64 >> PAGE_SHIFT);
+    (*pte).set_wr(0);
+    flush_tlb(vaddr as *const c_void);
+    get_page(&*kpage);
+    rmap::page_add_anon_rmap(kpage, mm, vaddr);
+    rmap::page_remove_rmap(page, mm, vaddr);
+    put_page(page);
+}
+
+// (mm, vaddr) maps the merged `kpage` instead of `page` if their contents still match
+unsafe fn try_to_merge_with_ksm_page(mm : *mut MMStruct, vaddr : u64, page : *mut Page, kpage : *mut Page) -> bool
+{
+    if !write_protect_pte(mm, vaddr, page) || !pages_identical(page, kpage)
+    {
+        return false;
+    }
+    replace_page(mm, vaddr, page, kpage);
+    true
+}
+
+unsafe fn stable_tree_search(page : *mut Page, checksum : u32) -> *mut Page
+{
+    if let Some(kpages) = STABLE_TREE.get(&checksum)
+    {
+        for &kpage in kpages.iter() {
+            if pages_identical(page, kpage)
+            {
+                return kpage;
+            }
+        }
+    }
+    core::ptr::null_mut()
+}
+
+// `tpage` turns into a merged page and (mm, vaddr) maps it instead of `page`
+unsafe fn try_to_merge_two_pages(mm : *mut MMStruct, vaddr : u64, page : *mut Page, tpage : *mut Page, checksum : u32) -> bool
+{
+    if tpage == page || !page_mergeable(tpage) || !write_protect_pte(mm, vaddr, page)
+    {
+        return false;
+    }
+    // compared once more after nobody can write to either of them anymore
+    write_protect_page(tpage);
+    if !page_mergeable(tpage) || !pages_identical(page, tpage)
+    {
+        return false;
+    }
+    (*tpage).flags.insert(Pageflags::PgKsm);
+    vmscan::lru_cache_del(tpage);
+    get_page(&*tpage);
+    STABLE_TREE.entry(checksum).or_default().push(tpage);
+    replace_page(mm, vaddr, page, tpage);
+    true
+}
+
+// merge the page at (mm, vaddr) with an identical one, or remember it as a candidate
+unsafe fn cmp_and_merge_page(mm : *mut MMStruct, vaddr : u64, seq : usize)
+{
+    let page = follow_page(mm, vaddr);
+    if !page_mergeable(page)
+    {
+        return;
+    }
+    let checksum = calc_checksum(page);
+    let kpage = stable_tree_search(page, checksum);
+    if !kpage.is_null()
+    {
+        try_to_merge_with_ksm_page(mm, vaddr, page, kpage);
+        return;
+    }
+    // a page that changed since the last pass is likely to change again
+    let old = OLD_CHECKSUMS.insert((mm as usize, vaddr), (checksum, seq));
+    if old.map(|(old_checksum, _)| old_checksum) != Some(checksum)
+    {
+        return;
+    }
+    let candidates = UNSTABLE_TREE.entry(checksum).or_default();
+    let mut var = 0;
+    while var < candidates.len() {
+        let (tmm, taddr) = candidates[var];
+        if try_to_merge_two_pages(mm, vaddr, page, follow_page(tmm, taddr), checksum)
+        {
+            candidates.swap_remove(var);
+            return;
+        }
+        var += 1;
+    }
+    candidates.push((mm, vaddr));
+}
+
+// merged pages nobody maps anymore leave the stable tree, the rest are counted
+unsafe fn stable_tree_prune()
+{
+    KSM_PAGES_SHARED = 0;
+    KSM_PAGES_SHARING = 0;
+    STABLE_TREE.retain(|_, kpages| {
+        kpages.retain(|&kpage| {
+            let mapcount = rmap::page_mapcount(&*kpage) as usize;
+            if mapcount == 0
+            {
+                put_page(kpage);
+                return false;
+            }
+            KSM_PAGES_SHARED += 1;
+            KSM_PAGES_SHARING += mapcount - 1;
+            true
+        });
+        !kpages.is_empty()
+    });
+}
+
+// one pass over the mergeable areas of every process
+// the areas of an mm only change in its syscalls and faults, which run with interrupts off,
+// so each mm is walked with interrupts off too and nothing is freed under ksmd
+unsafe fn ksm_do_scan()
+{
+    let seq = KSM_FULL_SCANS;
+    for_each_process(|pcb| {
+        if (*pcb).flags & PF_KTHREAD != 0
+        {
+            return;
+        }
+        let state = interrupt_disable();
+        let mm = addr_of_mut!((*pcb).mm);
+        let mut vma = (*mm).mmap;
+        while !vma.is_null() {
+            if (*vma).get_flags().contains(MmapType::VM_MERGEABLE)
+            {
+                let mut addr = (*vma).get_start();
+                while addr < (*vma).get_end() {
+                    cmp_and_merge_page(mm, addr, seq);
+                    addr += PAGE_SIZE as u64;
+                }
+            }
+            vma = (*vma).get_next();
+        }
+        set_interrupt_state(state);
+    });
+    UNSTABLE_TREE.clear();
+    OLD_CHECKSUMS.retain(|_, (_, pass)| *pass == seq);
+    stable_tree_prune();
+    KSM_FULL_SCANS += 1;
+}
+
+// [start, end) of `mm` is being unmapped, ksmd must not look at those addresses again
+pub unsafe fn ksm_unmap(mm : *mut MMStruct, start : u64, end : u64)
+{
+    let state = interrupt_disable();
+    if !UNSTABLE_TREE.is_empty()
+    {
+        UNSTABLE_TREE.retain(|_, candidates| {
+            candidates.retain(|&(cmm, vaddr)| cmm != mm || vaddr < start || vaddr >= end);
+            !candidates.is_empty()
+        });
+    }
+    OLD_CHECKSUMS.retain(|&(cmm, vaddr), _| cmm != mm as usize || vaddr < start || vaddr >= end);
+    set_interrupt_state(state);
+}
+
+// MADV_UNMERGEABLE, every merged page of [start, end) in `vma` gets a private copy back
+// read-only areas keep sharing, nothing can write to them anyway
+pub unsafe fn unmerge_ksm_pages(vma : *mut VMAreaStruct, start : u64, end : u64)
+{
+    let mm = addr_of_mut!((*get_current_running_process()).mm);
+    let mut addr = start;
+    while addr < end {
+        let page = follow_page(mm, addr);
+        if !page.is_null() && page_ksm(&*page)
+        {
+            populate_vma_page_range(vma, addr, addr + PAGE_SIZE as u64);
+        }
+        addr += PAGE_SIZE as u64;
+    }
+}
+
+fn ksmd()
+{
+    unsafe
+    {
+        loop {
+            ksm_do_scan();
+            schedule_timeout(KSM_SLEEP_JIFFIES);
+        }
+    }
+}
+
+pub fn ksm_init()
+{
+    unsafe
+    {
+        let pcb = PCB::create_new_process(ksmd as u64, 0);
+        compiler_builtins::mem::memcpy((*pcb).name.as_ptr() as *mut u8, "ksmd".as_ptr(), 4);
+        (*pcb).flags |= PF_KTHREAD;
+        (*pcb).pml4 = get_cr3_reg() as *mut _;
+        (*pcb).insert_to_task_table();
+        logk!("ksmd started\n");
+    }
+}
+
+pub fn show_ksm_state()
+{
+    unsafe
+    {
+        logk!("ksm pages shared: {}, pages sharing: {}, full scans: {}\n", KSM_PAGES_SHARED, KSM_PAGES_SHARING, KSM_FULL_SCANS);
+    }
+}
+
+// /proc/ksm, the counters linux keeps in /sys/kernel/mm/ksm
+pub fn ksm_stat() -> String
+{
+    let mut buf = String::new();
+    unsafe
+    {
+        let _ = write!(buf, "pages_shared {}\npages_sharing {}\nfull_scans {}\n", KSM_PAGES_SHARED, KSM_PAGES_SHARING, KSM_FULL_SCANS);
+    }
+    buf
+}
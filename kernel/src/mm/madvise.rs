@@ -0,0 +1,185 @@
+use core::ffi::c_void;
+
+use crate::{fs::ext4::Idx, kernel::{cpu::flush_tlb, errno_base::{EINVAL, ENOMEM}, sched::get_current_running_process, Err}};
+
+use super::{filemap, huge_memory, ksm, memory::{self, follow_page, follow_pmd, follow_pte, pmd_trans_huge, swapin_pte, PAGE_SIZE}, mm_type::{MMStruct, MmapType, VMAreaStruct}, page::Pageflags, rmap, swapfile};
+
+pub const MADV_NORMAL : i32 = 0; // no particular access pattern
+pub const MADV_RANDOM : i32 = 1; // pages are touched in no order, readahead is wasted
+pub const MADV_SEQUENTIAL : i32 = 2; // pages are touched in order and not again
+pub const MADV_WILLNEED : i32 = 3; // pages are wanted soon, bring them in now
+pub const MADV_DONTNEED : i32 = 4; // contents can go, the next touch reads them afresh
+pub const MADV_FREE : i32 = 8; // contents can go, but only when memory runs short
+pub const MADV_MERGEABLE : i32 = 12; // ksmd may share identical pages
+pub const MADV_UNMERGEABLE : i32 = 13; // every page gets a frame of its own again
+pub const MADV_HUGEPAGE : i32 = 14; // worth backing with huge pages
+pub const MADV_NOHUGEPAGE : i32 = 15; // not worth backing with huge pages
+
+// pages read ahead on a fault in a sequential area
+pub const VM_READAHEAD_PAGES : usize = 8;
+
+fn madvise_behavior_valid(advice : i32) -> bool
+{
+    matches!(advice, MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL | MADV_WILLNEED | MADV_DONTNEED | MADV_FREE | MADV_MERGEABLE | MADV_UNMERGEABLE | MADV_HUGEPAGE | MADV_NOHUGEPAGE)
+}
+
+// advice kept in the area flags, the range gets areas of its own for it
+fn madvise_need_split(advice : i32) -> bool
+{
+    !matches!(advice, MADV_WILLNEED | MADV_DONTNEED | MADV_FREE)
+}
+
+// huge page hints only change the area flags, pages already mapped stay as they are
+fn hugepage_madvise(flags : MmapType, advice : i32) -> MmapType
+{
+    let mut flags = flags;
+    if advice == MADV_HUGEPAGE
+    {
+        flags.remove(MmapType::VM_NOHUGEPAGE);
+        flags.insert(MmapType::VM_HUGEPAGE);
+    }
+    else {
+        flags.remove(MmapType::VM_HUGEPAGE);
+        flags.insert(MmapType::VM_NOHUGEPAGE);
+    }
+    flags
+}
+
+fn madvise_vma_flags(flags : MmapType, advice : i32) -> MmapType
+{
+    match advice {
+        MADV_NORMAL => flags - (MmapType::VM_SEQ_READ | MmapType::VM_RAND_READ),
+        MADV_RANDOM => flags - MmapType::VM_SEQ_READ | MmapType::VM_RAND_READ,
+        MADV_SEQUENTIAL => flags - MmapType::VM_RAND_READ | MmapType::VM_SEQ_READ,
+        MADV_MERGEABLE | MADV_UNMERGEABLE => ksm::ksm_madvise(flags, advice),
+        _ => hugepage_madvise(flags, advice)
+    }
+}
+
+// read file pages into the page cache and swapped out anonymous pages back in
+unsafe fn madvise_willneed(mm : *mut MMStruct, vma : *mut VMAreaStruct, start : u64, end : u64) -> Err
+{
+    let file_t = (*vma).get_file();
+    if !file_t.is_null()
+    {
+        let mapping = (*file_t).f_mapping;
+        if !mapping.is_null()
+        {
+            let index = (start - (*vma).get_start() + (*vma).get_offset() as u64) / PAGE_SIZE as u64;
+            filemap::page_cache_readahead(mapping, index as Idx, ((end - start) / PAGE_SIZE as u64) as usize);
+        }
+        return 0;
+    }
+    let mut addr = start;
+    while addr < end {
+        let mut step = PAGE_SIZE as u64;
+        let pte = follow_pte(mm, addr, &mut step);
+        if !pte.is_null() && swapfile::is_swap_pte((*pte).raw())
+        {
+            let err = swapin_pte(mm, pte, addr, (*vma).get_prot());
+            if err < 0
+            {
+                return err;
+            }
+        }
+        addr = (addr & !(step - 1)) + step;
+    }
+    0
+}
+
+// drop the pages now, a locked area has to stay resident
+unsafe fn madvise_dontneed(mm : *mut MMStruct, vma : *mut VMAreaStruct, start : u64, end : u64) -> Err
+{
+    if (*vma).get_flags().contains(MmapType::VM_LOCKED)
+    {
+        return -EINVAL;
+    }
+    memory::zap_page_range(mm, start, end)
+}
+
+// let reclaim discard private anonymous pages without writing them out
+// the dirty bit is cleared so a later write shows the contents are wanted again
+unsafe fn madvise_free(mm : *mut MMStruct, vma : *mut VMAreaStruct, start : u64, end : u64) -> Err
+{
+    let flags = (*vma).get_flags();
+    if !(*vma).get_file().is_null() || flags.contains(MmapType::MAP_SHARED) || flags.contains(MmapType::VM_LOCKED)
+    {
+        return -EINVAL;
+    }
+    let mut addr = start;
+    while addr < end {
+        // huge pages are left alone, splitting them to free part is not worth it
+        let pmd = follow_pmd(mm, addr);
+        if !pmd.is_null() && pmd_trans_huge(&*pmd)
+        {
+            addr = (addr & huge_memory::HPAGE_PMD_MASK) + huge_memory::HPAGE_PMD_SIZE;
+            continue;
+        }
+        let page = follow_page(mm, addr);
+        // a page shared with a forked process still matters to the other side
+        if !page.is_null() && rmap::page_anon(&*page) && rmap::page_mapcount(&*page) == 1
+        {
+            let mut step = PAGE_SIZE as u64;
+            let pte = follow_pte(mm, addr, &mut step);
+            (*pte).set_dirty(0);
+            flush_tlb(addr as *const c_void);
+            (*page).flags.insert(Pageflags::PgLazyfree);
+        }
+        addr += PAGE_SIZE as u64;
+    }
+    0
+}
+
+pub fn sys_madvise(addr : *const c_void, length : usize, advice : i32) -> Err
+{
+    unsafe
+    {
+        if (addr as usize) & (PAGE_SIZE - 1) != 0 || !madvise_behavior_valid(advice)
+        {
+            return -EINVAL;
+        }
+        let mm = &mut (*get_current_running_process()).mm;
+        let start = addr as u64;
+        let end = start + (length.div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64;
+        if start == end
+        {
+            return 0;
+        }
+        if !mm.range_mapped(start, end)
+        {
+            return -ENOMEM;
+        }
+        if madvise_need_split(advice)
+        {
+            let mut vma = match mm.isolate_range(start, end) {
+                Ok(vma) => vma,
+                Err(err) => return err
+            };
+            while !vma.is_null() && (*vma).get_start() < end {
+                (*vma).set_flags(madvise_vma_flags((*vma).get_flags(), advice));
+                if advice == MADV_UNMERGEABLE
+                {
+                    ksm::unmerge_ksm_pages(vma, (*vma).get_start(), (*vma).get_end() + 1);
+                }
+                vma = (*vma).get_next();
+            }
+            return 0;
+        }
+        let mut vma = mm.find_vma_intersection(start, end);
+        while !vma.is_null() && (*vma).get_start() < end {
+            let vstart = start.max((*vma).get_start());
+            let vend = end.min((*vma).get_end() + 1);
+            let err = match advice {
+                MADV_WILLNEED => madvise_willneed(mm, vma, vstart, vend),
+                MADV_DONTNEED => madvise_dontneed(mm, vma, vstart, vend),
+                _ => madvise_free(mm, vma, vstart, vend)
+            };
+            if err < 0
+            {
+                return err;
+            }
+            vma = (*vma).get_next();
+        }
+        0
+    }
+}
@@ -1,15 +1,12 @@
 use alloc::alloc::{Layout, alloc, alloc_zeroed};
-use alloc::boxed::Box;
 use proc_macro::__init;
 use core::alloc::GlobalAlloc;
 use core::fmt::Display;
 use core::intrinsics::size_of;
-use core::ops::Range;
 use core::ptr::{addr_of_mut, null, null_mut};
 use core::{ffi::c_void, arch::asm, fmt};
 
 use bitfield::bitfield;
-use buddy_system_allocator::LockedFrameAllocator;
 
 use crate::fs::ext4::Idx;
 use crate::fs::file::FS;
@@ -22,11 +19,13 @@ use crate::{bochs_break, logk, printk};
 
 
 use crate::kernel::cpu;
-use super::mm_type::VMAreaStruct;
+use super::mm_type::{mm_counter_file, MMCounter, MMStruct, MmapType, VMAreaStruct};
 use super::page::{self, Pageflags, GFP};
+use super::{filemap, huge_memory, kmemleak, ksm, madvise, mlock, page_alloc, rmap, swapfile, vmalloc, vmscan};
 use super::slub;
-use crate::kernel::process::{PtRegs, PCB};
-use crate::kernel::{relocation, bitmap, string::memset, semaphore};
+use crate::kernel::process::{kill_process, sys_exit, PtRegs, PCB, PF_KTHREAD};
+use crate::kernel::{kaslr, multiboot, relocation, bitmap, string::memset, semaphore, Err};
+use crate::kernel::errno_base::ENOMEM;
 const ARDS_BUFFER : *const c_void = 0x7c00 as *const c_void;
 static mut KERNEL_PAGE_DIR : *const c_void = 0x0 as *const c_void;
 pub static mut MEMORY_DESCRIPTOR : MemoryDescriptor = MemoryDescriptor{ size : 0, all_pages : 0, start : core::ptr::null() };
@@ -36,13 +35,21 @@ pub const PAGE_SHIFT : usize = 12;
 pub const PAGE_SIZE : usize = 1 << 12;
 pub const MAX_USER_STACK_SIZE : usize = 8 * 1024 * 1024;
 const KERNEL_START : usize = 0xffff800000100000;
-const VIRTADDR_START : usize = 0xffff800000000000;
+pub const VIRTADDR_START : usize = 0xffff800000000000;
 const PHYADDR_START : *mut c_void = 0x100000 as *mut c_void;
+// pfn of mem_map[0]
+pub const PHYS_PFN_OFFSET : usize = 0x100000 >> PAGE_SHIFT;
 pub const LINEAR_MAP_AREA_START : *mut c_void = 0xffff880000000000 as *mut c_void;
 pub const LINEAR_MAP_ARREA_END : *mut c_void = 0xffffc80000000000 as *mut c_void;
 pub const USER_STACK_TOP : *mut c_void = 0x00007ffffffff000 as *mut c_void;
 pub const USER_STACK_BOTTOM : *mut c_void = (0x00007ffffffff000 - MAX_USER_STACK_SIZE) as *mut c_void;
-pub const MMAP_START : *mut c_void = 0x30000000000000 as *mut c_void;
+pub const MMAP_START : *mut c_void = 0x300000000000 as *mut c_void;
+// address space randomization, each base is shifted down (stack, mmap) or up (pie, heap) by a random number of pages within its range
+pub const ELF_ET_DYN_BASE : u64 = 0x555555554000;
+pub const STACK_RND_RANGE : u64 = 1 << 34;
+pub const MMAP_RND_RANGE : u64 = 1 << 40;
+pub const ELF_ET_DYN_RND_RANGE : u64 = 1 << 40;
+pub const BRK_RND_RANGE : u64 = 1 << 25;
 
 bitflags::bitflags! {
     pub struct CloneFlags : u64
@@ -87,8 +94,19 @@ bitflags::bitflags! {
 
 
 
+// the page allocator already reclaimed and ran the oom killer for this,
+// a heap allocation can't fail back to its caller, so the task asking exits unless it is the kernel itself
 pub fn handle_alloc_error(layout : Layout) -> !
 {
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        if !pcb.is_null() && (*pcb).flags & PF_KTHREAD == 0
+        {
+            logk!("heap alloction error, layout = {:?}, killing process {}\n", layout, (*pcb).pid);
+            sys_exit(9);
+        }
+    }
     panic!("heap alloction error, layout = {:?}", layout);
 }
 unsafe impl GlobalAlloc for MemoryPool {
@@ -96,11 +114,22 @@ unsafe impl GlobalAlloc for MemoryPool {
         if layout.size() <= 2048
         {
             let kmem_cache = slub::kmalloc_slab(layout.size(), GFP::empty());
-            (*kmem_cache).alloc() as *mut u8
+            let ptr = (*kmem_cache).alloc() as *mut u8;
+            if ptr.is_null()
+            {
+                handle_alloc_error(layout);
+            }
+            ptr
         }
         else {
             let need_pages = (layout.size() / PAGE_SIZE) + ((layout.size() % PAGE_SIZE != 0) as usize);
-            MEMORY_POOL.alloc_frames(need_pages) as *mut u8
+            let ptr = MEMORY_POOL.alloc_frames(need_pages) as *mut u8;
+            if ptr.is_null()
+            {
+                handle_alloc_error(layout);
+            }
+            kmemleak::kmemleak_alloc(null(), ptr as *const c_void, layout.size());
+            ptr
         }
     }
 
@@ -109,6 +138,14 @@ unsafe impl GlobalAlloc for MemoryPool {
         {
             return;
         }
+        if layout.size() > 2048
+        {
+            kmemleak::kmemleak_free(null(), ptr as *const c_void);
+            MEMORY_POOL.free_frames(ptr as *mut c_void, layout.size().div_ceil(PAGE_SIZE));
+        }
+        else {
+            slub::kfree(ptr as *mut c_void);
+        }
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
@@ -144,8 +181,7 @@ pub struct MemoryPool
 {
     pub mem_map : *mut page::Page,
     lowest_idx : usize,
-    free_pages : usize,
-    frame_allocator : *mut LockedFrameAllocator
+    free_pages : usize // boot time only, page_alloc keeps the count afterwards
 }
 
 // struct BuddySystem
@@ -302,7 +338,7 @@ pub fn get_free_pointer(kmem_struck : &slub::KmemCache, object : *const c_void)
 pub fn set_free_pointer(kmem_struck : &slub::KmemCache, object : *const c_void, fp : *const c_void)
 {
     unsafe {
-        (*(object.offset(kmem_struck.offset.try_into().unwrap()) as *mut *const c_void)) = fp.offset(kmem_struck.offset.try_into().unwrap());
+        (*(object.offset(kmem_struck.offset.try_into().unwrap()) as *mut *const c_void)) = fp;
     }
 }
 
@@ -351,7 +387,7 @@ impl MemoryPool {
                 (*slub::SLAB_CACHES.offset((var + 1) as isize)).set_prev(slub::SLAB_CACHES.offset(var as isize));
                 var += 1
             }
-            (*slub::SLAB_CACHES.offset(0)).set_next(null_mut());
+            (*slub::SLAB_CACHES.offset(0)).set_prev(null_mut());
             (*slub::SLAB_CACHES.offset((slub::KMALLOC_CACHES_NUM - 1) as isize)).set_next(null_mut());
             // var = 0;
             // while var < slub::KMALLOC_CACHES_NUM {
@@ -369,45 +405,32 @@ impl MemoryPool {
             while var < slub::KMALLOC_CACHES_NUM {
                 (*kmem_cache_ptr) = slub::KmemCache::create_cache(slub::KMALLOC_INFO[var].name as *const str, slub::KMALLOC_INFO[var].size, slub::KMALLOC_INFO[var].size, null_mut(), Pageflags::PgSlab);
                 slub::KMALLOC_CACHES[var] = kmem_cache_ptr;
-                for node in &mut (*kmem_cache_ptr).node
-                {
-                    node.kmem_cache_node_bootstrap(&(*kmem_cache_ptr));
-                }
+                (*kmem_cache_ptr).bootstrap();
                 var += 1;
                 kmem_cache_ptr = kmem_cache_ptr.offset(1);
             }
         }
     }
 
+    // rounded up to a power of two block
     pub fn alloc_frames(&mut self, page_num : usize) -> *mut c_void
     {
-        unsafe
+        let result = page_alloc::get_free_pages(GFP::KERNEL, page_alloc::get_order(page_num * PAGE_SIZE));
+        if result.is_null()
         {
-            let mut unlocked_allocater = (*self.frame_allocator).lock();
-            let page_frame = unlocked_allocater.alloc(page_num);
-            match &page_frame
-            {
-                None => {
-                    logk!("out of memory!");
-                    return null_mut()
-                },
-                Some(start_frame) =>
-                {
-                    let mut var = 0;
-                    while var < page_num {
-                        (*self.mem_map.offset((start_frame + var) as isize))._refcount.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
-                        var += 1;
-                    }
-                    self.free_pages -= page_num;
-                    page2virt(*start_frame as u64)
-                }
-            }
+            logk!("out of memory!");
         }
+        result
+    }
+
+    pub fn free_frames(&mut self, addr : *mut c_void, page_num : usize)
+    {
+        page_alloc::free_pages(addr, page_alloc::get_order(page_num * PAGE_SIZE));
     }
 
     const fn new() -> MemoryPool
     {
-        let memory_pool = MemoryPool{ mem_map : null_mut(), lowest_idx : 0, free_pages : 0, frame_allocator: null_mut() }; // kernel_vmem_pool: BuddySystem { bucket: [MemorySpan::new(); MAX_ORDER], lock: semaphore::SpinLock::new(1), current_vmemory: null_mut() } 
+        let memory_pool = MemoryPool{ mem_map : null_mut(), lowest_idx : 0, free_pages : 0 }; // kernel_vmem_pool: BuddySystem { bucket: [MemorySpan::new(); MAX_ORDER], lock: semaphore::SpinLock::new(1), current_vmemory: null_mut() } 
         memory_pool
     }
 
@@ -433,7 +456,13 @@ impl MemoryPool {
             compiler_builtins::mem::memset(self.mem_map as *mut u8, 0, size_of::<page::Page>() * memory_descriptor.all_pages);
             self.free_pages -= used_page;
             self.lowest_idx += used_page;
+            let image_end = relocation::KERNEL_SIZE;
             self.init_pml4(pml4_position as *mut Pml4, pml4_position - KERNEL_START + 0x100000 + PAGE_SIZE, (KERNEL_START - 0x100000) as *mut c_void, 0x0 as *mut c_void);
+            if kaslr::kaslr_enabled()
+            {
+                // the code runs in the randomized window, keep it mapped across the cr3 switch
+                self.init_pml4(pml4_position as *mut Pml4, image_end - VIRTADDR_START, kaslr::kernel_map_base() as *mut c_void, 0x0 as *mut c_void);
+            }
             Self::init_linear_map_area(pml4_position as *mut Pml4);
             self.init_used_page_counter(used_page + 1);
             printk!("Pml4: {}", (*(pml4_position as *mut Pml4)).entry[272]);
@@ -442,9 +471,7 @@ impl MemoryPool {
             //(*self.frame_allocator).lock().add_frame(start, end);
             set_cr3_reg(pml4_position as *const c_void);
             self.kmalloc_bootstrap();
-            self.frame_allocator = Box::leak(Box::new(LockedFrameAllocator::new()));
-            (*self.frame_allocator).lock().insert(Range{start: self.lowest_idx, end: self.lowest_idx + 32 });
-            (*self.frame_allocator).lock().insert(Range{start: self.lowest_idx + 32, end: MEMORY_DESCRIPTOR.all_pages });
+            page_alloc::free_area_init(self.lowest_idx, MEMORY_DESCRIPTOR.all_pages);
         }
     }
     #[no_mangle]
@@ -759,7 +786,7 @@ struct Pt
     entry : [PtEntry; 512]
 }
 type PdptEntry = Pml4Entry;
-type PdtEntry = Pml4Entry;
+pub type PdtEntry = Pml4Entry;
 type PdEntry = Pml4Entry;
 
 bitfield!
@@ -799,12 +826,12 @@ impl Display for Pml4Entry {
 
 bitfield!
 {
-    struct PtEntry(u64);
+    pub struct PtEntry(u64);
     u64;
     // 0 exist in memory
-    get_present, set_present : 0, 0;
+    pub get_present, set_present : 0, 0;
     // 0 readonly / 1 read & writable
-    get_wr, set_wr : 1, 1;
+    pub get_wr, set_wr : 1, 1;
     // 0 supervisor / 1 everyone
     get_us, set_us : 2, 2;
     // 1 Write Through / 0 Write Back
@@ -812,16 +839,29 @@ bitfield!
     // page cache disable
     get_pcd, set_pcd : 4, 4;
     // page accessed
-    get_accessed, set_accessed : 5, 5;
+    pub get_accessed, set_accessed : 5, 5;
     // dirty
-    get_dirty, set_dirty : 6, 6;
+    pub get_dirty, set_dirty : 6, 6;
     // page size
     get_pat, set_pat : 7, 7;
     // global
     get_global, set_global : 8, 8;
     // avaliable
     get_avl, set_avl : 11, 9;
-    get_page_offset, set_page_offset : 63, 12;
+    pub get_page_offset, set_page_offset : 63, 12;
+}
+
+impl PtEntry {
+    // the whole entry, a swap entry when present is clear
+    pub fn raw(&self) -> u64
+    {
+        self.0
+    }
+
+    pub fn set_raw(&mut self, value : u64)
+    {
+        self.0 = value;
+    }
 }
 
 #[inline(always)]
@@ -949,7 +989,7 @@ unsafe fn get_kernel_size() -> usize
     relocation::KERNEL_SIZE - KERNEL_START
 }
 
-pub fn copy_page_table(src_pcb : *mut PCB, clone_flags : &CloneFlags) -> *mut c_void
+pub fn copy_page_table(src_pcb : *mut PCB, dst_mm : *mut MMStruct, clone_flags : &CloneFlags) -> *mut c_void
 {
     unsafe
     {
@@ -961,7 +1001,7 @@ pub fn copy_page_table(src_pcb : *mut PCB, clone_flags : &CloneFlags) -> *mut c_
         }
         let mut vma = (*src_pcb).mm.mmap;
         while !vma.is_null() {
-            arch_copy_page_table(dst, phys2virt((*src_pcb).pml4 as *mut c_void) as *mut c_void, (*vma).get_start() as *mut c_void, (*vma).get_end() as *mut c_void, &clone_flags);
+            arch_copy_page_table(dst, phys2virt((*src_pcb).pml4 as *mut c_void) as *mut c_void, (*vma).get_start() as *mut c_void, (*vma).get_end() as *mut c_void, dst_mm, &clone_flags);
             vma = (*vma).get_next();
         }
         arch_copy_kernel_space(dst, phys2virt((*src_pcb).pml4 as *mut c_void));
@@ -982,12 +1022,12 @@ fn x86_64_copy_kernel_space(dst : *mut Pml4, src : *const Pml4)
     }
 }
 
-pub fn arch_copy_page_table(dst : *mut c_void, src : *mut c_void, start : *mut c_void, end : *mut c_void, clone_flags : &CloneFlags)
+pub fn arch_copy_page_table(dst : *mut c_void, src : *mut c_void, start : *mut c_void, end : *mut c_void, dst_mm : *mut MMStruct, clone_flags : &CloneFlags)
 {
-    x86_64_copy_pml4(dst as *mut Pml4, src as *mut Pml4, start, end, clone_flags);
+    x86_64_copy_pml4(dst as *mut Pml4, src as *mut Pml4, start, end, dst_mm, clone_flags);
 }
 
-fn x86_64_copy_pml4(dst : *mut Pml4, src : *mut Pml4, mut start : *mut c_void, end : *mut c_void, clone_flags : &CloneFlags)
+fn x86_64_copy_pml4(dst : *mut Pml4, src : *mut Pml4, mut start : *mut c_void, end : *mut c_void, dst_mm : *mut MMStruct, clone_flags : &CloneFlags)
 {
     unsafe
     {
@@ -1068,12 +1108,35 @@ fn x86_64_copy_pml4(dst : *mut Pml4, src : *mut Pml4, mut start : *mut c_void, e
                     let max_pt = (start as u64 & 0xffffffffffe00000) + (1 << 21) - 1;
                     while (start as u64) < max_pt && start < end {
                         let pt_no = get_pt_offset(start);
-                        if (*src_pt_ptr).entry[pt_no].get_wr() != 0
+                        if (*src_pt_ptr).entry[pt_no].get_present() != 0
+                        {
+                            let desc = pte_page((*src_pt_ptr).entry[pt_no].0);
+                            // page cache pages of shared mappings stay shared
+                            if !desc.is_null() && rmap::page_file(&*desc)
+                            {
+                                page_alloc::get_page(&*desc);
+                                rmap::page_add_file_rmap(&*desc);
+                                (*dst_mm).inc_mm_counter(mm_counter_file(&*desc));
+                            }
+                            else {
+                                // both sides fault on the next write and copy_on_write sorts it out
+                                if (*src_pt_ptr).entry[pt_no].get_wr() != 0
+                                {
+                                    (*src_pt_ptr).entry[pt_no].set_wr(0);
+                                    flush_tlb(start);
+                                }
+                                if !desc.is_null() && rmap::page_anon(&*desc)
+                                {
+                                    page_alloc::get_page(&*desc);
+                                    rmap::page_add_anon_rmap(desc, dst_mm, start as u64);
+                                    (*dst_mm).inc_mm_counter(MMCounter::AnonPages);
+                                }
+                            }
+                        }
+                        else if swapfile::is_swap_pte((*src_pt_ptr).entry[pt_no].0)
                         {
-                            (*src_pt_ptr).entry[pt_no].set_wr(0);
-                            flush_tlb(start);
-                            let desc = MemoryPool::get_page_descripter(phys2page(((*src_pt_ptr).entry[pt_no].get_page_offset() << PAGE_SHIFT) as *const c_void) as isize);
-                            (*desc)._refcount.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+                            swapfile::swap_duplicate(swapfile::pte_to_swp_entry((*src_pt_ptr).entry[pt_no].0));
+                            (*dst_mm).inc_mm_counter(MMCounter::SwapEnts);
                         }
                         (*dst_pt_ptr).entry[pt_no].0 = (*src_pt_ptr).entry[pt_no].0;
                         start = start.offset(PAGE_SIZE as isize);
@@ -1085,27 +1148,56 @@ fn x86_64_copy_pml4(dst : *mut Pml4, src : *mut Pml4, mut start : *mut c_void, e
     }
 }
 
+// grub and qemu -kernel describe memory with the same types the bios does
+unsafe fn multiboot_memory_map(address : *const c_void)
+{
+    let tag = multiboot::multiboot_find_tag(address, multiboot::MULTIBOOT_TAG_TYPE_MMAP) as *const multiboot::MultibootTagMmap;
+    if tag.is_null()
+    {
+        panic!("no memory map from the boot loader");
+    }
+    let end = (tag as *const u8).add((*tag).size as usize);
+    let mut entry = tag.add(1) as *const u8;
+    while entry < end {
+        let e820map_addr = entry as *const E820Map;
+        printk!("{}", (*e820map_addr));
+        if (*e820map_addr).memory_type == 1
+        {
+            get_useable_memory(e820map_addr);
+        }
+        entry = entry.add((*tag).entry_size as usize);
+    }
+}
+
+// magic and address are what a multiboot loader handed over, 0 and null for the loader in boot/
 #[__init]
-pub fn init_memory(magic : u32, address : *const c_void)
+pub unsafe fn init_memory(magic : u32, address : *const c_void)
 {
-    let mut e820map_addr : *mut E820Map = ARDS_BUFFER as *mut E820Map;
-    print_ards(e820map_addr);
     unsafe
     {
-        e820map_addr = e820map_addr.offset(1);
-        loop {
-            match (*e820map_addr).memory_type {
-                1 => {
-                    get_useable_memory(e820map_addr);
-                    e820map_addr = e820map_addr.offset(1);
-                    continue;
-                },
-                2 | 3 => {
-                    e820map_addr = e820map_addr.offset(1);
-                    continue;
-                },
-                _ => break
-            } 
+        if magic == multiboot::MULTIBOOT2_BOOTLOADER_MAGIC || magic == multiboot::MULTIBOOT_BOOTLOADER_MAGIC
+        {
+            multiboot_memory_map(address);
+        }
+        else
+        {
+            let mut e820map_addr : *mut E820Map = ARDS_BUFFER as *mut E820Map;
+            print_ards(e820map_addr);
+            e820map_addr = e820map_addr.offset(1);
+            loop {
+                match (*e820map_addr).memory_type {
+                    1 => {
+                        get_useable_memory(e820map_addr);
+                        e820map_addr = e820map_addr.offset(1);
+                        continue;
+                    },
+                    2 | 3 => {
+                        e820map_addr = e820map_addr.offset(1);
+                        continue;
+                    },
+                    _ => break
+                } 
+            }
         }
         get_page_size();
         printk!("total page num: {}\n", MEMORY_DESCRIPTOR.all_pages);
@@ -1113,7 +1205,6 @@ pub fn init_memory(magic : u32, address : *const c_void)
         bochs_break!();
         MEMORY_POOL.init(&mut *addr_of_mut!(MEMORY_DESCRIPTOR));
         set_interrupt_handler(page_fault as interrupt::HandlerFn, interrupt::INTR_PF as u8);
-        sched::RUNNING_PROCESS.resize(get_cpu_number(), null_mut());
     }
 }
 
@@ -1183,13 +1274,459 @@ fn change_page_prot_by_prot_bit(vaddr : *const c_void, prot_bit : u64)
     }
 }
 
-pub fn link_user_page(vaddr : *const c_void, prot_bit : u64)
+// false when no page could be had, the oom victim gets none
+pub fn link_user_page(vaddr : *const c_void, prot_bit : u64) -> bool
 {
     unsafe
     {
-        let new_page = MEMORY_POOL.alloc_frames(1);
+        let new_page = page_alloc::get_free_pages(GFP::USER, 0);
+        if new_page.is_null()
+        {
+            return false;
+        }
+        let mm = addr_of_mut!((*get_current_running_process()).mm);
+        rmap::page_add_new_anon_rmap(page_alloc::virt_to_page(new_page), mm, get_page_start(vaddr) as u64);
+        vmscan::lru_cache_add(page_alloc::virt_to_page(new_page));
+        (*mm).inc_mm_counter(MMCounter::AnonPages);
         link_user_page_by_prot_bit(get_page_start(vaddr), virt2phys(new_page), prot_bit);
+        true
+    }
+}
+
+// page descriptor behind a present pte, null for frames outside mem_map
+pub fn pte_page(pte : u64) -> *mut page::Page
+{
+    unsafe
+    {
+        let idx = phys2page((pte & 0x000ffffffffff000) as *const c_void) as usize;
+        if idx >= MEMORY_DESCRIPTOR.all_pages
+        {
+            return null_mut();
+        }
+        MemoryPool::get_page_descripter(idx as isize)
+    }
+}
+
+// pte of `vaddr` under `pml4` without allocating tables
+// on a missing level returns null and sets `step` to the size that level covers
+unsafe fn lookup_pte(pml4 : *mut Pml4, vaddr : *const c_void, step : &mut u64) -> *mut PtEntry
+{
+    let pml4_entry = &(*pml4).entry[get_pml4_offset(vaddr)];
+    if pml4_entry.get_present() == 0
+    {
+        *step = 1 << 39;
+        return null_mut();
+    }
+    let pdpt = phys2virt((pml4_entry.get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdpt;
+    let pdpt_entry = &(*pdpt).entry[get_pdpt_offset(vaddr)];
+    if pdpt_entry.get_present() == 0 || pdpt_entry.get_ps() != 0
+    {
+        *step = 1 << 30;
+        return null_mut();
+    }
+    let pdt = phys2virt((pdpt_entry.get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdt;
+    let pdt_entry = &(*pdt).entry[get_pdt_offset(vaddr)];
+    if pdt_entry.get_present() == 0 || pdt_entry.get_ps() != 0
+    {
+        *step = 1 << 21;
+        return null_mut();
+    }
+    *step = PAGE_SIZE as u64;
+    let pt = phys2virt((pdt_entry.get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pt;
+    &mut (*pt).entry[get_pt_offset(vaddr)]
+}
+
+// pte of `vaddr` in the address space of `mm`, see lookup_pte
+pub unsafe fn follow_pte(mm : *mut MMStruct, vaddr : u64, step : &mut u64) -> *mut PtEntry
+{
+    let pml4_phys = (*(*mm).pcb_ptr).pml4;
+    if pml4_phys.is_null()
+    {
+        *step = 1 << 39;
+        return null_mut();
+    }
+    lookup_pte(phys2virt(pml4_phys as *const c_void) as *mut Pml4, vaddr as *const c_void, step)
+}
+
+// pde of `vaddr` under `pml4`, null when the page directory is missing
+unsafe fn lookup_pmd(pml4 : *mut Pml4, vaddr : *const c_void) -> *mut PdtEntry
+{
+    let pml4_entry = &(*pml4).entry[get_pml4_offset(vaddr)];
+    if pml4_entry.get_present() == 0
+    {
+        return null_mut();
+    }
+    let pdpt = phys2virt((pml4_entry.get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdpt;
+    let pdpt_entry = &(*pdpt).entry[get_pdpt_offset(vaddr)];
+    if pdpt_entry.get_present() == 0 || pdpt_entry.get_ps() != 0
+    {
+        return null_mut();
+    }
+    let pdt = phys2virt((pdpt_entry.get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdt;
+    &mut (*pdt).entry[get_pdt_offset(vaddr)]
+}
+
+pub unsafe fn follow_pmd(mm : *mut MMStruct, vaddr : u64) -> *mut PdtEntry
+{
+    let pml4_phys = (*(*mm).pcb_ptr).pml4;
+    if pml4_phys.is_null()
+    {
+        return null_mut();
+    }
+    lookup_pmd(phys2virt(pml4_phys as *const c_void) as *mut Pml4, vaddr as *const c_void)
+}
+
+// pde of `vaddr` in `mm`, the pml4 and pdpt levels above it are allocated on the way
+pub unsafe fn pmd_alloc(mm : *mut MMStruct, vaddr : u64) -> *mut PdtEntry
+{
+    let vaddr = vaddr as *const c_void;
+    let pml4 = phys2virt((*(*mm).pcb_ptr).pml4 as *const c_void) as *mut Pml4;
+    if (*pml4).entry[get_pml4_offset(vaddr)].get_present() == 0
+    {
+        let new_pdpt = page_alloc::get_free_pages(GFP::KERNEL | GFP::__ZERO, 0);
+        if new_pdpt.is_null()
+        {
+            return null_mut();
+        }
+        MemoryPool::set_pml4(pml4, vaddr, virt2phys(new_pdpt), false, false, true);
+    }
+    let pdpt = phys2virt(((*pml4).entry[get_pml4_offset(vaddr)].get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdpt;
+    if (*pdpt).entry[get_pdpt_offset(vaddr)].get_present() == 0
+    {
+        let new_pdt = page_alloc::get_free_pages(GFP::KERNEL | GFP::__ZERO, 0);
+        if new_pdt.is_null()
+        {
+            return null_mut();
+        }
+        MemoryPool::set_pdpt(pdpt, vaddr, virt2phys(new_pdt), false, false, true);
+    }
+    let pdt = phys2virt(((*pdpt).entry[get_pdpt_offset(vaddr)].get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdt;
+    &mut (*pdt).entry[get_pdt_offset(vaddr)]
+}
+
+// pte of `vaddr` in `mm`, a missing page table is allocated on the way
+// null when out of memory or when a huge page maps `vaddr`
+pub unsafe fn pte_alloc(mm : *mut MMStruct, vaddr : u64) -> *mut PtEntry
+{
+    let pmd = pmd_alloc(mm, vaddr);
+    if pmd.is_null() || pmd_trans_huge(&*pmd)
+    {
+        return null_mut();
+    }
+    if (*pmd).get_present() == 0
+    {
+        let pt = page_alloc::get_free_pages(GFP::KERNEL | GFP::__ZERO, 0);
+        if pt.is_null()
+        {
+            return null_mut();
+        }
+        (*pmd).0 = 0;
+        (*pmd).set_page_offset(MemoryPool::get_page_idx(virt2phys(pt)));
+        (*pmd).set_present(1);
+        (*pmd).set_wr(1);
+        (*pmd).set_us(1);
+    }
+    let pt = phys2virt(((*pmd).get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pt;
+    &mut (*pt).entry[get_pt_offset(vaddr as *const c_void)]
+}
+
+// page mapped at `vaddr`, the head page for a huge mapping, null when nothing is present
+pub unsafe fn follow_page(mm : *mut MMStruct, vaddr : u64) -> *mut page::Page
+{
+    let pmd = follow_pmd(mm, vaddr);
+    if !pmd.is_null() && pmd_trans_huge(&*pmd)
+    {
+        return pmd_page(&*pmd);
+    }
+    let mut step = PAGE_SIZE as u64;
+    let pte = follow_pte(mm, vaddr, &mut step);
+    if pte.is_null() || (*pte).get_present() == 0
+    {
+        return null_mut();
+    }
+    pte_page((*pte).0)
+}
+
+#[inline(always)]
+pub fn pmd_none(pmd : &PdtEntry) -> bool
+{
+    pmd.get_present() == 0
+}
+
+// the pde maps a 2 MiB page itself instead of pointing at a page table
+#[inline(always)]
+pub fn pmd_trans_huge(pmd : &PdtEntry) -> bool
+{
+    pmd.get_present() != 0 && pmd.get_ps() != 0
+}
+
+pub fn pmd_dirty(pmd : &PdtEntry) -> bool
+{
+    pmd.get_dirty() != 0
+}
+
+pub fn pmd_young(pmd : &PdtEntry) -> bool
+{
+    pmd.get_accessed() != 0
+}
+
+// head page descriptor behind a huge pde
+pub fn pmd_page(pmd : &PdtEntry) -> *mut page::Page
+{
+    pte_page(pmd.0 & 0x000fffffffe00000)
+}
+
+// map the 2 MiB frame at `paddr` with one pde, `prot` as in VMAreaStruct::get_prot
+pub unsafe fn set_pmd_huge(pmd : *mut PdtEntry, paddr : *const c_void, prot : u64)
+{
+    (*pmd).0 = 0;
+    (*pmd).set_page_offset(MemoryPool::get_page_idx(paddr));
+    (*pmd).0 |= prot;
+    (*pmd).set_ps(1);
+}
+
+pub unsafe fn pmd_clear(pmd : *mut PdtEntry, vaddr : u64)
+{
+    (*pmd).0 = 0;
+    flush_tlb(vaddr as *const c_void);
+}
+
+// hand whatever `old_pmd` maps to the empty `new_pmd`
+pub unsafe fn pmd_move(old_pmd : *mut PdtEntry, old_vaddr : u64, new_pmd : *mut PdtEntry)
+{
+    (*new_pmd).0 = (*old_pmd).0;
+    pmd_clear(old_pmd, old_vaddr);
+}
+
+pub unsafe fn pmd_set_wr(pmd : *mut PdtEntry, vaddr : u64, writable : bool)
+{
+    (*pmd).set_wr(writable as u64);
+    flush_tlb(vaddr as *const c_void);
+}
+
+// give a huge pde a page table mapping the same frames 4 KiB each with the same protection
+pub unsafe fn pmd_split_to_pt(pmd : *mut PdtEntry, haddr : u64) -> Err
+{
+    let pt = page_alloc::get_free_pages(GFP::KERNEL | GFP::__ZERO, 0) as *mut Pt;
+    if pt.is_null()
+    {
+        return -ENOMEM;
+    }
+    let paddr = (*pmd).0 & 0x000fffffffe00000;
+    // present, writable, user, accessed and dirty carry over to every pte
+    let prot = (*pmd).0 & 0x67;
+    let mut var = 0;
+    while var < 512 {
+        (*pt).entry[var].0 = (paddr + (var * PAGE_SIZE) as u64) | prot;
+        var += 1;
+    }
+    (*pmd).0 = 0;
+    (*pmd).set_page_offset(MemoryPool::get_page_idx(virt2phys(pt as *const c_void)));
+    (*pmd).set_present(1);
+    (*pmd).set_wr(1);
+    (*pmd).set_us(1);
+    flush_tlb(haddr as *const c_void);
+    0
+}
+
+// give the pml4 slot of `vaddr` a page directory pointer table now,
+// task page tables copy the kernel half at fork and share it from then on
+#[__init]
+pub unsafe fn preallocate_kernel_pdpt(vaddr : u64)
+{
+    let pml4 = phys2virt((get_cr3_reg() & 0xfffffffffffff000) as *const c_void) as *mut Pml4;
+    let pdpt = page_alloc::get_free_pages(GFP::KERNEL | GFP::__ZERO, 0);
+    assert!(!pdpt.is_null(), "out of memory for kernel page tables");
+    MemoryPool::set_pml4(pml4, vaddr as *const c_void, virt2phys(pdpt), false, true, true);
+}
+
+// map one kernel page, the tables below the pml4 are allocated on the way
+pub unsafe fn map_kernel_page(vaddr : u64, paddr : *const c_void, writable : bool) -> Err
+{
+    let vaddr = vaddr as *const c_void;
+    let pml4 = phys2virt((get_cr3_reg() & 0xfffffffffffff000) as *const c_void) as *mut Pml4;
+    let pml4_entry = &(*pml4).entry[get_pml4_offset(vaddr)];
+    assert!(pml4_entry.get_present() != 0, "kernel pml4 slot of {:#x} was never populated", vaddr as u64);
+    let pdpt = phys2virt((pml4_entry.get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdpt;
+    if (*pdpt).entry[get_pdpt_offset(vaddr)].get_present() == 0
+    {
+        let new_pdt = page_alloc::get_free_pages(GFP::KERNEL | GFP::__ZERO, 0);
+        if new_pdt.is_null()
+        {
+            return -ENOMEM;
+        }
+        MemoryPool::set_pdpt(pdpt, vaddr, virt2phys(new_pdt), false, true, true);
+    }
+    let pdt = phys2virt(((*pdpt).entry[get_pdpt_offset(vaddr)].get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pdt;
+    if (*pdt).entry[get_pdt_offset(vaddr)].get_present() == 0
+    {
+        let new_pt = page_alloc::get_free_pages(GFP::KERNEL | GFP::__ZERO, 0);
+        if new_pt.is_null()
+        {
+            return -ENOMEM;
+        }
+        MemoryPool::set_pdt(pdt, vaddr, virt2phys(new_pt), false, true, true);
+    }
+    let pt = phys2virt(((*pdt).entry[get_pdt_offset(vaddr)].get_page_offset() << PAGE_SHIFT) as *const c_void) as *mut Pt;
+    let pte = &mut (*pt).entry[get_pt_offset(vaddr)];
+    assert!(pte.get_present() == 0, "kernel page {:#x} is already mapped", vaddr as u64);
+    MemoryPool::set_pt(pte, paddr, true, writable, false, false, false, false, false, false, false);
+    0
+}
+
+// clear the pte of a kernel page, the page tables stay for the next mapping
+pub unsafe fn unmap_kernel_page(vaddr : u64)
+{
+    let pml4 = phys2virt((get_cr3_reg() & 0xfffffffffffff000) as *const c_void) as *mut Pml4;
+    let mut step = 0;
+    let pte = lookup_pte(pml4, vaddr as *const c_void, &mut step);
+    if !pte.is_null()
+    {
+        (*pte).set_raw(0);
+        flush_tlb(vaddr as *const c_void);
+    }
+}
+
+// frame behind a mapped kernel page, null when nothing is mapped there
+pub unsafe fn kernel_page_phys(vaddr : u64) -> *mut c_void
+{
+    let pml4 = phys2virt((get_cr3_reg() & 0xfffffffffffff000) as *const c_void) as *mut Pml4;
+    let mut step = 0;
+    let pte = lookup_pte(pml4, vaddr as *const c_void, &mut step);
+    if pte.is_null() || (*pte).get_present() == 0
+    {
+        return null_mut();
+    }
+    (((*pte).get_page_offset() << PAGE_SHIFT) | (vaddr & (PAGE_SIZE as u64 - 1))) as *mut c_void
+}
+
+// read the slot a swap pte of `mm` points at into a new page and map that with `prot`
+// every swapped out mapping gets a copy of its own, there is no swap cache to share one
+pub unsafe fn swapin_pte(mm : *mut MMStruct, pte : *mut PtEntry, vaddr : u64, prot : u64) -> Err
+{
+    let entry = swapfile::pte_to_swp_entry((*pte).0);
+    let page = page_alloc::alloc_pages(GFP::USER, 0);
+    if page.is_null()
+    {
+        return -ENOMEM;
+    }
+    let error = swapfile::swap_readpage(page, entry);
+    if error < 0
+    {
+        page_alloc::put_page(page);
+        return error;
+    }
+    rmap::page_add_new_anon_rmap(page, mm, vaddr);
+    vmscan::lru_cache_add(page);
+    (*pte).0 = 0;
+    (*pte).set_page_offset(MemoryPool::get_page_idx(virt2phys(page_alloc::page_address(page))));
+    (*pte).0 |= prot;
+    (*mm).inc_mm_counter(MMCounter::AnonPages);
+    (*mm).dec_mm_counter(MMCounter::SwapEnts);
+    swapfile::swap_free(entry);
+    flush_tlb(vaddr as *const c_void);
+    0
+}
+
+// unmap [start, end) of `mm`, a frame goes back to the allocator only when its last mapping goes
+// -ENOMEM when a huge page only partly in the range could not be split, what came before it is unmapped
+pub unsafe fn zap_page_range(mm : *mut MMStruct, start : u64, end : u64) -> Err
+{
+    let pml4_phys = (*(*mm).pcb_ptr).pml4;
+    if pml4_phys.is_null()
+    {
+        return 0;
+    }
+    let pml4 = phys2virt(pml4_phys as *const c_void) as *mut Pml4;
+    let mut addr = start & !(PAGE_SIZE as u64 - 1);
+    while addr < end {
+        let pmd = lookup_pmd(pml4, addr as *const c_void);
+        if !pmd.is_null() && pmd_trans_huge(&*pmd)
+        {
+            let haddr = addr & huge_memory::HPAGE_PMD_MASK;
+            if addr == haddr && end >= haddr + huge_memory::HPAGE_PMD_SIZE
+            {
+                huge_memory::zap_huge_pmd(mm, pmd, haddr);
+                addr = haddr + huge_memory::HPAGE_PMD_SIZE;
+                continue;
+            }
+            let err = huge_memory::split_huge_pmd(mm, haddr);
+            if err < 0
+            {
+                return err;
+            }
+        }
+        let mut step = PAGE_SIZE as u64;
+        let pte = lookup_pte(pml4, addr as *const c_void, &mut step);
+        if !pte.is_null() && (*pte).get_present() != 0
+        {
+            let desc = pte_page((*pte).0);
+            if !desc.is_null() && rmap::page_anon(&*desc)
+            {
+                rmap::page_remove_rmap(desc, mm, addr);
+                page_alloc::put_page(desc);
+                (*mm).dec_mm_counter(MMCounter::AnonPages);
+            }
+            else if !desc.is_null() && rmap::page_file(&*desc)
+            {
+                rmap::page_remove_file_rmap(&*desc);
+                page_alloc::put_page(desc);
+                (*mm).dec_mm_counter(mm_counter_file(&*desc));
+            }
+            (*pte).0 = 0;
+            flush_tlb(addr as *const c_void);
+        }
+        else if !pte.is_null() && swapfile::is_swap_pte((*pte).0)
+        {
+            swapfile::swap_free(swapfile::pte_to_swp_entry((*pte).0));
+            (*mm).dec_mm_counter(MMCounter::SwapEnts);
+            (*pte).0 = 0;
+        }
+        addr = (addr & !(step - 1)) + step;
+    }
+    0
+}
+
+// give the present ptes of [start, end) in `mm` the protection `prot`
+// private ptes only lose write access here, copy_on_write hands it back on the next write
+pub unsafe fn change_protection(mm : *mut MMStruct, start : u64, end : u64, prot : u64, shared : bool) -> Err
+{
+    let pml4_phys = (*(*mm).pcb_ptr).pml4;
+    if pml4_phys.is_null()
+    {
+        return 0;
+    }
+    let pml4 = phys2virt(pml4_phys as *const c_void) as *mut Pml4;
+    let writable = arch_check_prot_writable(prot);
+    let mut addr = start;
+    while addr < end {
+        let pmd = lookup_pmd(pml4, addr as *const c_void);
+        if !pmd.is_null() && pmd_trans_huge(&*pmd)
+        {
+            let haddr = addr & huge_memory::HPAGE_PMD_MASK;
+            if addr == haddr && end >= haddr + huge_memory::HPAGE_PMD_SIZE
+            {
+                // a huge page has a single mapper, nothing to copy on write
+                pmd_set_wr(pmd, haddr, writable);
+                addr = haddr + huge_memory::HPAGE_PMD_SIZE;
+                continue;
+            }
+            let err = huge_memory::split_huge_pmd(mm, haddr);
+            if err < 0
+            {
+                return err;
+            }
+        }
+        let mut step = PAGE_SIZE as u64;
+        let pte = lookup_pte(pml4, addr as *const c_void, &mut step);
+        if !pte.is_null() && (*pte).get_present() != 0 && (!writable || shared)
+        {
+            (*pte).set_wr(writable as u64);
+            flush_tlb(addr as *const c_void);
+        }
+        addr = (addr & !(step - 1)) + step;
     }
+    0
 }
 
 fn arch_check_prot_writable(prot : u64) ->bool
@@ -1202,7 +1739,18 @@ fn x86_64_check_prot_writable(prot : u64) -> bool
     (prot & 0x2) != 0
 }
 
-fn copy_on_write(vaddr : *const c_void)
+fn arch_clear_prot_writable(prot : u64) -> u64
+{
+    x86_64_clear_prot_writable(prot)
+}
+
+fn x86_64_clear_prot_writable(prot : u64) -> u64
+{
+    prot & !0x2
+}
+
+// false when there was no page to copy into, the pte stays read-only
+fn copy_on_write(vaddr : *const c_void) -> bool
 {
     unsafe
     {
@@ -1229,20 +1777,78 @@ fn copy_on_write(vaddr : *const c_void)
         }
         let pt = phys2virt(((*pdt).entry[pdt_offset].get_page_offset() << 12) as *const c_void) as *mut Pt;
         let pt_offset = get_pt_offset(vaddr);
-        let page = phys2page(((*pt).entry[pt_offset].get_page_offset() << PAGE_SHIFT) as *mut c_void);
-        let desc = MemoryPool::get_page_descripter(page as isize);
-        if (*desc)._refcount.fetch_sub(1, core::sync::atomic::Ordering::AcqRel) > 1
+        let desc = pte_page((*pt).entry[pt_offset].0);
+        let mm = addr_of_mut!((*get_current_running_process()).mm);
+        let page_vaddr = get_page_start(vaddr) as u64;
+        // the last mapper of an anonymous page just takes it over, a merged page stays read-only for ksmd
+        if desc.is_null() || !rmap::page_anon(&*desc) || rmap::page_mapcount(&*desc) > 1 || ksm::page_ksm(&*desc)
         {
-            let new_page = alloc(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()) as *mut c_void;
-            compiler_builtins::mem::memcpy(new_page as *mut u8, page2virt(page) as *const u8, PAGE_SIZE);
+            let new_page = page_alloc::get_free_pages(GFP::USER, 0);
+            if new_page.is_null()
+            {
+                return false;
+            }
+            compiler_builtins::mem::memcpy(new_page as *mut u8, phys2virt(((*pt).entry[pt_offset].get_page_offset() << PAGE_SHIFT) as *const c_void) as *const u8, PAGE_SIZE);
+            rmap::page_add_new_anon_rmap(page_alloc::virt_to_page(new_page), mm, page_vaddr);
+            vmscan::lru_cache_add(page_alloc::virt_to_page(new_page));
+            (*mm).inc_mm_counter(MMCounter::AnonPages);
             (*pt).entry[pt_offset].set_page_offset(MemoryPool::get_page_idx(virt2phys(new_page)));
+            if !desc.is_null() && rmap::page_anon(&*desc)
+            {
+                rmap::page_remove_rmap(desc, mm, page_vaddr);
+                // a locked area now maps the copy instead
+                mlock::munlock_page(desc);
+                page_alloc::put_page(desc);
+                (*mm).dec_mm_counter(MMCounter::AnonPages);
+            }
+            else if !desc.is_null() && rmap::page_file(&*desc)
+            {
+                rmap::page_remove_file_rmap(&*desc);
+                mlock::munlock_page(desc);
+                page_alloc::put_page(desc);
+                (*mm).dec_mm_counter(mm_counter_file(&*desc));
+            }
         }
-        else
+        (*pt).entry[pt_offset].set_wr(1);
+        flush_tlb(vaddr);
+        true
+    }
+}
+
+// fault in [start, end) of `vma` in the current mm as if the user touched every page
+// writable private areas are written to, so copy on write is broken up front
+pub unsafe fn populate_vma_page_range(vma : *mut VMAreaStruct, start : u64, end : u64)
+{
+    let mm = addr_of_mut!((*get_current_running_process()).mm);
+    let write = arch_check_prot_writable((*vma).get_prot()) && !(*vma).get_flags().contains(MmapType::MAP_SHARED);
+    let mut error = PageFaultErrorCode::USER;
+    if write
+    {
+        error.insert(PageFaultErrorCode::WRITE);
+    }
+    let mut addr = start;
+    while addr < end {
+        let pmd = follow_pmd(mm, addr);
+        if !pmd.is_null() && pmd_trans_huge(&*pmd)
         {
-            (*desc)._refcount.fetch_add(1, core::sync::atomic::Ordering::AcqRel);
+            if write
+            {
+                huge_memory::do_huge_pmd_wp_page(addr);
+            }
+            addr = (addr & huge_memory::HPAGE_PMD_MASK) + huge_memory::HPAGE_PMD_SIZE;
+            continue;
         }
-        (*pt).entry[pt_offset].set_wr(1);
-        flush_tlb(vaddr)
+        let mut step = PAGE_SIZE as u64;
+        let pte = follow_pte(mm, addr, &mut step);
+        if pte.is_null() || (*pte).get_present() == 0
+        {
+            page_fault_page_not_exist(error, vma, addr as *const c_void);
+        }
+        else if write && (*pte).get_wr() == 0 && !copy_on_write(addr as *const c_void)
+        {
+            fault_kill(error, FAULT_OOM_CODE);
+        }
+        addr += PAGE_SIZE as u64;
     }
 }
 
@@ -1258,7 +1864,14 @@ fn page_fault_page_exist(error : PageFaultErrorCode, vma : *mut VMAreaStruct, pg
         {
             if arch_check_prot_writable((*vma).get_prot())
             {
-                copy_on_write(pg_fault_pos);
+                if huge_memory::do_huge_pmd_wp_page(pg_fault_pos as u64)
+                {
+                    return;
+                }
+                if !copy_on_write(pg_fault_pos)
+                {
+                    fault_kill(error, FAULT_OOM_CODE);
+                }
             }
             else {
                 panic!("segment error");
@@ -1268,28 +1881,113 @@ fn page_fault_page_exist(error : PageFaultErrorCode, vma : *mut VMAreaStruct, pg
 
 }
 
+// map the page cache page behind a file mapping
+// private mappings get it read-only, the first write copies it away from the cache
+unsafe fn filemap_fault(error : PageFaultErrorCode, vma : *mut VMAreaStruct, pg_fault_pos : *const c_void)
+{
+    let mapping = (*(*vma).get_file()).f_mapping;
+    if mapping.is_null()
+    {
+        panic!("file mapping without page cache");
+    }
+    let vaddr = get_page_start(pg_fault_pos);
+    let idx = (vaddr as u64 - (*vma).get_start() + (*vma).get_offset() as u64) / PAGE_SIZE as u64;
+    let page = match filemap::read_cache_page(mapping, idx as Idx) {
+        Ok(page) => page,
+        Err(_) => panic!("unable read file page")
+    };
+    // a sequential reader wants the next pages soon
+    if (*vma).get_flags().contains(MmapType::VM_SEQ_READ)
+    {
+        filemap::page_cache_readahead(mapping, idx as Idx + 1, madvise::VM_READAHEAD_PAGES);
+    }
+    let shared = (*vma).get_flags().contains(MmapType::MAP_SHARED);
+    let mut prot = (*vma).get_prot();
+    if !shared
+    {
+        prot = arch_clear_prot_writable(prot);
+    }
+    // the reference read_cache_page took now belongs to the pte
+    rmap::page_add_file_rmap(&*page);
+    (*get_current_running_process()).mm.inc_mm_counter(mm_counter_file(&*page));
+    link_user_page_by_prot_bit(vaddr, virt2phys(page_alloc::page_address(page)), prot);
+    if error.contains(PageFaultErrorCode::WRITE)
+    {
+        if shared
+        {
+            (*page).flags.insert(Pageflags::PgDirty);
+        }
+        else if arch_check_prot_writable((*vma).get_prot()) && !copy_on_write(pg_fault_pos)
+        {
+            fault_kill(error, FAULT_OOM_CODE);
+        }
+        else {
+            panic!("segment error");
+        }
+    }
+}
+
+// exit code of a task whose swapped out page could not be read back, SIGBUS
+const SWAPIN_FAILED_CODE : i64 = 7;
+// and of one a fault found no memory for, SIGKILL like the oom killer
+const FAULT_OOM_CODE : i64 = 9;
+
+// a fault that can't be resolved ends the task, a user one on its way back to user mode
+// the kernel would only fault on it again, so it can't wait for that
+unsafe fn fault_kill(error : PageFaultErrorCode, code : i64)
+{
+    if !error.contains(PageFaultErrorCode::USER)
+    {
+        sys_exit(code);
+    }
+    kill_process(get_current_running_process(), code);
+}
+
+// reclaim wrote the page out and left its swap entry in the pte
+// when it can't be read back the task gets killed, the pte keeps the entry either way
+unsafe fn do_swap_page(error : PageFaultErrorCode, vma : *mut VMAreaStruct, pg_fault_pos : *const c_void) -> bool
+{
+    let mm = addr_of_mut!((*get_current_running_process()).mm);
+    let vaddr = get_page_start(pg_fault_pos) as u64;
+    let mut step = PAGE_SIZE as u64;
+    let pte = follow_pte(mm, vaddr, &mut step);
+    if pte.is_null() || !swapfile::is_swap_pte((*pte).0)
+    {
+        return false;
+    }
+    let ret = swapin_pte(mm, pte, vaddr, (*vma).get_prot());
+    if ret < 0
+    {
+        logk!("swap in at {:#x} failed {}\n", vaddr, ret);
+        fault_kill(error, SWAPIN_FAILED_CODE);
+    }
+    true
+}
+
 fn page_fault_page_not_exist(error : PageFaultErrorCode, vma : *mut VMAreaStruct, pg_fault_pos : *const c_void)
 {
     unsafe
     {
         if !vma.is_null()
         {
+            if do_swap_page(error, vma, pg_fault_pos)
+            {
+                return;
+            }
             let file_t = (*vma).get_file();
             if !file_t.is_null()
             {
-                let idx = (pg_fault_pos as u64 - (*vma).get_start() + (*vma).get_offset() as u64) / PAGE_SIZE as u64;
-                let buff = FS.read_file_logic_block( (*vma).get_file(), idx as Idx);
-                if buff.is_null()
-                {
-                    panic!("unable read file block");
-                }
-                (*buff).dirty = true;
-                link_user_page_by_prot_bit(get_page_start(pg_fault_pos), virt2phys((*buff).buffer), (*vma).get_prot());
+                filemap_fault(error, vma, pg_fault_pos);
             }
-            else {                
-                link_user_page(pg_fault_pos, (*vma).get_prot());
+            else if !huge_memory::do_huge_pmd_anonymous_page(vma, pg_fault_pos as u64) && !link_user_page(pg_fault_pos, (*vma).get_prot())
+            {
+                fault_kill(error, FAULT_OOM_CODE);
             }
         }
+        else if vmalloc::is_vmalloc_addr(pg_fault_pos)
+        {
+            panic!("unable to handle page fault for address {:#x}, a vmalloc guard page or freed area", pg_fault_pos as u64);
+        }
         // if pg_fault_pos == null()
         // {
         //     let new_page = MEMORY_POOL.alloc_frames(1);
@@ -1316,7 +2014,12 @@ extern "C" fn page_fault(vector : u64, regs : PtRegs)
                 else {
                     page_fault_page_not_exist(error, vma, pg_fault_pos);
                 }
-                
+                // whatever got mapped into a locked area is pinned right away
+                if !vma.is_null() && (*vma).get_flags().contains(MmapType::VM_LOCKED)
+                {
+                    mlock::mlock_vma_page(follow_page(addr_of_mut!((*pcb).mm), pg_fault_pos as u64));
+                }
+
             },
             None => panic!("unexpected pagefault error code"),
         }
@@ -0,0 +1,65 @@
+use crate::{fs::ext4::Idx, kernel::{errno_base::{EINVAL, ENOMEM}, sched::get_current_running_process, Err}};
+
+use super::{memory::{follow_pmd, follow_pte, pmd_trans_huge, PAGE_SIZE}, mm_type::{MMStruct, VMAreaStruct}};
+
+// 1 when the page at `addr` of `vma` is in memory
+unsafe fn mincore_page(mm : *mut MMStruct, vma : *mut VMAreaStruct, addr : u64) -> u8
+{
+    let pmd = follow_pmd(mm, addr);
+    if !pmd.is_null() && pmd_trans_huge(&*pmd)
+    {
+        return 1;
+    }
+    let mut step = PAGE_SIZE as u64;
+    let pte = follow_pte(mm, addr, &mut step);
+    if !pte.is_null() && (*pte).get_present() != 0
+    {
+        return 1;
+    }
+    // a swapped out page is gone, so is an anonymous page nobody touched yet
+    if (!pte.is_null() && (*pte).raw() != 0) || (*vma).get_file().is_null()
+    {
+        return 0;
+    }
+    // a file page nobody maps may still sit in the page cache
+    let mapping = (*(*vma).get_file()).f_mapping;
+    let idx = (addr - (*vma).get_start() + (*vma).get_offset() as u64) / PAGE_SIZE as u64;
+    (!mapping.is_null() && !(*mapping).seek(idx as Idx).is_null()) as u8
+}
+
+// one byte per page of [start, end) into `vec`, the range is known to be mapped
+fn do_mincore(mm : &mut MMStruct, start : u64, end : u64, vec : *mut u8)
+{
+    unsafe
+    {
+        let mut vma = mm.find_vma_intersection(start, end);
+        let mut addr = start;
+        while addr < end {
+            if (*vma).get_end() < addr
+            {
+                vma = (*vma).get_next();
+            }
+            *vec.add(((addr - start) / PAGE_SIZE as u64) as usize) = mincore_page(mm, vma, addr);
+            addr += PAGE_SIZE as u64;
+        }
+    }
+}
+
+pub fn sys_mincore(addr : u64, len : usize, vec : *mut u8) -> Err
+{
+    unsafe
+    {
+        if addr & (PAGE_SIZE as u64 - 1) != 0
+        {
+            return -EINVAL;
+        }
+        let mm = &mut (*get_current_running_process()).mm;
+        let end = addr + (len.div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64;
+        if !mm.range_mapped(addr, end)
+        {
+            return -ENOMEM;
+        }
+        do_mincore(mm, addr, end, vec);
+        0
+    }
+}
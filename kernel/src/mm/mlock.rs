@@ -0,0 +1,213 @@
+use crate::kernel::{errno_base::{EINVAL, ENOMEM}, sched::get_current_running_process, Err};
+
+use super::{huge_memory, memory::{follow_page, populate_vma_page_range, PAGE_SIZE}, mm_type::{MMStruct, MmapType, VMAreaStruct}, page::{Page, Pageflags}, rmap, vmscan};
+
+pub const MCL_CURRENT : i32 = 1; // lock every page mapped now
+pub const MCL_FUTURE : i32 = 2; // and every area mapped from now on
+pub const MCL_ONFAULT : i32 = 4; // but only once the pages are faulted in
+
+// a locked page leaves the lru, so reclaim never gets to see it
+pub unsafe fn mlock_vma_page(page : *mut Page)
+{
+    if page.is_null() || (*page).flags.contains(Pageflags::PgMlocked)
+    {
+        return;
+    }
+    (*page).flags.insert(Pageflags::PgMlocked);
+    vmscan::lru_cache_del(page);
+}
+
+// true while some other locked area still maps the anonymous page
+unsafe fn page_mlocked_elsewhere(page : *mut Page) -> bool
+{
+    let mut locked = false;
+    rmap::rmap_walk(page, &mut |mm, vaddr| {
+        let vma = (*mm).contain(vaddr);
+        locked = !vma.is_null() && (*vma).get_flags().contains(MmapType::VM_LOCKED);
+        !locked
+    });
+    locked
+}
+
+// page cache pages don't know who maps them, the first unlock lets them go
+pub unsafe fn munlock_page(page : *mut Page)
+{
+    if page.is_null() || !(*page).flags.contains(Pageflags::PgMlocked) || page_mlocked_elsewhere(page)
+    {
+        return;
+    }
+    (*page).flags.remove(Pageflags::PgMlocked);
+    // huge pages never go on the lru
+    if !(*page).flags.contains(Pageflags::PgHead)
+    {
+        vmscan::lru_cache_add(page);
+    }
+}
+
+// the page at `addr` and the address right after it, a huge page is one step
+unsafe fn next_page(mm : *mut MMStruct, addr : u64) -> (*mut Page, u64)
+{
+    let page = follow_page(mm, addr);
+    if !page.is_null() && (*page).flags.contains(Pageflags::PgHead)
+    {
+        return (page, (addr & huge_memory::HPAGE_PMD_MASK) + huge_memory::HPAGE_PMD_SIZE);
+    }
+    (page, addr + PAGE_SIZE as u64)
+}
+
+// pin whatever is mapped in [start, end) of `vma` right now
+unsafe fn mlock_vma_pages_range(mm : *mut MMStruct, start : u64, end : u64)
+{
+    let mut addr = start;
+    while addr < end {
+        let (page, next) = next_page(mm, addr);
+        mlock_vma_page(page);
+        addr = next;
+    }
+}
+
+// `vma` stops being locked, its pages go back on the lru unless another area holds them
+pub unsafe fn munlock_vma_pages_range(mm : *mut MMStruct, vma : *mut VMAreaStruct, start : u64, end : u64)
+{
+    if !(*vma).get_flags().contains(MmapType::VM_LOCKED)
+    {
+        return;
+    }
+    (*vma).set_flags((*vma).get_flags() - (MmapType::VM_LOCKED | MmapType::VM_LOCKONFAULT));
+    let mut addr = start;
+    while addr < end {
+        let (page, next) = next_page(mm, addr);
+        munlock_page(page);
+        addr = next;
+    }
+}
+
+// give `vma` the lock bits in `flags`, an empty `flags` unlocks it
+// a plain lock faults the whole area in, a lock on fault only pins what is already there
+// and the page fault handler pins the rest as it comes in
+unsafe fn mlock_fixup(mm : *mut MMStruct, vma : *mut VMAreaStruct, flags : MmapType)
+{
+    let start = (*vma).get_start();
+    let end = (*vma).get_end() + 1;
+    if (*vma).get_flags().intersects(MmapType::VM_IO | MmapType::VM_PFNMAP)
+    {
+        return;
+    }
+    if !flags.contains(MmapType::VM_LOCKED)
+    {
+        munlock_vma_pages_range(mm, vma, start, end);
+        return;
+    }
+    (*vma).set_flags((*vma).get_flags() - (MmapType::VM_LOCKED | MmapType::VM_LOCKONFAULT) | flags);
+    // the stack area is reserved at its full size, only the part in use gets pinned
+    if !flags.contains(MmapType::VM_LOCKONFAULT) && !(*vma).get_flags().contains(MmapType::VM_GROWSDOWN)
+    {
+        populate_vma_page_range(vma, start, end);
+    }
+    mlock_vma_pages_range(mm, start, end);
+}
+
+unsafe fn apply_vma_lock_flags(addr : u64, len : usize, flags : MmapType) -> Err
+{
+    let mm = &mut (*get_current_running_process()).mm;
+    let start = addr & !(PAGE_SIZE as u64 - 1);
+    let end = (addr + len as u64).div_ceil(PAGE_SIZE as u64) * PAGE_SIZE as u64;
+    if start == end
+    {
+        return 0;
+    }
+    if !mm.range_mapped(start, end)
+    {
+        return -ENOMEM;
+    }
+    let mut vma = match mm.isolate_range(start, end) {
+        Ok(vma) => vma,
+        Err(err) => return err
+    };
+    while !vma.is_null() && (*vma).get_start() < end {
+        mlock_fixup(mm, vma, flags);
+        vma = (*vma).get_next();
+    }
+    0
+}
+
+// an area mapped while mlockall(MCL_FUTURE) or with MAP_LOCKED is faulted in and pinned up front
+pub unsafe fn mm_populate(start : u64, len : usize)
+{
+    let mm = &mut (*get_current_running_process()).mm;
+    let end = start + len as u64;
+    let mut vma = mm.find_vma_intersection(start, end);
+    while !vma.is_null() && (*vma).get_start() < end {
+        let flags = (*vma).get_flags();
+        if flags.contains(MmapType::VM_LOCKED) && !flags.contains(MmapType::VM_LOCKONFAULT)
+        {
+            let vstart = start.max((*vma).get_start());
+            let vend = end.min((*vma).get_end() + 1);
+            populate_vma_page_range(vma, vstart, vend);
+            mlock_vma_pages_range(mm, vstart, vend);
+        }
+        vma = (*vma).get_next();
+    }
+}
+
+pub fn sys_mlock(addr : u64, len : usize) -> Err
+{
+    unsafe
+    {
+        apply_vma_lock_flags(addr, len, MmapType::VM_LOCKED)
+    }
+}
+
+pub fn sys_munlock(addr : u64, len : usize) -> Err
+{
+    unsafe
+    {
+        apply_vma_lock_flags(addr, len, MmapType::empty())
+    }
+}
+
+pub fn sys_mlockall(flags : i32) -> Err
+{
+    unsafe
+    {
+        if flags == 0 || flags & !(MCL_CURRENT | MCL_FUTURE | MCL_ONFAULT) != 0 || flags == MCL_ONFAULT
+        {
+            return -EINVAL;
+        }
+        let mm = &mut (*get_current_running_process()).mm;
+        let mut lock_flags = MmapType::VM_LOCKED;
+        if flags & MCL_ONFAULT != 0
+        {
+            lock_flags.insert(MmapType::VM_LOCKONFAULT);
+        }
+        mm.def_flags.remove(MmapType::VM_LOCKED | MmapType::VM_LOCKONFAULT);
+        if flags & MCL_FUTURE != 0
+        {
+            mm.def_flags.insert(lock_flags);
+        }
+        if flags & MCL_CURRENT != 0
+        {
+            let mut vma = mm.mmap;
+            while !vma.is_null() {
+                mlock_fixup(mm, vma, lock_flags);
+                vma = (*vma).get_next();
+            }
+        }
+        0
+    }
+}
+
+pub fn sys_munlockall() -> Err
+{
+    unsafe
+    {
+        let mm = &mut (*get_current_running_process()).mm;
+        mm.def_flags.remove(MmapType::VM_LOCKED | MmapType::VM_LOCKONFAULT);
+        let mut vma = mm.mmap;
+        while !vma.is_null() {
+            mlock_fixup(mm, vma, MmapType::empty());
+            vma = (*vma).get_next();
+        }
+        0
+    }
+}
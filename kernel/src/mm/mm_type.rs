@@ -1,8 +1,8 @@
 use core::{sync::atomic::AtomicI64, ptr::null_mut, cmp::Ordering, alloc::{GlobalAlloc, Layout}, ffi::c_void};
 use alloc::collections::BTreeSet;
-use crate::{kernel::{list::ListHead, process, Off}, mm::memory::{MMAP_START, USER_STACK_BOTTOM}, fs::{namei::Fd, file::{File, FS}}};
+use crate::{kernel::{errno_base::ENOMEM, list::ListHead, process, Err, Off}, mm::memory::{MAX_USER_STACK_SIZE, MMAP_START, USER_STACK_TOP}, fs::{namei::Fd, file::{File, FSType, FS}, fs::AddressSpace}};
 
-use super::{page::Pageflags, memory::MEMORY_POOL};
+use super::{huge_memory, ksm, mlock, page::{Page, Pageflags}, memory::{self, MEMORY_POOL, PAGE_SIZE}};
 
 pub struct MMStruct
 {
@@ -10,7 +10,40 @@ pub struct MMStruct
     pub mm_rb : BTreeSet<VMAPtrCmp>,
     pub stack : *mut VMAreaStruct,
     pub mmap_cache : *mut VMAreaStruct,
-    pub pcb_ptr : *mut process::ProcessControlBlock
+    pub pcb_ptr : *mut process::ProcessControlBlock,
+    pub mmap_base : u64, // mmap searches upward from here
+    pub stack_top : u64,
+    pub start_brk : u64,
+    pub brk : u64,
+    pub rss_stat : [usize; NR_MM_COUNTERS], // pages and swap entries, indexed by MMCounter
+    pub hiwater_rss : usize, // most pages mapped at once
+    pub hiwater_vm : usize, // largest size of the address space in pages, as of the last munmap
+    pub def_flags : MmapType // given to every new area, mlockall(MCL_FUTURE) puts VM_LOCKED here
+}
+
+pub const NR_MM_COUNTERS : usize = 4;
+
+#[derive(Clone, Copy)]
+pub enum MMCounter
+{
+    FilePages = 0,
+    AnonPages = 1,
+    SwapEnts = 2,
+    ShmemPages = 3
+}
+
+// page cache pages of shmem files are shared memory, the rest are file pages
+pub fn mm_counter_file(page : &Page) -> MMCounter
+{
+    unsafe
+    {
+        let mapping = page.mapping as *mut AddressSpace;
+        if !mapping.is_null() && !(*mapping).host.is_null() && !(*(*mapping).host).logical_part_ptr.is_null() && (*(*(*mapping).host).logical_part_ptr).old_fs_type == FSType::Shmem
+        {
+            return MMCounter::ShmemPages;
+        }
+        MMCounter::FilePages
+    }
 }
 
 bitflags::bitflags! {
@@ -80,6 +113,7 @@ bitflags::bitflags! {
         const VM_ARCH_1 = 0x01000000;	/* Architecture-specific flag */
         const VM_WIPEONFORK = 0x02000000;	/* Wipe VMA contents in child. */
         const VM_DONTDUMP = 0x04000000;	/* Do not include in the core dump */
+        const VM_EXEC = 0x08000000;	/* mapped with PROT_EXEC, the pte bits can't tell */
         
         const VM_MIXEDMAP = 0x10000000;	/* Can contain "struct page" and pure PFN pages */
         const VM_HUGEPAGE = 0x20000000;	/* MADV_HUGEPAGE marked this vma */
@@ -180,7 +214,9 @@ pub struct VMAreaStruct
     vm_ref_count : AtomicI64,
     file : *mut File,
     offset : Off,
-    vm_page_prot : u64
+    vm_page_prot : u64,
+    // the PROT_ bits asked for, the pte bits above can't tell PROT_READ from PROT_NONE
+    vm_prot : MmapType
 }
 
 impl MMStruct {
@@ -190,17 +226,49 @@ impl MMStruct {
         {
             self.mmap_cache = null_mut();
             self.mm_rb.clear();
+            self.def_flags = MmapType::empty();
+            self.hiwater_rss = 0;
+            self.hiwater_vm = 0;
+            ksm::ksm_unmap(self, 0, u64::MAX);
             let mut vma_ptr = self.mmap;
             self.mmap = null_mut();
             while !vma_ptr.is_null() {
                 let prev_vma = vma_ptr;
                 vma_ptr = (*vma_ptr).get_next();
+                mlock::munlock_vma_pages_range(self, prev_vma, (*prev_vma).get_start(), (*prev_vma).get_end() + 1);
+                memory::zap_page_range(self, (*prev_vma).get_start(), (*prev_vma).get_end() + 1);
                 Self::free_vma(prev_vma);
             }
         }
     }
 
-    pub fn scan_empty_space(&mut self, mut start : *const c_void, length : usize, mut max : *const c_void) -> *mut VMAreaStruct
+    // a forked child starts with a copy of every parent area
+    pub fn dup_mmap(&mut self, src : &MMStruct)
+    {
+        unsafe
+        {
+            self.release_all();
+            let mut src_vma = src.mmap;
+            while !src_vma.is_null() {
+                let vma_ptr = MEMORY_POOL.alloc(Layout::new::<VMAreaStruct>()) as *mut VMAreaStruct;
+                // memory locks are not inherited, the child faults its own copies in on demand
+                vma_ptr.write(VMAreaStruct::new((*src_vma).vm_start, (*src_vma).vm_end + 1, self as *mut MMStruct, (*src_vma).vm_flags - (MmapType::VM_LOCKED | MmapType::VM_LOCKONFAULT)));
+                (*vma_ptr).vm_page_prot = (*src_vma).vm_page_prot;
+                (*vma_ptr).vm_prot = (*src_vma).vm_prot;
+                (*vma_ptr).set_file((*src_vma).file);
+                (*vma_ptr).offset = (*src_vma).offset;
+                self.insert_vma(vma_ptr);
+                src_vma = (*src_vma).get_next();
+            }
+            self.mmap_base = src.mmap_base;
+            self.stack_top = src.stack_top;
+            self.start_brk = src.start_brk;
+            self.brk = src.brk;
+        }
+    }
+
+    // lowest hole of `length` bytes from `start` on that ends below `max`, 0 when there is none
+    pub fn get_unmapped_area(&mut self, mut start : *const c_void, length : usize, mut max : *const c_void) -> u64
     {
         assert!((start as u64 & 0xfff) == 0);
         assert!((length & 0xfff) == 0);
@@ -208,17 +276,17 @@ impl MMStruct {
         {
             if start.is_null()
             {
-                start = MMAP_START;
+                start = self.mmap_base as *const c_void;
             }
             if max.is_null()
             {
-                max = USER_STACK_BOTTOM;
+                max = (self.stack_top - MAX_USER_STACK_SIZE as u64) as *const c_void;
             }
             let mut last_ptr: *mut VMAreaStruct = null_mut();
             let mut vm_ptr = self.mmap;
             if vm_ptr.is_null() || (*vm_ptr).get_start() > start as u64
             {
-                return self.create_new_mem_area(start as u64, start as u64 + length as u64);
+                return start as u64;
             }
             while !vm_ptr.is_null() {
                 if (*vm_ptr).get_start() > start as u64 && (*last_ptr).get_end() as usize + 1  + length < max as usize
@@ -233,11 +301,11 @@ impl MMStruct {
                     {
                         if (*last_ptr).get_end() as usize + length > max as usize
                         {
-                            return self.create_new_mem_area((*last_ptr).get_end() + 1, (*last_ptr).get_end() + length as u64);
+                            return (*last_ptr).get_end() + 1;
                         }
-                        return null_mut();
+                        return 0;
                     }
-                    return self.create_new_mem_area((*last_ptr).get_end() + 1, (*last_ptr).get_end() + length as u64)
+                    return (*last_ptr).get_end() + 1;
                 }
                 else {
                     last_ptr = vm_ptr;
@@ -246,15 +314,97 @@ impl MMStruct {
             }
             if (*last_ptr).get_end() < max as u64
             {
-                return self.create_new_mem_area((*last_ptr).get_end() + 1, (*last_ptr).get_end() + length as u64);
+                return (*last_ptr).get_end() + 1;
             }
-            null_mut()
+            0
         }
     }
 
+    pub fn scan_empty_space(&mut self, start : *const c_void, length : usize, max : *const c_void) -> *mut VMAreaStruct
+    {
+        let addr = self.get_unmapped_area(start, length, max);
+        if addr == 0
+        {
+            return null_mut();
+        }
+        self.create_new_mem_area(addr, addr + length as u64)
+    }
+
     pub fn new(pcb_ptr : *mut process::ProcessControlBlock) -> MMStruct
     {
-        MMStruct { mmap: null_mut(), mm_rb: BTreeSet::new(), mmap_cache: null_mut(), pcb_ptr, stack: null_mut() }
+        MMStruct { mmap: null_mut(), mm_rb: BTreeSet::new(), mmap_cache: null_mut(), pcb_ptr, stack: null_mut(), mmap_base: MMAP_START as u64, stack_top: USER_STACK_TOP as u64, start_brk: 0, brk: 0, rss_stat: [0; NR_MM_COUNTERS], hiwater_rss: 0, hiwater_vm: 0, def_flags: MmapType::empty() }
+    }
+
+    pub fn inc_mm_counter(&mut self, member : MMCounter)
+    {
+        self.rss_stat[member as usize] += 1;
+        self.update_hiwater_rss();
+    }
+
+    pub fn dec_mm_counter(&mut self, member : MMCounter)
+    {
+        self.rss_stat[member as usize] -= 1;
+    }
+
+    pub fn add_mm_counter(&mut self, member : MMCounter, value : isize)
+    {
+        self.rss_stat[member as usize] = self.rss_stat[member as usize].wrapping_add_signed(value);
+        self.update_hiwater_rss();
+    }
+
+    pub fn get_mm_counter(&self, member : MMCounter) -> usize
+    {
+        self.rss_stat[member as usize]
+    }
+
+    // pages mapped into this address space
+    pub fn get_mm_rss(&self) -> usize
+    {
+        self.rss_stat[MMCounter::FilePages as usize] + self.rss_stat[MMCounter::AnonPages as usize] + self.rss_stat[MMCounter::ShmemPages as usize]
+    }
+
+    fn update_hiwater_rss(&mut self)
+    {
+        self.hiwater_rss = self.hiwater_rss.max(self.get_mm_rss());
+    }
+
+    // called before areas go away, growing is cheap to catch when the peak is read
+    pub fn update_hiwater_vm(&mut self)
+    {
+        self.hiwater_vm = self.hiwater_vm.max(self.total_vm());
+    }
+
+    // pages covered by all areas, resident or not
+    pub fn total_vm(&self) -> usize
+    {
+        let mut pages = 0;
+        let mut vma_ptr = self.mmap;
+        unsafe
+        {
+            while !vma_ptr.is_null() {
+                pages += ((*vma_ptr).get_end() + 1 - (*vma_ptr).get_start()) as usize / PAGE_SIZE;
+                vma_ptr = (*vma_ptr).get_next();
+            }
+        }
+        pages
+    }
+
+    // pages covered by mlocked areas, resident or not
+    pub fn locked_vm(&self) -> usize
+    {
+        let mut pages = 0;
+        let mut vma_ptr = self.mmap;
+        unsafe
+        {
+            while !vma_ptr.is_null() {
+                if (*vma_ptr).get_flags().contains(MmapType::VM_LOCKED)
+                {
+                    pages += ((*vma_ptr).get_end() + 1 - (*vma_ptr).get_start()) as usize / PAGE_SIZE;
+                }
+                vma_ptr = (*vma_ptr).get_next();
+            }
+        }
+        pages
     }
 
     pub fn dispose(mm_ptr : *mut MMStruct)
@@ -268,9 +418,9 @@ impl MMStruct {
         {
             let mut vma_ptr = self.mmap;
             while !vma_ptr.is_null() {
-                if (*vma_ptr).vm_start < addr
+                if (*vma_ptr).vm_start <= addr
                 {
-                    if (*vma_ptr).vm_end > addr
+                    if (*vma_ptr).vm_end >= addr
                     {
                         return vma_ptr;
                     }
@@ -291,10 +441,46 @@ impl MMStruct {
     {
         unsafe
         {
+            FS.release_file((*vma_ptr).file);
             MEMORY_POOL.dealloc(vma_ptr as *mut u8, Layout::new::<VMAreaStruct>())
         }
     }
 
+    // first vma overlapping [start, end)
+    pub fn find_vma_intersection(&mut self, start : u64, end : u64) -> *mut VMAreaStruct
+    {
+        unsafe
+        {
+            let mut vma_ptr = self.mmap;
+            while !vma_ptr.is_null() {
+                if (*vma_ptr).vm_start >= end
+                {
+                    break;
+                }
+                if (*vma_ptr).vm_end >= start
+                {
+                    return vma_ptr;
+                }
+                vma_ptr = (*vma_ptr).get_next();
+            }
+            null_mut()
+        }
+    }
+
+    // area is filled in before it is linked, so it only merges with a compatible neighbour
+    pub fn create_mem_area_fixed(&mut self, start : u64, end : u64, prot : MmapType, flags : MmapType, file_t : *mut File, offset : Off) -> *mut VMAreaStruct
+    {
+        unsafe
+        {
+            let vma_ptr = MEMORY_POOL.alloc(Layout::new::<VMAreaStruct>()) as *mut VMAreaStruct;
+            vma_ptr.write(VMAreaStruct::new(start, end, self as *mut MMStruct, flags));
+            (*vma_ptr).set_prot(prot);
+            (*vma_ptr).set_file(file_t);
+            (*vma_ptr).set_offset(offset);
+            self.insert_vma(vma_ptr)
+        }
+    }
+
     pub fn create_new_mem_area(&mut self, start : u64, end : u64) -> *mut VMAreaStruct
     {
         unsafe
@@ -307,81 +493,182 @@ impl MMStruct {
         }
     } 
 
-    fn insert_vma(&mut self, mut new_vma : *mut VMAreaStruct) -> *mut VMAreaStruct
+    // `next` starts right where `prev` ends and both describe the same kind of memory
+    unsafe fn vma_mergeable(prev : *const VMAreaStruct, next : *const VMAreaStruct) -> bool
+    {
+        (*prev).vm_end + 1 == (*next).vm_start && (*prev).get_file() == (*next).get_file() && (*prev).get_flags().bits() == (*next).get_flags().bits() && (*prev).get_prot() == (*next).get_prot() && (*prev).get_vm_prot().bits() == (*next).get_vm_prot().bits() && (*prev).get_offset() + ((*prev).get_end() - (*prev).get_start() + 1) as Off == (*next).get_offset()
+    }
+
+    // link an area in address order, it is folded into a neighbour it continues
+    fn insert_vma(&mut self, new_vma : *mut VMAreaStruct) -> *mut VMAreaStruct
     {
         unsafe {
-            let mut vma_ptr = self.mmap;
-            let mut last_ptr = null_mut();
-            if vma_ptr.is_null()
-            {
-                self.mmap = new_vma;
-                return new_vma;
-            }
-            while !vma_ptr.is_null() {
-                let result = (*vma_ptr).partial_cmp(&*new_vma);
-                match result {
-                    Some(Ordering::Equal) => 
-                    {
-                        panic!("vitrual memory arna overlapped!")
-                    },
-                    Some(Ordering::Greater) =>
-                    {
-                        if (*vma_ptr).vm_start == (*new_vma).vm_end + 1 && (*new_vma).vm_flags.difference((*vma_ptr).vm_flags).is_empty()
-                        {
-                            (*vma_ptr).vm_start = (*new_vma).vm_start;
-                            Self::free_vma(new_vma);
-                            new_vma = vma_ptr;
-                        }
-                        let p_vma_ptr = (*vma_ptr).get_prev();
-                        if p_vma_ptr.is_null()
-                        {
-                            self.mmap = new_vma;
-                            (*new_vma).set_next(vma_ptr);
-                            (*vma_ptr).set_prev(new_vma);
-                        }
-                        else
-                        {
-                            if (*vma_ptr).vm_end + 1 == (*new_vma).vm_start && (*vma_ptr).get_file() == (*new_vma).get_file() && (*vma_ptr).get_flags().difference((*new_vma).get_flags()).is_empty() && (*vma_ptr).get_prot() == (*new_vma).get_prot() && (*vma_ptr).get_offset() + ((*vma_ptr).get_end() - (*vma_ptr).get_start() + 1) as Off == (*new_vma).get_offset()
-                            {
-                                // merge two vma
-                                (*vma_ptr).vm_end = (*new_vma).vm_end;
-                                Self::free_vma(new_vma);
-                                new_vma = vma_ptr;
-                            }
-                            else {
-                                (*(*vma_ptr).get_next()).set_prev(new_vma);
-                                (*new_vma).set_next((*vma_ptr).get_next());
-                                (*vma_ptr).set_next(new_vma);
-                                (*new_vma).set_prev(vma_ptr);
-                            }
-                        }
-                        return new_vma;
-                    }
+            let mut prev : *mut VMAreaStruct = null_mut();
+            let mut next = self.mmap;
+            while !next.is_null() {
+                match (*next).partial_cmp(&*new_vma) {
                     Some(Ordering::Less) =>
                     {
-                        last_ptr = vma_ptr;
-                        vma_ptr = (*vma_ptr).get_next();
+                        prev = next;
+                        next = (*next).get_next();
                     }
-                    None => panic!("vitrual memory arna overlapped!"),
+                    Some(Ordering::Greater) => break,
+                    _ => panic!("vitrual memory arna overlapped!")
                 }
             }
-            if (*last_ptr).vm_end + 1 == (*new_vma).vm_start && (*last_ptr).get_file() == (*new_vma).get_file() && (*last_ptr).get_flags().difference((*new_vma).get_flags()).is_empty() && (*last_ptr).get_prot() == (*new_vma).get_prot() && (*last_ptr).get_offset() + ((*last_ptr).get_end() - (*last_ptr).get_start() + 1) as Off == (*new_vma).get_offset()
+            if !prev.is_null() && Self::vma_mergeable(prev, new_vma)
             {
-                if (*last_ptr).get_prev() == last_ptr
-                {
-                    (*last_ptr).set_next((*new_vma).get_next());
-                    (*(*new_vma).get_next()).set_prev(last_ptr);
-                }
+                (*prev).vm_end = (*new_vma).vm_end;
                 Self::free_vma(new_vma);
-                new_vma = last_ptr;
+                return prev;
+            }
+            if !next.is_null() && Self::vma_mergeable(new_vma, next)
+            {
+                (*next).vm_start = (*new_vma).vm_start;
+                (*next).offset = (*new_vma).offset;
+                Self::free_vma(new_vma);
+                return next;
+            }
+            (*new_vma).set_prev(prev);
+            (*new_vma).set_next(next);
+            if prev.is_null()
+            {
+                self.mmap = new_vma;
             }
             else {
-                (*last_ptr).set_next(new_vma);
-                (*new_vma).set_prev(last_ptr);
+                (*prev).set_next(new_vma);
+            }
+            if !next.is_null()
+            {
+                (*next).set_prev(new_vma);
             }
             new_vma
         }
     }
+
+    // cut `vma` in two at `addr`, the returned area is the upper part
+    // null when a huge page across `addr` could not be split, `vma` is left whole then
+    pub unsafe fn split_vma(&mut self, vma : *mut VMAreaStruct, addr : u64) -> *mut VMAreaStruct
+    {
+        assert!(addr > (*vma).vm_start && addr <= (*vma).vm_end && addr & 0xfff == 0);
+        // a huge page can't straddle two areas
+        if addr & !huge_memory::HPAGE_PMD_MASK != 0 && huge_memory::split_huge_pmd(self, addr & huge_memory::HPAGE_PMD_MASK) < 0
+        {
+            return null_mut();
+        }
+        let new_vma = MEMORY_POOL.alloc(Layout::new::<VMAreaStruct>()) as *mut VMAreaStruct;
+        new_vma.write(VMAreaStruct::new(addr, (*vma).vm_end + 1, self as *mut MMStruct, (*vma).vm_flags));
+        (*new_vma).vm_page_prot = (*vma).vm_page_prot;
+        (*new_vma).vm_prot = (*vma).vm_prot;
+        (*new_vma).set_file((*vma).file);
+        (*new_vma).offset = (*vma).offset + (addr - (*vma).vm_start) as Off;
+        (*vma).vm_end = addr - 1;
+        let next = (*vma).get_next();
+        (*new_vma).set_prev(vma);
+        (*new_vma).set_next(next);
+        (*vma).set_next(new_vma);
+        if !next.is_null()
+        {
+            (*next).set_prev(new_vma);
+        }
+        new_vma
+    }
+
+    // a new area at [addr, addr + len) describing the same memory as `vma` from file offset `offset` on
+    pub unsafe fn copy_vma(&mut self, vma : *mut VMAreaStruct, addr : u64, len : usize, offset : Off) -> *mut VMAreaStruct
+    {
+        let new_vma = MEMORY_POOL.alloc(Layout::new::<VMAreaStruct>()) as *mut VMAreaStruct;
+        new_vma.write(VMAreaStruct::new(addr, addr + len as u64, self as *mut MMStruct, (*vma).vm_flags));
+        (*new_vma).vm_page_prot = (*vma).vm_page_prot;
+        (*new_vma).vm_prot = (*vma).vm_prot;
+        (*new_vma).set_file((*vma).file);
+        (*new_vma).offset = offset;
+        self.insert_vma(new_vma)
+    }
+
+    // let `vma` run up to `end`, nothing may be mapped in between
+    pub unsafe fn expand_vma(&mut self, vma : *mut VMAreaStruct, end : u64)
+    {
+        assert!(end > (*vma).vm_end && self.find_vma_intersection((*vma).vm_end + 1, end).is_null());
+        (*vma).vm_end = end - 1;
+    }
+
+    // unlink and free an area, its pages are the caller's business
+    pub unsafe fn remove_vma(&mut self, vma : *mut VMAreaStruct)
+    {
+        let prev = (*vma).get_prev();
+        let next = (*vma).get_next();
+        if prev.is_null()
+        {
+            self.mmap = next;
+        }
+        else {
+            (*prev).set_next(next);
+        }
+        if !next.is_null()
+        {
+            (*next).set_prev(prev);
+        }
+        if self.mmap_cache == vma
+        {
+            self.mmap_cache = null_mut();
+        }
+        Self::free_vma(vma);
+    }
+
+    // every byte of [start, end) belongs to some area
+    pub fn range_mapped(&mut self, start : u64, end : u64) -> bool
+    {
+        unsafe
+        {
+            let mut addr = start;
+            let mut vma_ptr = self.find_vma_intersection(start, end);
+            while addr < end {
+                if vma_ptr.is_null() || (*vma_ptr).vm_start > addr
+                {
+                    return false;
+                }
+                addr = (*vma_ptr).vm_end + 1;
+                vma_ptr = (*vma_ptr).get_next();
+            }
+            true
+        }
+    }
+
+    // split the areas [start, end) cuts through so the range is made of whole areas, returns the first of them
+    // -ENOMEM when a huge page on an edge could not be split, the areas split so far stay split
+    pub fn isolate_range(&mut self, start : u64, end : u64) -> Result<*mut VMAreaStruct, Err>
+    {
+        unsafe
+        {
+            let mut first = self.find_vma_intersection(start, end);
+            if first.is_null()
+            {
+                return Ok(first);
+            }
+            if (*first).vm_start < start
+            {
+                first = self.split_vma(first, start);
+                if first.is_null()
+                {
+                    return Err(-ENOMEM);
+                }
+            }
+            let mut vma_ptr = first;
+            while !vma_ptr.is_null() && (*vma_ptr).vm_start < end {
+                if (*vma_ptr).vm_end >= end
+                {
+                    if self.split_vma(vma_ptr, end).is_null()
+                    {
+                        return Err(-ENOMEM);
+                    }
+                    break;
+                }
+                vma_ptr = (*vma_ptr).get_next();
+            }
+            Ok(first)
+        }
+    }
 }
 
 impl PartialEq for VMAreaStruct
@@ -462,7 +749,14 @@ impl VMAreaStruct {
 
     pub fn set_prot(&mut self, prot : MmapType)
     {
+        self.vm_flags.set(MmapType::VM_EXEC, prot.contains(MmapType::PROT_EXEC));
         self.vm_page_prot = Self::get_vm_page_prot(prot);
+        self.vm_prot = prot & (MmapType::PROT_READ | MmapType::PROT_WRITE | MmapType::PROT_EXEC);
+    }
+
+    pub fn get_vm_prot(&self) -> MmapType
+    {
+        self.vm_prot
     }
 
     fn get_vm_page_prot(prot : MmapType) -> u64
@@ -484,9 +778,18 @@ impl VMAreaStruct {
         result
     }
 
+    // the area holds its own reference to the file it maps
     pub fn set_file(&mut self, file_t : *mut File)
     {
-        self.file = file_t;            
+        unsafe
+        {
+            if !file_t.is_null()
+            {
+                (*file_t).get();
+            }
+            FS.release_file(self.file);
+        }
+        self.file = file_t;
     }
 
     pub fn get_file(&self) -> *mut File
@@ -506,7 +809,7 @@ impl VMAreaStruct {
 
     pub fn new(strat : u64, end : u64, mm_struct : *mut MMStruct, flags : MmapType) -> VMAreaStruct
     {
-        VMAreaStruct { vm_start: strat, vm_end: end - 1, list: ListHead::empty(), vm_mm: mm_struct, vm_flags: flags, vm_ref_count: AtomicI64::new(1), file: null_mut(), offset: 0, vm_page_prot: 0 }
+        VMAreaStruct { vm_start: strat, vm_end: end - 1, list: ListHead::empty(), vm_mm: mm_struct, vm_flags: flags, vm_ref_count: AtomicI64::new(1), file: null_mut(), offset: 0, vm_page_prot: 0, vm_prot: MmapType::PROT_NONE }
     }
 
     pub fn get_next(&self) -> *mut VMAreaStruct
@@ -1,8 +1,8 @@
 use core::{ffi::c_void, ptr::null_mut};
 
-use crate::{kernel::{Off, sched::get_current_running_process}, fs::{namei::Fd, file::{File, EOF}}};
+use crate::{kernel::{errno_base::{is_err, EINVAL, ENOMEM}, random::randomize_page, sched::get_current_running_process, Err, Off}, fs::{namei::Fd, file::{File, FileFlag, EOF, FS}}};
 
-use super::mm_type::{VMAreaStruct, MmapType};
+use super::{huge_memory, ksm, mlock, shmem::shmem_file_setup, memory::{self, BRK_RND_RANGE, MMAP_RND_RANGE, MMAP_START, PAGE_SIZE, STACK_RND_RANGE, USER_STACK_TOP}, mm_type::{MMStruct, VMAreaStruct, MmapType}};
 
 
 
@@ -13,23 +13,75 @@ pub fn sys_mmap(addr : *const c_void, length : usize, port : MmapType, flags : M
     unsafe
     {
         let pcb = get_current_running_process();
-        let file_t = (*pcb).get_file(fd);
-        let vma = __do_mmap(addr, length, port, flags, file_t, offset);
-        if vma.is_null()
+        let mut file_t = null_mut();
+        let mut offset = offset;
+        let length = length.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        // the other mmap flags share their bits with area flags
+        let flags = flags & (MmapType::MAP_SHARED | MmapType::MAP_PRIVATE | MmapType::MAP_ANONYMOUS | MmapType::MAP_LOCKED);
+        if (addr as usize) & (PAGE_SIZE - 1) != 0 || offset & (PAGE_SIZE - 1) != 0
         {
-            EOF as *mut c_void
+            return EOF as *mut c_void;
         }
-        else {
-            (*vma).get_start() as *mut c_void
+        let shared_anon = flags.contains(MmapType::MAP_ANONYMOUS | MmapType::MAP_SHARED);
+        if !flags.contains(MmapType::MAP_ANONYMOUS)
+        {
+            file_t = (*pcb).get_file(fd);
+            if file_t.is_null()
+            {
+                return EOF as *mut c_void;
+            }
+        }
+        else if shared_anon
+        {
+            // an unlinked shmem file behind it, so fork shares the pages instead of copying them
+            file_t = shmem_file_setup(length, FileFlag::O_RDWR);
+            if is_err(file_t)
+            {
+                return EOF as *mut c_void;
+            }
+            offset = 0;
+        }
+        let start = __do_mmap(addr, length, port, flags, file_t, offset);
+        // the area holds the only reference now
+        if shared_anon
+        {
+            FS.release_file(file_t);
+        }
+        if start.is_null()
+        {
+            return EOF as *mut c_void;
         }
+        // MAP_LOCKED or an earlier mlockall(MCL_FUTURE) wants the pages in now
+        mlock::mm_populate(start as u64, length);
+        start
     }
 }
-pub fn __do_mmap(addr : *const c_void, length : usize, prot : MmapType, flags : MmapType, file_t : *mut File, offset : Off) -> *mut VMAreaStruct
+
+// where the mapping went, null when there was no room for it
+// the area may have merged with a neighbour, so its start is not necessarily the mapping's
+pub fn __do_mmap(addr : *const c_void, length : usize, prot : MmapType, flags : MmapType, file_t : *mut File, offset : Off) -> *mut c_void
 {
     unsafe
     {
         assert!((addr as u64 & 0xfff) == 0);
         let pcb = get_current_running_process();
+        let flags = flags | (*pcb).mm.def_flags;
+        // honour the hint when nothing is mapped there
+        if !addr.is_null() && (*pcb).mm.find_vma_intersection(addr as u64, addr as u64 + length as u64).is_null()
+        {
+            (*pcb).mm.create_mem_area_fixed(addr as u64, addr as u64 + length as u64, prot, flags, file_t, offset);
+            return addr as *mut c_void;
+        }
+        // big anonymous areas start on a 2 MiB boundary so faults can map huge pages
+        if addr.is_null() && file_t.is_null()
+        {
+            let thp_addr = huge_memory::thp_get_unmapped_area(&mut (*pcb).mm, length, flags);
+            if thp_addr != 0
+            {
+                (*pcb).mm.create_mem_area_fixed(thp_addr, thp_addr + length as u64, prot, flags, file_t, offset);
+                return thp_addr as *mut c_void;
+            }
+        }
         let vma = (*pcb).mm.scan_empty_space(addr, length, null_mut());
         if !vma.is_null()
         {
@@ -37,8 +89,141 @@ pub fn __do_mmap(addr : *const c_void, length : usize, prot : MmapType, flags :
             (*vma).set_prot(prot);
             (*vma).set_flags(flags);
             (*vma).set_offset(offset);
-            return vma;
+            return (*vma).get_start() as *mut c_void;
         }
         null_mut()
     }
 }
+
+pub fn sys_munmap(addr : *const c_void, length : usize) -> Err
+{
+    unsafe
+    {
+        if (addr as usize) & (PAGE_SIZE - 1) != 0 || length == 0
+        {
+            return -EINVAL;
+        }
+        let start = addr as u64;
+        do_munmap(&mut (*get_current_running_process()).mm, start, start + (length.div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64)
+    }
+}
+
+// drop every area and page in [start, end), areas cut by the range keep their other part
+// nothing is unmapped when an edge of the range can't be split off
+pub unsafe fn do_munmap(mm : &mut MMStruct, start : u64, end : u64) -> Err
+{
+    if start >= end
+    {
+        return 0;
+    }
+    let mut vma = match mm.isolate_range(start, end) {
+        Ok(vma) => vma,
+        Err(err) => return err
+    };
+    mm.update_hiwater_vm();
+    ksm::ksm_unmap(mm, start, end);
+    while !vma.is_null() && (*vma).get_start() < end {
+        let next = (*vma).get_next();
+        mlock::munlock_vma_pages_range(mm, vma, (*vma).get_start(), (*vma).get_end() + 1);
+        // whole areas, every huge page in them is whole as well
+        memory::zap_page_range(mm, (*vma).get_start(), (*vma).get_end() + 1);
+        mm.remove_vma(vma);
+        vma = next;
+    }
+    0
+}
+
+pub fn sys_mprotect(addr : *const c_void, length : usize, prot : MmapType) -> Err
+{
+    unsafe
+    {
+        if (addr as usize) & (PAGE_SIZE - 1) != 0 || !(MmapType::PROT_READ | MmapType::PROT_WRITE | MmapType::PROT_EXEC).contains(prot)
+        {
+            return -EINVAL;
+        }
+        let mm = &mut (*get_current_running_process()).mm;
+        let start = addr as u64;
+        let end = start + (length.div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64;
+        if start == end
+        {
+            return 0;
+        }
+        if !mm.range_mapped(start, end)
+        {
+            return -ENOMEM;
+        }
+        let mut vma = match mm.isolate_range(start, end) {
+            Ok(vma) => vma,
+            Err(err) => return err
+        };
+        while !vma.is_null() && (*vma).get_start() < end {
+            (*vma).set_prot(prot);
+            let err = memory::change_protection(mm, (*vma).get_start(), (*vma).get_end() + 1, (*vma).get_prot(), (*vma).get_flags().contains(MmapType::MAP_SHARED));
+            if err < 0
+            {
+                return err;
+            }
+            vma = (*vma).get_next();
+        }
+        0
+    }
+}
+
+// choose stack top and mmap base for a fresh image
+pub fn arch_pick_mmap_layout(mm : &mut MMStruct, randomize : bool)
+{
+    if randomize
+    {
+        mm.stack_top = USER_STACK_TOP as u64 - randomize_page(0, STACK_RND_RANGE);
+        mm.mmap_base = randomize_page(MMAP_START as u64, MMAP_RND_RANGE);
+    }
+    else {
+        mm.stack_top = USER_STACK_TOP as u64;
+        mm.mmap_base = MMAP_START as u64;
+    }
+}
+
+// heap starts at the first page after the image, plus a random gap
+pub fn arch_setup_brk(mm : &mut MMStruct, end_data : u64, randomize : bool)
+{
+    let mut start = end_data.div_ceil(PAGE_SIZE as u64) * PAGE_SIZE as u64;
+    if randomize
+    {
+        start = randomize_page(start, BRK_RND_RANGE);
+    }
+    mm.start_brk = start;
+    mm.brk = start;
+}
+
+pub fn sys_brk(brk : u64) -> u64
+{
+    unsafe
+    {
+        let mm = &mut (*get_current_running_process()).mm;
+        if brk < mm.start_brk
+        {
+            return mm.brk;
+        }
+        let new_end = brk.div_ceil(PAGE_SIZE as u64) * PAGE_SIZE as u64;
+        let mut map_start = mm.brk.div_ceil(PAGE_SIZE as u64) * PAGE_SIZE as u64;
+        // pages left behind by an earlier shrink are still mapped
+        while map_start < new_end && !mm.find_vma_intersection(map_start, map_start + 1).is_null() {
+            map_start += PAGE_SIZE as u64;
+        }
+        if map_start < new_end
+        {
+            if !mm.find_vma_intersection(map_start, new_end).is_null()
+            {
+                return mm.brk;
+            }
+            let vma = mm.create_mem_area_fixed(map_start, new_end, MmapType::PROT_READ | MmapType::PROT_WRITE, MmapType::MAP_PRIVATE | MmapType::MAP_ANONYMOUS | mm.def_flags, null_mut(), 0);
+            if vma.is_null()
+            {
+                return mm.brk;
+            }
+            mlock::mm_populate(map_start, (new_end - map_start) as usize);
+        }
+        mm.brk = brk;
+        brk
+    }
+}
@@ -1,7 +1,20 @@
 pub mod memory;
 pub mod page;
+pub mod page_alloc;
+pub mod rmap;
 pub mod slub;
 pub mod mm_type;
+pub mod filemap;
+pub mod vmscan;
+pub mod oom_kill;
+pub mod swapfile;
 pub mod mmap;
 pub mod shmem;
-
+pub mod kmemleak;
+pub mod vmalloc;
+pub mod huge_memory;
+pub mod madvise;
+pub mod mlock;
+pub mod mincore;
+pub mod mremap;
+pub mod ksm;
@@ -0,0 +1,181 @@
+use core::{ffi::c_void, ptr::null};
+
+use crate::{fs::file::EOF, kernel::{cpu::flush_tlb, errno_base::ENOMEM, sched::get_current_running_process, Off}};
+
+use super::{huge_memory, memory::{follow_page, follow_pmd, follow_pte, pmd_trans_huge, pte_alloc, MAX_USER_STACK_SIZE, PAGE_SIZE}, mlock, mm_type::{MMStruct, MmapType, VMAreaStruct}, mmap::do_munmap, rmap};
+
+pub const MREMAP_MAYMOVE : u64 = 1; // the mapping may go somewhere else
+pub const MREMAP_FIXED : u64 = 2; // and that place is `new_addr`, whatever was mapped there goes
+
+// carry the entries of [old_addr, old_addr + len) over to new_addr, the frames stay where they are
+// huge pages move whole when both sides line up, otherwise they are split first
+// returns how far it got, less than `len` when a page table could not be allocated or a huge page split
+unsafe fn move_page_tables(mm : *mut MMStruct, old_addr : u64, new_addr : u64, len : u64) -> u64
+{
+    let mut offset = 0;
+    while offset < len {
+        let old = old_addr + offset;
+        let new = new_addr + offset;
+        let pmd = follow_pmd(mm, old);
+        if !pmd.is_null() && pmd_trans_huge(&*pmd)
+        {
+            let aligned = old & !huge_memory::HPAGE_PMD_MASK == 0 && new & !huge_memory::HPAGE_PMD_MASK == 0;
+            if aligned && len - offset >= huge_memory::HPAGE_PMD_SIZE && huge_memory::move_huge_pmd(mm, old, new)
+            {
+                offset += huge_memory::HPAGE_PMD_SIZE;
+                continue;
+            }
+            if huge_memory::split_huge_pmd(mm, old & huge_memory::HPAGE_PMD_MASK) < 0
+            {
+                return offset;
+            }
+        }
+        let mut step = PAGE_SIZE as u64;
+        let old_pte = follow_pte(mm, old, &mut step);
+        if old_pte.is_null()
+        {
+            // nothing mapped up to the end of the missing table
+            offset = ((old & !(step - 1)) + step).min(old_addr + len) - old_addr;
+            continue;
+        }
+        if (*old_pte).raw() != 0
+        {
+            let new_pte = pte_alloc(mm, new);
+            if new_pte.is_null()
+            {
+                return offset;
+            }
+            let page = follow_page(mm, old);
+            (*new_pte).set_raw((*old_pte).raw());
+            (*old_pte).set_raw(0);
+            flush_tlb(old as *const c_void);
+            if !page.is_null() && rmap::page_anon(&*page)
+            {
+                rmap::page_remove_rmap(page, mm, old);
+                rmap::page_add_anon_rmap(page, mm, new);
+            }
+        }
+        offset += PAGE_SIZE as u64;
+    }
+    len
+}
+
+// the range [old_addr, old_addr + old_len) of `vma` becomes a new area at new_addr
+unsafe fn move_vma(mm : &mut MMStruct, vma : *mut VMAreaStruct, old_addr : u64, old_len : usize, new_addr : u64, new_len : usize) -> *mut c_void
+{
+    let offset = (*vma).get_offset() + (old_addr - (*vma).get_start()) as Off;
+    mm.copy_vma(vma, new_addr, new_len, offset);
+    let moved = move_page_tables(mm, old_addr, new_addr, old_len as u64);
+    if moved < old_len as u64
+    {
+        // put back what already moved, the old side still has its tables
+        move_page_tables(mm, new_addr, old_addr, moved);
+        do_munmap(mm, new_addr, new_addr + new_len as u64);
+        return -ENOMEM as *mut c_void;
+    }
+    // only empty ptes are left behind, locked pages keep their lock in the new area
+    do_munmap(mm, old_addr, old_addr + old_len as u64);
+    if new_len > old_len
+    {
+        mlock::mm_populate(new_addr + old_len as u64, new_len - old_len);
+    }
+    new_addr as *mut c_void
+}
+
+// MREMAP_FIXED, the old range first loses what won't fit
+// both munmaps may split or free the area the old range was in, so it is looked up again afterwards
+unsafe fn mremap_to(mm : &mut MMStruct, old_addr : u64, old_len : usize, new_addr : u64, new_len : usize) -> *mut c_void
+{
+    if new_addr & (PAGE_SIZE as u64 - 1) != 0 || new_addr == 0 || (new_addr < old_addr + old_len as u64 && old_addr < new_addr + new_len as u64)
+    {
+        return EOF as *mut c_void;
+    }
+    let err = do_munmap(mm, new_addr, new_addr + new_len as u64);
+    if err < 0
+    {
+        return err as *mut c_void;
+    }
+    let mut old_len = old_len;
+    if old_len > new_len
+    {
+        let err = do_munmap(mm, old_addr + new_len as u64, old_addr + old_len as u64);
+        if err < 0
+        {
+            return err as *mut c_void;
+        }
+        old_len = new_len;
+    }
+    let vma = mm.contain(old_addr);
+    if vma.is_null() || old_addr + old_len as u64 > (*vma).get_end() + 1
+    {
+        return EOF as *mut c_void;
+    }
+    move_vma(mm, vma, old_addr, old_len, new_addr, new_len)
+}
+
+// `vma` can run `delta` bytes further without hitting another area or the stack gap
+unsafe fn vma_expandable(mm : &mut MMStruct, vma : *mut VMAreaStruct, delta : usize) -> bool
+{
+    let end = (*vma).get_end() + 1 + delta as u64;
+    !(*vma).get_flags().contains(MmapType::VM_DONTEXPAND) && end <= mm.stack_top - MAX_USER_STACK_SIZE as u64 && mm.find_vma_intersection((*vma).get_end() + 1, end).is_null()
+}
+
+// resize the mapping at `old_addr`, growing in place when the space after it is free
+// page table entries move along with a moved mapping, nothing is copied
+pub fn sys_mremap(old_addr : *const c_void, old_len : usize, new_len : usize, flags : u64, new_addr : *const c_void) -> *mut c_void
+{
+    unsafe
+    {
+        let old = old_addr as u64;
+        let old_len = old_len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let new_len = new_len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        if old & (PAGE_SIZE as u64 - 1) != 0 || flags & !(MREMAP_MAYMOVE | MREMAP_FIXED) != 0 || flags == MREMAP_FIXED || old_len == 0 || new_len == 0
+        {
+            return EOF as *mut c_void;
+        }
+        let mm = &mut (*get_current_running_process()).mm;
+        let vma = mm.contain(old);
+        // the old range has to lie in a single area
+        if vma.is_null() || old + old_len as u64 > (*vma).get_end() + 1
+        {
+            return EOF as *mut c_void;
+        }
+        if flags & MREMAP_FIXED != 0
+        {
+            return mremap_to(mm, old, old_len, new_addr as u64, new_len);
+        }
+        if new_len <= old_len
+        {
+            let err = do_munmap(mm, old + new_len as u64, old + old_len as u64);
+            if err < 0
+            {
+                return err as *mut c_void;
+            }
+            return old_addr as *mut c_void;
+        }
+        if old + old_len as u64 == (*vma).get_end() + 1 && vma_expandable(mm, vma, new_len - old_len)
+        {
+            mm.expand_vma(vma, old + new_len as u64);
+            mlock::mm_populate(old + old_len as u64, new_len - old_len);
+            return old_addr as *mut c_void;
+        }
+        if flags & MREMAP_MAYMOVE == 0
+        {
+            return EOF as *mut c_void;
+        }
+        let mut new = 0;
+        if (*vma).get_file().is_null()
+        {
+            new = huge_memory::thp_get_unmapped_area(mm, new_len, (*vma).get_flags());
+        }
+        if new == 0
+        {
+            new = mm.get_unmapped_area(null(), new_len, null());
+        }
+        if new == 0
+        {
+            return EOF as *mut c_void;
+        }
+        move_vma(mm, vma, old, old_len, new, new_len)
+    }
+}
@@ -0,0 +1,99 @@
+use core::{ffi::CStr, ptr::null_mut};
+
+use crate::{kernel::{buffer::show_buffer_cache, process::{fatal_signal_pending, for_each_process, kill_process, PCB, PF_KTHREAD}}, logk};
+
+use super::{memory::PAGE_SIZE, mm_type::MMCounter, page::GFP, page_alloc::{nr_free_pages, show_buddyinfo}, slub::show_slabinfo, vmscan::show_reclaim_state};
+
+// exit code of a task the oom killer took, SIGKILL
+const OOM_KILL_CODE : i64 = 9;
+
+fn task_name(pcb : *const PCB) -> &'static str
+{
+    unsafe
+    {
+        CStr::from_ptr((*pcb).name.as_ptr()).to_str().unwrap_or("?")
+    }
+}
+
+// user tasks only, pid 1 is init and has to stay
+fn oom_unkillable_task(pcb : *const PCB) -> bool
+{
+    unsafe
+    {
+        (*pcb).flags & PF_KTHREAD != 0 || (*pcb).pid <= 1
+    }
+}
+
+// a victim that has not exited yet
+fn oom_victim_pending() -> bool
+{
+    let mut pending = false;
+    for_each_process(|pcb| unsafe {
+        pending |= fatal_signal_pending(&*pcb) && !oom_unkillable_task(pcb);
+    });
+    pending
+}
+
+// the task with the most resident and swapped out pages
+fn select_bad_process() -> *mut PCB
+{
+    let mut chosen = null_mut();
+    let mut chosen_points = 0;
+    for_each_process(|pcb| unsafe {
+        if oom_unkillable_task(pcb)
+        {
+            return;
+        }
+        let points = (*pcb).mm.get_mm_rss() + (*pcb).mm.get_mm_counter(MMCounter::SwapEnts);
+        if points > chosen_points
+        {
+            chosen = pcb;
+            chosen_points = points;
+        }
+    });
+    chosen
+}
+
+fn dump_tasks()
+{
+    logk!("[  pid  ]   rss  swapents  name\n");
+    for_each_process(|pcb| unsafe {
+        logk!("[{:>7}] {:>6}  {:>8}  {}\n", (*pcb).pid, (*pcb).mm.get_mm_rss(), (*pcb).mm.get_mm_counter(MMCounter::SwapEnts), task_name(pcb));
+    });
+}
+
+fn dump_header(gfp : GFP, order : usize)
+{
+    logk!("out of memory: gfp {:#x}, order {}, {} pages free\n", gfp.bits(), order, nr_free_pages());
+    show_buddyinfo();
+    show_reclaim_state();
+    show_buffer_cache();
+    show_slabinfo();
+    dump_tasks();
+}
+
+// reclaim got nowhere, kill the biggest task to free its memory
+// the victim frees it when it exits on its way to user mode, the caller has to give it the cpu
+// false when nothing could be killed
+pub fn out_of_memory(gfp : GFP, order : usize) -> bool
+{
+    unsafe
+    {
+        // the last victim is still on its way out, a second one would not help
+        if oom_victim_pending()
+        {
+            return true;
+        }
+        dump_header(gfp, order);
+        let victim = select_bad_process();
+        if victim.is_null()
+        {
+            logk!("out of memory and no killable processes\n");
+            return false;
+        }
+        let mm = &(*victim).mm;
+        logk!("Out of memory: Killed process {} ({}) anon-rss:{}kB, file-rss:{}kB, shmem-rss:{}kB\n", (*victim).pid, task_name(victim), mm.get_mm_counter(MMCounter::AnonPages) * PAGE_SIZE / 1024, mm.get_mm_counter(MMCounter::FilePages) * PAGE_SIZE / 1024, mm.get_mm_counter(MMCounter::ShmemPages) * PAGE_SIZE / 1024);
+        kill_process(victim, OOM_KILL_CODE);
+        true
+    }
+}
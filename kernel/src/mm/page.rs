@@ -37,6 +37,10 @@ bitflags!{
         const PgUncached = 1 << 22;            /* Page has been mapped as uncached */
         const PgHwpoison = 1 << 23;            /* hardware poisoned page. Don't touch */
         const PgCompoundLock = 1 << 24;
+        const PgBuddy = 1 << 25;               /* Free block head in the buddy allocator */
+        const PgAnon = 1 << 26;                /* Anonymous page, mapping is the rmap chain */
+        const PgLazyfree = 1 << 27;            /* MADV_FREE page, dropped instead of swapped while clean */
+        const PgKsm = 1 << 28;                 /* Merged by ksmd, read-only while in the stable tree */
         const PgChecked = 1 << 8;
         const PgFsCache = 1 << 12;
         const PgPinned = 1 << 8;
@@ -72,7 +76,7 @@ bitflags! {
         const __NOFAIL = 0x40000;
         const __NORETRY = 0x80000;
         const __COLD = 0x100000;
-        const __NOWARN = 0x200000;
+        const __NOWARN = 0x1000000;
         const __ZERO = 0x400000;
         const __NOTRACK = 0x800000;
         const __OTHER_NODE = 0x100000;
@@ -91,9 +95,10 @@ pub struct Page
 {
     pub flags : Pageflags,
     pub lru : ListHead,
-    pub filter : *mut c_void,
+    pub mapping : *mut c_void,
     pub reserved : u64,
-    pub _refcount : AtomicI32
+    pub _refcount : AtomicI32,
+    pub _mapcount : AtomicI32 // ptes mapping this page
 }
 
 impl Page {
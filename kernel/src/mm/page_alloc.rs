@@ -0,0 +1,519 @@
+use core::{ffi::c_void, mem::size_of, ptr::{addr_of_mut, null_mut}, sync::atomic::Ordering};
+
+use proc_macro::__init;
+
+use crate::{container_of, kernel::{clock::schedule_timeout, list::ListHead, process::fatal_signal_pending, sched::get_current_running_process, semaphore::UnreenterabkeSpinLock}, logk, printk};
+
+use super::{oom_kill, vmscan, memory::{page2virt, virt2page, MEMORY_POOL, PAGE_SHIFT, PAGE_SIZE, PHYS_PFN_OFFSET}, page::{Page, Pageflags, GFP}};
+
+// free blocks are 1 << 0 .. 1 << (MAX_ORDER - 1) pages
+pub const MAX_ORDER : usize = 11;
+pub const MAX_NR_ZONES : usize = 3;
+// zone limits, physical addresses
+const MAX_DMA_ADDRESS : usize = 16 * 1024 * 1024;
+const MAX_DMA32_ADDRESS : usize = 4 * 1024 * 1024 * 1024;
+// Zone::watermark, below low kswapd starts, below min only reclaim itself may allocate
+pub const WMARK_MIN : usize = 0;
+pub const WMARK_LOW : usize = 1;
+pub const WMARK_HIGH : usize = 2;
+pub const NR_WMARK : usize = 3;
+// rounds of direct reclaim and oom kills before an allocation gives up
+const MAX_RECLAIM_RETRIES : usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ZoneType
+{
+    Dma = 0,
+    Dma32 = 1,
+    Normal = 2
+}
+
+#[derive(Clone, Copy)]
+pub struct FreeArea
+{
+    pub free_list : ListHead, // head blocks of this order, linked by Page::lru
+    pub nr_free : usize
+}
+
+pub struct Zone
+{
+    pub name : &'static str,
+    pub start_pfn : usize,
+    pub end_pfn : usize, // exclusive
+    pub free_area : [FreeArea; MAX_ORDER],
+    pub free_pages : usize,
+    pub managed_pages : usize,
+    pub watermark : [usize; NR_WMARK],
+    lock : UnreenterabkeSpinLock
+}
+
+pub static mut ZONES : [Zone; MAX_NR_ZONES] = [Zone::new("DMA"), Zone::new("DMA32"), Zone::new("Normal")];
+
+impl FreeArea {
+    const fn new() -> Self
+    {
+        Self { free_list: ListHead::empty(), nr_free: 0 }
+    }
+}
+
+impl Zone {
+    const fn new(name : &'static str) -> Self
+    {
+        Self { name, start_pfn: 0, end_pfn: 0, free_area: [FreeArea::new(); MAX_ORDER], free_pages: 0, managed_pages: 0, watermark: [0; NR_WMARK], lock: UnreenterabkeSpinLock::new(1) }
+    }
+
+    fn contains(&self, pfn : usize) -> bool
+    {
+        pfn >= self.start_pfn && pfn < self.end_pfn
+    }
+
+    // still `mark` pages free after taking 1 << order
+    pub fn watermark_ok(&self, order : usize, mark : usize) -> bool
+    {
+        self.free_pages >= (1 << order) + mark
+    }
+
+    // merge with free buddies as far as they go, then queue the block
+    unsafe fn free_one_page(&mut self, mut pfn : usize, mut order : usize)
+    {
+        while order < MAX_ORDER - 1 {
+            let buddy_pfn = pfn ^ (1 << order);
+            if !self.contains(buddy_pfn) || !page_is_buddy(pfn_to_page(buddy_pfn), order)
+            {
+                break;
+            }
+            let buddy = pfn_to_page(buddy_pfn);
+            (*buddy).lru.delete();
+            self.free_area[order].nr_free -= 1;
+            clear_buddy(buddy);
+            pfn &= buddy_pfn;
+            order += 1;
+        }
+        let page = pfn_to_page(pfn);
+        set_buddy(page, order);
+        (*page).lru.head_insert(&mut self.free_area[order].free_list);
+        self.free_area[order].nr_free += 1;
+    }
+
+    // take the smallest block that fits and hand the unused halves back
+    unsafe fn rmqueue(&mut self, order : usize) -> *mut Page
+    {
+        let mut current_order = order;
+        while current_order < MAX_ORDER {
+            let area = &mut self.free_area[current_order];
+            if !area.free_list.is_empty()
+            {
+                let page = container_of!(area.free_list.next, Page, lru);
+                (*page).lru.delete();
+                area.nr_free -= 1;
+                clear_buddy(page);
+                let pfn = page_to_pfn(page);
+                let mut high = current_order;
+                while high > order {
+                    high -= 1;
+                    let half = pfn_to_page(pfn + (1 << high));
+                    set_buddy(half, high);
+                    (*half).lru.head_insert(&mut self.free_area[high].free_list);
+                    self.free_area[high].nr_free += 1;
+                }
+                self.free_pages -= 1 << order;
+                return page;
+            }
+            current_order += 1;
+        }
+        null_mut()
+    }
+}
+
+#[inline(always)]
+pub fn pfn_to_page(pfn : usize) -> *mut Page
+{
+    unsafe
+    {
+        MEMORY_POOL.mem_map.add(pfn - PHYS_PFN_OFFSET)
+    }
+}
+
+#[inline(always)]
+pub fn page_to_pfn(page : *const Page) -> usize
+{
+    unsafe
+    {
+        (page as usize - MEMORY_POOL.mem_map as usize) / size_of::<Page>() + PHYS_PFN_OFFSET
+    }
+}
+
+#[inline(always)]
+pub fn page_address(page : *const Page) -> *mut c_void
+{
+    page2virt((page_to_pfn(page) - PHYS_PFN_OFFSET) as u64)
+}
+
+#[inline(always)]
+pub fn virt_to_page(addr : *const c_void) -> *mut Page
+{
+    pfn_to_page(virt2page(addr) as usize + PHYS_PFN_OFFSET)
+}
+
+// smallest order whose block holds `size` bytes
+pub fn get_order(size : usize) -> usize
+{
+    let pages = size.div_ceil(PAGE_SIZE);
+    let mut order = 0;
+    while (1 << order) < pages {
+        order += 1;
+    }
+    order
+}
+
+// the order of a free head page lives in Page::reserved
+unsafe fn set_buddy(page : *mut Page, order : usize)
+{
+    (*page).flags.insert(Pageflags::PgBuddy);
+    (*page).reserved = order as u64;
+}
+
+unsafe fn clear_buddy(page : *mut Page)
+{
+    (*page).flags.remove(Pageflags::PgBuddy);
+    (*page).reserved = 0;
+}
+
+unsafe fn page_is_buddy(page : *const Page, order : usize) -> bool
+{
+    (*page).flags.contains(Pageflags::PgBuddy) && (*page).reserved == order as u64
+}
+
+// highest zone the request may use, lower zones are the fallback
+fn gfp_zone(gfp : GFP) -> ZoneType
+{
+    if gfp.contains(GFP::__DMA)
+    {
+        ZoneType::Dma
+    }
+    else if gfp.contains(GFP::__DMA32)
+    {
+        ZoneType::Dma32
+    }
+    else {
+        ZoneType::Normal
+    }
+}
+
+fn page_zone(pfn : usize) -> *mut Zone
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < MAX_NR_ZONES {
+            if ZONES[var].contains(pfn)
+            {
+                return addr_of_mut!(ZONES[var]);
+            }
+            var += 1;
+        }
+        null_mut()
+    }
+}
+
+unsafe fn prep_new_page(page : *mut Page, order : usize, gfp : GFP)
+{
+    let mut var = 0;
+    while var < 1 << order {
+        let p = page.add(var);
+        (*p).flags = Pageflags::empty();
+        (*p).mapping = null_mut();
+        (*p)._refcount.store(1, Ordering::Relaxed);
+        (*p)._mapcount.store(0, Ordering::Relaxed);
+        var += 1;
+    }
+    if gfp.contains(GFP::__ZERO)
+    {
+        compiler_builtins::mem::memset(page_address(page) as *mut u8, 0, PAGE_SIZE << order);
+    }
+}
+
+// first zone that stays above `wmark` after the allocation, None ignores the watermarks
+unsafe fn get_page_from_freelist(gfp : GFP, order : usize, wmark : Option<usize>) -> *mut Page
+{
+    let mut zone_idx = gfp_zone(gfp) as usize + 1;
+    while zone_idx > 0 {
+        zone_idx -= 1;
+        let zone = &mut ZONES[zone_idx];
+        if zone.managed_pages == 0
+        {
+            continue;
+        }
+        if let Some(wmark) = wmark
+        {
+            let mut mark = zone.watermark[wmark];
+            // atomic callers can't reclaim, let them go deeper
+            if gfp.contains(GFP::__HIGH)
+            {
+                mark /= 2;
+            }
+            if !zone.watermark_ok(order, mark)
+            {
+                continue;
+            }
+        }
+        zone.lock.acquire(1);
+        let page = zone.rmqueue(order);
+        zone.lock.release(1);
+        if !page.is_null()
+        {
+            prep_new_page(page, order, gfp);
+            return page;
+        }
+    }
+    null_mut()
+}
+
+// below the low watermark: wake kswapd and dip to min, then reclaim directly
+// and when that makes no progress kill the biggest process and try again
+unsafe fn __alloc_pages_slowpath(gfp : GFP, order : usize) -> *mut Page
+{
+    vmscan::wakeup_kswapd();
+    let page = get_page_from_freelist(gfp, order, Some(WMARK_MIN));
+    if !page.is_null()
+    {
+        return page;
+    }
+    if vmscan::current_is_reclaiming()
+    {
+        let page = get_page_from_freelist(gfp, order, None);
+        if !page.is_null()
+        {
+            return page;
+        }
+    }
+    else if gfp.contains(GFP::__WAIT)
+    {
+        let mut retries = 0;
+        while retries < MAX_RECLAIM_RETRIES {
+            let progress = vmscan::try_to_free_pages(order);
+            let page = get_page_from_freelist(gfp, order, Some(WMARK_MIN));
+            if !page.is_null()
+            {
+                return page;
+            }
+            if progress == 0
+            {
+                if !oom_kill::out_of_memory(gfp, order)
+                {
+                    break;
+                }
+                // a killed caller fails and exits, anyone else lets the victim run and tries again
+                let current = get_current_running_process();
+                if !current.is_null() && fatal_signal_pending(&*current)
+                {
+                    break;
+                }
+                schedule_timeout(1);
+            }
+            retries += 1;
+        }
+    }
+    if !gfp.contains(GFP::__NOWARN)
+    {
+        logk!("page allocation failure: order {}, gfp {:#x}\n", order, gfp.bits());
+    }
+    null_mut()
+}
+
+pub fn alloc_pages(gfp : GFP, order : usize) -> *mut Page
+{
+    unsafe
+    {
+        if order >= MAX_ORDER
+        {
+            return null_mut();
+        }
+        let page = get_page_from_freelist(gfp, order, Some(WMARK_LOW));
+        if !page.is_null()
+        {
+            return page;
+        }
+        __alloc_pages_slowpath(gfp, order)
+    }
+}
+
+pub fn __free_pages(page : *mut Page, order : usize)
+{
+    unsafe
+    {
+        let pfn = page_to_pfn(page);
+        let zone = page_zone(pfn);
+        assert!(!zone.is_null(), "free page {:#x} outside every zone", pfn);
+        let mut var = 0;
+        while var < 1 << order {
+            let p = pfn_to_page(pfn + var);
+            (*p).flags = Pageflags::empty();
+            (*p).mapping = null_mut();
+            (*p)._refcount.store(0, Ordering::Relaxed);
+            (*p)._mapcount.store(0, Ordering::Relaxed);
+            var += 1;
+        }
+        (*zone).lock.acquire(1);
+        (*zone).free_one_page(pfn, order);
+        (*zone).free_pages += 1 << order;
+        (*zone).lock.release(1);
+    }
+}
+
+pub fn get_page(page : &Page)
+{
+    page._refcount.fetch_add(1, Ordering::Relaxed);
+}
+
+// drop a reference, the last one frees the page
+pub unsafe fn put_page(page : *mut Page)
+{
+    if (*page)._refcount.fetch_sub(1, Ordering::AcqRel) == 1
+    {
+        if (*page).flags.contains(Pageflags::PgLru)
+        {
+            vmscan::lru_cache_del(page);
+        }
+        __free_pages(page, 0);
+    }
+}
+
+// linear map address of 1 << order pages
+pub fn get_free_pages(gfp : GFP, order : usize) -> *mut c_void
+{
+    let page = alloc_pages(gfp, order);
+    if page.is_null()
+    {
+        return null_mut();
+    }
+    page_address(page)
+}
+
+pub fn free_pages(addr : *const c_void, order : usize)
+{
+    if addr.is_null()
+    {
+        return;
+    }
+    __free_pages(virt_to_page(addr), order);
+}
+
+pub fn nr_free_pages() -> usize
+{
+    unsafe
+    {
+        let mut result = 0;
+        let mut var = 0;
+        while var < MAX_NR_ZONES {
+            result += ZONES[var].free_pages;
+            var += 1;
+        }
+        result
+    }
+}
+
+// free block count of every order in a zone, like /proc/buddyinfo
+pub fn zone_free_area_counts(zone : ZoneType) -> [usize; MAX_ORDER]
+{
+    unsafe
+    {
+        let mut result = [0; MAX_ORDER];
+        let mut var = 0;
+        while var < MAX_ORDER {
+            result[var] = ZONES[zone as usize].free_area[var].nr_free;
+            var += 1;
+        }
+        result
+    }
+}
+
+pub fn show_buddyinfo()
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < MAX_NR_ZONES {
+            if ZONES[var].managed_pages != 0
+            {
+                printk!("zone {:>6}: {:?}\n", ZONES[var].name, ZONES[var].free_area.map(|area| area.nr_free));
+            }
+            var += 1;
+        }
+    }
+}
+
+// min_free_kbytes = sqrt(16 * lowmem kbytes) split between the zones by size,
+// low and high sit a quarter and a half of min above it
+#[__init]
+fn setup_per_zone_wmarks()
+{
+    unsafe
+    {
+        let mut managed = 0;
+        let mut var = 0;
+        while var < MAX_NR_ZONES {
+            managed += ZONES[var].managed_pages;
+            var += 1;
+        }
+        let lowmem_kbytes = managed * (PAGE_SIZE / 1024);
+        let mut min_free_kbytes = 0;
+        while (min_free_kbytes + 1) * (min_free_kbytes + 1) <= lowmem_kbytes * 16 {
+            min_free_kbytes += 1;
+        }
+        min_free_kbytes = min_free_kbytes.clamp(128, 65536);
+        let min_pages = min_free_kbytes / (PAGE_SIZE / 1024);
+        var = 0;
+        while var < MAX_NR_ZONES {
+            let zone = &mut ZONES[var];
+            if zone.managed_pages != 0
+            {
+                let min = min_pages * zone.managed_pages / managed;
+                zone.watermark = [min, min + min / 4, min + min / 2];
+            }
+            var += 1;
+        }
+    }
+}
+
+// hand every unused page in mem_map[start_idx..end_idx] to the buddy allocator
+#[__init]
+pub fn free_area_init(start_idx : usize, end_idx : usize)
+{
+    unsafe
+    {
+        let limits = [MAX_DMA_ADDRESS >> PAGE_SHIFT, MAX_DMA32_ADDRESS >> PAGE_SHIFT, usize::MAX];
+        let mut zone_start = PHYS_PFN_OFFSET;
+        let mut var = 0;
+        while var < MAX_NR_ZONES {
+            let zone = &mut ZONES[var];
+            zone.start_pfn = zone_start;
+            zone.end_pfn = core::cmp::max(zone_start, core::cmp::min(limits[var], end_idx + PHYS_PFN_OFFSET));
+            zone_start = zone.end_pfn;
+            let mut order = 0;
+            while order < MAX_ORDER {
+                zone.free_area[order].free_list.init();
+                order += 1;
+            }
+            var += 1;
+        }
+        let mut pfn = start_idx + PHYS_PFN_OFFSET;
+        while pfn < end_idx + PHYS_PFN_OFFSET {
+            let page = pfn_to_page(pfn);
+            if (*page)._refcount.load(Ordering::Relaxed) == 0
+            {
+                let zone = page_zone(pfn);
+                (*zone).free_one_page(pfn, 0);
+                (*zone).free_pages += 1;
+                (*zone).managed_pages += 1;
+            }
+            pfn += 1;
+        }
+        setup_per_zone_wmarks();
+        var = 0;
+        while var < MAX_NR_ZONES {
+            logk!("zone {}: pfn {:#x}-{:#x}, {} pages free, watermarks {:?}\n", ZONES[var].name, ZONES[var].start_pfn, ZONES[var].end_pfn, ZONES[var].free_pages, ZONES[var].watermark);
+            var += 1;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use core::{alloc::{GlobalAlloc, Layout}, ffi::c_void, ptr::null_mut, sync::atomic::Ordering};
+
+use crate::kernel::cpu::flush_tlb;
+
+use super::{memory::{follow_pte, MEMORY_POOL, PAGE_SIZE}, mm_type::{MMCounter, MMStruct, MmapType}, page::{Page, Pageflags}, page_alloc::put_page, swapfile::{swap_duplicate, swp_entry_to_pte, SwpEntry}};
+
+// one pte mapping an anonymous page, chained from Page::mapping
+pub struct AnonRmap
+{
+    pub mm : *mut MMStruct,
+    pub vaddr : u64,
+    next : *mut AnonRmap
+}
+
+pub fn page_mapcount(page : &Page) -> i32
+{
+    page._mapcount.load(Ordering::Relaxed)
+}
+
+pub fn page_anon(page : &Page) -> bool
+{
+    page.flags.contains(Pageflags::PgAnon)
+}
+
+// page cache pages only count their ptes, Page::mapping is the owning AddressSpace
+pub fn page_file(page : &Page) -> bool
+{
+    !page_anon(page) && !page.mapping.is_null()
+}
+
+pub fn page_add_file_rmap(page : &Page)
+{
+    page._mapcount.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn page_remove_file_rmap(page : &Page)
+{
+    page._mapcount.fetch_sub(1, Ordering::Relaxed);
+}
+
+// a freshly allocated page gets its first mapping
+pub unsafe fn page_add_new_anon_rmap(page : *mut Page, mm : *mut MMStruct, vaddr : u64)
+{
+    (*page).flags.insert(Pageflags::PgAnon);
+    (*page).mapping = null_mut();
+    (*page)._mapcount.store(0, Ordering::Relaxed);
+    page_add_anon_rmap(page, mm, vaddr);
+}
+
+pub unsafe fn page_add_anon_rmap(page : *mut Page, mm : *mut MMStruct, vaddr : u64)
+{
+    let item = MEMORY_POOL.alloc(Layout::new::<AnonRmap>()) as *mut AnonRmap;
+    item.write(AnonRmap { mm, vaddr, next: (*page).mapping.cast() });
+    (*page).mapping = item.cast();
+    (*page)._mapcount.fetch_add(1, Ordering::Relaxed);
+}
+
+pub unsafe fn page_remove_rmap(page : *mut Page, mm : *mut MMStruct, vaddr : u64)
+{
+    let mut link = &mut (*page).mapping as *mut *mut core::ffi::c_void as *mut *mut AnonRmap;
+    while !(*link).is_null() {
+        let item = *link;
+        if (*item).mm == mm && (*item).vaddr == vaddr
+        {
+            *link = (*item).next;
+            MEMORY_POOL.dealloc(item.cast(), Layout::new::<AnonRmap>());
+            (*page)._mapcount.fetch_sub(1, Ordering::Relaxed);
+            return;
+        }
+        link = &mut (*item).next;
+    }
+    panic!("page_remove_rmap: {:#x} not mapped by this mm", vaddr);
+}
+
+// visit every (mm, vaddr) mapping the page until `f` returns false
+pub unsafe fn rmap_walk(page : *mut Page, f : &mut dyn FnMut(*mut MMStruct, u64) -> bool)
+{
+    if !(*page).flags.contains(Pageflags::PgAnon)
+    {
+        return;
+    }
+    let mut item = (*page).mapping as *mut AnonRmap;
+    while !item.is_null() {
+        let next = (*item).next;
+        if !f((*item).mm, (*item).vaddr)
+        {
+            return;
+        }
+        item = next;
+    }
+}
+
+// clear the accessed bit in every pte of the page, true if any had it set
+pub unsafe fn page_referenced(page : *mut Page) -> bool
+{
+    let mut referenced = false;
+    rmap_walk(page, &mut |mm, vaddr| {
+        let mut step = PAGE_SIZE as u64;
+        let pte = follow_pte(mm, vaddr, &mut step);
+        if !pte.is_null() && (*pte).get_accessed() != 0
+        {
+            (*pte).set_accessed(0);
+            flush_tlb(vaddr as *const c_void);
+            // a sequential reader is done with the page once past it
+            let vma = (*mm).contain(vaddr);
+            referenced |= vma.is_null() || !(*vma).get_flags().contains(MmapType::VM_SEQ_READ);
+        }
+        true
+    });
+    referenced
+}
+
+// clear the dirty bit in every pte of the page, true if any had it set
+pub unsafe fn page_mkclean(page : *mut Page) -> bool
+{
+    let mut dirty = false;
+    rmap_walk(page, &mut |mm, vaddr| {
+        let mut step = PAGE_SIZE as u64;
+        let pte = follow_pte(mm, vaddr, &mut step);
+        if !pte.is_null() && (*pte).get_dirty() != 0
+        {
+            (*pte).set_dirty(0);
+            flush_tlb(vaddr as *const c_void);
+            dirty = true;
+        }
+        true
+    });
+    dirty
+}
+
+// point every pte of an anonymous page at its swap slot, the last put frees the page
+// without a slot the ptes are cleared and the next touch gets a zeroed page
+pub unsafe fn try_to_unmap(page : *mut Page, entry : Option<SwpEntry>)
+{
+    rmap_walk(page, &mut |mm, vaddr| {
+        let mut step = PAGE_SIZE as u64;
+        let pte = follow_pte(mm, vaddr, &mut step);
+        match entry {
+            Some(entry) =>
+            {
+                (*pte).set_raw(swp_entry_to_pte(entry));
+                swap_duplicate(entry);
+                (*mm).inc_mm_counter(MMCounter::SwapEnts);
+            }
+            None => (*pte).set_raw(0)
+        }
+        flush_tlb(vaddr as *const c_void);
+        (*mm).dec_mm_counter(MMCounter::AnonPages);
+        page_remove_rmap(page, mm, vaddr);
+        put_page(page);
+        true
+    });
+}
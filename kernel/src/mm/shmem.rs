@@ -1,19 +1,23 @@
 use core::mem::ManuallyDrop;
-use core::{alloc::Layout, mem::size_of, ptr::{null_mut, addr_of, addr_of_mut, null}, ffi::c_void, sync::atomic::AtomicI32};
+use core::{alloc::Layout, mem::size_of, ptr::{null_mut, addr_of, addr_of_mut, null}, ffi::{c_char, c_void, CStr}, sync::atomic::{AtomicI32, Ordering}};
 use core::intrinsics::unlikely;
 
 use alloc::alloc::alloc;
 use alloc::collections::{BTreeSet, BTreeMap};
 use alloc::string::String;
+use alloc::sync::Arc;
 use proc_macro::__init;
 
 use crate::fs::dcache::{DEntryOperations, DEntry};
-use crate::fs::{file::{DirEntry, FSPermission, FileMode, FileFlag}, libfs::simple_lookup, super_block::{kill_litter_super, get_tree_nodev}, fs_context::{FsContextOperations, FsContext}};
+use crate::fs::{ext4::Idx, file::{DirEntry, FSPermission, File, FileMode, FileFlag, FileSystem}, mount::{vfs_kern_mount, VFSMount}, path::Path, libfs::simple_lookup, super_block::{kill_litter_super, get_tree_nodev}, fs_context::{FsContextOperations, FsContext}};
 use crate::{kernel::{time, Err}, printk};
-use crate::{fs::{file::{LogicalPart, FSType, FS}, fs::{FileSystemType, SB_KERNMOUNT, FileSystemFlags}, mnt_idmapping::{MntIdmap, NOP_MNT_IDMAP}, inode::{Inode, InodeOperations}}, kernel::{{errno_base::{ENOSPC, ENOMEM, err_ptr, is_err, ptr_err}, io::SECTOR_SIZE}, list::ListHead, device::DevT, process::{Gid, Uid}, semaphore::SpinLock, time::Time, Off, sched::get_current_running_process}};
+use crate::{fs::{file::{LogicalPart, FSType, FS}, fs::{AddressSpace, AddressSpaceOperations, FileSystemType, SB_KERNMOUNT, FileSystemFlags}, mnt_idmapping::{MntIdmap, NOP_MNT_IDMAP}, inode::{Inode, InodeOperations}}, kernel::{{errno_base::{EEXIST, EFAULT, EINVAL, ENAMETOOLONG, ENOENT, ENOSPC, ENOMEM, err_ptr, is_err, ptr_err}, io::SECTOR_SIZE}, list::ListHead, device::DevT, process::{Gid, Uid}, semaphore::SpinLock, time::Time, Off, sched::get_current_running_process}};
 
-use super::{memory::{MemoryPool, PAGE_SIZE}, page::Pageflags, slub::{KMallocInfoStruct, KmemCache}};
+use super::{filemap::filemap_read, memory::{MemoryPool, PAGE_SIZE}, page::{Page, Pageflags}, page_alloc::{page_address, put_page}, slub::{kmem_cache_create, KMallocInfoStruct, KmemCache}};
 pub static mut DEV_FS : *mut ShmemSbInfo = null_mut();
+// kernel internal instance behind shared anonymous memory and shm_open
+static mut SHM_MNT : *mut VFSMount = null_mut();
+const NAME_MAX : usize = 255;
 const BOGO_INODE_SIZE : i64 = 1024;
 const VM_NORESERV : u32 = 0x00200000;
 const F_SEAL_SEAL : u32 = 1;
@@ -69,6 +73,11 @@ pub static SHMEM_DIR_INODEOPERATIONS : InodeOperations = InodeOperations
     mkdir: Some(shmem_mkdir)
 };
 
+pub static SHMEM_AOPS : AddressSpaceOperations = AddressSpaceOperations
+{
+    read_folio: Some(shmem_read_folio)
+};
+
 pub static SHMEM_INODE_OPERATIONS : InodeOperations = InodeOperations
 {
     lookup: None,
@@ -152,9 +161,7 @@ pub fn init_shmem()
 {
     unsafe
     {
-        SHMEM_INODE_CACHEP = alloc(Layout::new::<KmemCache>()) as *mut KmemCache;
-        (*SHMEM_INODE_CACHEP) = KmemCache::create_cache("shmem_inode_cache\0", size_of::<KmemCache>() as u32, size_of::<KmemCache>() as u32, null_mut(), Pageflags::empty());
-        (*SHMEM_INODE_CACHEP).link_to_cache_list();
+        SHMEM_INODE_CACHEP = kmem_cache_create("shmem_inode_cache", size_of::<KmemCache>() as u32, size_of::<KmemCache>() as u32, null_mut(), Pageflags::empty());
         DEV_FS = ShmemSbInfo::new();
         FS.register_filesystem(addr_of_mut!(SHMEM_FS_TYPE));
     }
@@ -360,6 +367,7 @@ fn __shmem_get_inode(idmap : *mut MntIdmap, lp : *mut LogicalPart, dir : *mut In
             FileMode::IFREG => 
             {
                 (*inode).i_operations = addr_of!(SHMEM_INODE_OPERATIONS);
+                (*inode).address_space = AddressSpace::new(inode, addr_of!(SHMEM_AOPS), Pageflags::empty(), FileFlag::empty());
             },
             FileMode::IFDIR => 
             {
@@ -374,6 +382,26 @@ fn __shmem_get_inode(idmap : *mut MntIdmap, lp : *mut LogicalPart, dir : *mut In
         return inode;
     } 
 }
+// shmem files live only in the page cache, a page never written reads as zeros
+// with no swap behind them reclaim would throw data away, so they stay off the lru
+fn shmem_read_folio(_mapping : *mut AddressSpace, page : *mut Page) -> Err
+{
+    unsafe
+    {
+        compiler_builtins::mem::memset(page_address(page) as *mut u8, 0, PAGE_SIZE);
+        (*page).flags.insert(Pageflags::PgUnevictable);
+    }
+    0
+}
+
+pub fn shmem_file_read(inode : *mut Inode, buffer : *mut c_void, len : usize, offset : usize) -> i64
+{
+    unsafe
+    {
+        filemap_read((*inode).address_space, buffer, len, offset)
+    }
+}
+
 #[inline(always)]
 fn shmem_get_inode(idmap : *mut MntIdmap, lp : *mut LogicalPart, dir : *mut Inode, mode : FileMode, dev : DevT, flags : FileFlag) -> *mut Inode
 {
@@ -406,3 +434,178 @@ pub fn shmem_mkdir(idmap : *mut MntIdmap, dir : *mut Inode, dentry : *mut DEntry
     }
 }
 
+
+// a file for `inode` on the internal mount, it takes over the caller's inode reference
+unsafe fn shmem_alloc_file(inode : *mut Inode, flags : FileFlag) -> *mut File
+{
+    let file_t = alloc(Layout::new::<File>()) as *mut File;
+    if file_t.is_null()
+    {
+        return null_mut();
+    }
+    file_t.write(File::new());
+    (*file_t).inode = inode;
+    (*file_t).flag = flags;
+    (*file_t).f_mapping = (*inode).address_space;
+    file_t
+}
+
+// an unlinked file of `size` bytes, it goes away with its last fd or mapping
+pub fn shmem_file_setup(size : usize, flags : FileFlag) -> *mut File
+{
+    unsafe
+    {
+        let lp = (*SHM_MNT).mnt_sb;
+        let inode = shmem_get_inode(addr_of_mut!(NOP_MNT_IDMAP), lp, null_mut(), FileMode::IFREG, 0, FileFlag::empty());
+        if is_err(inode)
+        {
+            return inode.cast();
+        }
+        (*inode).i_size = size;
+        let file_t = shmem_alloc_file(inode, flags);
+        if file_t.is_null()
+        {
+            (*lp).release_inode(inode);
+            return err_ptr(-ENOMEM);
+        }
+        file_t
+    }
+}
+
+// grow or shrink a shmem file, cached pages past the new end are dropped
+// pages still mapped stay with their mappers until they unmap them
+pub unsafe fn shmem_setsize(inode : *mut Inode, size : usize)
+{
+    let old_size = (*inode).i_size;
+    (*inode).i_size = size;
+    if size >= old_size
+    {
+        return;
+    }
+    let mapping = (*inode).address_space;
+    let partial = size % PAGE_SIZE;
+    // the cut off tail must read back as zeros if the file grows again
+    if partial != 0
+    {
+        let page = (*mapping).find_get_page((size / PAGE_SIZE) as Idx);
+        if !page.is_null()
+        {
+            compiler_builtins::mem::memset((page_address(page) as *mut u8).add(partial), 0, PAGE_SIZE - partial);
+            put_page(page);
+        }
+    }
+    (*mapping).truncate_pages_from(size.div_ceil(PAGE_SIZE) as Idx);
+}
+
+// "/name" or "name", one component only
+fn shm_name(name : *const c_char) -> Result<String, Err>
+{
+    if name.is_null()
+    {
+        return Err(-EFAULT);
+    }
+    let name = match unsafe { CStr::from_ptr(name) }.to_str() {
+        Ok(name) => name.strip_prefix('/').unwrap_or(name),
+        Err(_) => return Err(-EINVAL)
+    };
+    if name.is_empty() || name.contains('/') || name == "." || name == ".."
+    {
+        return Err(-EINVAL);
+    }
+    if name.len() > NAME_MAX
+    {
+        return Err(-ENAMETOOLONG);
+    }
+    Ok(String::from(name))
+}
+
+// named objects sit in the root of the internal mount until shm_unlink
+pub fn sys_shm_open(name : *const c_char, oflag : FileFlag, mode : FSPermission) -> Err
+{
+    unsafe
+    {
+        let name = match shm_name(name) {
+            Ok(name) => name,
+            Err(err) => return err
+        };
+        let root = (*SHM_MNT).mnt_root;
+        let mut path = Path::empty();
+        path.mnt = SHM_MNT;
+        let mut dentry = (*root).look_up(&name, &mut path);
+        if dentry.is_null()
+        {
+            if !oflag.contains(FileFlag::O_CREAT)
+            {
+                return -ENOENT;
+            }
+            dentry = (*root).new_child(&name);
+            // the dentry keeps the reference mknod gives it until shm_unlink
+            let err = FileSystem::do_mknodat(addr_of_mut!(NOP_MNT_IDMAP), (*root).d_inode, dentry, FileMode::IFREG, 0);
+            if err < 0
+            {
+                (*dentry).dput();
+                (*root).d_ref.fetch_sub(1, Ordering::AcqRel);
+                return err;
+            }
+            (*(*dentry).d_inode).i_perm = mode;
+        }
+        else if oflag.contains(FileFlag::O_CREAT | FileFlag::O_EXCL)
+        {
+            return -EEXIST;
+        }
+        let inode = (*dentry).d_inode;
+        if oflag.contains(FileFlag::O_TRUNC)
+        {
+            shmem_setsize(inode, 0);
+        }
+        (*inode).count.fetch_add(1, Ordering::Relaxed);
+        let file_t = shmem_alloc_file(inode, oflag);
+        if file_t.is_null()
+        {
+            (*(*inode).logical_part_ptr).release_inode(inode);
+            return -ENOMEM;
+        }
+        (*get_current_running_process()).insert_to_fd(file_t) as Err
+    }
+}
+
+// the name goes now, the object once nothing has it open or mapped
+pub fn sys_shm_unlink(name : *const c_char) -> Err
+{
+    unsafe
+    {
+        let name = match shm_name(name) {
+            Ok(name) => name,
+            Err(err) => return err
+        };
+        let root = (*SHM_MNT).mnt_root;
+        let mut path = Path::empty();
+        path.mnt = SHM_MNT;
+        let dentry = (*root).look_up(&name, &mut path);
+        if dentry.is_null()
+        {
+            return -ENOENT;
+        }
+        let inode = (*dentry).d_inode;
+        (*dentry).d_inode = null_mut();
+        // mknod's pin, then the one that keeps it in the directory
+        (*dentry).dput();
+        (*dentry).dput();
+        (*root).d_ref.fetch_sub(1, Ordering::AcqRel);
+        (*(*inode).logical_part_ptr).release_inode(inode);
+        0
+    }
+}
+
+#[__init]
+pub fn shmem_kern_mount()
+{
+    unsafe
+    {
+        SHM_MNT = vfs_kern_mount(addr_of_mut!(SHMEM_FS_TYPE), SB_KERNMOUNT, Arc::new(String::from("shm")), null_mut());
+        if is_err(SHM_MNT)
+        {
+            panic!("Could not kern_mount shmem");
+        }
+    }
+}
@@ -1,14 +1,54 @@
-use core::{ffi::c_void, ptr::{null_mut, null}, sync::atomic::AtomicU32, intrinsics::{log2f64, powf64, ceilf64}};
+use core::{alloc::{GlobalAlloc, Layout}, ffi::c_void, fmt::Write, mem::size_of, ptr::{null_mut, null}, sync::atomic::AtomicU32, intrinsics::{log2f64, powf64, ceilf64}};
 use alloc::{rc::Rc, string::String};
-use crate::{kernel::bitops, bochs_break};
+use proc_macro::__init;
+use crate::{kernel::{bitops, clock::JIFFIES, percpu::{get_current, preempt_disable, preempt_enable, smp_processor_id, MAX_CPU_NUM}, stacktrace::{print_stack_trace, save_stack_trace}}, bochs_break, logk};
+use bitflags::bitflags;
 use core::arch::asm;
 use crate::kernel::{list::ListHead, semaphore, math};
 
-use super::{page::{GFP, self}, memory::{self, MEMORY_POOL}};
+use super::{kmemleak, page::{GFP, self}, page_alloc, memory::{self, MEMORY_POOL}, vmscan::{register_shrinker, Shrinker}};
 const MAX_NUMNODES : usize = 1;
 const KMALLOC_THREASHHOLD : usize = 0x800;
 pub const KMALLOC_CACHES_NUM : usize = 12;
 pub static mut SLAB_CACHES : *mut KmemCache = null_mut();
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    pub struct SlabDebugFlags : u32
+    {
+        const CONSISTENCY_CHECKS = 1 << 0; // freed pointers must be objects of the cache
+        const RED_ZONE = 1 << 1;
+        const POISON = 1 << 2;
+        const STORE_USER = 1 << 3; // last alloc and free call site of each object
+    }
+}
+
+// caches created from here on pick this up
+pub static mut SLUB_DEBUG : SlabDebugFlags = if cfg!(feature = "slub_debug") { SlabDebugFlags::all() } else { SlabDebugFlags::empty() };
+
+const POISON_FREE : u8 = 0x6b;
+const POISON_END : u8 = 0xa5;
+const SLUB_RED_INACTIVE : u8 = 0xbb;
+const SLUB_RED_ACTIVE : u8 = 0xcc;
+const DEBUG_MAX_ALIGN : u32 = 64;
+// return addresses kept per alloc and free, the allocator's own frames come first
+const TRACK_ADDRS : usize = 8;
+
+#[derive(Clone, Copy)]
+struct Track
+{
+    addrs : [u64; TRACK_ADDRS],
+    pid : i32,
+    cpu : u32,
+    when : u64
+}
+
+#[derive(Clone, Copy)]
+enum TrackItem
+{
+    Alloc = 0,
+    Free = 1
+}
 macro_rules! init_kmalloc_info {
     ($size: expr, $__short_size: expr) => {
         KMallocInfoStruct::new(concat!("kmalloc-rcl-", stringify!($__short_size)), $size)
@@ -93,30 +133,37 @@ pub fn kmalloc_slab(size : usize,  flags : GFP) -> *mut KmemCache
         index = SIZE_INDEX[size_index_elem(size)];
     }
     else {
-        index = bitops::fls64(size as u64) as u8;
+        index = bitops::fls64(size as u64 - 1) as u8;
     }
     unsafe { KMALLOC_CACHES [index as usize] }
 }
 
+// objects freed on a cpu wait here and go out again before the node is asked
+#[derive(Clone, Copy)]
 struct KmemCacheCpu
 {
-    freelist : *mut *mut c_void,
-    tid : u64,
-    page : page::Page,
-    partial : *mut page::Page
+    freelist : *mut c_void,
+    nr_free : u32,
+    tid : u64 // bumped by every alloc and free on this cpu
 }
 
 pub struct KmemCacheNode
 {
     lock : semaphore::UnreenterabkeSpinLock,
-    nr_partial : u64, // number of slub
-    partial : ListHead // free slub list
+    nr_free : u64, // free objects over all slabs of the node
+    nr_slabs : u64,
+    nr_empty : u64, // slabs with no object handed out
+    total_objects : u64,
+    partial : ListHead // every slab of the node, null terminated
 }
 
+// overlays the page descriptor of a slab page
 #[repr(C)]
 pub struct Slab
 {
     __page_flags : page::Pageflags,
+    inuse : u16, // objects handed out
+    objects : u16,
     slab_cache : *mut KmemCache,
     slab_list : ListHead,
     free_list : *mut c_void,
@@ -150,6 +197,15 @@ impl Slab {
     }
 }
 
+#[inline(always)]
+fn virt_to_slab(object : *const c_void) -> *mut Slab
+{
+    unsafe
+    {
+        MEMORY_POOL.mem_map.offset(memory::virt2page(object) as isize) as *mut Slab
+    }
+}
+
 struct KmemCacheOrderObjects
 {
     x : u32
@@ -166,7 +222,7 @@ impl KmemCacheOrderObjects {
 pub struct KmemCache
 {
     flags : page::Pageflags,
-    min_partial : u64,
+    min_partial : u64, // empty slabs a node keeps instead of freeing
     size : u32,
     object_size : u32,
     reciprocal_size : u8,
@@ -177,64 +233,94 @@ pub struct KmemCache
     inuse : u32,
     align : u32,
     red_left_pad : u32,
+    cpu_partial : u32, // objects a cpu freelist holds at most
+    debug_flags : SlabDebugFlags,
     name : *const str,
     pub list : ListHead,
+    cpu_slab : [KmemCacheCpu; MAX_CPU_NUM],
     pub node : [KmemCacheNode; MAX_NUMNODES]
 }
 
+impl KmemCacheCpu {
+    const fn new() -> KmemCacheCpu
+    {
+        KmemCacheCpu { freelist: null_mut(), nr_free: 0, tid: 0 }
+    }
+}
+
 impl KmemCacheNode {
     fn new() -> KmemCacheNode
     {
-        KmemCacheNode { lock: semaphore::UnreenterabkeSpinLock::new(1), nr_partial: 0, partial: ListHead::empty() }
+        KmemCacheNode { lock: semaphore::UnreenterabkeSpinLock::new(1), nr_free: 0, nr_slabs: 0, nr_empty: 0, total_objects: 0, partial: ListHead::empty() }
     }
 
-    pub fn kmem_cache_node_bootstrap(&mut self, kmem_cache : &KmemCache)
+    fn add_slab(&mut self, slab : *mut Slab)
     {
-        if kmem_cache.object_size == 0
+        unsafe
         {
-            return;
+            let next = self.partial.next as *mut Slab;
+            (*slab).set_prev(null_mut());
+            (*slab).set_next(next);
+            if !next.is_null()
+            {
+                (*next).set_prev(slab);
+            }
+            self.partial.next = slab as *mut ListHead;
+            self.nr_slabs += 1;
+            self.nr_empty += 1;
+            self.total_objects += (*slab).objects as u64;
+            self.nr_free += (*slab).objects as u64;
         }
+    }
+
+    fn remove_slab(&mut self, slab : *mut Slab)
+    {
         unsafe
         {
-            let mut new_frame = memory::MEMORY_POOL.alloc_frame_temporary();
-            let allocable_object_num = memory::PAGE_SIZE / kmem_cache.object_size as usize - 1;
-            let page_discriptor = MEMORY_POOL.mem_map.offset(memory::virt2page(new_frame) as isize) as *mut Slab;
-            (*page_discriptor).free_list = new_frame;
-            (*page_discriptor).__page_flags = kmem_cache.flags;
-            let mut var = 0;
-            while var < allocable_object_num {
-                let next_object = new_frame.offset(kmem_cache.object_size as isize);
-                memory::set_free_pointer(kmem_cache, new_frame, next_object);
-                new_frame = next_object;
-                var += 1;
+            let prev = (*slab).prev();
+            let next = (*slab).next();
+            if prev.is_null()
+            {
+                self.partial.next = next as *mut ListHead;
+            }
+            else {
+                (*prev).set_next(next);
             }
-            self.nr_partial += (allocable_object_num + 1) as u64;
-            memory::set_free_pointer(kmem_cache, new_frame, null());
-            let next = self.partial.next as *mut Slab;
-            (*page_discriptor).slab_list.prev = null_mut();
             if !next.is_null()
             {
-                (*next).slab_list.prev = page_discriptor as *mut ListHead;
-                (*page_discriptor).slab_list.next = next as *mut ListHead;
+                (*next).set_prev(prev);
             }
-            self.partial.next = page_discriptor as *mut ListHead;
+            self.nr_slabs -= 1;
+            self.nr_empty -= 1;
+            self.total_objects -= (*slab).objects as u64;
+            self.nr_free -= (*slab).objects as u64;
         }
     }
-    // fn allocate_slab(&mut self, s : &mut KmemCache)
-    // {
-    //     unsafe
-    //     {
-    //         let mut slab = Self::allocate_slab_page();
-    //         (*slab).slab_cache = s as *mut KmemCache;
-    //     }
-    // }
-}
-
-impl KmemCacheCpu {
-    
 }
 
 impl KmemCache {
+    // the kmalloc caches get a first slab before the buddy allocator is up
+    pub fn bootstrap(&mut self)
+    {
+        if self.object_size == 0
+        {
+            return;
+        }
+        unsafe
+        {
+            let mut nid = 0;
+            while nid < MAX_NUMNODES {
+                let new_frame = memory::MEMORY_POOL.alloc_frame_temporary();
+                let page_discriptor = virt_to_slab(new_frame);
+                // boot pages never went through the buddy allocator, they stay
+                (*page_discriptor).__page_flags = self.flags | page::Pageflags::PgSlab | page::Pageflags::PgReserved;
+                self.init_slab(page_discriptor, new_frame);
+                self.node[nid].add_slab(page_discriptor);
+                nid += 1;
+            }
+        }
+    }
+
     // allocate object from KmemCacheNode
     fn alloc_node(&mut self, nid : usize) -> *mut c_void
     {
@@ -242,7 +328,7 @@ impl KmemCache {
         {
             self.node[nid].lock.acquire(1);
             // have object to allocate
-            if self.node[nid].nr_partial > 0
+            if self.node[nid].nr_free > 0
             {
                 let mut slab_discriptor = self.node[nid].partial.next as *mut Slab;
                 while (*slab_discriptor).free_list.is_null() {
@@ -250,7 +336,12 @@ impl KmemCache {
                 }
                 let result = (*slab_discriptor).free_list;
                 (*slab_discriptor).free_list = memory::get_free_pointer(self, result);
-                self.node[nid].nr_partial -= 1;
+                if (*slab_discriptor).inuse == 0
+                {
+                    self.node[nid].nr_empty -= 1;
+                }
+                (*slab_discriptor).inuse += 1;
+                self.node[nid].nr_free -= 1;
                 self.node[nid].lock.release(1);
                 return result as *mut c_void;
             }
@@ -266,14 +357,39 @@ impl KmemCache {
     {
         unsafe
         {
+            self.set_prev(null());
             self.set_next(SLAB_CACHES);
+            if !SLAB_CACHES.is_null()
+            {
+                (*SLAB_CACHES).set_prev(self);
+            }
             SLAB_CACHES = self as *mut Self;
         }
     }
 
+    fn unlink_from_cache_list(&mut self)
+    {
+        unsafe
+        {
+            let prev = self.get_prev();
+            let next = self.get_next();
+            if prev.is_null()
+            {
+                SLAB_CACHES = next;
+            }
+            else {
+                (*prev).set_next(next);
+            }
+            if !next.is_null()
+            {
+                (*next).set_prev(prev);
+            }
+        }
+    }
+
     pub fn create_cache(name : *const str, size : u32, align : u32, ctor : *mut extern fn(*mut c_void), flags : page::Pageflags) -> KmemCache
     {
-        let mut new_slub = KmemCache { flags, min_partial: 0, size, object_size: 0, reciprocal_size: 0, offset: 0, allocflags: page::GFP::empty(), refcount: 1, ctor, inuse: 0, align, red_left_pad: 0, name, list: ListHead::empty(), node: [KmemCacheNode::new(); MAX_NUMNODES] };
+        let mut new_slub = KmemCache { flags, min_partial: 0, size, object_size: 0, reciprocal_size: 0, offset: 0, allocflags: page::GFP::empty(), refcount: 1, ctor, inuse: 0, align, red_left_pad: 0, cpu_partial: 0, debug_flags: SlabDebugFlags::empty(), name, list: ListHead::empty(), cpu_slab: [KmemCacheCpu::new(); MAX_CPU_NUM], node: [KmemCacheNode::new(); MAX_NUMNODES] };
         new_slub.list.init();
         new_slub.kmem_cache_open(page::GFP::KERNEL);
         new_slub
@@ -285,40 +401,100 @@ impl KmemCache {
         {
             return;
         }
-        self.object_size = math::upround(self.size as u64, self.align as u64) as u32;
+        if self.debug_flags.is_empty()
+        {
+            self.object_size = math::upround(self.size as u64, self.align as u64) as u32;
+            self.inuse = self.object_size;
+            return;
+        }
+        // [left red zone][object][right red zone][free pointer][alloc track][free track]
+        let align = (1 << self.align.trailing_zeros()).clamp(8, DEBUG_MAX_ALIGN);
+        let mut size = math::upround(self.size as u64, 8) as u32;
+        if self.debug_flags.contains(SlabDebugFlags::RED_ZONE)
+        {
+            self.red_left_pad = align;
+            size += 8;
+        }
+        self.inuse = size;
+        // the poison would overwrite a free pointer inside the object
+        self.offset = size;
+        size += 8;
+        if self.debug_flags.contains(SlabDebugFlags::STORE_USER)
+        {
+            size += 2 * size_of::<Track>() as u32;
+        }
+        self.object_size = math::upround((self.red_left_pad + size) as u64, align as u64) as u32;
+    }
+
+    // smaller objects keep more of them around per cpu
+    fn set_cpu_partial(&mut self)
+    {
+        // every free of a debug cache has to reach the checks
+        if !self.debug_flags.is_empty()
+        {
+            self.cpu_partial = 0;
+            return;
+        }
+        self.cpu_partial = if self.object_size >= 1024 { 6 } else if self.object_size >= 256 { 13 } else { 30 };
     }
 
     fn kmem_cache_open(&mut self, flags: page::GFP)
     {
         self.allocflags = flags;
+        self.min_partial = MIN_PARTIAL;
+        self.debug_flags = unsafe { SLUB_DEBUG };
         self.calculate_size();
+        self.set_cpu_partial();
     }
 
-    fn alloc_single_from_new_slab(&mut self, new_slab : *mut Slab) -> *mut c_void
+    fn oo_objects(&self) -> usize
+    {
+        memory::PAGE_SIZE / self.object_size as usize
+    }
+
+    pub fn name(&self) -> &str
     {
         unsafe
         {
-            let mut var = 0;
-            let num_object = memory::PAGE_SIZE / self.object_size as usize - 1;
-            let mut object = (*new_slab).free_list;
-            // create object list
+            (*self.name).trim_end_matches('\0')
+        }
+    }
+
+    // chain every object of a fresh slab page into its freelist
+    fn init_slab(&mut self, slab : *mut Slab, start : *mut c_void)
+    {
+        unsafe
+        {
+            let num_object = self.oo_objects();
+            let first = start.offset(self.red_left_pad as isize);
+            let mut object = first;
+            let mut var = 1;
             while var < num_object {
                 let next_object = object.offset(self.object_size as isize);
                 memory::set_free_pointer(self, object, next_object);
+                self.setup_object_debug(object);
                 object = next_object;
                 var += 1;
             }
             memory::set_free_pointer(self, object, null());
+            self.setup_object_debug(object);
+            (*slab).free_list = first;
+            (*slab).slab_cache = self;
+            (*slab).inuse = 0;
+            (*slab).objects = num_object as u16;
+        }
+    }
+
+    fn alloc_single_from_new_slab(&mut self, new_slab : *mut Slab) -> *mut c_void
+    {
+        unsafe
+        {
+            self.init_slab(new_slab, (*new_slab).free_list);
             // link to partial list
             let nid = (*new_slab).slab_nid();
-            let next = self.node[nid].partial.next as *mut Slab;
-            if !next.is_null()
-            {
-                (*next).slab_list.prev = new_slab as *mut ListHead;
-            }
-            (*new_slab).slab_list.next = next as *mut ListHead;
-            self.node[nid].partial.next = new_slab as *mut ListHead;
-            self.node[nid].nr_partial += num_object as u64 + 1;
+            self.node[nid].lock.acquire(1);
+            self.node[nid].add_slab(new_slab);
+            self.node[nid].lock.release(1);
             self.alloc_node(nid)
         }
     }
@@ -347,15 +523,13 @@ impl KmemCache {
     {
         unsafe
         {
-            let result = memory::MEMORY_POOL.alloc_frames(page_num);
-            if result.is_null()
+            let result = page_alloc::get_free_pages(self.allocflags, page_alloc::get_order(page_num * memory::PAGE_SIZE));
+            if !result.is_null()
             {
-                panic!("out of memory");
-            }
-            else {
+                let first = memory::virt2page(result) as isize;
                 let mut var = 0;
                 while var < page_num {
-                    (*memory::MEMORY_POOL.mem_map.offset(var as isize)).flags = self.flags;
+                    (*memory::MEMORY_POOL.mem_map.offset(first + var as isize)).flags = self.flags | page::Pageflags::PgSlab;
                     var += 1;
                 }
             }
@@ -367,15 +541,548 @@ impl KmemCache {
     {
         unsafe
         {
+            preempt_disable();
+            let cpu = smp_processor_id();
+            let object = self.cpu_slab[cpu].freelist;
+            if !object.is_null()
+            {
+                self.cpu_slab[cpu].freelist = memory::get_free_pointer(self, object);
+                self.cpu_slab[cpu].nr_free -= 1;
+                self.cpu_slab[cpu].tid += 1;
+                preempt_enable();
+                // free forgot it before it went onto the cpu freelist
+                kmemleak::kmemleak_alloc(self, object, self.size as usize);
+                return object;
+            }
+            preempt_enable();
             let mut object = self.alloc_node(0);
             if object.is_null()
             {
                 let new_page = self.alloc_from_buddy_system(1);
-                let page_discriptor = memory::MEMORY_POOL.mem_map.offset(memory::virt2page(new_page).try_into().unwrap()) as *mut Slab;
+                if new_page.is_null()
+                {
+                    return null_mut();
+                }
+                let page_discriptor = virt_to_slab(new_page);
                 (*page_discriptor).free_list = new_page;
                 object = self.alloc_single_from_new_slab(page_discriptor);
             }
+            if !object.is_null() && !self.debug_flags.is_empty()
+            {
+                let mut caller = [0; TRACK_ADDRS];
+                save_stack_trace(&mut caller, 1);
+                self.alloc_debug_processing(object, &caller);
+            }
+            kmemleak::kmemleak_alloc(self, object, self.size as usize);
             object
         }
     }
-}
\ No newline at end of file
+
+    pub fn free(&mut self, object : *mut c_void)
+    {
+        if !self.debug_flags.is_empty()
+        {
+            let mut caller = [0; TRACK_ADDRS];
+            save_stack_trace(&mut caller, 1);
+            if !self.free_debug_processing(object, &caller)
+            {
+                return;
+            }
+        }
+        kmemleak::kmemleak_free(self, object);
+        unsafe
+        {
+            preempt_disable();
+            let cpu = smp_processor_id();
+            if self.cpu_slab[cpu].nr_free < self.cpu_partial
+            {
+                memory::set_free_pointer(self, object, self.cpu_slab[cpu].freelist);
+                self.cpu_slab[cpu].freelist = object;
+                self.cpu_slab[cpu].nr_free += 1;
+                self.cpu_slab[cpu].tid += 1;
+                preempt_enable();
+                return;
+            }
+            preempt_enable();
+            self.slab_free(object);
+        }
+    }
+
+    // back onto the freelist of its slab, an empty slab goes once the node has enough spare
+    fn slab_free(&mut self, object : *mut c_void)
+    {
+        unsafe
+        {
+            let slab = virt_to_slab(object);
+            memory::set_free_pointer(self, object, (*slab).free_list);
+            let nid = (*slab).slab_nid();
+            let node = &mut self.node[nid];
+            node.lock.acquire(1);
+            (*slab).free_list = object;
+            (*slab).inuse -= 1;
+            node.nr_free += 1;
+            if (*slab).inuse == 0
+            {
+                node.nr_empty += 1;
+                if node.nr_empty > self.min_partial && !(*slab).__page_flags.contains(page::Pageflags::PgReserved)
+                {
+                    node.remove_slab(slab);
+                    node.lock.release(1);
+                    Self::discard_slab(slab);
+                    return;
+                }
+            }
+            node.lock.release(1);
+        }
+    }
+
+    fn discard_slab(slab : *mut Slab)
+    {
+        page_alloc::free_pages(page_alloc::page_address(slab as *mut page::Page), 0);
+    }
+
+    // hand every object sitting on a cpu freelist back to its slab
+    // only the boot cpu runs kernel code, so the other lists are quiet
+    fn flush_cpu_slabs(&mut self)
+    {
+        unsafe
+        {
+            let mut var = 0;
+            while var < MAX_CPU_NUM {
+                preempt_disable();
+                let mut object = self.cpu_slab[var].freelist;
+                self.cpu_slab[var].freelist = null_mut();
+                self.cpu_slab[var].nr_free = 0;
+                preempt_enable();
+                while !object.is_null() {
+                    let next = memory::get_free_pointer(self, object);
+                    self.slab_free(object);
+                    object = next;
+                }
+                var += 1;
+            }
+        }
+    }
+
+    // free every empty slab, returns the pages given back
+    pub fn shrink(&mut self) -> usize
+    {
+        unsafe
+        {
+            self.flush_cpu_slabs();
+            let mut freed = 0;
+            let mut nid = 0;
+            while nid < MAX_NUMNODES {
+                let node = &mut self.node[nid];
+                node.lock.acquire(1);
+                let mut slab = node.partial.next as *mut Slab;
+                while !slab.is_null() {
+                    let next = (*slab).next();
+                    if (*slab).inuse == 0 && !(*slab).__page_flags.contains(page::Pageflags::PgReserved)
+                    {
+                        node.remove_slab(slab);
+                        Self::discard_slab(slab);
+                        freed += 1;
+                    }
+                    slab = next;
+                }
+                node.lock.release(1);
+                nid += 1;
+            }
+            freed
+        }
+    }
+
+    fn count_free(&self) -> u64
+    {
+        let mut free = 0;
+        let mut var = 0;
+        while var < MAX_NUMNODES {
+            free += self.node[var].nr_free;
+            var += 1;
+        }
+        var = 0;
+        while var < MAX_CPU_NUM {
+            free += self.cpu_slab[var].nr_free as u64;
+            var += 1;
+        }
+        free
+    }
+
+    fn sum_nodes(&self, f : fn(&KmemCacheNode) -> u64) -> u64
+    {
+        let mut total = 0;
+        let mut var = 0;
+        while var < MAX_NUMNODES {
+            total += f(&self.node[var]);
+            var += 1;
+        }
+        total
+    }
+}
+
+// debug mode, every object is checked when it changes hands
+impl KmemCache {
+    fn setup_object_debug(&self, object : *mut c_void)
+    {
+        if self.debug_flags.is_empty()
+        {
+            return;
+        }
+        unsafe
+        {
+            if self.debug_flags.contains(SlabDebugFlags::STORE_USER)
+            {
+                core::ptr::write_bytes(self.get_track(object, TrackItem::Alloc) as *mut u8, 0, 2 * size_of::<Track>());
+            }
+            self.init_object(object, SLUB_RED_INACTIVE);
+        }
+    }
+
+    // red zones take `val`, a free object is poisoned as well
+    unsafe fn init_object(&self, object : *mut c_void, val : u8)
+    {
+        let p = object as *mut u8;
+        if self.debug_flags.contains(SlabDebugFlags::RED_ZONE)
+        {
+            core::ptr::write_bytes(p.sub(self.red_left_pad as usize), val, self.red_left_pad as usize);
+            core::ptr::write_bytes(p.add(self.size as usize), val, (self.inuse - self.size) as usize);
+        }
+        if self.debug_flags.contains(SlabDebugFlags::POISON) && val == SLUB_RED_INACTIVE && self.size > 0
+        {
+            core::ptr::write_bytes(p, POISON_FREE, self.size as usize - 1);
+            *p.add(self.size as usize - 1) = POISON_END;
+        }
+    }
+
+    fn get_track(&self, object : *mut c_void, item : TrackItem) -> *mut Track
+    {
+        unsafe
+        {
+            ((object as *mut u8).add(self.offset as usize + 8) as *mut Track).add(item as usize)
+        }
+    }
+
+    fn set_track(&self, object : *mut c_void, item : TrackItem, caller : &[u64; TRACK_ADDRS])
+    {
+        if !self.debug_flags.contains(SlabDebugFlags::STORE_USER)
+        {
+            return;
+        }
+        unsafe
+        {
+            let current = get_current();
+            let pid = if current.is_null() { 0 } else { (*current).pid };
+            self.get_track(object, item).write(Track { addrs: *caller, pid, cpu: smp_processor_id() as u32, when: JIFFIES });
+        }
+    }
+
+    fn print_track(s : &str, track : &Track)
+    {
+        if track.addrs[0] == 0
+        {
+            return;
+        }
+        let mut trace = String::new();
+        print_stack_trace(&mut trace, &track.addrs[1..], "\t");
+        unsafe
+        {
+            logk!("INFO: {} in [<{:#x}>] age={} cpu={} pid={}\n{}", s, track.addrs[0], JIFFIES - track.when, track.cpu, track.pid, trace);
+        }
+    }
+
+    fn slab_bug(&self, msg : &str)
+    {
+        logk!("=============================================================================\n");
+        logk!("BUG {}: {}\n", self.name(), msg);
+        logk!("-----------------------------------------------------------------------------\n");
+    }
+
+    fn print_trailer(&self, slab : *mut Slab, object : *mut c_void)
+    {
+        unsafe
+        {
+            if self.debug_flags.contains(SlabDebugFlags::STORE_USER)
+            {
+                Self::print_track("Allocated", &*self.get_track(object, TrackItem::Alloc));
+                Self::print_track("Freed", &*self.get_track(object, TrackItem::Free));
+            }
+            logk!("INFO: Slab {:#x} objects={} used={} fp={:#x}\n", page_alloc::page_address(slab as *mut page::Page) as u64, (*slab).objects, (*slab).inuse, (*slab).free_list as u64);
+            logk!("INFO: Object {:#x} @offset={} size={}\n", object as u64, object as u64 - page_alloc::page_address(slab as *mut page::Page) as u64, self.size);
+            let p = object as *const u8;
+            let len = self.size.min(64) as usize;
+            let mut var = 0;
+            while var < len {
+                if var % 16 == 0
+                {
+                    logk!("Object {:#x}:", p.add(var) as u64);
+                }
+                logk!(" {:02x}", *p.add(var));
+                if var % 16 == 15 || var + 1 == len
+                {
+                    logk!("\n");
+                }
+                var += 1;
+            }
+        }
+    }
+
+    // report the first run of bytes in [start, start + len) that lost `value` and put it back
+    unsafe fn check_bytes_and_report(&self, slab : *mut Slab, object : *mut c_void, what : &str, start : *mut u8, len : usize, value : u8) -> bool
+    {
+        let mut var = 0;
+        while var < len && *start.add(var) == value {
+            var += 1;
+        }
+        if var == len
+        {
+            return true;
+        }
+        let fault = start.add(var);
+        let mut end = len;
+        while end > var && *start.add(end - 1) == value {
+            end -= 1;
+        }
+        let mut msg = String::new();
+        let _ = write!(msg, "{} overwritten", what);
+        self.slab_bug(&msg);
+        logk!("INFO: {:#x}-{:#x} @offset={}. First byte {:#x} instead of {:#x}\n", fault as u64, start.add(end - 1) as u64, fault as u64 - object as u64, *fault, value);
+        self.print_trailer(slab, object);
+        logk!("FIX {}: Restoring {} {:#x}-{:#x}={:#x}\n", self.name(), what, fault as u64, start.add(end - 1) as u64, value);
+        core::ptr::write_bytes(fault, value, end - var);
+        false
+    }
+
+    unsafe fn check_object(&self, slab : *mut Slab, object : *mut c_void, val : u8) -> bool
+    {
+        let p = object as *mut u8;
+        let mut ok = true;
+        if self.debug_flags.contains(SlabDebugFlags::RED_ZONE)
+        {
+            ok &= self.check_bytes_and_report(slab, object, "Left Redzone", p.sub(self.red_left_pad as usize), self.red_left_pad as usize, val);
+            ok &= self.check_bytes_and_report(slab, object, "Right Redzone", p.add(self.size as usize), (self.inuse - self.size) as usize, val);
+        }
+        if self.debug_flags.contains(SlabDebugFlags::POISON) && val == SLUB_RED_INACTIVE && self.size > 0
+        {
+            ok &= self.check_bytes_and_report(slab, object, "Poison", p, self.size as usize - 1, POISON_FREE);
+            ok &= self.check_bytes_and_report(slab, object, "End Poison", p.add(self.size as usize - 1), 1, POISON_END);
+        }
+        ok
+    }
+
+    fn check_valid_pointer(&self, slab : *mut Slab, object : *mut c_void) -> bool
+    {
+        unsafe
+        {
+            let base = page_alloc::page_address(slab as *mut page::Page) as u64 + self.red_left_pad as u64;
+            let object = object as u64;
+            object >= base && (object - base) % self.object_size as u64 == 0 && (object - base) / (self.object_size as u64) < (*slab).objects as u64
+        }
+    }
+
+    fn on_freelist(&self, slab : *mut Slab, object : *mut c_void) -> bool
+    {
+        unsafe
+        {
+            let mut fp = (*slab).free_list;
+            let mut nr = 0;
+            while !fp.is_null() && nr <= (*slab).objects {
+                if fp == object
+                {
+                    return true;
+                }
+                fp = memory::get_free_pointer(self, fp);
+                nr += 1;
+            }
+            false
+        }
+    }
+
+    fn alloc_debug_processing(&self, object : *mut c_void, caller : &[u64; TRACK_ADDRS])
+    {
+        unsafe
+        {
+            let slab = virt_to_slab(object);
+            // anything off here was written after the object was freed
+            self.check_object(slab, object, SLUB_RED_INACTIVE);
+            self.set_track(object, TrackItem::Alloc, caller);
+            self.init_object(object, SLUB_RED_ACTIVE);
+        }
+    }
+
+    // false keeps the object away from the freelists
+    fn free_debug_processing(&self, object : *mut c_void, caller : &[u64; TRACK_ADDRS]) -> bool
+    {
+        unsafe
+        {
+            let slab = virt_to_slab(object);
+            if self.debug_flags.contains(SlabDebugFlags::CONSISTENCY_CHECKS)
+            {
+                if (*slab).slab_cache != self as *const Self as *mut Self
+                {
+                    let mut msg = String::new();
+                    let _ = write!(msg, "Wrong slab cache, {:#x} belongs to another cache", object as u64);
+                    self.slab_bug(&msg);
+                    logk!("INFO: freed from [<{:#x}>]\n", caller[0]);
+                    return false;
+                }
+                if !self.check_valid_pointer(slab, object)
+                {
+                    let mut msg = String::new();
+                    let _ = write!(msg, "Invalid object pointer {:#x}", object as u64);
+                    self.slab_bug(&msg);
+                    logk!("INFO: freed from [<{:#x}>]\n", caller[0]);
+                    return false;
+                }
+            }
+            if self.on_freelist(slab, object)
+            {
+                self.slab_bug("Object already free");
+                logk!("INFO: double free from [<{:#x}>]\n", caller[0]);
+                self.print_trailer(slab, object);
+                return false;
+            }
+            // a damaged red zone means a write past either end of the object
+            self.check_object(slab, object, SLUB_RED_ACTIVE);
+            self.set_track(object, TrackItem::Free, caller);
+            self.init_object(object, SLUB_RED_INACTIVE);
+            true
+        }
+    }
+}
+
+const MIN_PARTIAL : u64 = 1;
+
+// a cache of `size` byte objects, on the list /proc/slabinfo walks
+pub fn kmem_cache_create(name : &'static str, size : u32, align : u32, ctor : *mut extern fn(*mut c_void), flags : page::Pageflags) -> *mut KmemCache
+{
+    unsafe
+    {
+        let cache = MEMORY_POOL.alloc(Layout::new::<KmemCache>()) as *mut KmemCache;
+        cache.write(KmemCache::create_cache(name, size, align, ctor, flags));
+        (*cache).link_to_cache_list();
+        cache
+    }
+}
+
+// drop a reference, the last one frees the cache once no object is left in it
+pub unsafe fn kmem_cache_destroy(cache : *mut KmemCache)
+{
+    (*cache).refcount -= 1;
+    if (*cache).refcount != 0
+    {
+        return;
+    }
+    (*cache).shrink();
+    let remaining = (*cache).sum_nodes(|node| node.nr_slabs);
+    if remaining != 0
+    {
+        logk!("kmem_cache_destroy {}: Slab cache still has objects, {} slabs left\n", (*cache).name(), remaining);
+        (*cache).refcount += 1;
+        return;
+    }
+    (*cache).unlink_from_cache_list();
+    MEMORY_POOL.dealloc(cache as *mut u8, Layout::new::<KmemCache>());
+}
+
+pub unsafe fn kmem_cache_shrink(cache : *mut KmemCache) -> usize
+{
+    (*cache).shrink()
+}
+
+// the object goes back to whatever cache its slab belongs to
+pub unsafe fn kfree(object : *mut c_void)
+{
+    let slab = virt_to_slab(object);
+    assert!((*slab).__page_flags.contains(page::Pageflags::PgSlab), "kfree: {:#x} is not a slab object", object as u64);
+    (*(*slab).slab_cache).free(object);
+}
+
+// start of the slab object `addr` points into, null outside slab pages
+pub unsafe fn nearest_obj(addr : *const c_void) -> *mut c_void
+{
+    let slab = virt_to_slab(addr);
+    if !(*slab).__page_flags.contains(page::Pageflags::PgSlab)
+    {
+        return null_mut();
+    }
+    let cache = (*slab).slab_cache;
+    let base = page_alloc::page_address(slab as *mut page::Page) as u64 + (*cache).red_left_pad as u64;
+    let addr = addr as u64;
+    if addr < base
+    {
+        return null_mut();
+    }
+    let index = (addr - base) / (*cache).object_size as u64;
+    let object = base + index * (*cache).object_size as u64;
+    if index >= (*slab).objects as u64 || addr >= object + (*cache).size as u64
+    {
+        return null_mut();
+    }
+    object as *mut c_void
+}
+
+fn for_each_cache(mut f : impl FnMut(&mut KmemCache))
+{
+    unsafe
+    {
+        let mut cache = SLAB_CACHES;
+        while !cache.is_null() {
+            let next = (*cache).get_next();
+            if (*cache).object_size != 0
+            {
+                f(&mut *cache);
+            }
+            cache = next;
+        }
+    }
+}
+
+// one line per cache in the format of /proc/slabinfo
+pub fn slabinfo() -> String
+{
+    let mut info = String::new();
+    let _ = writeln!(info, "slabinfo - version: 2.1");
+    let _ = writeln!(info, "# name            <active_objs> <num_objs> <objsize> <objperslab> <pagesperslab> : tunables <limit> <batchcount> <sharedfactor> : slabdata <active_slabs> <num_slabs> <sharedavail>");
+    for_each_cache(|cache| {
+        let num_objs = cache.sum_nodes(|node| node.total_objects);
+        let num_slabs = cache.sum_nodes(|node| node.nr_slabs);
+        let active_slabs = num_slabs - cache.sum_nodes(|node| node.nr_empty);
+        let _ = writeln!(info, "{:<17} {:>6} {:>6} {:>6} {:>4} {:>4} : tunables {:>4} {:>4} {:>4} : slabdata {:>6} {:>6} {:>6}", cache.name(), num_objs - cache.count_free(), num_objs, cache.object_size, cache.oo_objects(), 1, cache.cpu_partial, 0, 0, active_slabs, num_slabs, 0);
+    });
+    info
+}
+
+pub fn show_slabinfo()
+{
+    logk!("{}", slabinfo());
+}
+
+fn slab_shrink_count() -> usize
+{
+    let mut count = 0;
+    for_each_cache(|cache| {
+        count += cache.sum_nodes(|node| node.nr_empty) as usize;
+    });
+    count
+}
+
+fn slab_shrink_scan(nr_to_scan : usize) -> usize
+{
+    let mut freed = 0;
+    for_each_cache(|cache| {
+        if freed < nr_to_scan
+        {
+            freed += cache.shrink();
+        }
+    });
+    freed
+}
+
+static SLAB_SHRINKER : Shrinker = Shrinker { name: "slab", count_objects: slab_shrink_count, scan_objects: slab_shrink_scan };
+
+#[__init]
+pub fn kmem_cache_init_late()
+{
+    register_shrinker(&SLAB_SHRINKER);
+}
@@ -0,0 +1,532 @@
+use core::{alloc::Layout, ffi::c_char, ptr::{addr_of_mut, null_mut}, sync::atomic::Ordering};
+
+use alloc::{vec, vec::Vec};
+
+use crate::{fs::{ext4::Idx, inode::Inode, namei::namei}, kernel::{bitmap::BitMap, device::{device_ioctl, device_request, DevReqType, DevT, DEV_CMD_SECTOR_COUNT}, errno_base::{EBUSY, EFAULT, EINVAL, EIO, ENOENT, ENOMEM, EPERM}, io::SECTOR_SIZE, process::{for_each_process, PF_KTHREAD}, semaphore::UnreenterabkeSpinLock, syscall_defs::{SWAP_FLAG_PREFER, SWAP_FLAG_PRIO_MASK}, Err}, logk};
+
+use super::{memory::{follow_pte, swapin_pte, PAGE_SIZE}, page::{Page, GFP}, page_alloc::{alloc_pages, nr_free_pages, page_address, put_page}};
+
+pub const MAX_SWAPFILES : usize = 8;
+
+const SWP_USED : u32 = 1 << 0;
+const SWP_WRITEOK : u32 = 1 << 1;
+const SWP_BLKDEV : u32 = 1 << 2;
+
+// per slot reference counts, a bad slot is never handed out
+const SWAP_MAP_MAX : u8 = 0x3e;
+const SWAP_MAP_BAD : u8 = 0x3f;
+
+const SECTORS_PER_PAGE : usize = PAGE_SIZE / SECTOR_SIZE as usize;
+
+// a swap entry lives in a non-present pte: area in bits 1..5, slot from bit 12 up
+const SWP_TYPE_SHIFT : u64 = 1;
+const SWP_TYPE_MASK : u64 = 0xf;
+const SWP_OFFSET_SHIFT : u64 = 12;
+
+// the first page of every swap area, as mkswap writes it
+#[repr(C)]
+struct SwapHeader
+{
+    bootbits : [u8; 1024],
+    version : u32,
+    last_page : u32,
+    nr_badpages : u32,
+    sws_uuid : [u8; 16],
+    sws_volume : [u8; 16],
+    padding : [u32; 117],
+    badpages : [u32; 1]
+}
+
+const SWAP_MAGIC : &[u8] = b"SWAPSPACE2";
+const MAX_SWAP_BADPAGES : usize = (PAGE_SIZE - SWAP_MAGIC.len() - 1536) / 4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SwpEntry(u64);
+
+impl SwpEntry {
+    pub const fn new(swp_type : usize, offset : usize) -> SwpEntry
+    {
+        SwpEntry(((swp_type as u64 & SWP_TYPE_MASK) << SWP_TYPE_SHIFT) | ((offset as u64) << SWP_OFFSET_SHIFT))
+    }
+
+    pub const fn swp_type(&self) -> usize
+    {
+        ((self.0 >> SWP_TYPE_SHIFT) & SWP_TYPE_MASK) as usize
+    }
+
+    pub const fn swp_offset(&self) -> usize
+    {
+        (self.0 >> SWP_OFFSET_SHIFT) as usize
+    }
+}
+
+// slot 0 is the header, so a swap pte is never zero
+pub const fn is_swap_pte(pte : u64) -> bool
+{
+    pte != 0 && pte & 1 == 0
+}
+
+pub const fn pte_to_swp_entry(pte : u64) -> SwpEntry
+{
+    SwpEntry(pte)
+}
+
+pub const fn swp_entry_to_pte(entry : SwpEntry) -> u64
+{
+    entry.0
+}
+
+struct SwapInfo
+{
+    flags : u32,
+    prio : i32,
+    bdev : DevT,
+    inode : *mut Inode,
+    max : usize, // slots, the header included
+    pages : usize, // slots that can be handed out
+    inuse_pages : usize,
+    slot_bits : Vec<u8>,
+    slots : BitMap, // set for the header, bad and taken slots
+    swap_map : Vec<u8>,
+    sectors : Vec<Idx>, // first sector of each slot of a swap file, empty for a device
+    cluster_next : usize
+}
+
+impl SwapInfo {
+    fn slot_sector(&self, offset : usize) -> Idx
+    {
+        if self.sectors.is_empty()
+        {
+            (offset * SECTORS_PER_PAGE) as Idx
+        }
+        else {
+            self.sectors[offset]
+        }
+    }
+
+    fn mark_bad(&mut self, offset : usize)
+    {
+        if self.swap_map[offset] != SWAP_MAP_BAD
+        {
+            self.swap_map[offset] = SWAP_MAP_BAD;
+            self.slots.set(offset, 1, true);
+            self.pages -= 1;
+        }
+    }
+}
+
+static mut SWAP_INFO : [*mut SwapInfo; MAX_SWAPFILES] = [null_mut(); MAX_SWAPFILES];
+static mut SWAP_LOCK : UnreenterabkeSpinLock = UnreenterabkeSpinLock::new(1);
+// free slots over every area still taking pages
+pub static mut NR_SWAP_PAGES : usize = 0;
+pub static mut TOTAL_SWAP_PAGES : usize = 0;
+static mut LEAST_PRIORITY : i32 = 0;
+
+// a free slot on the highest priority area with room
+pub fn get_swap_page() -> Option<SwpEntry>
+{
+    unsafe
+    {
+        SWAP_LOCK.acquire(1);
+        let mut chosen = MAX_SWAPFILES;
+        let mut var = 0;
+        while var < MAX_SWAPFILES {
+            let si = SWAP_INFO[var];
+            if !si.is_null() && (*si).flags & SWP_WRITEOK != 0 && (*si).inuse_pages < (*si).pages && (chosen == MAX_SWAPFILES || (*si).prio > (*SWAP_INFO[chosen]).prio)
+            {
+                chosen = var;
+            }
+            var += 1;
+        }
+        if chosen == MAX_SWAPFILES
+        {
+            SWAP_LOCK.release(1);
+            return None;
+        }
+        let si = &mut *SWAP_INFO[chosen];
+        let offset = match si.slots.find_next_zero(si.cluster_next) {
+            Some(offset) => offset,
+            None => si.slots.find_next_zero(1).unwrap()
+        };
+        si.slots.set(offset, 1, true);
+        si.swap_map[offset] = 1;
+        si.inuse_pages += 1;
+        si.cluster_next = offset + 1;
+        NR_SWAP_PAGES -= 1;
+        SWAP_LOCK.release(1);
+        Some(SwpEntry::new(chosen, offset))
+    }
+}
+
+// another pte refers to the slot
+pub fn swap_duplicate(entry : SwpEntry)
+{
+    unsafe
+    {
+        SWAP_LOCK.acquire(1);
+        let si = &mut *SWAP_INFO[entry.swp_type()];
+        assert!(si.swap_map[entry.swp_offset()] != 0 && si.swap_map[entry.swp_offset()] < SWAP_MAP_MAX, "swap_duplicate: bad swap entry {:#x}", entry.0);
+        si.swap_map[entry.swp_offset()] += 1;
+        SWAP_LOCK.release(1);
+    }
+}
+
+// drop a reference, the last one gives the slot back
+pub fn swap_free(entry : SwpEntry)
+{
+    unsafe
+    {
+        SWAP_LOCK.acquire(1);
+        let si = &mut *SWAP_INFO[entry.swp_type()];
+        let offset = entry.swp_offset();
+        assert!(si.swap_map[offset] != 0 && si.swap_map[offset] != SWAP_MAP_BAD, "swap_free: bad swap entry {:#x}", entry.0);
+        si.swap_map[offset] -= 1;
+        if si.swap_map[offset] == 0
+        {
+            si.slots.set(offset, 1, false);
+            si.inuse_pages -= 1;
+            // an area being turned off no longer counts as free space
+            if si.flags & SWP_WRITEOK != 0
+            {
+                NR_SWAP_PAGES += 1;
+            }
+        }
+        SWAP_LOCK.release(1);
+    }
+}
+
+unsafe fn swap_io(page : *mut Page, entry : SwpEntry, req_type : DevReqType) -> Err
+{
+    let si = &*SWAP_INFO[entry.swp_type()];
+    if device_request(si.bdev, page_address(page), SECTORS_PER_PAGE, si.slot_sector(entry.swp_offset()), 0, req_type) < 0
+    {
+        return -EIO;
+    }
+    0
+}
+
+pub unsafe fn swap_writepage(page : *mut Page, entry : SwpEntry) -> Err
+{
+    swap_io(page, entry, DevReqType::Write)
+}
+
+pub unsafe fn swap_readpage(page : *mut Page, entry : SwpEntry) -> Err
+{
+    swap_io(page, entry, DevReqType::Read)
+}
+
+// first sector of each page of a swap file, 0 where its blocks are not contiguous on disk
+// -EINVAL for a file with holes, writing there would need blocks allocated
+unsafe fn setup_swap_extents(inode : *mut Inode, nr_pages : usize) -> Result<Vec<Idx>, Err>
+{
+    let lp = &mut *(*inode).logical_part_ptr;
+    let blocks_per_page = PAGE_SIZE / (lp.logic_block_size as usize * 1024);
+    let mut sectors = Vec::with_capacity(nr_pages);
+    let mut page_no = 0;
+    while page_no < nr_pages {
+        let first = lp.bmap(inode, (page_no * blocks_per_page) as Idx);
+        let mut contiguous = true;
+        let mut var = 0;
+        while var < blocks_per_page {
+            let block = lp.bmap(inode, (page_no * blocks_per_page + var) as Idx);
+            if block == 0
+            {
+                logk!("swapon: swapfile has holes\n");
+                return Err(-EINVAL);
+            }
+            contiguous &= block == first + var as Idx;
+            var += 1;
+        }
+        if contiguous
+        {
+            sectors.push(first * 2 * lp.logic_block_size as Idx);
+        }
+        else {
+            sectors.push(0);
+        }
+        page_no += 1;
+    }
+    Ok(sectors)
+}
+
+// check the header mkswap left in the first page and size the area from it
+unsafe fn parse_swap_header(si : &mut SwapInfo, header : *const SwapHeader, nr_pages : usize) -> Err
+{
+    let magic = core::slice::from_raw_parts((header as *const u8).add(PAGE_SIZE - SWAP_MAGIC.len()), SWAP_MAGIC.len());
+    if magic != SWAP_MAGIC
+    {
+        logk!("swapon: unable to find swap-space signature\n");
+        return -EINVAL;
+    }
+    if (*header).version != 1
+    {
+        logk!("swapon: unable to handle swap header version {}\n", (*header).version);
+        return -EINVAL;
+    }
+    let nr_badpages = (*header).nr_badpages as usize;
+    if nr_badpages > MAX_SWAP_BADPAGES
+    {
+        return -EINVAL;
+    }
+    let max = core::cmp::min((*header).last_page as usize + 1, nr_pages);
+    if max <= 1
+    {
+        logk!("swapon: empty swap area\n");
+        return -EINVAL;
+    }
+    si.max = max;
+    si.pages = max - 1;
+    si.slot_bits = vec![0; max / 8 + 1];
+    si.slots = BitMap::new(si.slot_bits.as_mut_ptr(), max);
+    si.swap_map = vec![0; max];
+    si.slots.set(0, 1, true);
+    si.swap_map[0] = SWAP_MAP_BAD;
+    let badpages = (*header).badpages.as_ptr();
+    let mut var = 0;
+    while var < nr_badpages {
+        let offset = *badpages.add(var) as usize;
+        if offset != 0 && offset < max
+        {
+            si.mark_bad(offset);
+        }
+        var += 1;
+    }
+    var = 1;
+    while var < si.sectors.len() && var < max {
+        if si.sectors[var] == 0
+        {
+            si.mark_bad(var);
+        }
+        var += 1;
+    }
+    0
+}
+
+unsafe fn read_swap_header(si : &mut SwapInfo, nr_pages : usize) -> Err
+{
+    let page = alloc_pages(GFP::KERNEL, 0);
+    if page.is_null()
+    {
+        return -ENOMEM;
+    }
+    let mut result = -EIO;
+    if device_request(si.bdev, page_address(page), SECTORS_PER_PAGE, si.slot_sector(0), 0, DevReqType::Read) >= 0
+    {
+        result = parse_swap_header(si, page_address(page) as *const SwapHeader, nr_pages);
+    }
+    put_page(page);
+    result
+}
+
+unsafe fn find_swap_info(inode : *mut Inode) -> usize
+{
+    let mut var = 0;
+    while var < MAX_SWAPFILES {
+        if !SWAP_INFO[var].is_null() && (*SWAP_INFO[var]).inode == inode
+        {
+            return var;
+        }
+        var += 1;
+    }
+    MAX_SWAPFILES
+}
+
+unsafe fn free_swap_info(si : *mut SwapInfo)
+{
+    si.drop_in_place();
+    alloc::alloc::dealloc(si.cast(), Layout::new::<SwapInfo>());
+}
+
+// claim a free type for `si`, it takes no pages until SWP_WRITEOK is set
+unsafe fn alloc_swap_info(si : *mut SwapInfo) -> Result<usize, Err>
+{
+    SWAP_LOCK.acquire(1);
+    if find_swap_info((*si).inode) != MAX_SWAPFILES
+    {
+        SWAP_LOCK.release(1);
+        return Err(-EBUSY);
+    }
+    let mut var = 0;
+    while var < MAX_SWAPFILES && !SWAP_INFO[var].is_null() {
+        var += 1;
+    }
+    if var < MAX_SWAPFILES
+    {
+        SWAP_INFO[var] = si;
+    }
+    SWAP_LOCK.release(1);
+    if var == MAX_SWAPFILES
+    {
+        return Err(-EPERM);
+    }
+    Ok(var)
+}
+
+pub fn sys_swapon(specialfile : *const c_char, swap_flags : u64) -> Err
+{
+    unsafe
+    {
+        if specialfile.is_null()
+        {
+            return -EFAULT;
+        }
+        let path = namei(specialfile);
+        if path.dentry.is_null() || (*path.dentry).d_inode.is_null()
+        {
+            return -ENOENT;
+        }
+        let inode = (*path.dentry).d_inode;
+        let si = alloc::alloc::alloc(Layout::new::<SwapInfo>()) as *mut SwapInfo;
+        si.write(SwapInfo { flags: SWP_USED, prio: 0, bdev: 0, inode, max: 0, pages: 0, inuse_pages: 0, slot_bits: Vec::new(), slots: BitMap::null_bitmap(), swap_map: Vec::new(), sectors: Vec::new(), cluster_next: 1 });
+        let swp_type = match alloc_swap_info(si) {
+            Ok(swp_type) => swp_type,
+            Err(error) =>
+            {
+                free_swap_info(si);
+                return error;
+            }
+        };
+        let mut nr_pages;
+        if (*inode).is_blk()
+        {
+            (*si).flags |= SWP_BLKDEV;
+            (*si).bdev = (*inode).i_rdev;
+            let sectors = device_ioctl((*si).bdev, DEV_CMD_SECTOR_COUNT, null_mut(), 0);
+            nr_pages = if sectors < 0 { 0 } else { sectors as usize / SECTORS_PER_PAGE };
+        }
+        else if (*inode).is_file()
+        {
+            (*si).bdev = (*(*inode).logical_part_ptr).s_dev;
+            nr_pages = (*inode).get_size() / PAGE_SIZE;
+            match setup_swap_extents(inode, nr_pages) {
+                Ok(sectors) => (*si).sectors = sectors,
+                Err(_) => nr_pages = 0
+            }
+        }
+        else {
+            nr_pages = 0;
+        }
+        let mut error = -EINVAL;
+        if nr_pages != 0 && ((*si).sectors.is_empty() || (*si).sectors[0] != 0)
+        {
+            error = read_swap_header(&mut *si, nr_pages);
+        }
+        if error < 0
+        {
+            SWAP_LOCK.acquire(1);
+            SWAP_INFO[swp_type] = null_mut();
+            SWAP_LOCK.release(1);
+            free_swap_info(si);
+            return error;
+        }
+        if swap_flags & SWAP_FLAG_PREFER != 0
+        {
+            (*si).prio = (swap_flags & SWAP_FLAG_PRIO_MASK) as i32;
+        }
+        else {
+            LEAST_PRIORITY -= 1;
+            (*si).prio = LEAST_PRIORITY;
+        }
+        // the area pins its inode until swapoff
+        (*inode).count.fetch_add(1, Ordering::Relaxed);
+        SWAP_LOCK.acquire(1);
+        (*si).flags |= SWP_WRITEOK;
+        NR_SWAP_PAGES += (*si).pages;
+        TOTAL_SWAP_PAGES += (*si).pages;
+        SWAP_LOCK.release(1);
+        logk!("Adding {}k swap on {} {}. Priority:{} bad:{}\n", (*si).pages * PAGE_SIZE / 1024, if (*si).flags & SWP_BLKDEV != 0 { "device" } else { "file" }, swp_type, (*si).prio, (*si).max - 1 - (*si).pages);
+        0
+    }
+}
+
+// swap every pte on area `swp_type` back in, process by process
+unsafe fn try_to_unuse(swp_type : usize) -> Err
+{
+    let mut error = 0;
+    for_each_process(|pcb| {
+        if error < 0 || (*pcb).flags & PF_KTHREAD != 0
+        {
+            return;
+        }
+        let mm = addr_of_mut!((*pcb).mm);
+        let mut vma = (*mm).mmap;
+        while !vma.is_null() {
+            let mut addr = (*vma).get_start();
+            while addr <= (*vma).get_end() {
+                let mut step = PAGE_SIZE as u64;
+                let pte = follow_pte(mm, addr, &mut step);
+                if !pte.is_null() && is_swap_pte((*pte).raw()) && pte_to_swp_entry((*pte).raw()).swp_type() == swp_type
+                {
+                    error = swapin_pte(mm, pte, addr, (*vma).get_prot());
+                    if error < 0
+                    {
+                        return;
+                    }
+                }
+                addr = (addr & !(step - 1)) + step;
+            }
+            vma = (*vma).get_next();
+        }
+    });
+    error
+}
+
+pub fn sys_swapoff(specialfile : *const c_char) -> Err
+{
+    unsafe
+    {
+        if specialfile.is_null()
+        {
+            return -EFAULT;
+        }
+        let path = namei(specialfile);
+        if path.dentry.is_null() || (*path.dentry).d_inode.is_null()
+        {
+            return -ENOENT;
+        }
+        let inode = (*path.dentry).d_inode;
+        SWAP_LOCK.acquire(1);
+        let swp_type = find_swap_info(inode);
+        if swp_type == MAX_SWAPFILES || (*SWAP_INFO[swp_type]).flags & SWP_WRITEOK == 0
+        {
+            SWAP_LOCK.release(1);
+            return -EINVAL;
+        }
+        let si = SWAP_INFO[swp_type];
+        // everything on it has to fit in memory again
+        if (*si).inuse_pages > nr_free_pages()
+        {
+            SWAP_LOCK.release(1);
+            return -ENOMEM;
+        }
+        (*si).flags &= !SWP_WRITEOK;
+        NR_SWAP_PAGES -= (*si).pages - (*si).inuse_pages;
+        TOTAL_SWAP_PAGES -= (*si).pages;
+        SWAP_LOCK.release(1);
+        let error = try_to_unuse(swp_type);
+        SWAP_LOCK.acquire(1);
+        // slots something swapped out again meanwhile keep the area in use
+        if error < 0 || (*si).inuse_pages != 0
+        {
+            (*si).flags |= SWP_WRITEOK;
+            NR_SWAP_PAGES += (*si).pages - (*si).inuse_pages;
+            TOTAL_SWAP_PAGES += (*si).pages;
+            SWAP_LOCK.release(1);
+            return if error < 0 { error } else { -EBUSY };
+        }
+        SWAP_INFO[swp_type] = null_mut();
+        SWAP_LOCK.release(1);
+        logk!("Removed {}k swap on {}\n", (*si).pages * PAGE_SIZE / 1024, swp_type);
+        (*(*inode).logical_part_ptr).release_inode(inode);
+        free_swap_info(si);
+        0
+    }
+}
+
+pub fn show_swap_state()
+{
+    unsafe
+    {
+        logk!("swap: {} free of {} pages\n", NR_SWAP_PAGES, TOTAL_SWAP_PAGES);
+    }
+}
@@ -0,0 +1,322 @@
+use core::{alloc::Layout, ffi::c_void, fmt::Write, panic::Location, ptr::null_mut};
+
+use alloc::{string::String, vec::Vec};
+use proc_macro::__init;
+
+use crate::{kernel::{math, semaphore::UnreenterabkeSpinLock, Err}, logk};
+
+use super::{kmemleak, memory::{kernel_page_phys, map_kernel_page, phys2page, preallocate_kernel_pdpt, unmap_kernel_page, virt2phys, MemoryPool, PAGE_SIZE}, page::{Page, GFP}, page_alloc::{free_pages, get_free_pages, page_address, virt_to_page}};
+
+// one pml4 slot past the linear map, virtually contiguous and backed page by page
+pub const VMALLOC_START : u64 = 0xffffc90000000000;
+pub const VMALLOC_END : u64 = VMALLOC_START + (1 << 39);
+
+const VM_ALLOC : u32 = 1 << 1; // pages came from vmalloc and go back on vfree
+const VM_MAP : u32 = 1 << 2; // caller's pages put together by vmap
+
+pub struct VmStruct
+{
+    next : *mut VmStruct,
+    addr : u64,
+    size : usize, // the guard page after the area included
+    flags : u32,
+    pages : Vec<*mut Page>,
+    caller : &'static Location<'static>
+}
+
+// every area sorted by address, the gaps between them are free
+static mut VMLIST : *mut VmStruct = null_mut();
+static mut VMAP_AREA_LOCK : UnreenterabkeSpinLock = UnreenterabkeSpinLock::new(1);
+static mut NR_VMALLOC_PAGES : usize = 0;
+
+#[inline(always)]
+pub fn is_vmalloc_addr(addr : *const c_void) -> bool
+{
+    (addr as u64) >= VMALLOC_START && (addr as u64) < VMALLOC_END
+}
+
+impl VmStruct {
+    // the size the caller asked for, without the guard page
+    fn get_size(&self) -> usize
+    {
+        self.size - PAGE_SIZE
+    }
+}
+
+// reserve `size` bytes of address space and a guard page, first fit
+fn get_vm_area(size : usize, flags : u32, caller : &'static Location<'static>) -> *mut VmStruct
+{
+    unsafe
+    {
+        let area = alloc::alloc::alloc(Layout::new::<VmStruct>()) as *mut VmStruct;
+        if area.is_null()
+        {
+            return null_mut();
+        }
+        area.write(VmStruct { next: null_mut(), addr: 0, size: size + PAGE_SIZE, flags, pages: Vec::new(), caller });
+        VMAP_AREA_LOCK.acquire(1);
+        let mut addr = VMALLOC_START;
+        let mut link = &mut VMLIST as *mut *mut VmStruct;
+        while !(*link).is_null() {
+            if addr + (*area).size as u64 <= (**link).addr
+            {
+                break;
+            }
+            addr = (**link).addr + (**link).size as u64;
+            link = &mut (**link).next;
+        }
+        if addr + (*area).size as u64 > VMALLOC_END
+        {
+            VMAP_AREA_LOCK.release(1);
+            logk!("vmap allocation for size {} failed\n", size);
+            free_vm_struct(area);
+            return null_mut();
+        }
+        (*area).addr = addr;
+        (*area).next = *link;
+        *link = area;
+        VMAP_AREA_LOCK.release(1);
+        area
+    }
+}
+
+// take the area starting at `addr` off the list, only when it was made with `flags`
+fn remove_vm_area(addr : u64, flags : u32) -> *mut VmStruct
+{
+    unsafe
+    {
+        VMAP_AREA_LOCK.acquire(1);
+        let mut link = &mut VMLIST as *mut *mut VmStruct;
+        while !(*link).is_null() && (**link).addr != addr {
+            link = &mut (**link).next;
+        }
+        let mut area = *link;
+        if !area.is_null() && (*area).flags & flags == 0
+        {
+            area = null_mut();
+        }
+        if !area.is_null()
+        {
+            *link = (*area).next;
+        }
+        VMAP_AREA_LOCK.release(1);
+        area
+    }
+}
+
+unsafe fn free_vm_struct(area : *mut VmStruct)
+{
+    core::ptr::drop_in_place(area);
+    alloc::alloc::dealloc(area as *mut u8, Layout::new::<VmStruct>());
+}
+
+unsafe fn vmap_pages_range(addr : u64, pages : &[*mut Page]) -> Err
+{
+    let mut var = 0;
+    while var < pages.len() {
+        let error = map_kernel_page(addr + (var * PAGE_SIZE) as u64, virt2phys(page_address(pages[var])), true);
+        if error < 0
+        {
+            vunmap_range(addr, var * PAGE_SIZE);
+            return error;
+        }
+        var += 1;
+    }
+    0
+}
+
+unsafe fn vunmap_range(addr : u64, size : usize)
+{
+    let mut offset = 0;
+    while offset < size {
+        unmap_kernel_page(addr + offset as u64);
+        offset += PAGE_SIZE;
+    }
+}
+
+// unmap an area, its pages go back too when vmalloc allocated them
+unsafe fn __vunmap(area : *mut VmStruct)
+{
+    vunmap_range((*area).addr, (*area).get_size());
+    if (*area).flags & VM_ALLOC != 0
+    {
+        for page in (*area).pages.iter() {
+            free_pages(page_address(*page), 0);
+        }
+        NR_VMALLOC_PAGES -= (*area).pages.len();
+    }
+    free_vm_struct(area);
+}
+
+fn __vmalloc(size : usize, gfp : GFP, caller : &'static Location<'static>) -> *mut c_void
+{
+    unsafe
+    {
+        let size = math::upround(size as u64, PAGE_SIZE as u64) as usize;
+        let nr_pages = size / PAGE_SIZE;
+        if nr_pages == 0 || nr_pages > MemoryPool::total_pages()
+        {
+            return null_mut();
+        }
+        let area = get_vm_area(size, VM_ALLOC, caller);
+        if area.is_null()
+        {
+            return null_mut();
+        }
+        (*area).pages.reserve_exact(nr_pages);
+        while (*area).pages.len() < nr_pages {
+            let page = get_free_pages(gfp, 0);
+            if page.is_null()
+            {
+                break;
+            }
+            (*area).pages.push(virt_to_page(page));
+            NR_VMALLOC_PAGES += 1;
+        }
+        if (*area).pages.len() < nr_pages || vmap_pages_range((*area).addr, &(*area).pages) < 0
+        {
+            logk!("vmalloc: allocation failure: {} bytes, from {}\n", size, caller);
+            remove_vm_area((*area).addr, VM_ALLOC);
+            __vunmap(area);
+            return null_mut();
+        }
+        // the area list holds one reference of its own
+        kmemleak::kmemleak_vmalloc((*area).addr as *const c_void, size);
+        (*area).addr as *mut c_void
+    }
+}
+
+// virtually contiguous memory from scattered frames
+#[track_caller]
+pub fn vmalloc(size : usize) -> *mut c_void
+{
+    __vmalloc(size, GFP::KERNEL, Location::caller())
+}
+
+#[track_caller]
+pub fn vzalloc(size : usize) -> *mut c_void
+{
+    __vmalloc(size, GFP::KERNEL | GFP::__ZERO, Location::caller())
+}
+
+pub fn vfree(addr : *const c_void)
+{
+    unsafe
+    {
+        if addr.is_null()
+        {
+            return;
+        }
+        let area = remove_vm_area(addr as u64, VM_ALLOC);
+        if area.is_null()
+        {
+            logk!("Trying to vfree() nonexistent vm area ({:#x})\n", addr as u64);
+            return;
+        }
+        kmemleak::kmemleak_free(null_mut(), addr);
+        __vunmap(area);
+    }
+}
+
+// map pages the caller owns into one contiguous range, they stay the caller's
+#[track_caller]
+pub fn vmap(pages : &[*mut Page]) -> *mut c_void
+{
+    unsafe
+    {
+        if pages.is_empty()
+        {
+            return null_mut();
+        }
+        let area = get_vm_area(pages.len() * PAGE_SIZE, VM_MAP, Location::caller());
+        if area.is_null()
+        {
+            return null_mut();
+        }
+        if vmap_pages_range((*area).addr, pages) < 0
+        {
+            remove_vm_area((*area).addr, VM_MAP);
+            free_vm_struct(area);
+            return null_mut();
+        }
+        (*area).addr as *mut c_void
+    }
+}
+
+pub fn vunmap(addr : *const c_void)
+{
+    unsafe
+    {
+        let area = remove_vm_area(addr as u64, VM_MAP);
+        if area.is_null()
+        {
+            logk!("Trying to vunmap() nonexistent vm area ({:#x})\n", addr as u64);
+            return;
+        }
+        __vunmap(area);
+    }
+}
+
+pub fn vmalloc_to_page(addr : *const c_void) -> *mut Page
+{
+    unsafe
+    {
+        let phys = kernel_page_phys(addr as u64);
+        if phys.is_null()
+        {
+            return null_mut();
+        }
+        MemoryPool::get_page_descripter(phys2page(phys) as isize)
+    }
+}
+
+// one line per area in the format of /proc/vmallocinfo
+pub fn vmallocinfo() -> String
+{
+    let mut info = String::new();
+    unsafe
+    {
+        VMAP_AREA_LOCK.acquire(1);
+        let mut nr_areas = 0;
+        let mut area = VMLIST;
+        while !area.is_null() {
+            nr_areas += 1;
+            area = (*area).next;
+        }
+        VMAP_AREA_LOCK.release(1);
+        // formatting allocates, collect the lines first
+        let mut lines = Vec::with_capacity(nr_areas);
+        VMAP_AREA_LOCK.acquire(1);
+        area = VMLIST;
+        while !area.is_null() && lines.len() < lines.capacity() {
+            lines.push(((*area).addr, (*area).size, (*area).caller, (*area).pages.len(), (*area).flags));
+            area = (*area).next;
+        }
+        VMAP_AREA_LOCK.release(1);
+        for (addr, size, caller, nr_pages, flags) in lines.iter() {
+            let _ = write!(info, "{:#018x}-{:#018x} {:>7} {}", addr, addr + *size as u64, size, caller);
+            if *nr_pages != 0
+            {
+                let _ = write!(info, " pages={}", nr_pages);
+            }
+            let _ = writeln!(info, "{}", if flags & VM_ALLOC != 0 { " vmalloc" } else { " vmap" });
+        }
+    }
+    info
+}
+
+pub fn show_vmallocinfo()
+{
+    logk!("vmalloc: {} pages in use\n", unsafe { NR_VMALLOC_PAGES });
+    logk!("{}", vmallocinfo());
+}
+
+#[__init]
+pub fn vmalloc_init()
+{
+    unsafe
+    {
+        preallocate_kernel_pdpt(VMALLOC_START);
+        logk!("vmalloc area {:#x}-{:#x}\n", VMALLOC_START, VMALLOC_END);
+    }
+}
@@ -0,0 +1,378 @@
+use core::{cmp::max, ptr::{null, null_mut}, sync::atomic::Ordering};
+
+use proc_macro::__init;
+
+use crate::{container_of, fs::fs::AddressSpace, kernel::{clock::{schedule_timeout, JIFFY}, interrupt::{interrupt_disable, set_interrupt_state}, list::ListHead, zram, process::{wake_up_process, PCB, PF_KTHREAD, PF_MEMALLOC}, sched::get_current_running_process, semaphore::UnreenterabkeSpinLock}, logk};
+
+use super::{huge_memory, ksm, memory::get_cr3_reg, page::{Page, Pageflags}, page_alloc::{get_page, nr_free_pages, put_page, MAX_NR_ZONES, WMARK_HIGH, WMARK_LOW, WMARK_MIN, ZONES}, rmap::{page_mapcount, page_mkclean, page_referenced, try_to_unmap}, swapfile::{get_swap_page, show_swap_state, swap_free, swap_writepage, NR_SWAP_PAGES}};
+
+// a pass at priority p scans (size >> p) of each cache, priority 0 scans everything
+const DEF_PRIORITY : usize = 12;
+// reclaim works in batches of at least this many pages
+const SWAP_CLUSTER_MAX : usize = 32;
+const MAX_SHRINKERS : usize = 16;
+
+// a cache outside the page cache that can give memory back
+pub struct Shrinker
+{
+    pub name : &'static str,
+    pub count_objects : fn() -> usize, // objects that could be freed right now
+    pub scan_objects : fn(usize) -> usize // free up to that many, returns how many went
+}
+
+static mut SHRINKERS : [*const Shrinker; MAX_SHRINKERS] = [null(); MAX_SHRINKERS];
+static mut NR_SHRINKERS : usize = 0;
+
+// page cache and anonymous pages, the least recently added first, linked by Page::lru
+static mut FILE_LRU : ListHead = ListHead::empty();
+static mut ANON_LRU : ListHead = ListHead::empty();
+static mut LRU_LOCK : UnreenterabkeSpinLock = UnreenterabkeSpinLock::new(1);
+pub static mut NR_FILE_LRU : usize = 0;
+pub static mut NR_ANON_LRU : usize = 0;
+
+// set by the allocator when a zone drops below its low watermark
+static mut KSWAPD_WAKEUP : bool = false;
+static mut KSWAPD : *mut PCB = null_mut();
+// the allocator wakes kswapd, this only catches what it missed
+const KSWAPD_SLEEP_JIFFIES : u64 = 1000 / JIFFY;
+
+pub fn register_shrinker(shrinker : &'static Shrinker)
+{
+    unsafe
+    {
+        assert!(NR_SHRINKERS < MAX_SHRINKERS, "too many shrinkers");
+        SHRINKERS[NR_SHRINKERS] = shrinker;
+        NR_SHRINKERS += 1;
+    }
+}
+
+// a page entering the page cache or getting its first anonymous mapping
+pub unsafe fn lru_cache_add(page : *mut Page)
+{
+    LRU_LOCK.acquire(1);
+    // reclaim has nowhere to put these or must not touch them, so they never go on a list
+    if !(*page).flags.intersects(Pageflags::PgLru | Pageflags::PgUnevictable | Pageflags::PgMlocked | Pageflags::PgKsm)
+    {
+        (*page).flags.insert(Pageflags::PgLru);
+        if (*page).flags.contains(Pageflags::PgAnon)
+        {
+            (*page).lru.tail_insert(&mut ANON_LRU);
+            NR_ANON_LRU += 1;
+        }
+        else {
+            (*page).lru.tail_insert(&mut FILE_LRU);
+            NR_FILE_LRU += 1;
+        }
+    }
+    LRU_LOCK.release(1);
+}
+
+pub unsafe fn lru_cache_del(page : *mut Page)
+{
+    LRU_LOCK.acquire(1);
+    if (*page).flags.contains(Pageflags::PgLru)
+    {
+        (*page).flags.remove(Pageflags::PgLru);
+        (*page).lru.delete();
+        if (*page).flags.contains(Pageflags::PgAnon)
+        {
+            NR_ANON_LRU -= 1;
+        }
+        else {
+            NR_FILE_LRU -= 1;
+        }
+    }
+    LRU_LOCK.release(1);
+}
+
+// drop up to `nr_to_scan` clean, unmapped pages from the cold end of the lru
+// a referenced page gets a second trip round, returns the pages freed
+unsafe fn shrink_file_lru(nr_to_scan : usize) -> usize
+{
+    // an allocation under the lru lock is reclaiming from inside lru_cache_add
+    if !LRU_LOCK.try_acquire(1)
+    {
+        return 0;
+    }
+    let mut freed = 0;
+    let mut scanned = 0;
+    while scanned < nr_to_scan && !FILE_LRU.is_empty() {
+        scanned += 1;
+        let page = container_of!(FILE_LRU.next, Page, lru);
+        (*page).lru.delete();
+        let busy = (*page)._mapcount.load(Ordering::Relaxed) > 0 || (*page)._refcount.load(Ordering::Relaxed) > 1 || (*page).flags.intersects(Pageflags::PgDirty | Pageflags::PgLocked | Pageflags::PgWriteback);
+        if busy || (*page).flags.contains(Pageflags::PgReferenced)
+        {
+            (*page).flags.remove(Pageflags::PgReferenced);
+            (*page).lru.tail_insert(&mut FILE_LRU);
+            continue;
+        }
+        let mapping = (*page).mapping as *mut AddressSpace;
+        if !(*mapping).delete_from_page_cache(page)
+        {
+            (*page).lru.tail_insert(&mut FILE_LRU);
+            continue;
+        }
+        (*page).flags.remove(Pageflags::PgLru);
+        NR_FILE_LRU -= 1;
+        // the cache's reference was the last one
+        put_page(page);
+        freed += 1;
+    }
+    LRU_LOCK.release(1);
+    freed
+}
+
+// write one isolated anonymous page out to swap, the caller holds a reference on it
+// a page whose ptes were used since the last pass, or that is pinned, is kept
+// clean MADV_FREE pages are simply dropped, so they go even without swap
+unsafe fn shrink_anon_page(page : *mut Page) -> bool
+{
+    let pinned = (*page)._refcount.load(Ordering::Relaxed) != page_mapcount(&*page) + 1 || (*page).flags.contains(Pageflags::PgLocked);
+    if pinned || page_referenced(page)
+    {
+        return false;
+    }
+    if (*page).flags.contains(Pageflags::PgLazyfree)
+    {
+        if !page_mkclean(page)
+        {
+            try_to_unmap(page, None);
+            return true;
+        }
+        // written to since madvise, the contents matter again
+        (*page).flags.remove(Pageflags::PgLazyfree);
+    }
+    if NR_SWAP_PAGES == 0
+    {
+        return false;
+    }
+    let entry = match get_swap_page() {
+        Some(entry) => entry,
+        None => return false
+    };
+    // a write through a pte while the page is on its way out sets the dirty bit again
+    page_mkclean(page);
+    if swap_writepage(page, entry) < 0 || page_mkclean(page)
+    {
+        swap_free(entry);
+        return false;
+    }
+    try_to_unmap(page, Some(entry));
+    // every pte holds the slot now
+    swap_free(entry);
+    true
+}
+
+// take up to `nr_to_scan` anonymous pages off the cold end of the lru and write them out to swap
+// they are isolated under the lru lock and written without it, the ones that stay go back to the lru
+unsafe fn shrink_anon_lru(nr_to_scan : usize) -> usize
+{
+    if !LRU_LOCK.try_acquire(1)
+    {
+        return 0;
+    }
+    let mut page_list = ListHead::empty();
+    page_list.init();
+    let mut scanned = 0;
+    while scanned < nr_to_scan && !ANON_LRU.is_empty() {
+        scanned += 1;
+        let page = container_of!(ANON_LRU.next, Page, lru);
+        (*page).lru.delete();
+        // off the lru lru_cache_del leaves it alone, the reference keeps it from being freed meanwhile
+        (*page).flags.remove(Pageflags::PgLru);
+        NR_ANON_LRU -= 1;
+        get_page(&*page);
+        (*page).lru.tail_insert(&mut page_list);
+    }
+    LRU_LOCK.release(1);
+    let mut freed = 0;
+    while !page_list.is_empty() {
+        let page = container_of!(page_list.next, Page, lru);
+        (*page).lru.delete();
+        if shrink_anon_page(page)
+        {
+            freed += 1;
+        }
+        else {
+            lru_cache_add(page);
+        }
+        put_page(page);
+    }
+    freed
+}
+
+// ask every shrinker for its share at this priority
+unsafe fn shrink_slab(priority : usize) -> usize
+{
+    let mut freed = 0;
+    let mut var = 0;
+    while var < NR_SHRINKERS {
+        let shrinker = &*SHRINKERS[var];
+        let count = (shrinker.count_objects)();
+        if count != 0
+        {
+            freed += (shrinker.scan_objects)(max(count >> priority, 1));
+        }
+        var += 1;
+    }
+    freed
+}
+
+// one round over every cache at `priority`, returns the pages it gave back
+unsafe fn shrink_node(priority : usize) -> usize
+{
+    let before = nr_free_pages();
+    shrink_file_lru(max(NR_FILE_LRU >> priority, SWAP_CLUSTER_MAX));
+    shrink_anon_lru(max(NR_ANON_LRU >> priority, SWAP_CLUSTER_MAX));
+    shrink_slab(priority);
+    nr_free_pages().saturating_sub(before)
+}
+
+// reclaim with increasing effort until 1 << order pages, at least a batch, came free
+unsafe fn do_try_to_free_pages(order : usize) -> usize
+{
+    let target = max(1 << order, SWAP_CLUSTER_MAX);
+    let mut reclaimed = 0;
+    let mut priority = DEF_PRIORITY + 1;
+    while priority > 0 && reclaimed < target {
+        priority -= 1;
+        reclaimed += shrink_node(priority);
+    }
+    reclaimed
+}
+
+// direct reclaim on behalf of a failing allocation, returns the pages freed
+pub fn try_to_free_pages(order : usize) -> usize
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        if pcb.is_null()
+        {
+            return 0;
+        }
+        (*pcb).flags |= PF_MEMALLOC;
+        let reclaimed = do_try_to_free_pages(order);
+        (*pcb).flags &= !PF_MEMALLOC;
+        reclaimed
+    }
+}
+
+// allocations made while reclaiming must not recurse into reclaim
+pub fn current_is_reclaiming() -> bool
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        !pcb.is_null() && (*pcb).flags & PF_MEMALLOC != 0
+    }
+}
+
+pub fn wakeup_kswapd()
+{
+    unsafe
+    {
+        KSWAPD_WAKEUP = true;
+        if !KSWAPD.is_null()
+        {
+            wake_up_process(KSWAPD);
+        }
+    }
+}
+
+fn zones_below(wmark : usize) -> bool
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < MAX_NR_ZONES {
+            let zone = &ZONES[var];
+            if zone.managed_pages != 0 && !zone.watermark_ok(0, zone.watermark[wmark])
+            {
+                return true;
+            }
+            var += 1;
+        }
+        false
+    }
+}
+
+// reclaim in the background until every zone is back above its high watermark
+fn kswapd()
+{
+    unsafe
+    {
+        let pcb = get_current_running_process();
+        (*pcb).flags |= PF_MEMALLOC;
+        loop {
+            if KSWAPD_WAKEUP || zones_below(WMARK_LOW)
+            {
+                KSWAPD_WAKEUP = false;
+                while zones_below(WMARK_HIGH) {
+                    if do_try_to_free_pages(0) == 0
+                    {
+                        break;
+                    }
+                }
+            }
+            // a wakeup between the check and the sleep would be lost otherwise
+            let state = interrupt_disable();
+            if !KSWAPD_WAKEUP && !zones_below(WMARK_LOW)
+            {
+                schedule_timeout(KSWAPD_SLEEP_JIFFIES);
+            }
+            set_interrupt_state(state);
+        }
+    }
+}
+
+#[__init]
+pub fn vmscan_init()
+{
+    unsafe
+    {
+        FILE_LRU.init();
+        ANON_LRU.init();
+    }
+}
+
+pub fn kswapd_init()
+{
+    unsafe
+    {
+        let pcb = PCB::create_new_process(kswapd as u64, 0);
+        compiler_builtins::mem::memcpy((*pcb).name.as_ptr() as *mut u8, "kswapd".as_ptr(), 6);
+        (*pcb).flags |= PF_KTHREAD;
+        (*pcb).pml4 = get_cr3_reg() as *mut _;
+        (*pcb).insert_to_task_table();
+        KSWAPD = pcb;
+        logk!("kswapd started\n");
+    }
+}
+
+pub fn show_reclaim_state()
+{
+    unsafe
+    {
+        let mut var = 0;
+        while var < MAX_NR_ZONES {
+            let zone = &ZONES[var];
+            if zone.managed_pages != 0
+            {
+                logk!("zone {}: free {}, min {}, low {}, high {}\n", zone.name, zone.free_pages, zone.watermark[WMARK_MIN], zone.watermark[WMARK_LOW], zone.watermark[WMARK_HIGH]);
+            }
+            var += 1;
+        }
+        logk!("file lru pages: {}, anon lru pages: {}\n", NR_FILE_LRU, NR_ANON_LRU);
+        show_swap_state();
+        huge_memory::show_thp_state();
+        ksm::show_ksm_state();
+        zram::show_zram_state();
+        var = 0;
+        while var < NR_SHRINKERS {
+            logk!("shrinker {}: {} objects\n", (*SHRINKERS[var]).name, ((*SHRINKERS[var]).count_objects)());
+            var += 1;
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// elf definitions used by the dynamic linker, mirrors kernel/src/kernel/elf64.rs and relocation.rs
+
+pub const EI_NIDENT : usize = 0x10;
+pub const ELF_MAGIC : [u8; 4] = [0x7f, 0x45, 0x4c, 0x46];
+pub const EM_X86_64 : u16 = 62;
+pub const ET_DYN : u16 = 3;
+
+pub const PF_X : u32 = 0x1;
+pub const PF_W : u32 = 0x2;
+pub const PF_R : u32 = 0x4;
+
+pub const PT_LOAD : u32 = 1;
+pub const PT_DYNAMIC : u32 = 2;
+pub const PT_PHDR : u32 = 6;
+
+pub const DT_NULL : i64 = 0;
+pub const DT_NEEDED : i64 = 1;
+pub const DT_PLTRELSZ : i64 = 2;
+pub const DT_HASH : i64 = 4;
+pub const DT_STRTAB : i64 = 5;
+pub const DT_SYMTAB : i64 = 6;
+pub const DT_RELA : i64 = 7;
+pub const DT_RELASZ : i64 = 8;
+pub const DT_JMPREL : i64 = 23;
+pub const DT_GNU_HASH : i64 = 0x6ffffef5;
+
+pub const AT_NULL : u64 = 0;
+pub const AT_PHDR : u64 = 3;
+pub const AT_PHNUM : u64 = 5;
+pub const AT_ENTRY : u64 = 9;
+
+pub const R_X86_64_NONE : u32 = 0;
+pub const R_X86_64_64 : u32 = 1;
+pub const R_X86_64_COPY : u32 = 5;
+pub const R_X86_64_GLOB_DAT : u32 = 6;
+pub const R_X86_64_JUMP_SLOT : u32 = 7;
+pub const R_X86_64_RELATIVE : u32 = 8;
+
+pub const SHN_UNDEF : u16 = 0;
+pub const STB_LOCAL : u8 = 0;
+pub const STB_WEAK : u8 = 2;
+
+#[repr(packed)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Elf64Ehdr
+{
+    pub e_ident : [u8 ; EI_NIDENT],
+    pub e_type : u16,
+    pub e_machine : u16,
+    pub e_version : u32,
+    pub e_entry : u64,
+    pub e_phoff : u64,
+    pub e_shoff : u64,
+    pub e_flags : u32,
+    pub e_ehsize : u16,
+    pub e_phentsize : u16,
+    pub e_phnum : u16,
+    pub e_shentsize : u16,
+    pub e_shnum : u16,
+    pub e_shstrndx : u16
+}
+
+#[repr(packed)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Elf64Phdr
+{
+    pub p_type : u32,
+    pub p_flags : u32,
+    pub p_offset : u64,
+    pub p_vaddr : u64,
+    pub p_paddr : u64,
+    pub p_filesz : u64,
+    pub p_memsz : u64,
+    pub p_align : u64
+}
+
+#[repr(C)]
+pub struct Elf64Dyn
+{
+    pub d_tag : i64,
+    pub d_val : u64
+}
+
+#[repr(C)]
+pub struct Elf64Sym
+{
+    pub st_name : u32,
+    pub st_info : u8,
+    pub st_other : u8,
+    pub st_shndx : u16,
+    pub st_value : u64,
+    pub st_size : u64
+}
+
+#[repr(C)]
+#[repr(packed)]
+pub struct Elf64Rela
+{
+    pub r_offset : u64,
+    pub r_type : u32,
+    pub r_sym : u32,
+    pub r_addend : u64
+}
@@ -0,0 +1,458 @@
+#![no_main]
+#![no_std]
+use core::{arch::global_asm, ffi::{c_char, c_void, CStr}, mem::size_of, ptr::null};
+use lib::{mman::{mmap, MAP_ANONYMOUS, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE}, println, unistd::{close, exit, open, pread, O_RDONLY}};
+
+use elf::*;
+
+mod elf;
+
+const PAGE_SIZE : u64 = 4096;
+const MAX_SHARED_OBJECTS : usize = 16;
+const MAX_PHDR_NUM : usize = 16;
+const AUX_VECTOR_SIZE : usize = 32;
+const LIBRARY_PATH : &[u8] = b"/lib/";
+const LIBRARY_PATH_LEN : usize = 256;
+// the kernel maps us at 0x7f0000000000, libraries go below
+const LIBRARY_LOAD_START : u64 = 0x7e0000000000;
+
+// rsp points at argc, the program gets the same stack back with rdi = argc, rsi = argv
+global_asm!(
+    ".globl _start",
+    "_start:",
+    "xor rbp, rbp",
+    "mov rbx, rsp",
+    "mov rdi, rsp",
+    "lea rsi, [rip + __ehdr_start]",
+    "lea rdx, [rip + _DYNAMIC]",
+    "and rsp, -16",
+    "call ld_start",
+    "mov rsp, rbx",
+    "mov rdi, [rsp]",
+    "lea rsi, [rsp + 8]",
+    "jmp rax"
+);
+
+#[derive(Clone, Copy)]
+struct SharedObject
+{
+    name : *const u8,
+    base : u64,
+    dynamic : *const Elf64Dyn,
+    strtab : *const u8,
+    symtab : *const Elf64Sym,
+    sym_count : usize,
+    rela : *const Elf64Rela,
+    rela_size : usize,
+    jmprel : *const Elf64Rela,
+    jmprel_size : usize
+}
+
+// objects in symbol lookup order, the executable first
+static mut OBJECTS : [SharedObject; MAX_SHARED_OBJECTS] = [SharedObject::empty(); MAX_SHARED_OBJECTS];
+static mut OBJECT_COUNT : usize = 0;
+static mut NEXT_LOAD_BASE : u64 = LIBRARY_LOAD_START;
+
+impl SharedObject {
+    const fn empty() -> Self
+    {
+        Self { name: null(), base: 0, dynamic: null(), strtab: null(), symtab: null(), sym_count: 0, rela: null(), rela_size: 0, jmprel: null(), jmprel_size: 0 }
+    }
+
+    unsafe fn new(name : *const u8, base : u64, dynamic : *const Elf64Dyn) -> Self
+    {
+        let mut object = Self::empty();
+        let mut hash : *const u32 = null();
+        let mut gnu_hash : *const u32 = null();
+        object.name = name;
+        object.base = base;
+        object.dynamic = dynamic;
+        let mut dyn_ptr = dynamic;
+        while (*dyn_ptr).d_tag != DT_NULL {
+            let value = (*dyn_ptr).d_val;
+            match (*dyn_ptr).d_tag {
+                DT_STRTAB => object.strtab = (base + value) as *const u8,
+                DT_SYMTAB => object.symtab = (base + value) as *const Elf64Sym,
+                DT_HASH => hash = (base + value) as *const u32,
+                DT_GNU_HASH => gnu_hash = (base + value) as *const u32,
+                DT_RELA => object.rela = (base + value) as *const Elf64Rela,
+                DT_RELASZ => object.rela_size = value as usize,
+                DT_JMPREL => object.jmprel = (base + value) as *const Elf64Rela,
+                DT_PLTRELSZ => object.jmprel_size = value as usize,
+                _ => { }
+            }
+            dyn_ptr = dyn_ptr.add(1);
+        }
+        if !hash.is_null()
+        {
+            // nchain
+            object.sym_count = *hash.add(1) as usize;
+        }
+        else if !gnu_hash.is_null()
+        {
+            object.sym_count = gnu_hash_symbol_count(gnu_hash);
+        }
+        object
+    }
+}
+
+// gnu hash has no symbol count, walk the chain of the last bucket
+unsafe fn gnu_hash_symbol_count(gnu_hash : *const u32) -> usize
+{
+    let nbuckets = *gnu_hash as usize;
+    let symoffset = *gnu_hash.add(1) as usize;
+    let bloom_size = *gnu_hash.add(2) as usize;
+    let buckets = (gnu_hash.add(4) as *const u64).add(bloom_size) as *const u32;
+    let chain = buckets.add(nbuckets);
+    let mut last = 0;
+    let mut var = 0;
+    while var < nbuckets {
+        if *buckets.add(var) as usize > last
+        {
+            last = *buckets.add(var) as usize;
+        }
+        var += 1;
+    }
+    if last < symoffset
+    {
+        return symoffset;
+    }
+    while *chain.add(last - symoffset) & 1 == 0 {
+        last += 1;
+    }
+    last + 1
+}
+
+// runs before our own relocations are applied, must not touch statics
+#[inline(always)]
+unsafe fn relocate_self(base : u64, dynamic : *const Elf64Dyn)
+{
+    let mut rela : *const Elf64Rela = null();
+    let mut rela_size = 0;
+    let mut dyn_ptr = dynamic;
+    while (*dyn_ptr).d_tag != DT_NULL {
+        if (*dyn_ptr).d_tag == DT_RELA
+        {
+            rela = base.wrapping_add((*dyn_ptr).d_val) as *const Elf64Rela;
+        }
+        else if (*dyn_ptr).d_tag == DT_RELASZ
+        {
+            rela_size = (*dyn_ptr).d_val as usize;
+        }
+        dyn_ptr = dyn_ptr.add(1);
+    }
+    let count = rela_size / size_of::<Elf64Rela>();
+    let mut var = 0;
+    while var < count {
+        let entry = rela.add(var);
+        if (*entry).r_type == R_X86_64_RELATIVE
+        {
+            *(base.wrapping_add((*entry).r_offset) as *mut u64) = base.wrapping_add((*entry).r_addend);
+        }
+        var += 1;
+    }
+}
+
+unsafe fn read_auxv(sp : *const u64, auxv : &mut [u64; AUX_VECTOR_SIZE])
+{
+    let argc = *sp as usize;
+    // skip argv and envp
+    let mut ptr = sp.add(1 + argc + 1);
+    while *ptr != 0 {
+        ptr = ptr.add(1);
+    }
+    ptr = ptr.add(1);
+    while *ptr != AT_NULL {
+        if (*ptr as usize) < AUX_VECTOR_SIZE
+        {
+            auxv[*ptr as usize] = *ptr.add(1);
+        }
+        ptr = ptr.add(2);
+    }
+}
+
+fn fatal(message : &str, name : *const u8) -> !
+{
+    let name = if name.is_null() { "" } else { unsafe { CStr::from_ptr(name as *const c_char).to_str().unwrap_or("?") } };
+    println!("ld-leeos: {} {}\n", message, name);
+    exit(127);
+}
+
+unsafe fn cstr_eq(mut left : *const u8, mut right : *const u8) -> bool
+{
+    while *left == *right {
+        if *left == 0
+        {
+            return true;
+        }
+        left = left.add(1);
+        right = right.add(1);
+    }
+    false
+}
+
+fn page_down(addr : u64) -> u64
+{
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_up(addr : u64) -> u64
+{
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+unsafe fn add_object(name : *const u8, base : u64, dynamic : *const Elf64Dyn)
+{
+    if OBJECT_COUNT == MAX_SHARED_OBJECTS
+    {
+        fatal("too many shared objects, can't load", name);
+    }
+    OBJECTS[OBJECT_COUNT] = SharedObject::new(name, base, dynamic);
+    OBJECT_COUNT += 1;
+}
+
+unsafe fn object_loaded(name : *const u8) -> bool
+{
+    let mut var = 1;
+    while var < OBJECT_COUNT {
+        if cstr_eq(OBJECTS[var].name, name)
+        {
+            return true;
+        }
+        var += 1;
+    }
+    false
+}
+
+unsafe fn map_segment(fd : i64, phdr : &Elf64Phdr, base : u64, name : *const u8)
+{
+    let start = page_down(base + phdr.p_vaddr);
+    let file_end = page_up(base + phdr.p_vaddr + phdr.p_filesz);
+    let mem_end = page_up(base + phdr.p_vaddr + phdr.p_memsz);
+    let mut prot = 0;
+    if phdr.p_flags & PF_R != 0
+    {
+        prot |= PROT_READ;
+    }
+    if phdr.p_flags & PF_W != 0
+    {
+        prot |= PROT_WRITE;
+    }
+    if phdr.p_flags & PF_X != 0
+    {
+        prot |= PROT_EXEC;
+    }
+    if phdr.p_filesz != 0 && mmap(start as *mut c_void, (file_end - start) as usize, prot, MAP_PRIVATE, fd, page_down(phdr.p_offset) as usize) as u64 != start
+    {
+        fatal("can't map segment of", name);
+    }
+    if phdr.p_memsz <= phdr.p_filesz
+    {
+        return;
+    }
+    // .bss: clear the rest of the last file page, the remaining pages are anonymous
+    let bss_start = base + phdr.p_vaddr + phdr.p_filesz;
+    if phdr.p_filesz != 0 && prot & PROT_WRITE != 0
+    {
+        core::ptr::write_bytes(bss_start as *mut u8, 0, (file_end - bss_start) as usize);
+    }
+    let anon_start = if phdr.p_filesz != 0 { file_end } else { start };
+    if mem_end > anon_start && mmap(anon_start as *mut c_void, (mem_end - anon_start) as usize, prot, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) as u64 != anon_start
+    {
+        fatal("can't map bss of", name);
+    }
+}
+
+unsafe fn load_library(name : *const u8)
+{
+    let mut path = [0u8; LIBRARY_PATH_LEN];
+    let name_len = CStr::from_ptr(name as *const c_char).to_bytes().len();
+    if LIBRARY_PATH.len() + name_len >= LIBRARY_PATH_LEN
+    {
+        fatal("library name too long", name);
+    }
+    path[..LIBRARY_PATH.len()].copy_from_slice(LIBRARY_PATH);
+    core::ptr::copy_nonoverlapping(name, path.as_mut_ptr().add(LIBRARY_PATH.len()), name_len);
+    let fd = open(path.as_ptr() as *const c_char, O_RDONLY);
+    if fd < 0
+    {
+        fatal("can't open shared library", path.as_ptr());
+    }
+    let mut ehdr : Elf64Ehdr = core::mem::zeroed();
+    if pread(fd, &mut ehdr as *mut Elf64Ehdr as *mut c_void, size_of::<Elf64Ehdr>(), 0) != size_of::<Elf64Ehdr>() as i64
+    {
+        fatal("can't read elf header of", name);
+    }
+    if ehdr.e_ident[..4] != ELF_MAGIC || ehdr.e_machine != EM_X86_64 || ehdr.e_type != ET_DYN || ehdr.e_phnum as usize > MAX_PHDR_NUM
+    {
+        fatal("not a shared library", name);
+    }
+    let mut phdrs : [Elf64Phdr; MAX_PHDR_NUM] = core::mem::zeroed();
+    let phdr_size = ehdr.e_phnum as usize * size_of::<Elf64Phdr>();
+    if pread(fd, phdrs.as_mut_ptr() as *mut c_void, phdr_size, ehdr.e_phoff as usize) != phdr_size as i64
+    {
+        fatal("can't read program headers of", name);
+    }
+    let phdrs = &phdrs[..ehdr.e_phnum as usize];
+    let mut span = 0;
+    let mut dynamic = 0;
+    for phdr in phdrs {
+        if phdr.p_type == PT_LOAD && phdr.p_vaddr + phdr.p_memsz > span
+        {
+            span = phdr.p_vaddr + phdr.p_memsz;
+        }
+        if phdr.p_type == PT_DYNAMIC
+        {
+            dynamic = phdr.p_vaddr;
+        }
+    }
+    if dynamic == 0
+    {
+        fatal("no dynamic section in", name);
+    }
+    let base = NEXT_LOAD_BASE;
+    // leave a guard page between libraries
+    NEXT_LOAD_BASE = page_up(base + span) + PAGE_SIZE;
+    for phdr in phdrs {
+        if phdr.p_type == PT_LOAD
+        {
+            map_segment(fd, phdr, base, name);
+        }
+    }
+    close(fd);
+    add_object(name, base, (base + dynamic) as *const Elf64Dyn);
+}
+
+// breadth first over DT_NEEDED, OBJECTS grows while we walk it
+unsafe fn load_needed()
+{
+    let mut idx = 0;
+    while idx < OBJECT_COUNT {
+        let object = OBJECTS[idx];
+        let mut dyn_ptr = object.dynamic;
+        while (*dyn_ptr).d_tag != DT_NULL {
+            if (*dyn_ptr).d_tag == DT_NEEDED
+            {
+                let name = object.strtab.add((*dyn_ptr).d_val as usize);
+                if !object_loaded(name)
+                {
+                    load_library(name);
+                }
+            }
+            dyn_ptr = dyn_ptr.add(1);
+        }
+        idx += 1;
+    }
+}
+
+// search objects from `first` on
+unsafe fn lookup_symbol(name : *const u8, first : usize) -> Option<u64>
+{
+    let mut idx = first;
+    while idx < OBJECT_COUNT {
+        let object = &OBJECTS[idx];
+        let mut var = 1;
+        while var < object.sym_count {
+            let sym = object.symtab.add(var);
+            if (*sym).st_shndx != SHN_UNDEF && (*sym).st_info >> 4 != STB_LOCAL && cstr_eq(object.strtab.add((*sym).st_name as usize), name)
+            {
+                return Some(object.base + (*sym).st_value);
+            }
+            var += 1;
+        }
+        idx += 1;
+    }
+    None
+}
+
+unsafe fn resolve_symbol(object : &SharedObject, sym_idx : u32) -> u64
+{
+    let sym = object.symtab.add(sym_idx as usize);
+    let name = object.strtab.add((*sym).st_name as usize);
+    match lookup_symbol(name, 0) {
+        Some(value) => value,
+        None =>
+        {
+            if (*sym).st_info >> 4 == STB_WEAK
+            {
+                return 0;
+            }
+            fatal("undefined symbol", name);
+        }
+    }
+}
+
+unsafe fn apply_rela(object : &SharedObject, rela : *const Elf64Rela, size : usize)
+{
+    if rela.is_null()
+    {
+        return;
+    }
+    let count = size / size_of::<Elf64Rela>();
+    let mut var = 0;
+    while var < count {
+        let entry = rela.add(var);
+        let target = (object.base + (*entry).r_offset) as *mut u64;
+        match (*entry).r_type {
+            R_X86_64_NONE => { },
+            R_X86_64_RELATIVE => *target = object.base.wrapping_add((*entry).r_addend),
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => *target = resolve_symbol(object, (*entry).r_sym),
+            R_X86_64_64 => *target = resolve_symbol(object, (*entry).r_sym).wrapping_add((*entry).r_addend),
+            R_X86_64_COPY =>
+            {
+                // the executable owns the variable, initialise it from the library's copy
+                let sym = object.symtab.add((*entry).r_sym as usize);
+                let name = object.strtab.add((*sym).st_name as usize);
+                match lookup_symbol(name, 1) {
+                    Some(value) => core::ptr::copy_nonoverlapping(value as *const u8, target as *mut u8, (*sym).st_size as usize),
+                    None => fatal("undefined symbol", name)
+                }
+            },
+            _ => fatal("unsupported relocation type in", object.name)
+        }
+        var += 1;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ld_start(sp : *const u64, base : u64, dynamic : *const Elf64Dyn) -> u64
+{
+    relocate_self(base, dynamic);
+    let mut auxv = [0u64; AUX_VECTOR_SIZE];
+    read_auxv(sp, &mut auxv);
+    let phdr = auxv[AT_PHDR as usize] as *const Elf64Phdr;
+    let phnum = auxv[AT_PHNUM as usize] as usize;
+    if phdr.is_null()
+    {
+        fatal("no program headers, run the program through execve", null());
+    }
+    let mut bias = 0;
+    let mut program_dynamic = 0;
+    let mut var = 0;
+    while var < phnum {
+        let header = &*phdr.add(var);
+        if header.p_type == PT_PHDR
+        {
+            bias = phdr as u64 - header.p_vaddr;
+        }
+        else if header.p_type == PT_DYNAMIC
+        {
+            program_dynamic = header.p_vaddr;
+        }
+        var += 1;
+    }
+    if program_dynamic != 0
+    {
+        add_object(b"\0".as_ptr(), bias, (bias + program_dynamic) as *const Elf64Dyn);
+        load_needed();
+        // dependencies first, the executable last
+        let mut idx = OBJECT_COUNT;
+        while idx > 0 {
+            idx -= 1;
+            let object = OBJECTS[idx];
+            apply_rela(&object, object.rela, object.rela_size);
+            apply_rela(&object, object.jmprel, object.jmprel_size);
+        }
+    }
+    auxv[AT_ENTRY as usize]
+}
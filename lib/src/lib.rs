@@ -6,4 +6,5 @@ pub mod syscall_defs;
 pub mod macros;
 pub mod print;
 pub mod unistd;
+pub mod mman;
 pub mod lang_items;
@@ -0,0 +1,131 @@
+use core::ffi::{c_char, c_void};
+use crate::syscall_defs::{self, __syscall0, __syscall1, __syscall2, __syscall3, __syscall5, __syscall6};
+
+pub const PROT_NONE : u64 = 0x0;
+pub const PROT_READ : u64 = 0x1;
+pub const PROT_WRITE : u64 = 0x2;
+pub const PROT_EXEC : u64 = 0x4;
+
+pub const MAP_SHARED : u64 = 0x01;
+pub const MAP_PRIVATE : u64 = 0x02;
+pub const MAP_ANONYMOUS : u64 = 0x20;
+pub const MAP_LOCKED : u64 = 0x2000;
+
+pub const MAP_FAILED : *mut c_void = usize::MAX as *mut c_void;
+
+pub const MREMAP_MAYMOVE : u64 = 1;
+pub const MREMAP_FIXED : u64 = 2;
+
+pub const MADV_NORMAL : i32 = 0;
+pub const MADV_RANDOM : i32 = 1;
+pub const MADV_SEQUENTIAL : i32 = 2;
+pub const MADV_WILLNEED : i32 = 3;
+pub const MADV_DONTNEED : i32 = 4;
+pub const MADV_FREE : i32 = 8;
+pub const MADV_MERGEABLE : i32 = 12;
+pub const MADV_UNMERGEABLE : i32 = 13;
+pub const MADV_HUGEPAGE : i32 = 14;
+pub const MADV_NOHUGEPAGE : i32 = 15;
+
+pub const MCL_CURRENT : i32 = 1;
+pub const MCL_FUTURE : i32 = 2;
+pub const MCL_ONFAULT : i32 = 4;
+
+pub fn mmap(addr : *mut c_void, length : usize, prot : u64, flags : u64, fd : i64, offset : usize) -> *mut c_void
+{
+    unsafe
+    {
+        __syscall6(syscall_defs::__NR_MMAP, addr as u64, length as u64, prot, flags, fd as u64, offset as u64) as *mut c_void
+    }
+}
+
+pub fn munmap(addr : *mut c_void, length : usize) -> i64
+{
+    unsafe
+    {
+        __syscall2(syscall_defs::__NR_MUNMAP, addr as u64, length as u64) as i64
+    }
+}
+
+// `new_addr` only counts with MREMAP_FIXED
+pub fn mremap(old_addr : *mut c_void, old_len : usize, new_len : usize, flags : u64, new_addr : *mut c_void) -> *mut c_void
+{
+    unsafe
+    {
+        __syscall5(syscall_defs::__NR_MREMAP, old_addr as u64, old_len as u64, new_len as u64, flags, new_addr as u64) as *mut c_void
+    }
+}
+
+pub fn mprotect(addr : *mut c_void, length : usize, prot : u64) -> i64
+{
+    unsafe
+    {
+        __syscall3(syscall_defs::__NR_MPROTECT, addr as u64, length as u64, prot) as i64
+    }
+}
+
+pub fn madvise(addr : *mut c_void, length : usize, advice : i32) -> i64
+{
+    unsafe
+    {
+        __syscall3(syscall_defs::__NR_MADVISE, addr as u64, length as u64, advice as u64) as i64
+    }
+}
+
+// one byte per page of the range, 1 when the page is in memory
+pub fn mincore(addr : *mut c_void, length : usize, vec : *mut u8) -> i64
+{
+    unsafe
+    {
+        __syscall3(syscall_defs::__NR_MINCORE, addr as u64, length as u64, vec as u64) as i64
+    }
+}
+
+pub fn mlock(addr : *const c_void, length : usize) -> i64
+{
+    unsafe
+    {
+        __syscall2(syscall_defs::__NR_MLOCK, addr as u64, length as u64) as i64
+    }
+}
+
+pub fn munlock(addr : *const c_void, length : usize) -> i64
+{
+    unsafe
+    {
+        __syscall2(syscall_defs::__NR_MUNLOCK, addr as u64, length as u64) as i64
+    }
+}
+
+pub fn mlockall(flags : i32) -> i64
+{
+    unsafe
+    {
+        __syscall1(syscall_defs::__NR_MLOCKALL, flags as u64) as i64
+    }
+}
+
+pub fn munlockall() -> i64
+{
+    unsafe
+    {
+        __syscall0(syscall_defs::__NR_MUNLOCKALL) as i64
+    }
+}
+
+// `name` is "/something", the object outlives every fd until shm_unlink
+pub fn shm_open(name : *const c_char, oflag : u64, mode : u64) -> i64
+{
+    unsafe
+    {
+        __syscall3(syscall_defs::__NR_SHM_OPEN, name as u64, oflag, mode) as i64
+    }
+}
+
+pub fn shm_unlink(name : *const c_char) -> i64
+{
+    unsafe
+    {
+        __syscall1(syscall_defs::__NR_SHM_UNLINK, name as u64) as i64
+    }
+}
@@ -2,10 +2,47 @@ use core::arch::asm;
 
 pub const __NR_READ : usize = 0;
 pub const __NR_WRITE : usize = 1;
+pub const __NR_OPEN : usize = 2;
+pub const __NR_CLOSE : usize = 3;
+pub const __NR_MMAP : usize = 9;
+pub const __NR_MPROTECT : usize = 10;
+pub const __NR_MUNMAP : usize = 11;
+pub const __NR_BRK : usize = 12;
+pub const __NR_PREAD64 : usize = 17;
 pub const __NR_SCHED_YIELD : usize = 24;
+pub const __NR_MREMAP : usize = 25;
+pub const __NR_MINCORE : usize = 27;
+pub const __NR_MADVISE : usize = 28;
 pub const __NR_FORK : usize = 57;
 pub const __NR_SYS_EXECVE : usize = 59;
 pub const __NR_EXIT : usize = 60;
+pub const __NR_FSYNC : usize = 74;
+pub const __NR_FTRUNCATE : usize = 77;
+pub const __NR_PERSONALITY : usize = 135;
+pub const __NR_MLOCK : usize = 149;
+pub const __NR_MUNLOCK : usize = 150;
+pub const __NR_MLOCKALL : usize = 151;
+pub const __NR_MUNLOCKALL : usize = 152;
+pub const __NR_ARCH_PRCTL : usize = 158;
+pub const __NR_SYNC : usize = 162;
+pub const __NR_SWAPON : usize = 167;
+pub const __NR_SWAPOFF : usize = 168;
+// lee_os only, a libc would build these on open and unlink under /dev/shm
+pub const __NR_SHM_OPEN : usize = 500;
+pub const __NR_SHM_UNLINK : usize = 501;
+
+pub const ARCH_SET_GS : u64 = 0x1001;
+pub const ARCH_SET_FS : u64 = 0x1002;
+pub const ARCH_GET_FS : u64 = 0x1003;
+pub const ARCH_GET_GS : u64 = 0x1004;
+
+// swapon flags
+pub const SWAP_FLAG_PREFER : u64 = 0x8000;
+pub const SWAP_FLAG_PRIO_MASK : u64 = 0x7fff;
+pub const SWAP_FLAG_PRIO_SHIFT : u64 = 0;
+
+// personality flags
+pub const ADDR_NO_RANDOMIZE : u64 = 0x0040000;
 
 pub unsafe fn __syscall0(nr : usize) -> usize
 {
@@ -13,7 +50,9 @@ pub unsafe fn __syscall0(nr : usize) -> usize
     asm!(
         "syscall",
         in("rax") nr,
-        lateout("rax") result
+        lateout("rax") result,
+        lateout("rcx") _,
+        lateout("r11") _
     );
     result
 }
@@ -26,7 +65,9 @@ pub unsafe fn __syscall1(nr : usize, arg1 : u64) -> usize
         "syscall",
         in("rax") nr,
         in("rdi") arg1,
-        lateout("rax") result
+        lateout("rax") result,
+        lateout("rcx") _,
+        lateout("r11") _
     );
     result
 }
@@ -40,7 +81,9 @@ pub unsafe fn __syscall2(nr : usize, arg1 : u64, arg2 : u64) -> usize
         in("rax") nr,
         in("rdi") arg1,
         in("rsi") arg2,
-        lateout("rax") result
+        lateout("rax") result,
+        lateout("rcx") _,
+        lateout("r11") _
     );
     result
 }
@@ -55,7 +98,9 @@ pub unsafe fn __syscall3(nr : usize, arg1 : u64, arg2 : u64, arg3 : u64) -> usiz
         in("rdi") arg1,
         in("rsi") arg2,
         in("rdx") arg3,
-        lateout("rax") result
+        lateout("rax") result,
+        lateout("rcx") _,
+        lateout("r11") _
     );
     result
 }
@@ -71,7 +116,9 @@ pub unsafe fn __syscall4(nr : usize, arg1 : u64, arg2 : u64, arg3 : u64, arg4 :
         in("rsi") arg2,
         in("rdx") arg3,
         in("r10") arg4,
-        lateout("rax") result
+        lateout("rax") result,
+        lateout("rcx") _,
+        lateout("r11") _
     );
     result
 }
@@ -88,7 +135,9 @@ pub unsafe fn __syscall5(nr : usize, arg1 : u64, arg2 : u64, arg3 : u64, arg4 :
         in("rdx") arg3,
         in("r10") arg4,
         in("r8") arg5,
-        lateout("rax") result
+        lateout("rax") result,
+        lateout("rcx") _,
+        lateout("r11") _
     );
     result
 }
@@ -106,7 +155,9 @@ pub unsafe fn __syscall6(nr : usize, arg1 : u64, arg2 : u64, arg3 : u64, arg4 :
         in("r10") arg4,
         in("r8") arg5,
         in("r9") arg6,
-        lateout("rax") result
+        lateout("rax") result,
+        lateout("rcx") _,
+        lateout("r11") _
     );
     result
 }
@@ -1,5 +1,5 @@
-use core::ffi::c_char;
-use crate::{syscall_defs::{self, __syscall0, __syscall1,  __syscall3}, println};
+use core::ffi::{c_char, c_void};
+use crate::{syscall_defs::{self, __syscall0, __syscall1, __syscall2, __syscall3, __syscall4}, println};
 
 pub fn write(fd : u32, buf : *const c_char, count : usize) -> usize
 {
@@ -34,3 +34,110 @@ pub fn execve(filename : *const c_char, argv : *const *const c_char, envp : *con
     }
 }
 
+
+
+pub fn arch_prctl(code : u64, addr : u64) -> i64
+{
+    unsafe
+    {
+        __syscall2(syscall_defs::__NR_ARCH_PRCTL, code, addr) as i64
+    }
+}
+
+pub fn personality(persona : u64) -> u64
+{
+    unsafe
+    {
+        __syscall1(syscall_defs::__NR_PERSONALITY, persona) as u64
+    }
+}
+
+// returns the new break, or the old one when it could not move
+pub fn brk(addr : u64) -> u64
+{
+    unsafe
+    {
+        __syscall1(syscall_defs::__NR_BRK, addr) as u64
+    }
+}
+
+// write every dirty block back to disk
+pub fn sync()
+{
+    unsafe
+    {
+        __syscall0(syscall_defs::__NR_SYNC);
+    }
+}
+
+pub fn fsync(fd : u32) -> i64
+{
+    unsafe
+    {
+        __syscall1(syscall_defs::__NR_FSYNC, fd as u64) as i64
+    }
+}
+
+pub fn swapon(path : *const c_char, swapflags : u64) -> i64
+{
+    unsafe
+    {
+        __syscall2(syscall_defs::__NR_SWAPON, path as u64, swapflags) as i64
+    }
+}
+
+pub fn swapoff(path : *const c_char) -> i64
+{
+    unsafe
+    {
+        __syscall1(syscall_defs::__NR_SWAPOFF, path as u64) as i64
+    }
+}
+
+pub const O_RDONLY : u64 = 0;
+pub const O_WRONLY : u64 = 0o1;
+pub const O_RDWR : u64 = 0o2;
+pub const O_CREAT : u64 = 0o100;
+pub const O_EXCL : u64 = 0o200;
+pub const O_TRUNC : u64 = 0o1000;
+
+pub fn open(path : *const c_char, flags : u64) -> i64
+{
+    unsafe
+    {
+        __syscall3(syscall_defs::__NR_OPEN, path as u64, flags, 0) as i64
+    }
+}
+
+pub fn close(fd : i64) -> i64
+{
+    unsafe
+    {
+        __syscall1(syscall_defs::__NR_CLOSE, fd as u64) as i64
+    }
+}
+
+pub fn read(fd : i64, buf : *mut c_void, count : usize) -> i64
+{
+    unsafe
+    {
+        __syscall3(syscall_defs::__NR_READ, fd as u64, buf as u64, count as u64) as i64
+    }
+}
+
+pub fn pread(fd : i64, buf : *mut c_void, count : usize, offset : usize) -> i64
+{
+    unsafe
+    {
+        __syscall4(syscall_defs::__NR_PREAD64, fd as u64, buf as u64, count as u64, offset as u64) as i64
+    }
+}
+
+// set the size of a shared memory object
+pub fn ftruncate(fd : i64, length : usize) -> i64
+{
+    unsafe
+    {
+        __syscall2(syscall_defs::__NR_FTRUNCATE, fd as u64, length as u64) as i64
+    }
+}
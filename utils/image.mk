@@ -2,10 +2,11 @@ $(BUILD)/master.img: $(BUILD)/boot/boot.asm.bin \
 	$(BUILD)/boot/loader.asm.bin \
 	./utils/master.sfdisk \
 	$(BUILD)/x86_64-unknown-none/debug/lee_os $(BUILD)/system.map \
-	$(BUILTIN_APP) ./utils/image.mk
+	$(BUILTIN_APP) $(LD_SO) ./utils/image.mk
 # 创建磁盘镜像
 	yes | bximage -q -hd=128 -func=create -sectsize=512 -imgmode=flat $@
 	dd if=$(BUILD)/boot/boot.asm.bin of=$@ bs=512 count=1 conv=notrunc
+	printf '%s\0' "$(CMDLINE)" | dd of=$@ bs=512 seek=1 count=1 conv=notrunc
 	dd if=$(BUILD)/boot/loader.asm.bin of=$@ bs=512 count=4 seek=2 conv=notrunc
 	dd if=$(BUILD)/x86_64-unknown-none/debug/lee_os of=$@ bs=512 seek=10 conv=notrunc
 
@@ -21,6 +22,10 @@ $(BUILD)/master.img: $(BUILD)/boot/boot.asm.bin \
 	mkdir -p /mnt/LeeOSDisk/bin
 	mkdir -p /mnt/LeeOSDisk/dev
 	mkdir -p /mnt/LeeOSDisk/mnt
+	mkdir -p /mnt/LeeOSDisk/lib
+	mkdir -p /mnt/LeeOSDisk/proc
+
+	cp $(LD_SO) /mnt/LeeOSDisk/lib/ld-leeos-x86-64.so
 
 	for app in $(BUILTIN_APP); \
 	do \